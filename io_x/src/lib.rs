@@ -25,6 +25,7 @@ pin_project! {
         inner: T,
         traffic_counter: Family<R, Counter>,
         label: R,
+        extra_counter: Option<Counter>,
     }
 }
 
@@ -38,8 +39,21 @@ where
             inner,
             traffic_counter,
             label,
+            extra_counter: None,
         }
     }
+
+    /// 额外累加到另一个counter，例如按用户汇总的流量
+    pub fn with_extra_counter(mut self, counter: Counter) -> Self {
+        self.extra_counter = Some(counter);
+        self
+    }
+}
+
+fn inc_extra(extra_counter: &Option<Counter>, size: u64) {
+    if let Some(counter) = extra_counter {
+        counter.inc_by(size);
+    }
 }
 
 impl<T, R> AsyncRead for CounterIO<T, R>
@@ -56,6 +70,7 @@ where
         match pro.inner.poll_read(cx, buf) {
             Poll::Ready(Ok(_)) => {
                 traffic_counter.get_or_create(label).inc_by(buf.filled().len() as u64);
+                inc_extra(pro.extra_counter, buf.filled().len() as u64);
                 Poll::Ready(Ok(()))
             }
             other => other,
@@ -76,6 +91,7 @@ where
             Poll::Ready(result) => {
                 if let Ok(size) = result {
                     traffic_counter.get_or_create(label).inc_by(size as u64);
+                    inc_extra(pro.extra_counter, size as u64);
                 }
                 Poll::Ready(result)
             }
@@ -101,6 +117,7 @@ where
         let pro = self.project();
         let count = bufs.iter().map(|buf| buf.len()).sum::<usize>() as u64;
         pro.traffic_counter.get_or_create(pro.label).inc_by(count);
+        inc_extra(pro.extra_counter, count);
         pro.inner.poll_write_vectored(cx, bufs)
     }
 }
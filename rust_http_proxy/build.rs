@@ -0,0 +1,61 @@
+//! 设置了 `RUST_HTTP_PROXY_EMBED_DIR` 时，将该目录下的文件嵌入到可执行文件中，配合 `--serve-embedded` 使用
+
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use std::{env, fs, io};
+
+const EMBED_DIR_ENV: &str = "RUST_HTTP_PROXY_EMBED_DIR";
+
+fn main() -> io::Result<()> {
+    println!("cargo:rerun-if-env-changed={EMBED_DIR_ENV}");
+    let mut files = Vec::new();
+    if let Some(dir) = env::var_os(EMBED_DIR_ENV).filter(|dir| !dir.is_empty()) {
+        let dir = fs::canonicalize(PathBuf::from(dir))?;
+        println!("cargo:rerun-if-changed={}", dir.display());
+        collect(&dir, &dir, &mut files)?;
+        files.sort();
+    }
+    let mut generated = String::from("&[\n");
+    for (url_path, path, modified) in &files {
+        // rerun-if-changed只检查目录本身的mtime，文件的修改也需要触发重新构建
+        println!("cargo:rerun-if-changed={}", path.display());
+        let _ = writeln!(
+            generated,
+            "    EmbeddedFile {{ path: {url_path:?}, data: include_bytes!({:?}), modified_secs: {modified} }},",
+            path.display().to_string()
+        );
+    }
+    generated.push(']');
+    let out_dir = env::var_os("OUT_DIR").ok_or_else(|| io::Error::other("OUT_DIR is not set"))?;
+    fs::write(Path::new(&out_dir).join("embedded_web_content.rs"), generated)
+}
+
+/// 跳过以 `.` 开头的文件和目录，例如 `.git`
+fn collect(root: &Path, dir: &Path, files: &mut Vec<(String, PathBuf, u64)>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        let path = entry.path();
+        let meta = fs::metadata(&path)?;
+        if meta.is_dir() {
+            println!("cargo:rerun-if-changed={}", path.display());
+            collect(root, &path, files)?;
+        } else if meta.is_file() {
+            let relative = path.strip_prefix(root).map_err(io::Error::other)?;
+            let url_path = relative
+                .components()
+                .map(|component| component.as_os_str().to_string_lossy())
+                .fold(String::new(), |url_path, component| url_path + "/" + &component);
+            let modified = meta
+                .modified()?
+                .duration_since(UNIX_EPOCH)
+                .map_err(io::Error::other)?
+                .as_secs();
+            files.push((url_path, path, modified));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,135 @@
+//! 访问控制的决策日志：每次允许或拒绝都输出一行logfmt格式的记录，包含生效的规则、客户端和目标，便于grep和审计
+//!
+//! 例如 `access_decision=deny rule=allowed_networks client=1.2.3.4 target="GET /index.html" reason="..."`
+
+use std::fmt::{self, Display, Formatter};
+use std::net::IpAddr;
+
+use clap::ValueEnum;
+use log::{info, warn};
+
+/// 输出哪些访问控制决策
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum AccessDecisionLog {
+    /// 只记录拒绝
+    #[default]
+    Deny,
+    /// 同时记录允许
+    All,
+}
+
+/// 做出决策的规则
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Rule {
+    /// --prohibit-serving
+    ProhibitServing,
+    /// --allow-serving-network
+    AllowedNetworks,
+    /// 代理的Proxy-Authorization
+    ProxyAuth,
+    /// /metrics等管理接口的Authorization
+    AdminAuth,
+    /// --auth-static时静态文件托管的Authorization
+    StaticAuth,
+    /// --listener-mode不允许正向代理
+    ListenerMode,
+    /// --allow-connect-port
+    AllowConnectPort,
+    /// --allow-method
+    AllowMethod,
+}
+
+impl Rule {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Rule::ProhibitServing => "prohibit_serving",
+            Rule::AllowedNetworks => "allowed_networks",
+            Rule::ProxyAuth => "proxy_auth",
+            Rule::AdminAuth => "admin_auth",
+            Rule::StaticAuth => "static_auth",
+            Rule::ListenerMode => "listener_mode",
+            Rule::AllowConnectPort => "allow_connect_port",
+            Rule::AllowMethod => "allow_method",
+        }
+    }
+}
+
+pub(crate) struct AccessDecision<'a> {
+    pub(crate) allow: bool,
+    pub(crate) rule: Rule,
+    pub(crate) client: IpAddr,
+    pub(crate) target: &'a dyn Display,
+    pub(crate) reason: &'a str,
+}
+
+impl AccessDecision<'_> {
+    pub(crate) fn allow(rule: Rule, client: IpAddr, target: &dyn Display, reason: &str) {
+        AccessDecision {
+            allow: true,
+            rule,
+            client,
+            target,
+            reason,
+        }
+        .log();
+    }
+
+    pub(crate) fn deny(rule: Rule, client: IpAddr, target: &dyn Display, reason: &str) {
+        AccessDecision {
+            allow: false,
+            rule,
+            client,
+            target,
+            reason,
+        }
+        .log();
+    }
+
+    fn log(&self) {
+        if self.allow && crate::CONFIG.access_decision_log != AccessDecisionLog::All {
+            return;
+        }
+        if crate::CONFIG.access_decision_to_access_log {
+            crate::access_log::emit(self.to_string());
+        } else if self.allow {
+            info!("{self}");
+        } else {
+            warn!("{self}");
+        }
+    }
+}
+
+impl Display for AccessDecision<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "access_decision={} rule={} client={} target={:?} reason={:?}",
+            if self.allow { "allow" } else { "deny" },
+            self.rule.as_str(),
+            self.client.to_canonical(),
+            self.target.to_string(),
+            self.reason
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format() {
+        let decision = AccessDecision {
+            allow: false,
+            rule: Rule::AllowedNetworks,
+            client: IpAddr::from([0, 0, 0, 0, 0, 0xffff, 0x0102, 0x0304]),
+            target: &"GET /index.html",
+            reason: "client is not in \"--allow-serving-network\"",
+        };
+        assert_eq!(
+            decision.to_string(),
+            "access_decision=deny rule=allowed_networks client=1.2.3.4 target=\"GET /index.html\" \
+            reason=\"client is not in \\\"--allow-serving-network\\\"\""
+        );
+    }
+}
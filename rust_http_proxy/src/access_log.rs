@@ -0,0 +1,281 @@
+//! NCSA Common/Combined格式的访问日志，便于复用现成的weblog分析工具
+//!
+//! 响应体发送完毕（或连接中断导致响应体被丢弃）时输出一行，`%b` 为实际发送的响应体字节数。
+
+use std::{
+    error::Error as StdError,
+    io::{self, Write},
+    net::IpAddr,
+    pin::Pin,
+    sync::OnceLock,
+    task::{Context, Poll},
+};
+
+use chrono::{DateTime, Local};
+use clap::ValueEnum;
+use http::{header, HeaderMap, Method, Uri, Version};
+use hyper::body::{Body, Bytes, Frame, SizeHint};
+use log::{info, warn};
+use pin_project_lite::pin_project;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::{channel, error::TrySendError, Sender};
+
+use crate::METRICS;
+
+/// 访问日志格式
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    /// 不输出单独的访问日志
+    #[default]
+    Default,
+    /// NCSA Common Log Format
+    Common,
+    /// NCSA Combined Log Format，在Common的基础上增加Referer和User-Agent
+    Combined,
+}
+
+/// location的 `access_log`，覆盖全局的访问日志设置
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum LocationAccessLog {
+    /// 与全局设置相同
+    On,
+    /// 不输出访问日志，反向代理的请求日志降为debug级别
+    Off,
+    /// Combined格式降为Common格式，反向代理的请求日志只保留客户端、方法和上游URL
+    Terse,
+}
+
+impl LocationAccessLog {
+    /// 该location实际使用的访问日志格式
+    pub(crate) fn format(self, global: LogFormat) -> LogFormat {
+        match (self, global) {
+            (LocationAccessLog::Off, _) => LogFormat::Default,
+            (LocationAccessLog::Terse, LogFormat::Combined) => LogFormat::Common,
+            (_, global) => global,
+        }
+    }
+}
+
+static ACCESS_LOG_WRITER: OnceLock<Sender<String>> = OnceLock::new();
+/// 等待写入文件的最大行数，写入跟不上时丢弃新的日志并计入 `access_log_dropped`，避免占用的内存无限增长
+const ACCESS_LOG_QUEUE: usize = 64 * 1024;
+
+/// 指定了访问日志文件时，由单独的task追加写入，避免阻塞请求处理；否则输出到主日志
+pub(crate) fn init(access_log_file: Option<&str>) -> io::Result<()> {
+    let Some(path) = access_log_file else {
+        return Ok(());
+    };
+    let file = std::fs::OpenOptions::new().create(true).append(true).open(path)?;
+    let (tx, mut rx) = channel::<String>(ACCESS_LOG_QUEUE);
+    tokio::spawn(async move {
+        let mut writer = io::LineWriter::new(file);
+        while let Some(line) = rx.recv().await {
+            if let Err(e) = writeln!(writer, "{line}") {
+                warn!("write access log error: {e}");
+            }
+        }
+    });
+    let _ = ACCESS_LOG_WRITER.set(tx);
+    info!("access log is output to {path}");
+    Ok(())
+}
+
+pub(crate) fn emit(line: String) {
+    match ACCESS_LOG_WRITER.get() {
+        Some(tx) => try_send(tx, line),
+        None => info!(target: "access_log", "{line}"),
+    }
+}
+
+fn try_send(tx: &Sender<String>, line: String) {
+    if let Err(TrySendError::Full(_) | TrySendError::Closed(_)) = tx.try_send(line) {
+        METRICS.access_log_dropped.inc();
+    }
+}
+
+/// 一次请求的访问日志，需在请求被处理前记录请求信息
+pub(crate) struct AccessLogEntry {
+    client_ip: IpAddr,
+    username: Option<String>,
+    time: DateTime<Local>,
+    request_line: String,
+    referer: Option<String>,
+    user_agent: Option<String>,
+    request_id: Option<String>,
+}
+
+impl AccessLogEntry {
+    pub(crate) fn new(
+        client_ip: IpAddr, username: Option<String>, method: &Method, uri: &Uri, version: Version, headers: &HeaderMap,
+        request_id: Option<&str>,
+    ) -> Self {
+        let header_value = |name| headers.get(name).and_then(|v| v.to_str().ok()).map(str::to_string);
+        AccessLogEntry {
+            client_ip: client_ip.to_canonical(),
+            username,
+            time: Local::now(),
+            request_line: format!("{method} {uri} {version:?}"),
+            referer: header_value(header::REFERER),
+            user_agent: header_value(header::USER_AGENT),
+            request_id: request_id.map(str::to_string),
+        }
+    }
+
+    fn format(&self, format: LogFormat, status: u16, bytes: u64) -> String {
+        let mut line = format!(
+            "{} - {} [{}] \"{}\" {} {}",
+            self.client_ip,
+            self.username.as_deref().map(escape).unwrap_or("-".to_string()),
+            self.time.format("%d/%b/%Y:%H:%M:%S %z"),
+            escape(&self.request_line),
+            status,
+            if bytes == 0 { "-".to_string() } else { bytes.to_string() },
+        );
+        if format == LogFormat::Combined {
+            line.push_str(&format!(
+                " \"{}\" \"{}\"",
+                self.referer.as_deref().map(escape).unwrap_or("-".to_string()),
+                self.user_agent.as_deref().map(escape).unwrap_or("-".to_string()),
+            ));
+        }
+        // 开启--request-id-header时追加在行尾
+        if let Some(request_id) = &self.request_id {
+            line.push_str(&format!(" \"{}\"", escape(request_id)));
+        }
+        line
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+struct AccessLogGuard {
+    entry: AccessLogEntry,
+    format: LogFormat,
+    status: u16,
+    bytes: u64,
+}
+
+impl Drop for AccessLogGuard {
+    fn drop(&mut self) {
+        emit(self.entry.format(self.format, self.status, self.bytes));
+    }
+}
+
+pin_project! {
+    /// 统计响应体的字节数，被丢弃时输出访问日志
+    pub(crate) struct AccessLogBody<B> {
+        #[pin]
+        inner: B,
+        guard: AccessLogGuard,
+    }
+}
+
+impl<B> AccessLogBody<B> {
+    pub(crate) fn new(inner: B, entry: AccessLogEntry, format: LogFormat, status: u16) -> Self {
+        AccessLogBody {
+            inner,
+            guard: AccessLogGuard {
+                entry,
+                format,
+                status,
+                bytes: 0,
+            },
+        }
+    }
+}
+
+impl<B> Body for AccessLogBody<B>
+where
+    B: Body<Data = Bytes>,
+    B::Error: Into<Box<dyn StdError + Send + Sync>>,
+{
+    type Data = Bytes;
+    type Error = B::Error;
+
+    fn poll_frame(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = self.project();
+        let poll = this.inner.poll_frame(cx);
+        if let Poll::Ready(Some(Ok(frame))) = &poll {
+            if let Some(data) = frame.data_ref() {
+                this.guard.bytes += data.len() as u64;
+            }
+        }
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use http::HeaderValue;
+
+    #[tokio::test]
+    async fn test_try_send() {
+        crate::test_server::init_config();
+        let (tx, mut rx) = channel::<String>(1);
+        let dropped = METRICS.access_log_dropped.get();
+        try_send(&tx, "first".to_string());
+        try_send(&tx, "second".to_string());
+        assert!(METRICS.access_log_dropped.get() > dropped);
+        assert_eq!(rx.try_recv().ok().as_deref(), Some("first"));
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_location_access_log_format() {
+        assert_eq!(LocationAccessLog::Off.format(LogFormat::Combined), LogFormat::Default);
+        assert_eq!(LocationAccessLog::Terse.format(LogFormat::Combined), LogFormat::Common);
+        assert_eq!(LocationAccessLog::Terse.format(LogFormat::Default), LogFormat::Default);
+        assert_eq!(LocationAccessLog::On.format(LogFormat::Combined), LogFormat::Combined);
+    }
+
+    #[test]
+    fn test_format() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::REFERER, HeaderValue::from_static("https://www.google.com/"));
+        headers.insert(header::USER_AGENT, HeaderValue::from_static("curl/8.0 \"quoted\""));
+        let uri: Uri = "/index.html?a=1".parse().unwrap_or_default();
+        let mut entry = AccessLogEntry::new(
+            IpAddr::from([127, 0, 0, 1]),
+            Some("arloor".to_string()),
+            &Method::GET,
+            &uri,
+            Version::HTTP_11,
+            &headers,
+            None,
+        );
+        if let Some(time) = chrono::FixedOffset::east_opt(8 * 3600)
+            .and_then(|tz| tz.with_ymd_and_hms(2024, 10, 10, 13, 55, 36).single())
+        {
+            entry.time = time.with_timezone(&Local);
+        }
+        let time = entry.time.format("%d/%b/%Y:%H:%M:%S %z").to_string();
+        assert_eq!(
+            entry.format(LogFormat::Common, 200, 2326),
+            format!("127.0.0.1 - arloor [{time}] \"GET /index.html?a=1 HTTP/1.1\" 200 2326")
+        );
+        assert_eq!(
+            entry.format(LogFormat::Combined, 304, 0),
+            format!(
+                "127.0.0.1 - arloor [{time}] \"GET /index.html?a=1 HTTP/1.1\" 304 - \"https://www.google.com/\" \"curl/8.0 \\\"quoted\\\"\""
+            )
+        );
+        entry.request_id = Some("abc-123".to_string());
+        assert_eq!(
+            entry.format(LogFormat::Common, 200, 2326),
+            format!("127.0.0.1 - arloor [{time}] \"GET /index.html?a=1 HTTP/1.1\" 200 2326 \"abc-123\"")
+        );
+    }
+}
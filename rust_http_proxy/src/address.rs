@@ -22,6 +22,13 @@ impl Address {
             Address::DomainNameAddress(host, _) => host.to_owned(),
         }
     }
+
+    pub fn port(&self) -> u16 {
+        match self {
+            Address::SocketAddress(addr) => addr.port(),
+            Address::DomainNameAddress(_, port) => *port,
+        }
+    }
 }
 
 // to_string() -> host:port
@@ -1,14 +1,17 @@
+use crate::access_decision::{AccessDecision, Rule};
 use crate::metrics::METRICS;
+use crate::traffic_report::TRAFFIC_REPORT;
 use askama::Template;
-use axum::extract::{ConnectInfo, MatchedPath, State};
+use axum::extract::{ConnectInfo, MatchedPath, Query, State};
 use axum::response::{Html, IntoResponse, Response};
-use axum::routing::get;
+use axum::routing::{get, post};
 use axum::Router;
 use axum_bootstrap::AppError;
 
-use http::{header, HeaderMap, HeaderName, HeaderValue, StatusCode};
-use log::{debug, warn};
+use http::{header, HeaderMap, HeaderName, HeaderValue, StatusCode, Uri};
+use log::warn;
 use prometheus_client::encoding::text::encode;
+use serde::Deserialize;
 use std::collections::HashMap;
 use std::fmt::Display;
 use std::io;
@@ -27,35 +30,23 @@ pub(crate) const BODY404: &str = include_str!("../html/404.html");
 
 pub(crate) struct AppState {
     pub basic_auth: HashMap<String, String>,
+    /// 是否为--admin-port上的router
+    pub on_admin_port: bool,
 }
 
 pub(crate) fn build_router(appstate: AppState) -> Router {
     // build our application with a route
-    let router = Router::new()
-        .route(
-            "/ip",
-            get(|ConnectInfo(addr): ConnectInfo<SocketAddr>| async move {
-                (StatusCode::OK, addr.ip().to_canonical().to_string())
-            }),
-        )
-        .route("/metrics", get(serve_metrics))
-        .fallback(get(|| async {
-            let mut header_map = HeaderMap::new();
-            #[allow(clippy::expect_used)]
-            header_map.insert(header::CONTENT_TYPE, HeaderValue::from_static("text/html; charset=utf-8"));
-            (StatusCode::NOT_FOUND, header_map, BODY404)
-        }))
-        .layer((
-            TraceLayer::new_for_http() // Create our own span for the request and include the matched path. The matched
-                // path is useful for figuring out which handler the request was routed to.
-                .make_span_with(make_span)
-                // By default `TraceLayer` will log 5xx responses but we're doing our specific
-                // logging of errors so disable that
-                .on_failure(()),
-            CorsLayer::permissive(),
-            TimeoutLayer::new(Duration::from_secs(30)),
-            CompressionLayer::new(),
-        ));
+    let router = Router::new().route("/ip", get(serve_ip));
+    // 设置了--admin-port时，管理接口只在该端口上提供
+    let router = match crate::CONFIG.admin_port {
+        Some(_) => router,
+        None => router.merge(admin_routes()),
+    };
+    let router = with_layers(router);
+    let router = match crate::CONFIG.admin_port {
+        Some(_) => router,
+        None => router.merge(mutating_admin_routes()),
+    };
     #[cfg(target_os = "linux")]
     let router = router
         .route("/nt", get(linux_axum_handler::count_stream))
@@ -66,6 +57,55 @@ pub(crate) fn build_router(appstate: AppState) -> Router {
     router.with_state(Arc::new(appstate))
 }
 
+/// --admin-port上的router，只提供管理接口
+pub(crate) fn build_admin_router(appstate: AppState) -> Router {
+    with_layers(admin_routes())
+        .merge(mutating_admin_routes())
+        .with_state(Arc::new(appstate))
+}
+
+fn admin_routes() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/metrics", get(serve_metrics))
+        .route("/traffic_report", get(serve_traffic_report))
+        .route("/uploads", get(serve_uploads))
+}
+
+/// 修改状态的管理接口，不允许跨域访问，见 [`mutating_admin_unauthorized`]
+fn mutating_admin_routes() -> Router<Arc<AppState>> {
+    with_base_layers(
+        Router::new()
+            .route("/fault_injection", get(fault_injection_status).post(toggle_fault_injection))
+            .route("/admin/reload", post(reload_config)),
+    )
+}
+
+fn with_layers(router: Router<Arc<AppState>>) -> Router<Arc<AppState>> {
+    with_base_layers(
+        router
+            .fallback(get(|| async {
+                let mut header_map = HeaderMap::new();
+                #[allow(clippy::expect_used)]
+                header_map.insert(header::CONTENT_TYPE, HeaderValue::from_static("text/html; charset=utf-8"));
+                (StatusCode::NOT_FOUND, header_map, BODY404)
+            }))
+            .layer(CorsLayer::permissive()),
+    )
+}
+
+fn with_base_layers(router: Router<Arc<AppState>>) -> Router<Arc<AppState>> {
+    router.layer((
+        TraceLayer::new_for_http() // Create our own span for the request and include the matched path. The matched
+            // path is useful for figuring out which handler the request was routed to.
+            .make_span_with(make_span)
+            // By default `TraceLayer` will log 5xx responses but we're doing our specific
+            // logging of errors so disable that
+            .on_failure(()),
+        TimeoutLayer::new(Duration::from_secs(30)),
+        CompressionLayer::new(),
+    ))
+}
+
 fn make_span(req: &http::Request<axum::body::Body>) -> tracing::Span {
     let method = req.method();
     let path = req.uri().path();
@@ -79,9 +119,13 @@ fn make_span(req: &http::Request<axum::body::Body>) -> tracing::Span {
     tracing::debug_span!("recv request", %method, %path, matched_path)
 }
 
-pub(crate) const AXUM_PATHS: [&str; 6] = [
+pub(crate) const AXUM_PATHS: [&str; 10] = [
     "/ip",
     "/metrics",
+    "/traffic_report",
+    "/uploads",
+    "/fault_injection",
+    "/admin/reload",
     "/nt",       // netstat
     "/net",      // net html
     "/netx",     // net extended html
@@ -99,15 +143,9 @@ pub(crate) fn check_auth(
     // Get Authorization header
     let auth_header = headers
         .get(header_name)
-        .ok_or_else(|| {
-            warn!("no Authorization header found");
-            std::io::Error::new(std::io::ErrorKind::NotFound, "no Authorization header found")
-        })?
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "no Authorization header found"))?
         .to_str()
-        .map_err(|e| {
-            warn!("Failed to parse Authorization header: {e:?}");
-            std::io::Error::new(std::io::ErrorKind::PermissionDenied, e)
-        })?;
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::PermissionDenied, e))?;
 
     // Check if auth header matches any configured auth
     for (key, value) in basic_auth {
@@ -119,27 +157,158 @@ pub(crate) fn check_auth(
     Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, "wrong Authorization header value"))
 }
 
+/// 客户端自己的IP，已按--real-ip-header解析，不需要鉴权。`Accept: application/json` 时返回JSON
+async fn serve_ip(ConnectInfo(addr): ConnectInfo<SocketAddr>, headers: HeaderMap) -> (StatusCode, HeaderMap, String) {
+    let ip = addr.ip().to_canonical().to_string();
+    let mut header_map = HeaderMap::new();
+    header_map.insert(header::VARY, HeaderValue::from_static("accept"));
+    let wants_json = headers
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains("application/json"));
+    if wants_json {
+        header_map.insert(header::CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        return (StatusCode::OK, header_map, serde_json::json!({ "ip": ip }).to_string());
+    }
+    header_map.insert(header::CONTENT_TYPE, HeaderValue::from_static("text/plain; charset=utf-8"));
+    (StatusCode::OK, header_map, ip)
+}
+
 async fn serve_metrics(
-    State(state): State<Arc<AppState>>, headers: HeaderMap,
+    State(state): State<Arc<AppState>>, ConnectInfo(addr): ConnectInfo<SocketAddr>, uri: Uri, headers: HeaderMap,
+) -> Result<(StatusCode, HeaderMap, String), AppError> {
+    if let Some(resp) = admin_unauthorized(&headers, &state, addr, &uri) {
+        return Ok(resp);
+    }
+
+    #[cfg(all(target_os = "linux", feature = "bpf"))]
+    crate::ebpf::snapshot_metrics();
+    let mut buffer = String::new();
+    encode(&mut buffer, &METRICS.registry).map_err(AppError::new)?;
+    Ok((http::StatusCode::OK, HeaderMap::new(), buffer))
+}
+
+#[derive(Deserialize)]
+struct TrafficReportQuery {
+    format: Option<String>,
+}
+
+/// 按用户汇总的流量报表，`?format=csv` 返回CSV，默认返回JSON
+async fn serve_traffic_report(
+    State(state): State<Arc<AppState>>, ConnectInfo(addr): ConnectInfo<SocketAddr>, uri: Uri, headers: HeaderMap,
+    Query(query): Query<TrafficReportQuery>,
+) -> Result<(StatusCode, HeaderMap, String), AppError> {
+    if let Some(resp) = admin_unauthorized(&headers, &state, addr, &uri) {
+        return Ok(resp);
+    }
+    let mut header_map = HeaderMap::new();
+
+    let snapshot = TRAFFIC_REPORT.snapshot(crate::CONFIG.traffic_report_period, chrono::Local::now());
+    let body = match query.format.as_deref() {
+        Some("csv") => {
+            header_map.insert(header::CONTENT_TYPE, HeaderValue::from_static("text/csv; charset=utf-8"));
+            header_map.insert(
+                header::CONTENT_DISPOSITION,
+                HeaderValue::from_static("attachment; filename=\"traffic_report.csv\""),
+            );
+            snapshot.to_csv()
+        }
+        Some("json") | None => {
+            header_map.insert(header::CONTENT_TYPE, HeaderValue::from_static("application/json"));
+            serde_json::to_string(&snapshot).map_err(AppError::new)?
+        }
+        Some(other) => return Ok((StatusCode::BAD_REQUEST, header_map, format!("unsupported format: {other}"))),
+    };
+    Ok((StatusCode::OK, header_map, body))
+}
+
+/// 进行中的上传，JSON数组，见--upload-progress-min-bytes
+async fn serve_uploads(
+    State(state): State<Arc<AppState>>, ConnectInfo(addr): ConnectInfo<SocketAddr>, uri: Uri, headers: HeaderMap,
 ) -> Result<(StatusCode, HeaderMap, String), AppError> {
+    if let Some(resp) = admin_unauthorized(&headers, &state, addr, &uri) {
+        return Ok(resp);
+    }
     let mut header_map = HeaderMap::new();
-    match check_auth(&headers, http::header::AUTHORIZATION, &state.basic_auth) {
-        Ok(some_user) => {
-            debug!("authorized request from [{some_user:?}]");
+    header_map.insert(header::CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    let body = serde_json::to_string(&crate::upload_progress::snapshot()).map_err(AppError::new)?;
+    Ok((StatusCode::OK, header_map, body))
+}
+
+#[derive(Deserialize)]
+struct FaultInjectionQuery {
+    enabled: bool,
+}
+
+/// 管理接口的鉴权，方式同Prometheus Exporter，失败时返回401响应
+fn admin_unauthorized(
+    headers: &HeaderMap, state: &AppState, addr: SocketAddr, uri: &Uri,
+) -> Option<(StatusCode, HeaderMap, String)> {
+    match check_auth(headers, http::header::AUTHORIZATION, &state.basic_auth) {
+        Ok(Some(user)) => {
+            AccessDecision::allow(Rule::AdminAuth, addr.ip(), &uri.path(), &format!("authorized as {user}"));
+            None
         }
+        Ok(None) => None,
         Err(e) => {
-            warn!("authorization failed: {e:?}");
+            AccessDecision::deny(Rule::AdminAuth, addr.ip(), &uri.path(), &e.to_string());
+            let mut header_map = HeaderMap::new();
             header_map
                 .insert(http::header::WWW_AUTHENTICATE, HeaderValue::from_static("Basic realm=\"are you kidding me\""));
-            return Ok((http::StatusCode::UNAUTHORIZED, header_map, format!("{e}")));
+            Some((http::StatusCode::UNAUTHORIZED, header_map, format!("{e}")))
         }
     }
+}
 
-    #[cfg(all(target_os = "linux", feature = "bpf"))]
-    crate::ebpf::snapshot_metrics();
-    let mut buffer = String::new();
-    encode(&mut buffer, &METRICS.registry).map_err(AppError::new)?;
-    Ok((http::StatusCode::OK, header_map, buffer))
+/// 修改状态的管理接口：没有配置--users时只能在--admin-port上访问，否则任何人都能通过代理端口修改
+fn mutating_admin_unauthorized(
+    headers: &HeaderMap, state: &AppState, addr: SocketAddr, uri: &Uri,
+) -> Option<(StatusCode, HeaderMap, String)> {
+    if state.basic_auth.is_empty() && !state.on_admin_port {
+        AccessDecision::deny(Rule::AdminAuth, addr.ip(), &uri.path(), "requires --users or --admin-port");
+        return Some((StatusCode::FORBIDDEN, HeaderMap::new(), "requires --users or --admin-port".to_string()));
+    }
+    admin_unauthorized(headers, state, addr, uri)
+}
+
+async fn fault_injection_status(
+    State(state): State<Arc<AppState>>, ConnectInfo(addr): ConnectInfo<SocketAddr>, uri: Uri, headers: HeaderMap,
+) -> (StatusCode, HeaderMap, String) {
+    if let Some(resp) = admin_unauthorized(&headers, &state, addr, &uri) {
+        return resp;
+    }
+    (StatusCode::OK, HeaderMap::new(), format!("enabled: {}", crate::fault_injection::is_enabled()))
+}
+
+/// `POST /fault_injection?enabled=true|false` 打开或关闭故障注入
+async fn toggle_fault_injection(
+    State(state): State<Arc<AppState>>, ConnectInfo(addr): ConnectInfo<SocketAddr>, uri: Uri, headers: HeaderMap,
+    Query(query): Query<FaultInjectionQuery>,
+) -> (StatusCode, HeaderMap, String) {
+    if let Some(resp) = mutating_admin_unauthorized(&headers, &state, addr, &uri) {
+        return resp;
+    }
+    crate::fault_injection::set_enabled(query.enabled);
+    (StatusCode::OK, HeaderMap::new(), format!("enabled: {}", query.enabled))
+}
+
+/// `POST /admin/reload` 重新加载反向代理配置，返回JSON格式的变化或错误
+async fn reload_config(
+    State(state): State<Arc<AppState>>, ConnectInfo(addr): ConnectInfo<SocketAddr>, uri: Uri, headers: HeaderMap,
+) -> (StatusCode, HeaderMap, String) {
+    if let Some(resp) = mutating_admin_unauthorized(&headers, &state, addr, &uri) {
+        return resp;
+    }
+    let mut header_map = HeaderMap::new();
+    header_map.insert(header::CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    let (status, body) = match crate::remote_config::reload(crate::CONFIG.reverse_proxy_config_url.as_deref()).await {
+        Ok(summary) => (StatusCode::OK, serde_json::json!({ "ok": true, "summary": summary })),
+        Err(e) => {
+            warn!("reload reverse proxy config error, keep the current one: {e}");
+            (StatusCode::BAD_REQUEST, serde_json::json!({ "ok": false, "error": e.to_string() }))
+        }
+    };
+    (status, header_map, body.to_string())
 }
 
 #[derive(Template)]
@@ -215,3 +384,78 @@ impl AppProxyError {
         Self(anyhow!(err))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use tower::ServiceExt;
+
+    async fn send(router: &Router, req: http::Request<Body>) -> Result<Response, crate::DynError> {
+        let mut req = req;
+        req.extensions_mut()
+            .insert(ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 40000))));
+        Ok(router.clone().oneshot(req).await?)
+    }
+
+    fn router_of(basic_auth: &[(&str, &str)], on_admin_port: bool) -> Router {
+        build_admin_router(AppState {
+            basic_auth: basic_auth
+                .iter()
+                .map(|(header, user)| (header.to_string(), user.to_string()))
+                .collect(),
+            on_admin_port,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_reload_requires_users_or_admin_port() -> Result<(), crate::DynError> {
+        crate::test_server::init_config();
+        let _reverse_config = crate::test_server::REVERSE_CONFIG_LOCK.lock().await;
+        let reload = || http::Request::post("/admin/reload").body(Body::empty());
+        // 没有--users时，代理端口上不能重新加载
+        assert_eq!(send(&router_of(&[], false), reload()?).await?.status(), StatusCode::FORBIDDEN);
+        assert_eq!(send(&router_of(&[], true), reload()?).await?.status(), StatusCode::OK);
+        let with_users = router_of(&[("Basic dXNlcjpwYXNz", "user")], false);
+        assert_eq!(send(&with_users, reload()?).await?.status(), StatusCode::UNAUTHORIZED);
+        let authorized = http::Request::post("/admin/reload")
+            .header(header::AUTHORIZATION, "Basic dXNlcjpwYXNz")
+            .body(Body::empty())?;
+        assert_eq!(send(&with_users, authorized).await?.status(), StatusCode::OK);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_toggle_fault_injection_requires_users_or_admin_port() -> Result<(), crate::DynError> {
+        crate::test_server::init_config();
+        let enable = || http::Request::post("/fault_injection?enabled=true").body(Body::empty());
+        assert_eq!(send(&router_of(&[], false), enable()?).await?.status(), StatusCode::FORBIDDEN);
+        let with_users = router_of(&[("Basic dXNlcjpwYXNz", "user")], false);
+        assert_eq!(send(&with_users, enable()?).await?.status(), StatusCode::UNAUTHORIZED);
+        // 查看状态不修改，与/metrics一样
+        let status = http::Request::get("/fault_injection").body(Body::empty())?;
+        assert_eq!(send(&router_of(&[], false), status).await?.status(), StatusCode::OK);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_no_cors_for_mutating_admin_routes() -> Result<(), crate::DynError> {
+        crate::test_server::init_config();
+        let router = router_of(&[], true);
+        let preflight = |path: &str| {
+            http::Request::builder()
+                .method(http::Method::OPTIONS)
+                .uri(path)
+                .header(header::ORIGIN, "https://evil.example")
+                .header(header::ACCESS_CONTROL_REQUEST_METHOD, "POST")
+                .body(Body::empty())
+        };
+        let resp = send(&router, preflight("/metrics")?).await?;
+        assert!(resp.headers().contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+        for path in ["/admin/reload", "/fault_injection"] {
+            let resp = send(&router, preflight(path)?).await?;
+            assert!(!resp.headers().contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN), "{path}");
+        }
+        Ok(())
+    }
+}
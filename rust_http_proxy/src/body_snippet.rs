@@ -0,0 +1,141 @@
+//! location的 `debug_body_snippet`：调试用，在日志中记录请求体和响应体的前N字节
+//!
+//! 仅用于排查有问题的上游，不要在生产环境长期开启：只能对单个location开启，不能用于 `/`。
+//! 转发时边读边复制前N字节，不影响转发；请求体或响应体结束（或被丢弃）时输出一行日志。
+//! 日志中 `password`、`token`、`secret` 等字段的值和 `Bearer` 凭证会被替换为 `***`，但无法保证覆盖所有敏感信息。
+
+use std::{
+    borrow::Cow,
+    pin::Pin,
+    sync::LazyLock,
+    task::{Context, Poll},
+};
+
+use hyper::body::{Body, Bytes, Frame, SizeHint};
+use log::info;
+use pin_project_lite::pin_project;
+use regex::Regex;
+
+/// `debug_body_snippet` 的上限，避免日志过大
+pub(crate) const MAX_SNIPPET_BYTES: usize = 4096;
+
+/// JSON、表单和query中的敏感字段，例如 `"password": "x"`、`access_token=x`
+static SECRET_FIELD: LazyLock<Regex> = LazyLock::new(|| {
+    #[allow(clippy::expect_used)]
+    Regex::new(
+        r#"(?i)((?:password|passwd|pwd|secret|token|api[_-]?key|access[_-]?key|authorization|credential|session)[a-z_-]*"?\s*[:=]\s*"?)(?:(?:bearer|basic)\s+)?[^"&,;\s}]+"#,
+    )
+    .expect("invalid secret field regex")
+});
+static BEARER: LazyLock<Regex> = LazyLock::new(|| {
+    #[allow(clippy::expect_used)]
+    Regex::new(r"(?i)\b(bearer|basic)(\s+)[A-Za-z0-9._~+/=-]+").expect("invalid bearer regex")
+});
+
+/// `limit` 为None时只是透传
+pub(crate) fn tee<B>(inner: B, limit: Option<usize>, label: impl FnOnce() -> String) -> SnippetBody<B> {
+    let snippet = limit.map(|limit| Snippet {
+        label: label(),
+        limit,
+        captured: Vec::new(),
+        total: 0,
+    });
+    SnippetBody { inner, snippet }
+}
+
+struct Snippet {
+    label: String,
+    limit: usize,
+    captured: Vec<u8>,
+    total: u64,
+}
+
+impl Drop for Snippet {
+    fn drop(&mut self) {
+        let text = String::from_utf8_lossy(&self.captured);
+        let truncated = if self.total > self.captured.len() as u64 {
+            ", truncated"
+        } else {
+            ""
+        };
+        info!("[debug_body_snippet] {} ({} bytes{truncated}): {:?}", self.label, self.total, redact(&text));
+    }
+}
+
+pin_project! {
+    pub(crate) struct SnippetBody<B> {
+        #[pin]
+        inner: B,
+        snippet: Option<Snippet>,
+    }
+}
+
+impl<B> Body for SnippetBody<B>
+where
+    B: Body<Data = Bytes>,
+{
+    type Data = Bytes;
+    type Error = B::Error;
+
+    fn poll_frame(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = self.project();
+        let poll = this.inner.poll_frame(cx);
+        match (&poll, this.snippet.as_mut()) {
+            (Poll::Ready(Some(Ok(frame))), Some(snippet)) => {
+                if let Some(data) = frame.data_ref() {
+                    let remaining = snippet.limit.saturating_sub(snippet.captured.len());
+                    snippet.captured.extend_from_slice(&data[..remaining.min(data.len())]);
+                    snippet.total += data.len() as u64;
+                }
+            }
+            // 结束时立即输出，不等待响应被丢弃
+            (Poll::Ready(None), Some(_)) => *this.snippet = None,
+            _ => {}
+        }
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+fn redact(text: &str) -> Cow<'_, str> {
+    match SECRET_FIELD.replace_all(text, "${1}***") {
+        Cow::Borrowed(text) => BEARER.replace_all(text, "${1}${2}***"),
+        Cow::Owned(text) => Cow::Owned(BEARER.replace_all(&text, "${1}${2}***").into_owned()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::{BodyExt, Full};
+
+    #[test]
+    fn test_redact() {
+        assert_eq!(
+            redact(r#"{"user":"a","password": "hunter2","access_token":"abc.def"}"#),
+            r#"{"user":"a","password": "***","access_token":"***"}"#
+        );
+        assert_eq!(redact("user=a&api_key=xyz&x=1"), "user=a&api_key=***&x=1");
+        assert_eq!(redact("Authorization: Bearer eyJhbGci.x"), "Authorization: ***");
+        assert_eq!(redact("token is Bearer eyJhbGci.x"), "token is Bearer ***");
+        assert_eq!(redact("hello world"), "hello world");
+    }
+
+    #[tokio::test]
+    async fn test_tee_does_not_change_body() -> Result<(), Box<dyn std::error::Error>> {
+        let mut body = tee(Full::new(Bytes::from("hello world")), Some(5), || "test".to_string());
+        let frame = body.frame().await.ok_or("no frame")??;
+        assert_eq!(frame.into_data().map_err(|_| "not data")?, "hello world");
+        let snippet = body.snippet.as_ref().ok_or("no snippet")?;
+        assert_eq!(snippet.captured, b"hello");
+        assert_eq!(snippet.total, 11);
+        Ok(())
+    }
+}
@@ -0,0 +1,151 @@
+//! `--buffer-response-threshold`：反向代理和静态文件托管的小响应读完后以 `Content-Length` 发送
+//!
+//! 上游的chunked响应和实时压缩的静态文件没有 `Content-Length`，部分客户端处理chunked有兼容问题，
+//! HTTP/1.0客户端只能靠关闭连接判断响应结束而无法复用连接。响应体不超过阈值时完整读取后发送；
+//! 读到的数据超过阈值、或者 [`BUFFER_DEADLINE`] 内没有读完时不再等待，已读取的部分和剩余的响应体照常流式发送，
+//! 因此长轮询、NDJSON、gRPC server streaming等缓慢的流最多延迟 [`BUFFER_DEADLINE`]。
+//! 以首字节延迟换取兼容性，`text/event-stream` 和带有 `X-Accel-Buffering: no` 的响应不缓冲。
+
+use std::io;
+use std::time::Duration;
+
+use futures_util::{stream, StreamExt};
+use http::header::{CONTENT_LENGTH, CONTENT_TYPE, TRANSFER_ENCODING};
+use http::{HeaderValue, Method, Response, StatusCode};
+use http_body_util::{combinators::BoxBody, BodyExt, BodyStream, StreamBody};
+use hyper::body::{Body, Bytes, Frame};
+
+use crate::proxy::full_body;
+
+const X_ACCEL_BUFFERING: &str = "x-accel-buffering";
+/// 最多等待的时间，超过后转为流式发送
+const BUFFER_DEADLINE: Duration = Duration::from_millis(100);
+
+/// `threshold` 为0时不缓冲；HEAD请求的响应没有响应体，不能据此设置 `Content-Length`
+pub(crate) async fn buffer_small_response(
+    resp: Response<BoxBody<Bytes, io::Error>>, method: &Method, threshold: usize,
+) -> Response<BoxBody<Bytes, io::Error>> {
+    if threshold == 0 || *method == Method::HEAD || !should_buffer(&resp) {
+        return resp;
+    }
+    let (mut parts, mut body) = resp.into_parts();
+    let mut buffered = Vec::new();
+    let mut len = 0;
+    let deadline = tokio::time::Instant::now() + BUFFER_DEADLINE;
+    loop {
+        let Ok(frame) = tokio::time::timeout_at(deadline, body.frame()).await else {
+            return Response::from_parts(parts, resume(buffered.into_iter().map(Ok).collect(), body));
+        };
+        match frame {
+            None => {
+                // hyper客户端保留了上游响应的Transfer-Encoding，不能与Content-Length同时发送
+                parts.headers.remove(TRANSFER_ENCODING);
+                parts.headers.insert(CONTENT_LENGTH, HeaderValue::from(len));
+                let data = buffered
+                    .into_iter()
+                    .filter_map(|frame: Frame<Bytes>| frame.into_data().ok())
+                    .fold(Vec::with_capacity(len), |mut data, chunk| {
+                        data.extend_from_slice(&chunk);
+                        data
+                    });
+                return Response::from_parts(parts, full_body(data));
+            }
+            Some(Ok(frame)) => {
+                // trailers只能以chunked发送
+                let is_trailers = frame.is_trailers();
+                len += frame.data_ref().map_or(0, Bytes::len);
+                buffered.push(frame);
+                if len > threshold || is_trailers {
+                    return Response::from_parts(parts, resume(buffered.into_iter().map(Ok).collect(), body));
+                }
+            }
+            Some(Err(e)) => {
+                let mut frames: Vec<_> = buffered.into_iter().map(Ok).collect();
+                frames.push(Err(e));
+                return Response::from_parts(parts, resume(frames, body));
+            }
+        }
+    }
+}
+
+/// 已经确定长度或者不应该有响应体的响应不需要缓冲
+fn should_buffer(resp: &Response<BoxBody<Bytes, io::Error>>) -> bool {
+    let status = resp.status();
+    if status.is_informational() || status == StatusCode::NO_CONTENT || status == StatusCode::NOT_MODIFIED {
+        return false;
+    }
+    let headers = resp.headers();
+    if headers.contains_key(CONTENT_LENGTH) || resp.body().size_hint().exact().is_some() {
+        return false;
+    }
+    let event_stream = headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.trim_start().starts_with("text/event-stream"));
+    let no_buffering = headers
+        .get(X_ACCEL_BUFFERING)
+        .is_some_and(|value| value.as_bytes().eq_ignore_ascii_case(b"no"));
+    !event_stream && !no_buffering
+}
+
+/// 先发送已读取的帧，再继续转发剩余的响应体
+fn resume(frames: Vec<Result<Frame<Bytes>, io::Error>>, rest: BoxBody<Bytes, io::Error>) -> BoxBody<Bytes, io::Error> {
+    BodyExt::boxed(StreamBody::new(stream::iter(frames).chain(BodyStream::new(rest))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunked(chunks: &[&'static str]) -> Response<BoxBody<Bytes, io::Error>> {
+        let frames = chunks
+            .iter()
+            .map(|chunk| Ok::<_, io::Error>(Frame::data(Bytes::from_static(chunk.as_bytes()))))
+            .collect::<Vec<_>>();
+        Response::new(BodyExt::boxed(StreamBody::new(stream::iter(frames))))
+    }
+
+    #[tokio::test]
+    async fn test_buffer_small_response() -> Result<(), io::Error> {
+        let resp = buffer_small_response(chunked(&["hello ", "world"]), &Method::GET, 64).await;
+        assert_eq!(resp.headers().get(CONTENT_LENGTH), Some(&HeaderValue::from(11)));
+        assert_eq!(resp.into_body().collect().await?.to_bytes(), "hello world");
+
+        // 超过阈值时继续流式发送，数据不丢失
+        let resp = buffer_small_response(chunked(&["hello ", "world"]), &Method::GET, 8).await;
+        assert!(!resp.headers().contains_key(CONTENT_LENGTH));
+        assert_eq!(resp.into_body().collect().await?.to_bytes(), "hello world");
+
+        let mut resp = chunked(&["hello"]);
+        resp.headers_mut()
+            .insert(TRANSFER_ENCODING, HeaderValue::from_static("chunked"));
+        let resp = buffer_small_response(resp, &Method::GET, 64).await;
+        assert!(!resp.headers().contains_key(TRANSFER_ENCODING));
+
+        let resp = buffer_small_response(chunked(&["hello"]), &Method::HEAD, 64).await;
+        assert!(!resp.headers().contains_key(CONTENT_LENGTH));
+
+        let mut resp = chunked(&["data: 1\n\n"]);
+        resp.headers_mut()
+            .insert(CONTENT_TYPE, HeaderValue::from_static("text/event-stream"));
+        let resp = buffer_small_response(resp, &Method::GET, 64).await;
+        assert!(!resp.headers().contains_key(CONTENT_LENGTH));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_slow_stream_not_stalled() -> Result<(), Box<dyn std::error::Error>> {
+        // 第一个chunk之后上游长时间没有数据，例如长轮询或者watch
+        let frames =
+            stream::iter([Ok::<_, io::Error>(Frame::data(Bytes::from_static(b"hello")))]).chain(stream::pending());
+        let resp = Response::new(BodyExt::boxed(StreamBody::new(frames)));
+        let resp = tokio::time::timeout(BUFFER_DEADLINE * 10, buffer_small_response(resp, &Method::GET, 64)).await?;
+        assert!(!resp.headers().contains_key(CONTENT_LENGTH));
+        let mut body = resp.into_body();
+        let frame = tokio::time::timeout(BUFFER_DEADLINE, body.frame())
+            .await?
+            .ok_or("no frame")??;
+        assert_eq!(frame.into_data().ok(), Some(Bytes::from_static(b"hello")));
+        Ok(())
+    }
+}
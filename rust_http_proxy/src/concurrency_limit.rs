@@ -0,0 +1,227 @@
+//! location级别的并发限制，保护承载能力有限的上游
+//!
+//! 达到 `max_concurrent_requests` 后，新请求最多排队 `concurrency_queue_timeout_ms`（默认不排队），
+//! 仍未获得许可时返回503。许可随响应体一起释放，流式响应在发送完之前都计入并发。
+//! 重新加载配置时沿用同一location的信号量，已持有的许可继续计数，上限变化时调整许可数。
+
+use std::{
+    io,
+    sync::atomic::AtomicI64,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use http::Response;
+use http_body_util::{combinators::BoxBody, BodyExt};
+use hyper::body::Bytes;
+use log::warn;
+use prom_label::LabelImpl;
+use prometheus_client::metrics::gauge::Gauge;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::proxy::{build_over_capacity_resp, LocationLabel};
+use crate::METRICS;
+
+/// 运行时状态，不参与配置的比较
+#[derive(Default)]
+pub(crate) struct ConcurrencyLimiter(Option<Arc<Limit>>);
+
+struct Limit {
+    semaphore: Arc<Semaphore>,
+    state: Mutex<LimitState>,
+}
+
+struct LimitState {
+    max: usize,
+    /// 调小上限时被占用、还没能收回的许可数，释放时直接丢弃
+    pending_forget: usize,
+}
+
+impl Limit {
+    fn new(max: usize) -> Self {
+        Limit {
+            semaphore: Arc::new(Semaphore::new(max)),
+            state: Mutex::new(LimitState { max, pending_forget: 0 }),
+        }
+    }
+
+    fn resize(&self, max: usize) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if max > state.max {
+            let mut grow = max - state.max;
+            let cancelled = grow.min(state.pending_forget);
+            state.pending_forget -= cancelled;
+            grow -= cancelled;
+            self.semaphore.add_permits(grow);
+        } else {
+            let shrink = state.max - max;
+            let forgotten = self.semaphore.forget_permits(shrink);
+            state.pending_forget += shrink - forgotten;
+        }
+        state.max = max;
+    }
+
+    /// 返回false时这个许可应被丢弃而不是归还
+    fn release(&self) -> bool {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if state.pending_forget > 0 {
+            state.pending_forget -= 1;
+            false
+        } else {
+            true
+        }
+    }
+}
+
+impl PartialEq for ConcurrencyLimiter {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for ConcurrencyLimiter {}
+
+impl ConcurrencyLimiter {
+    pub(crate) fn new(max_concurrent_requests: Option<usize>) -> Self {
+        ConcurrencyLimiter(max_concurrent_requests.map(|max| Arc::new(Limit::new(max))))
+    }
+
+    /// 重新加载配置时调用，沿用 `previous` 的信号量并调整为新的上限
+    pub(crate) fn inherit(&mut self, previous: &ConcurrencyLimiter) {
+        let (Some(current), Some(previous)) = (&self.0, &previous.0) else {
+            return;
+        };
+        let max = current.state.lock().unwrap_or_else(|e| e.into_inner()).max;
+        previous.resize(max);
+        self.0 = Some(previous.clone());
+    }
+
+    /// 不限制时返回 `Ok(None)`，排队超时返回503响应，`retry_after` 为--retry-after
+    pub(crate) async fn acquire(
+        &self, origin: &str, queue_timeout: Duration, retry_after: u64,
+    ) -> Result<Option<InFlight>, Response<BoxBody<Bytes, io::Error>>> {
+        let Some(limit) = &self.0 else {
+            return Ok(None);
+        };
+        let semaphore = &limit.semaphore;
+        let permit = match semaphore.clone().try_acquire_owned() {
+            Ok(permit) => Some(permit),
+            Err(_) if queue_timeout.is_zero() => None,
+            Err(_) => tokio::time::timeout(queue_timeout, semaphore.clone().acquire_owned())
+                .await
+                .ok()
+                .and_then(Result::ok),
+        };
+        let label = LabelImpl::new(LocationLabel {
+            origin: origin.to_string(),
+        });
+        let Some(permit) = permit else {
+            warn!("[reverse] {origin} reaches max_concurrent_requests, reject with 503");
+            METRICS.reverse_proxy_concurrency_rejected.get_or_create(&label).inc();
+            return Err(build_over_capacity_resp("Service Unavailable", retry_after));
+        };
+        let gauge = METRICS.reverse_proxy_in_flight.get_or_create(&label).clone();
+        gauge.inc();
+        Ok(Some(InFlight {
+            permit: Some(permit),
+            limit: limit.clone(),
+            gauge,
+        }))
+    }
+}
+
+/// 持有期间计入location的并发数
+pub(crate) struct InFlight {
+    permit: Option<OwnedSemaphorePermit>,
+    limit: Arc<Limit>,
+    gauge: Gauge<i64, AtomicI64>,
+}
+
+impl InFlight {
+    /// 响应体发送完毕或被丢弃时释放
+    pub(crate) fn hold(self, resp: Response<BoxBody<Bytes, io::Error>>) -> Response<BoxBody<Bytes, io::Error>> {
+        resp.map(|body| {
+            body.map_frame(move |frame| {
+                let _ = &self;
+                frame
+            })
+            .boxed()
+        })
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.gauge.dec();
+        if let Some(permit) = self.permit.take() {
+            if !self.limit.release() {
+                permit.forget();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::StatusCode;
+
+    #[tokio::test]
+    async fn test_acquire() {
+        let limiter = ConcurrencyLimiter::new(Some(1));
+        let first = limiter.acquire("test_acquire", Duration::ZERO, 5).await;
+        assert!(matches!(first, Ok(Some(_))));
+        let rejected = limiter.acquire("test_acquire", Duration::from_millis(10), 5).await;
+        assert!(rejected.is_err_and(|resp| resp.status() == StatusCode::SERVICE_UNAVAILABLE
+            && resp
+                .headers()
+                .get(http::header::RETRY_AFTER)
+                .is_some_and(|value| value == "5")));
+        let rejected = limiter.acquire("test_acquire", Duration::ZERO, 0).await;
+        assert!(rejected.is_err_and(|resp| !resp.headers().contains_key(http::header::RETRY_AFTER)));
+        drop(first);
+        assert!(matches!(limiter.acquire("test_acquire", Duration::ZERO, 5).await, Ok(Some(_))));
+        assert!(matches!(
+            ConcurrencyLimiter::default()
+                .acquire("test_acquire", Duration::ZERO, 5)
+                .await,
+            Ok(None)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_inherit() {
+        let acquired = |limiter: &ConcurrencyLimiter| {
+            let limiter = ConcurrencyLimiter(limiter.0.clone());
+            async move { limiter.acquire("test_inherit", Duration::ZERO, 0).await.ok().flatten() }
+        };
+        let previous = ConcurrencyLimiter::new(Some(2));
+        let first = acquired(&previous).await;
+        let second = acquired(&previous).await;
+        assert!(first.is_some() && second.is_some());
+
+        // 上限不变：重新加载前持有的许可仍然计数
+        let mut same = ConcurrencyLimiter::new(Some(2));
+        same.inherit(&previous);
+        assert!(acquired(&same).await.is_none());
+
+        // 调小上限：许可都被占用时，释放的许可被收回
+        let mut shrunk = ConcurrencyLimiter::new(Some(1));
+        shrunk.inherit(&same);
+        drop(first);
+        assert!(acquired(&shrunk).await.is_none());
+        drop(second);
+        let only = acquired(&shrunk).await;
+        assert!(only.is_some());
+        assert!(acquired(&shrunk).await.is_none());
+
+        // 调大上限
+        let mut grown = ConcurrencyLimiter::new(Some(3));
+        grown.inherit(&shrunk);
+        let more = [acquired(&grown).await, acquired(&grown).await];
+        assert!(more.iter().all(Option::is_some));
+        assert!(acquired(&grown).await.is_none());
+        drop(only);
+        assert!(acquired(&grown).await.is_some());
+    }
+}
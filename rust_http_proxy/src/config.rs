@@ -1,13 +1,26 @@
 use base64::engine::general_purpose;
 use base64::Engine;
 use clap::Parser;
+use http::{HeaderName, HeaderValue, Method};
 use ipnetwork::IpNetwork;
 use log::{info, warn};
 use log_x::init_log;
+use regex::Regex;
 use std::collections::HashMap;
+use std::net::SocketAddr;
 use std::str::FromStr;
 
-use crate::reverse::{parse_reverse_proxy_config, ReverseProxyConfig};
+use crate::access_decision::AccessDecisionLog;
+use crate::access_log::LogFormat;
+use crate::forwarded::ForwardedHeaders;
+use crate::host_override::HostOverrides;
+use crate::response_headers::DEFAULT_STRIP_RESPONSE_HEADERS;
+use crate::reverse::{parse_reverse_proxy_config, ReverseProxyConfig, ReverseProxyConfigSource};
+use crate::root_response::RootResponse;
+use crate::socket_x::{SocketBufferSize, MAX_SOCKET_BUFFER_SIZE};
+use crate::static_filter::StaticFileFilter;
+use crate::traffic_report::TrafficReportPeriod;
+use crate::upstream_ca::UpstreamCa;
 use crate::{DynError, IDLE_TIMEOUT};
 
 /// A HTTP proxy server based on Hyper and Rustls, which features TLS proxy and static file serving.
@@ -22,10 +35,23 @@ pub struct Param {
         short,
         long,
         value_name = "PORT",
-        default_value = "3128",
-        help = "可以多次指定来实现多端口\n"
+        help = "可以多次指定来实现多端口\n\
+        默认为3128。如指定了--unix-listen且未指定端口，则不监听TCP端口"
     )]
     port: Vec<u16>,
+    #[arg(
+        long,
+        help = "某个--port无法监听（例如被占用）时记录警告并跳过，继续监听其他端口\n\
+        默认启动失败。所有端口都无法监听时仍然启动失败"
+    )]
+    ignore_bind_errors: bool,
+    #[arg(
+        long,
+        value_name = "SOCKET_PATH",
+        help = "监听Unix domain socket，例如 /run/proxy.sock，适用于sidecar部署\n\
+        启动时会删除残留的socket文件，退出时删除socket文件。不使用TLS"
+    )]
+    unix_listen: Option<String>,
     #[arg(short, long, value_name = "CERT", default_value = "cert.pem")]
     cert: String,
     #[arg(short, long, value_name = "KEY", default_value = "privkey.pem")]
@@ -73,10 +99,57 @@ pub struct Param {
         如未设置任何网段，且未设置prohibit_serving，则允许所有IP访问静态文件"
     )]
     allow_serving_network: Vec<String>,
+    #[arg(
+        long,
+        help = "静态文件托管也要求--users中的用户以Authorization请求头鉴权，失败时返回401。\n\
+        与代理的Proxy-Authorization相互独立，用于以密码保护静态网站"
+    )]
+    auth_static: bool,
     #[arg(short, long, help = "if enable, proxy server will listen on https")]
     over_tls: bool,
-    #[arg(long, value_name = "FILE_PATH", help = r#"反向代理配置文件"#)]
-    reverse_proxy_config_file: Option<String>,
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        help = "签发TLS session ticket（无状态的会话恢复）并指定有效期，最大21600（6小时）\n\
+        默认不签发，只使用服务端缓存的会话恢复。不能与--tls-enable-0rtt同时使用"
+    )]
+    tls_ticket_lifetime: Option<u32>,
+    #[arg(
+        long,
+        help = "接受TLS 1.3的0-RTT early data，减少会话恢复时的连接延迟，默认关闭\n\
+        early data可能被重放，其中GET/HEAD/OPTIONS以外的请求返回425 Too Early，由客户端在握手完成后重试\n\
+        只用于服务端缓存的会话恢复，缓存的会话只能使用一次"
+    )]
+    tls_enable_0rtt: bool,
+    #[arg(
+        long,
+        value_name = "NUM",
+        help = "同时进行的TLS握手数的上限，所有端口共用，用于平滑大量新连接时握手带来的CPU尖峰\n\
+        达到上限时新连接排队等待，排队数超过--tls-handshake-queue时直接关闭。默认不限制"
+    )]
+    max_tls_handshakes: Option<usize>,
+    #[arg(
+        long,
+        value_name = "NUM",
+        default_value = "1024",
+        help = "达到--max-tls-handshakes时最多排队等待握手的连接数，0表示不排队"
+    )]
+    tls_handshake_queue: usize,
+    #[arg(
+        long,
+        value_name = "FILE_PATH",
+        help = "反向代理配置文件\n\
+        可以多次指定，也可以指定为目录（加载目录下所有.toml文件），按指定的顺序合并\n\
+        不同文件中定义了相同的host+location时报错"
+    )]
+    reverse_proxy_config_file: Vec<String>,
+    #[arg(
+        long,
+        value_name = "URL",
+        help = "启动时（以及收到SIGHUP时）从该地址获取反向代理配置，与--reverse-proxy-config-file合并\n\
+        获取成功且校验通过后缓存到日志目录下的reverse_proxy_config.cache.toml，获取失败时使用缓存"
+    )]
+    reverse_proxy_config_url: Option<String>,
     #[arg(long, help = r#"是否开启github proxy"#)]
     enable_github_proxy: bool,
     #[arg(
@@ -87,29 +160,877 @@ pub struct Param {
         则访问 https://your_domain/https://cdnjs.cloudflare.com 会被代理到 https://cdnjs.cloudflare.com"
     )]
     append_upstream_url: Vec<String>,
+    #[arg(
+        long,
+        value_name = "SECS",
+        default_value = "5",
+        help = "--append-upstream-url和--enable-github-proxy生成的location连接上游的超时时间"
+    )]
+    passthrough_connect_timeout: u64,
+    #[arg(
+        long,
+        value_name = "SECS",
+        default_value = "30",
+        help = "--append-upstream-url和--enable-github-proxy生成的location等待上游响应头的超时时间，超时返回504\n\
+        不限制响应体的下载时间"
+    )]
+    passthrough_response_timeout: u64,
+    #[arg(
+        long,
+        value_name = "NUM",
+        default_value = "1",
+        help = "--append-upstream-url和--enable-github-proxy生成的location出错或超时后的重试次数\n\
+        只重试没有请求体的GET、HEAD、OPTIONS请求"
+    )]
+    passthrough_retries: u32,
+    #[arg(
+        long,
+        value_name = "HOPS",
+        help = "--append-upstream-url和--enable-github-proxy生成的location由代理跟随上游的重定向，最多跟随的次数\n\
+        用于重定向到签名的存储URL等无法通过代理访问的地址，只跟随没有请求体的GET、HEAD请求。默认不跟随，原样返回给客户端"
+    )]
+    passthrough_follow_redirects: Option<u32>,
+    #[arg(
+        long,
+        value_name = "HOPS",
+        default_value = "5",
+        help = "跟随上游重定向的次数上限，location的follow_redirects和--passthrough-follow-redirects超过时按该值截断\n\
+        超过上限或者跳转回访问过的URL（例如A跳转到B再跳转回A）时返回508 Loop Detected"
+    )]
+    max_redirect_hops: u32,
+    #[arg(
+        long,
+        value_name = "NUM",
+        default_value = "5",
+        help = "反向代理与每个上游（scheme+host+port）保持的最大空闲连接数，0表示不保持空闲连接\n\
+        location的pool_max_idle_per_host可以单独覆盖"
+    )]
+    pool_max_idle_per_host: usize,
+    #[arg(
+        long,
+        value_name = "NUM",
+        help = "正向代理（非CONNECT）与每个目标保持的最大空闲连接数，按客户端IP、目标和用户名分别计算。默认不限制\n\
+        0表示不复用连接"
+    )]
+    forward_pool_max_idle: Option<usize>,
+    #[arg(
+        long,
+        value_name = "SECS",
+        default_value = "30",
+        help = "正向代理（非CONNECT）到目标的连接的空闲超时：空闲超过该时间的连接不再复用\n\
+        也是该连接的读写超时，等待目标响应超过该时间同样会断开。与客户端连接的空闲超时相互独立"
+    )]
+    forward_pool_idle_timeout: u64,
+    #[arg(
+        long,
+        value_name = "BYTES",
+        help = "设置SO_SNDBUF，作用于客户端连接、隧道和正向代理的目标连接、反向代理的上游连接\n\
+        范围为1到2147483647，高延迟高带宽的链路上调大可以提升吞吐。默认使用系统值"
+    )]
+    so_sndbuf: Option<usize>,
+    #[arg(
+        long,
+        value_name = "BYTES",
+        help = "设置SO_RCVBUF，作用范围同--so-sndbuf。默认使用系统值"
+    )]
+    so_rcvbuf: Option<usize>,
+    #[arg(
+        long,
+        value_enum,
+        value_name = "PERIOD",
+        default_value = "monthly",
+        help = "按用户汇总流量报表（/traffic_report）的重置周期"
+    )]
+    traffic_report_period: TrafficReportPeriod,
+    #[arg(
+        long,
+        value_name = "USER_AGENT",
+        help = "改写正向代理请求的User-Agent，以减少客户端指纹\n\
+        设置为remove则删除User-Agent，否则替换为指定的值。默认原样转发\n\
+        仅对普通HTTP代理生效，CONNECT隧道中的请求头是加密的，无法改写"
+    )]
+    rewrite_user_agent: Option<String>,
+    #[arg(
+        long,
+        help = "正向代理时，如果Host请求头与请求URI（或CONNECT的目标）不一致，则返回400\n\
+        用于防止请求走私和路由混淆。默认不校验"
+    )]
+    strict_host_check: bool,
+    #[arg(
+        long,
+        help = "静态文件托管时跟随指向web_content_path之外的符号链接\n\
+        默认只允许解析后仍在web_content_path之下的符号链接"
+    )]
+    follow_symlinks: bool,
+    #[arg(
+        long,
+        value_name = "EXT",
+        help = "静态文件托管时只允许这些扩展名的文件，可以多次指定。默认不限制\n\
+        以.开头的文件和目录（/.well-known/除外）、以~结尾的文件总是返回404"
+    )]
+    static_allow_extension: Vec<String>,
+    #[arg(
+        long,
+        value_name = "EXT",
+        default_values = crate::static_filter::DEFAULT_DENY_EXTENSIONS,
+        help = "静态文件托管时拒绝这些扩展名的文件，返回404，可以多次指定。指定后替换默认值"
+    )]
+    static_deny_extension: Vec<String>,
+    #[arg(
+        long,
+        value_name = "NAME",
+        default_values = crate::response_headers::DEFAULT_STRIP_RESPONSE_HEADERS,
+        help = "反向代理时删除上游响应中的这些响应头，避免暴露后端的软件和版本，可以多次指定。指定后替换默认值，指定为none时不删除\n\
+        location的strip_response_headers可以删除更多的响应头。Connection、Keep-Alive等逐跳响应头总是删除"
+    )]
+    strip_response_header: Vec<String>,
+    #[arg(
+        long,
+        help = "从构建时嵌入可执行文件的静态文件托管，不再读取web_content_path\n\
+        构建时设置环境变量RUST_HTTP_PROXY_EMBED_DIR为要嵌入的目录，没有嵌入文件时仍从磁盘读取"
+    )]
+    serve_embedded: bool,
+    #[arg(
+        long,
+        value_enum,
+        value_name = "FORMAT",
+        default_value = "default",
+        help = "访问日志格式，每个请求输出一行"
+    )]
+    log_format: LogFormat,
+    #[arg(
+        long,
+        value_name = "FILE_PATH",
+        help = "访问日志文件，仅在--log-format不为default时生效\n\
+        写入跟不上时丢弃新的日志，丢弃的行数计入Prometheus指标access_log_dropped\n\
+        默认输出到主日志中"
+    )]
+    access_log_file: Option<String>,
+    #[arg(
+        long,
+        value_enum,
+        value_name = "WHICH",
+        default_value = "deny",
+        help = "访问控制决策日志，记录生效的规则（prohibit_serving、allowed_networks、proxy_auth等）、客户端和目标"
+    )]
+    access_decision_log: AccessDecisionLog,
+    #[arg(
+        long,
+        help = "将访问控制决策日志输出到访问日志（--access-log-file，未指定时为主日志），而不是主日志"
+    )]
+    access_decision_to_access_log: bool,
+    #[arg(
+        long,
+        value_name = "PORT=MODE",
+        help = "限制端口的用途，可以多次指定。MODE可选：\n\
+        forward-proxy: 默认，正向代理、反向代理和静态文件托管均可用\n\
+        reverse-only: 只提供反向代理\n\
+        static-only: 只提供静态文件托管\n\
+        transparent: 透明代理，配合iptables REDIRECT使用，将连接原样转发到SO_ORIGINAL_DST，仅支持Linux\n\
+        reverse-only和static-only的端口对CONNECT和absolute-form的请求返回400"
+    )]
+    listener_mode: Vec<String>,
+    #[arg(
+        long,
+        value_name = "NUM",
+        help = "同时存在的CONNECT隧道的上限，超过时新的CONNECT请求返回503。默认不限制"
+    )]
+    max_tunnels: Option<usize>,
+    #[arg(
+        long,
+        value_name = "SECS",
+        default_value = "5",
+        help = "过载（--max-tunnels、--global-rate-limit、location的max_concurrent_requests）返回503时Retry-After响应头的秒数，0表示不返回"
+    )]
+    retry_after: u64,
+    #[arg(
+        long,
+        value_name = "QPS",
+        help = "整个服务每秒请求数的上限（令牌桶），超过时返回503，由axum处理的/metrics等管理接口不受限制（设置了--admin-port时代理端口上都受限制）。默认不限制"
+    )]
+    global_rate_limit: Option<u32>,
+    #[arg(
+        long,
+        value_name = "NUM",
+        help = "--global-rate-limit的令牌桶容量，即允许的突发请求数，默认等于--global-rate-limit"
+    )]
+    global_rate_burst: Option<u32>,
+    #[arg(
+        long,
+        value_name = "BYTES",
+        default_value_t = crate::proxy::DEFAULT_TUNNEL_BUFFER_SIZE,
+        help = "CONNECT隧道和透明代理每个方向的复制缓冲区大小，范围为1024到16777216\n\
+        每个隧道有两个缓冲区，占用内存约为 缓冲区大小 × 2 × 并发隧道数\n\
+        大量交互式的小连接可以调小以节省内存，少量大文件传输可以调大以提高吞吐"
+    )]
+    tunnel_buffer_size: usize,
+    #[arg(
+        long,
+        value_name = "PORT",
+        help = "允许CONNECT的目标端口，可以多次指定，例如 --allow-connect-port 443 --allow-connect-port 80\n\
+        其他端口返回403，避免被用于连接SMTP（25）等端口。默认不限制"
+    )]
+    allow_connect_port: Vec<u16>,
+    #[arg(
+        long,
+        value_name = "METHOD",
+        help = "正向代理允许的请求方法，可以多次指定，例如 --allow-method GET --allow-method POST --allow-method CONNECT\n\
+        其他方法返回405，避免被用于代理WebDAV等方法。不影响反向代理和静态文件托管。默认不限制"
+    )]
+    allow_method: Vec<String>,
+    #[arg(
+        long,
+        value_name = "NUM",
+        help = "每个HTTP/1.1 keep-alive连接最多处理的请求数，达到后在最后一个响应中加上Connection: close并关闭连接\n\
+        用于定期回收连接，便于负载均衡重新分配。默认不限制"
+    )]
+    keepalive_max_requests: Option<usize>,
+    #[arg(
+        long,
+        value_name = "STATUS",
+        help = "HTTP/1.x的响应为这些状态码时加上Connection: close并关闭连接，可以多次指定\n\
+        可以是具体的状态码（如502）或一类状态码（如5xx）。用于在上游出错后让客户端重新建立连接，默认不关闭"
+    )]
+    close_on_status: Vec<String>,
+    #[arg(
+        long,
+        value_name = "BYTES",
+        default_value = "8192",
+        help = "请求URI的最大长度，超过时返回414"
+    )]
+    max_uri_length: usize,
+    #[arg(
+        long,
+        help = "转发TRACE和TRACK请求。默认以405拒绝，避免上游回显请求头导致跨站追踪（XST）"
+    )]
+    allow_trace: bool,
+    #[arg(
+        long,
+        help = "记录CONNECT隧道建立各阶段的耗时：DNS解析、TCP建连、升级后收到目标的第一个字节\n\
+        以debug日志输出，并计入Prometheus histogram tunnel_phase_seconds"
+    )]
+    tunnel_timing: bool,
+    #[arg(
+        long,
+        value_name = "COUNT",
+        help = "每个请求最多的请求头数量，超过时返回431，在鉴权和代理之前检查\n\
+        HTTP/1.x的请求由解析器直接拒绝。默认不限制HTTP/2，HTTP/1.x为hyper默认的100"
+    )]
+    max_headers: Option<usize>,
+    #[arg(
+        long,
+        help = "校验上游（反向代理、远程配置）的证书时使用内置的Mozilla根证书，而不是系统的证书\n\
+        适用于scratch、distroless等没有系统证书的镜像"
+    )]
+    use_webpki_roots: bool,
+    #[arg(
+        long,
+        value_name = "DIR",
+        help = "额外信任该目录中所有.pem、.crt文件里的CA证书，用于校验上游（反向代理、远程配置）的证书\n\
+        启动时加载，任何一个文件无法解析时启动失败"
+    )]
+    upstream_ca_dir: Option<String>,
+    #[arg(
+        long,
+        value_enum,
+        value_name = "MODE",
+        default_value = "add",
+        help = "--upstream-ca-dir的证书与默认证书的关系\n\
+        add: 同时信任系统的证书（或--use-webpki-roots的Mozilla根证书）\n\
+        only: 只信任目录中的证书"
+    )]
+    upstream_ca_mode: UpstreamCaMode,
+    #[arg(
+        long,
+        value_name = "NAME=IP",
+        help = "只在代理内部将域名解析为指定的IP，优先于系统的DNS解析，可以多次指定，例如 --host-override api.example.com=10.0.0.1\n\
+        对CONNECT隧道、正向代理和反向代理的上游生效"
+    )]
+    host_override: Vec<String>,
+    #[arg(
+        long,
+        value_name = "IP:PORT",
+        help = "解析srv+http://、srv+https://形式的upstream时使用的DNS服务器，例如Consul的127.0.0.1:8600\n\
+        默认使用/etc/resolv.conf中的配置"
+    )]
+    srv_nameserver: Option<SocketAddr>,
+    #[cfg(feature = "statsd")]
+    #[arg(
+        long,
+        value_name = "HOST:PORT",
+        help = "以StatsD/DogStatsD格式通过UDP定期推送指标到该地址，例如127.0.0.1:8125。默认不推送"
+    )]
+    statsd_addr: Option<String>,
+    #[cfg(feature = "statsd")]
+    #[arg(long, value_name = "SECS", default_value = "10", help = "推送StatsD指标的间隔秒数")]
+    statsd_interval: u64,
+    #[cfg(feature = "statsd")]
+    #[arg(
+        long,
+        value_name = "PREFIX",
+        default_value = "rust_http_proxy",
+        help = "StatsD指标名的前缀，为空时不加前缀"
+    )]
+    statsd_prefix: String,
+    #[cfg(feature = "statsd")]
+    #[arg(long, value_enum, default_value_t = crate::statsd::StatsdFlavor::default(), help = "StatsD的格式，statsd格式不支持tag，标签值追加到指标名后")]
+    statsd_flavor: crate::statsd::StatsdFlavor,
+    #[arg(
+        long,
+        value_name = "REGEX",
+        default_value = DEFAULT_IMMUTABLE_ASSET_PATTERN,
+        help = "静态文件托管时，文件名匹配该正则的文件视为带有内容hash，返回长期缓存的Cache-Control\n\
+        例如app.abc123.js。设置为空字符串则关闭。HTML文件总是返回Cache-Control: no-cache"
+    )]
+    immutable_asset_pattern: String,
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value = "31536000",
+        help = "匹配--immutable-asset-pattern的文件的max-age"
+    )]
+    immutable_max_age: u64,
+    #[arg(
+        long,
+        value_name = "CHARSET",
+        default_value = "utf-8",
+        help = "静态文件托管时，文本类型（html、css、js、纯文本、json、xml等）的Content-Type中添加的charset\n\
+        设置为空字符串则不添加"
+    )]
+    default_charset: String,
+    #[arg(
+        long,
+        value_name = "VALUE",
+        num_args = 0..=1,
+        default_missing_value = "",
+        help = "在反向代理和静态文件的响应中添加X-Served-By响应头，用于定位是哪个节点处理的请求\n\
+        不指定VALUE时使用主机名，获取不到主机名时使用本机IP。默认不添加"
+    )]
+    served_by: Option<String>,
+    #[arg(
+        long,
+        value_name = "HEADER",
+        num_args = 0..=1,
+        default_missing_value = "X-Request-Id",
+        help = "为每个请求分配ID，在响应中返回，并输出到请求日志和访问日志中；反向代理时还通过该请求头转发给上游\n\
+        不指定HEADER时为X-Request-Id。请求已带有该请求头时保留原值，否则生成UUID。默认不开启"
+    )]
+    request_id_header: Option<String>,
+    #[arg(
+        long,
+        value_name = "BYTES",
+        help = "跟踪Content-Length不小于该字节数（或没有Content-Length）的请求体的上传进度，默认不跟踪\n\
+        进行中的上传见GET /uploads，以及Prometheus指标upload_in_progress、upload_bytes"
+    )]
+    upload_progress_min_bytes: Option<u64>,
+    #[arg(
+        long,
+        value_name = "status|URL",
+        help = "直接访问代理自身地址的根路径（GET /）时返回的响应，默认不开启\n\
+        status: 返回简单的状态页；http(s)://开头的URL: 302重定向到该地址\n\
+        反向代理的location（包括default_host）优先，代理自身的host见--proxy-hostname"
+    )]
+    root_response: Option<String>,
+    #[arg(
+        long,
+        value_name = "HOST",
+        help = "代理自身的host，可以多次指定，用于--root-response。IP地址总是视为代理自身\n\
+        默认为localhost和本机的主机名"
+    )]
+    proxy_hostname: Vec<String>,
+    #[arg(
+        long,
+        value_name = "HEADER",
+        help = "携带真实客户端IP的请求头，例如CF-Connecting-IP、X-Forwarded-For（取最后一个）\n\
+        仅当对端IP属于--trusted-proxy时生效，替换指标、日志和静态文件托管网段控制中使用的客户端IP"
+    )]
+    real_ip_header: Option<String>,
+    #[arg(
+        long,
+        value_name = "CIDR",
+        help = "可信的前置代理的网段，可以多次指定。来自其他IP的--real-ip-header请求头会被忽略\n\
+        反向代理时，来自其他IP的X-Forwarded-For等请求头会被删除，见--forwarded-headers"
+    )]
+    trusted_proxy: Vec<String>,
+    #[arg(
+        long,
+        value_enum,
+        value_name = "MODE",
+        default_value = "strip",
+        help = "反向代理如何处理客户端发送的X-Forwarded-For、Forwarded、X-Real-IP等请求头\n\
+        strip: 只保留来自--trusted-proxy的，其他的删除，并追加本代理的X-Forwarded-For、X-Forwarded-Proto、X-Forwarded-Host\n\
+        passthrough: 原样转发给上游，不追加"
+    )]
+    forwarded_headers: ForwardedHeaders,
+    #[arg(
+        long,
+        value_enum,
+        value_name = "POLICY",
+        default_value = "header",
+        help = "Host请求头中的端口与客户端连接的端口不一致时（例如连接443而Host为example.com:8080）的处理\n\
+        影响反向代理的重定向改写和X-Forwarded-Host，不影响正向代理请求\n\
+        header: 使用Host中的端口，端口映射（例如docker -p 8443:443）时应使用该选项\n\
+        listener: 使用监听的端口，非该scheme的默认端口时保留在原始URL中\n\
+        reject: 返回400"
+    )]
+    host_port_policy: HostPortPolicy,
+    #[arg(
+        long,
+        value_enum,
+        value_name = "MODE",
+        default_value = "tunnel",
+        help = "正向代理的普通请求（非CONNECT）带有Connection: Upgrade时（例如经过代理的WebSocket握手）的处理\n\
+        tunnel: 转发升级请求，上游返回101后在客户端和上游之间转发数据，计入--max-tunnels\n\
+        strip: 删除Upgrade请求头，作为普通请求转发\n\
+        reject: 返回400"
+    )]
+    forward_upgrade: ForwardUpgrade,
+    #[arg(
+        long,
+        value_name = "PORT",
+        help = "在单独的端口上以HTTP提供/metrics、/traffic_report、/fault_injection等管理接口\n\
+        设置后代理端口不再提供这些接口，便于单独配置防火墙"
+    )]
+    admin_port: Option<u16>,
+    #[arg(
+        long,
+        value_name = "NUM",
+        help = "静态文件托管时同时进行实时压缩的响应数的上限，超过时返回未压缩的响应。默认不限制"
+    )]
+    max_compressions: Option<usize>,
+    #[arg(
+        long,
+        value_name = "BYTES",
+        default_value_t = 65536,
+        help = "反向代理和静态文件托管的响应没有Content-Length且不超过该大小时，完整读取后以Content-Length发送，\n\
+        超过时仍流式发送。设置为0则不缓冲"
+    )]
+    buffer_response_threshold: usize,
+    #[arg(
+        long,
+        value_name = "ALGO,...",
+        default_value = "gzip,br",
+        help = "静态文件托管时实时压缩的算法，按优先级排列，可选gzip和br。设置为空字符串则不做实时压缩"
+    )]
+    compression: String,
+    #[arg(
+        long,
+        value_name = "ALGO=LEVEL",
+        help = "实时压缩的压缩等级，可以多次指定。gzip为1-9，默认6；br为0-11，默认4"
+    )]
+    compression_level: Vec<String>,
+    #[arg(
+        long,
+        value_name = "USER",
+        help = "绑定端口后切换到该用户运行，例如以root启动以绑定80、443端口。仅支持unix"
+    )]
+    run_as_user: Option<String>,
+    #[arg(
+        long,
+        value_name = "GROUP",
+        help = "绑定端口后切换到该组运行，默认为--run-as-user的主组"
+    )]
+    run_as_group: Option<String>,
+}
+
+pub(crate) const DEFAULT_IMMUTABLE_ASSET_PATTERN: &str = r"\.[0-9a-f]{6,}\.[0-9a-z]+$";
+const MIN_TUNNEL_BUFFER_SIZE: usize = 1024;
+const MAX_TUNNEL_BUFFER_SIZE: usize = 16 * 1024 * 1024;
+
+/// 代理服务器的配置，命令行参数见 [`Param`]
+pub struct Config {
+    pub cert: String,
+    pub key: String,
+    /// key为 `Basic base64(username:password)`，value为username
+    pub basic_auth: HashMap<String, String>,
+    pub web_content_path: String,
+    pub referer_keywords_to_self: Vec<String>,
+    pub never_ask_for_auth: bool,
+    pub serving_control: ServingControl,
+    pub over_tls: bool,
+    /// 为空时不签发session ticket
+    pub tls_ticket_lifetime: Option<u32>,
+    pub tls_enable_0rtt: bool,
+    pub max_tls_handshakes: Option<usize>,
+    pub tls_handshake_queue: usize,
+    pub port: Vec<u16>,
+    pub ignore_bind_errors: bool,
+    /// Unix domain socket的路径，来自该socket的连接没有真实的客户端IP
+    pub unix_listen: Option<String>,
+    /// 启动时的反向代理配置，运行时可能被SIGHUP重新加载
+    pub reverse_proxy_config: ReverseProxyConfig,
+    pub reverse_proxy_config_url: Option<String>,
+    pub socket_buffer_size: SocketBufferSize,
+    pub traffic_report_period: TrafficReportPeriod,
+    pub rewrite_user_agent: UserAgentRewrite,
+    pub strict_host_check: bool,
+    pub follow_symlinks: bool,
+    pub static_file_filter: StaticFileFilter,
+    pub serve_embedded: bool,
+    pub log_format: LogFormat,
+    pub access_log_file: Option<String>,
+    pub access_decision_log: AccessDecisionLog,
+    pub access_decision_to_access_log: bool,
+    /// 日志目录，SIGUSR1触发的指标快照也写在这里
+    pub log_dir: String,
+    /// 未指定的端口为 [`ListenerMode::ForwardProxy`]
+    pub listener_modes: HashMap<u16, ListenerMode>,
+    pub max_tunnels: Option<usize>,
+    pub retry_after: u64,
+    pub global_rate_limit: Option<u32>,
+    pub global_rate_burst: Option<u32>,
+    pub tunnel_buffer_size: usize,
+    /// 为空时不限制CONNECT的目标端口
+    pub allow_connect_ports: Vec<u16>,
+    pub allow_methods: Vec<Method>,
+    pub keepalive_max_requests: Option<usize>,
+    /// 为空时不因响应状态码关闭连接
+    pub close_on_status: Vec<StatusPattern>,
+    pub max_uri_length: usize,
+    pub allow_trace: bool,
+    pub tunnel_timing: bool,
+    pub max_headers: Option<usize>,
+    pub use_webpki_roots: bool,
+    pub upstream_ca: UpstreamCa,
+    pub host_overrides: HostOverrides,
+    pub passthrough_connect_timeout: u64,
+    pub passthrough_response_timeout: u64,
+    pub passthrough_retries: u32,
+    pub passthrough_follow_redirects: Option<u32>,
+    pub max_redirect_hops: u32,
+    pub pool_max_idle_per_host: usize,
+    pub forward_pool_max_idle: Option<usize>,
+    pub forward_pool_idle_timeout: u64,
+    pub srv_nameserver: Option<SocketAddr>,
+    #[cfg(feature = "statsd")]
+    pub statsd: Option<crate::statsd::StatsdConfig>,
+    /// 为None时不对带有内容hash的文件设置长期缓存
+    pub immutable_asset_pattern: Option<Regex>,
+    pub immutable_max_age: u64,
+    /// 为None时文本类型的Content-Type不带charset
+    pub default_charset: Option<String>,
+    /// X-Served-By响应头的值，为None时不添加
+    pub served_by: Option<HeaderValue>,
+    /// 为None时不分配请求ID
+    pub request_id_header: Option<HeaderName>,
+    /// 为None时不跟踪上传进度
+    pub upload_progress_min_bytes: Option<u64>,
+    pub root_response: Option<RootResponse>,
+    pub proxy_hostnames: Vec<String>,
+    /// 对端属于trusted_proxies时，从该请求头中取真实客户端IP
+    pub real_ip_header: Option<HeaderName>,
+    pub trusted_proxies: Vec<IpNetwork>,
+    pub forwarded_headers: ForwardedHeaders,
+    pub host_port_policy: HostPortPolicy,
+    pub forward_upgrade: ForwardUpgrade,
+    /// 为None时管理接口与代理共用端口
+    pub admin_port: Option<u16>,
+    pub max_compressions: Option<usize>,
+    pub buffer_response_threshold: usize,
+    /// 按优先级排列，为空时不做实时压缩
+    pub compression_preference: Vec<Compression>,
+    pub strip_response_headers: Vec<HeaderName>,
+    pub gzip_level: u32,
+    pub brotli_level: u32,
+    /// 绑定端口之后切换到的用户和组
+    pub run_as_user: Option<String>,
+    pub run_as_group: Option<String>,
+}
+
+/// 端口的用途
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ListenerMode {
+    /// 正向代理、反向代理和静态文件托管均可用
+    #[default]
+    ForwardProxy,
+    /// 只提供反向代理
+    ReverseOnly,
+    /// 只提供静态文件托管
+    StaticOnly,
+    /// 不解析HTTP，将TCP连接转发到iptables REDIRECT之前的目标地址
+    Transparent,
+}
+
+impl FromStr for ListenerMode {
+    type Err = DynError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "forward-proxy" => Ok(ListenerMode::ForwardProxy),
+            "reverse-only" => Ok(ListenerMode::ReverseOnly),
+            "static-only" => Ok(ListenerMode::StaticOnly),
+            "transparent" if cfg!(target_os = "linux") => Ok(ListenerMode::Transparent),
+            "transparent" => Err("listener mode transparent is only supported on linux".into()),
+            _ => Err(format!(
+                "invalid listener mode {s:?}, should be forward-proxy, reverse-only, static-only or transparent"
+            )
+            .into()),
+        }
+    }
 }
 
-pub(crate) struct Config {
-    pub(crate) cert: String,
-    pub(crate) key: String,
-    pub(crate) basic_auth: HashMap<String, String>,
-    pub(crate) web_content_path: String,
-    pub(crate) referer_keywords_to_self: Vec<String>,
-    pub(crate) never_ask_for_auth: bool,
-    pub(crate) serving_control: ServingControl,
-    pub(crate) over_tls: bool,
-    pub(crate) port: Vec<u16>,
-    pub(crate) reverse_proxy_config: ReverseProxyConfig,
+fn parse_listener_mode(s: &str) -> Result<(u16, ListenerMode), DynError> {
+    let (port, mode) = s
+        .split_once('=')
+        .ok_or_else(|| format!("invalid --listener-mode {s:?}, should be PORT=MODE"))?;
+    let port = port
+        .parse::<u16>()
+        .map_err(|e| format!("invalid port in --listener-mode {s:?}: {e}"))?;
+    Ok((port, mode.parse()?))
 }
 
-pub(crate) struct ServingControl {
-    pub(crate) prohibit_serving: bool,
-    pub(crate) allowed_networks: Vec<IpNetwork>,
+/// 静态文件托管时实时压缩的算法
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    Gzip,
+    Brotli,
+}
+
+impl Compression {
+    /// Content-Encoding的值
+    pub(crate) fn encoding(&self) -> &'static str {
+        match self {
+            Compression::Gzip => "gzip",
+            Compression::Brotli => "br",
+        }
+    }
+
+    fn level_range(&self) -> std::ops::RangeInclusive<u32> {
+        match self {
+            Compression::Gzip => 1..=9,
+            Compression::Brotli => 0..=11,
+        }
+    }
+
+    /// 兼顾压缩率和CPU，高等级的brotli开销很大
+    fn default_level(&self) -> u32 {
+        match self {
+            Compression::Gzip => 6,
+            Compression::Brotli => 4,
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = DynError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "gzip" => Ok(Compression::Gzip),
+            "br" => Ok(Compression::Brotli),
+            _ => Err(format!("invalid compression algorithm {s:?}, should be gzip or br").into()),
+        }
+    }
+}
+
+fn parse_compression_level(s: &str) -> Result<(Compression, u32), DynError> {
+    let (algo, level) = s
+        .split_once('=')
+        .ok_or_else(|| format!("invalid --compression-level {s:?}, should be ALGO=LEVEL"))?;
+    let algo: Compression = algo.parse()?;
+    let level = level
+        .parse::<u32>()
+        .map_err(|e| format!("invalid level in --compression-level {s:?}: {e}"))?;
+    let range = algo.level_range();
+    if !range.contains(&level) {
+        return Err(
+            format!("{} level should be {}-{}, got {level}", algo.encoding(), range.start(), range.end()).into()
+        );
+    }
+    Ok((algo, level))
+}
+
+/// Host请求头中的端口与监听端口不一致时的处理，见 `--host-port-policy`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum HostPortPolicy {
+    /// 使用Host中的端口
+    #[default]
+    Header,
+    /// 使用监听的端口
+    Listener,
+    /// 返回400
+    Reject,
+}
+
+/// 正向代理的升级请求的处理，见 `--forward-upgrade`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ForwardUpgrade {
+    /// 上游返回101后建立隧道
+    #[default]
+    Tunnel,
+    /// 作为普通请求转发
+    Strip,
+    /// 返回400
+    Reject,
+}
+
+/// `--upstream-ca-dir` 的证书与默认证书的关系
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum UpstreamCaMode {
+    /// 同时信任默认的证书
+    #[default]
+    Add,
+    /// 只信任目录中的证书
+    Only,
+}
+
+/// 正向代理（非CONNECT）时对User-Agent的处理
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum UserAgentRewrite {
+    /// 原样转发
+    #[default]
+    Passthrough,
+    /// 删除User-Agent
+    Remove,
+    /// 替换为固定的值
+    Fixed(HeaderValue),
+}
+
+impl FromStr for UserAgentRewrite {
+    type Err = DynError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "remove" {
+            Ok(UserAgentRewrite::Remove)
+        } else {
+            Ok(UserAgentRewrite::Fixed(
+                HeaderValue::from_str(s).map_err(|e| format!("invalid --rewrite-user-agent {s:?}: {e}"))?,
+            ))
+        }
+    }
+}
+
+/// 一个状态码或一类状态码，例如 `502`、`5xx`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StatusPattern {
+    Exact(u16),
+    /// 状态码的百位
+    Class(u16),
+}
+
+impl FromStr for StatusPattern {
+    type Err = DynError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid =
+            || format!("invalid --close-on-status {s:?}: should be a status code like 502 or a class like 5xx");
+        match s.to_ascii_lowercase().as_str() {
+            class @ ("1xx" | "2xx" | "3xx" | "4xx" | "5xx") => {
+                Ok(StatusPattern::Class(u16::from(class.as_bytes()[0] - b'0')))
+            }
+            code => match code.parse::<u16>() {
+                Ok(code @ 100..=599) => Ok(StatusPattern::Exact(code)),
+                _ => Err(invalid().into()),
+            },
+        }
+    }
+}
+
+impl StatusPattern {
+    pub(crate) fn matches(&self, status: http::StatusCode) -> bool {
+        match self {
+            StatusPattern::Exact(code) => status.as_u16() == *code,
+            StatusPattern::Class(class) => status.as_u16() / 100 == *class,
+        }
+    }
+}
+
+pub struct ServingControl {
+    pub prohibit_serving: bool,
+    pub allowed_networks: Vec<IpNetwork>,
+    pub auth_static: bool,
+}
+
+/// 与命令行参数的默认值保持一致
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            cert: "cert.pem".to_string(),
+            key: "privkey.pem".to_string(),
+            basic_auth: HashMap::new(),
+            web_content_path: "/usr/share/nginx/html".to_string(),
+            referer_keywords_to_self: vec![],
+            never_ask_for_auth: false,
+            serving_control: ServingControl {
+                prohibit_serving: false,
+                allowed_networks: vec![],
+                auth_static: false,
+            },
+            over_tls: false,
+            tls_ticket_lifetime: None,
+            tls_enable_0rtt: false,
+            max_tls_handshakes: None,
+            tls_handshake_queue: 1024,
+            port: vec![3128],
+            ignore_bind_errors: false,
+            unix_listen: None,
+            reverse_proxy_config: ReverseProxyConfig::default(),
+            reverse_proxy_config_url: None,
+            socket_buffer_size: SocketBufferSize::default(),
+            traffic_report_period: TrafficReportPeriod::default(),
+            rewrite_user_agent: UserAgentRewrite::default(),
+            strict_host_check: false,
+            follow_symlinks: false,
+            static_file_filter: StaticFileFilter::default(),
+            serve_embedded: false,
+            log_format: LogFormat::default(),
+            access_log_file: None,
+            access_decision_log: AccessDecisionLog::default(),
+            access_decision_to_access_log: false,
+            log_dir: "/tmp".to_string(),
+            listener_modes: HashMap::new(),
+            max_tunnels: None,
+            retry_after: 5,
+            global_rate_limit: None,
+            global_rate_burst: None,
+            tunnel_buffer_size: crate::proxy::DEFAULT_TUNNEL_BUFFER_SIZE,
+            allow_connect_ports: vec![],
+            allow_methods: vec![],
+            keepalive_max_requests: None,
+            close_on_status: vec![],
+            max_uri_length: 8192,
+            allow_trace: false,
+            tunnel_timing: false,
+            max_headers: None,
+            use_webpki_roots: false,
+            upstream_ca: UpstreamCa::default(),
+            host_overrides: HostOverrides::default(),
+            passthrough_connect_timeout: 5,
+            passthrough_response_timeout: 30,
+            passthrough_retries: 1,
+            passthrough_follow_redirects: None,
+            max_redirect_hops: 5,
+            pool_max_idle_per_host: 5,
+            forward_pool_max_idle: None,
+            forward_pool_idle_timeout: 30,
+            srv_nameserver: None,
+            #[cfg(feature = "statsd")]
+            statsd: None,
+            immutable_asset_pattern: Regex::new(DEFAULT_IMMUTABLE_ASSET_PATTERN).ok(),
+            immutable_max_age: 31536000,
+            default_charset: Some("utf-8".to_string()),
+            served_by: None,
+            request_id_header: None,
+            upload_progress_min_bytes: None,
+            root_response: None,
+            proxy_hostnames: crate::root_response::default_proxy_hostnames(),
+            real_ip_header: None,
+            trusted_proxies: vec![],
+            forwarded_headers: ForwardedHeaders::Strip,
+            host_port_policy: HostPortPolicy::Header,
+            forward_upgrade: ForwardUpgrade::Tunnel,
+            admin_port: None,
+            max_compressions: None,
+            buffer_response_threshold: 65536,
+            compression_preference: vec![Compression::Gzip, Compression::Brotli],
+            strip_response_headers: DEFAULT_STRIP_RESPONSE_HEADERS.map(HeaderName::from_static).to_vec(),
+            gzip_level: Compression::Gzip.default_level(),
+            brotli_level: Compression::Brotli.default_level(),
+            run_as_user: None,
+            run_as_group: None,
+        }
+    }
 }
 
 impl TryFrom<Param> for Config {
     type Error = DynError;
-    fn try_from(mut param: Param) -> Result<Self, Self::Error> {
+    fn try_from(param: Param) -> Result<Self, Self::Error> {
         let mut basic_auth = HashMap::new();
         for raw_user in param.users {
             let mut user = raw_user.split(':');
@@ -121,11 +1042,178 @@ impl TryFrom<Param> for Config {
             }
         }
         let reverse_proxy_config = parse_reverse_proxy_config(
-            &param.reverse_proxy_config_file,
-            &mut param.append_upstream_url,
-            param.enable_github_proxy,
+            ReverseProxyConfigSource {
+                files: param.reverse_proxy_config_file,
+                append_upstream_url: param.append_upstream_url,
+                enable_github_proxy: param.enable_github_proxy,
+            },
+            None,
         )?;
 
+        let port = if param.port.is_empty() && param.unix_listen.is_none() {
+            vec![3128]
+        } else {
+            param.port
+        };
+        let mut listener_modes = HashMap::new();
+        for raw in &param.listener_mode {
+            let (listener_port, mode) = parse_listener_mode(raw)?;
+            if !port.contains(&listener_port) {
+                return Err(format!("--listener-mode for port {listener_port}, which is not listened").into());
+            }
+            listener_modes.insert(listener_port, mode);
+        }
+        let immutable_asset_pattern = match param.immutable_asset_pattern.as_str() {
+            "" => None,
+            pattern => Some(Regex::new(pattern).map_err(|e| format!("invalid --immutable-asset-pattern: {e}"))?),
+        };
+        let default_charset = match param.default_charset.as_str() {
+            "" => None,
+            charset
+                if charset
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b"-_.:".contains(&b)) =>
+            {
+                Some(charset.to_string())
+            }
+            charset => return Err(format!("invalid --default-charset {charset:?}").into()),
+        };
+        let served_by = match param.served_by {
+            Some(value) => {
+                let value = match value.as_str() {
+                    "" => crate::ip_x::hostname()
+                        .or_else(|| crate::ip_x::local_ip().ok())
+                        .unwrap_or("unknown".to_string()),
+                    _ => value,
+                };
+                Some(HeaderValue::from_str(&value).map_err(|e| format!("invalid --served-by {value:?}: {e}"))?)
+            }
+            None => None,
+        };
+        let request_id_header = match &param.request_id_header {
+            Some(name) => {
+                Some(HeaderName::from_str(name).map_err(|e| format!("invalid --request-id-header {name:?}: {e}"))?)
+            }
+            None => None,
+        };
+        let root_response = param.root_response.as_deref().map(str::parse).transpose()?;
+        let proxy_hostnames = if param.proxy_hostname.is_empty() {
+            crate::root_response::default_proxy_hostnames()
+        } else {
+            param
+                .proxy_hostname
+                .iter()
+                .map(|name| name.trim_end_matches('.').to_ascii_lowercase())
+                .collect()
+        };
+        let real_ip_header = match &param.real_ip_header {
+            Some(name) => {
+                Some(HeaderName::from_str(name).map_err(|e| format!("invalid --real-ip-header {name:?}: {e}"))?)
+            }
+            None => None,
+        };
+        let trusted_proxies = param
+            .trusted_proxy
+            .iter()
+            .map(|cidr| IpNetwork::from_str(cidr).map_err(|e| format!("invalid --trusted-proxy {cidr}: {e}")))
+            .collect::<Result<Vec<_>, _>>()?;
+        if real_ip_header.is_some() && trusted_proxies.is_empty() {
+            return Err("--real-ip-header requires at least one --trusted-proxy".into());
+        }
+        if let Some(admin_port) = param.admin_port {
+            if port.contains(&admin_port) {
+                return Err(format!("--admin-port {admin_port} should not be one of the proxy ports").into());
+            }
+        }
+        if let Some(lifetime) = param.tls_ticket_lifetime {
+            if lifetime == 0 || lifetime > crate::tls_session::MAX_TICKET_LIFETIME {
+                return Err(format!(
+                    "--tls-ticket-lifetime should be between 1 and {}",
+                    crate::tls_session::MAX_TICKET_LIFETIME
+                )
+                .into());
+            }
+        }
+        if (param.tls_ticket_lifetime.is_some() || param.tls_enable_0rtt) && !param.over_tls {
+            return Err("--tls-ticket-lifetime and --tls-enable-0rtt require --over-tls".into());
+        }
+        if param.tls_ticket_lifetime.is_some() && param.tls_enable_0rtt {
+            // rustls只允许有状态的会话恢复使用0-RTT（RFC 8446 8.1）
+            return Err("--tls-enable-0rtt can not be used with --tls-ticket-lifetime".into());
+        }
+        if param.forward_pool_idle_timeout == 0 {
+            return Err("--forward-pool-idle-timeout should be greater than 0".into());
+        }
+        if param.max_tls_handshakes == Some(0) {
+            return Err("--max-tls-handshakes should be greater than 0".into());
+        }
+        if param.max_tls_handshakes.is_some() && !param.over_tls {
+            return Err("--max-tls-handshakes requires --over-tls".into());
+        }
+        if !(MIN_TUNNEL_BUFFER_SIZE..=MAX_TUNNEL_BUFFER_SIZE).contains(&param.tunnel_buffer_size) {
+            return Err(format!(
+                "--tunnel-buffer-size should be between {MIN_TUNNEL_BUFFER_SIZE} and {MAX_TUNNEL_BUFFER_SIZE}"
+            )
+            .into());
+        }
+        for (name, size) in [("--so-sndbuf", param.so_sndbuf), ("--so-rcvbuf", param.so_rcvbuf)] {
+            if size.is_some_and(|size| !(1..=MAX_SOCKET_BUFFER_SIZE).contains(&size)) {
+                return Err(format!("{name} should be between 1 and {MAX_SOCKET_BUFFER_SIZE}").into());
+            }
+        }
+        #[cfg(feature = "statsd")]
+        if param.statsd_interval == 0 {
+            return Err("--statsd-interval should be greater than 0".into());
+        }
+        if param.passthrough_follow_redirects == Some(0) {
+            return Err("--passthrough-follow-redirects should be greater than 0".into());
+        }
+        if param.max_redirect_hops == 0 {
+            return Err("--max-redirect-hops should be greater than 0".into());
+        }
+        if param.max_headers == Some(0) {
+            return Err("--max-headers should be greater than 0".into());
+        }
+        if param.auth_static && basic_auth.is_empty() {
+            return Err("--auth-static requires at least one --users".into());
+        }
+        if param.global_rate_limit == Some(0) || param.global_rate_burst == Some(0) {
+            return Err("--global-rate-limit and --global-rate-burst should be greater than 0".into());
+        }
+        if param.global_rate_burst.is_some() && param.global_rate_limit.is_none() {
+            return Err("--global-rate-burst requires --global-rate-limit".into());
+        }
+        let allow_methods = param
+            .allow_method
+            .iter()
+            .map(|method| {
+                Method::from_bytes(method.trim().to_ascii_uppercase().as_bytes())
+                    .map_err(|e| format!("invalid --allow-method {method:?}: {e}"))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if param.keepalive_max_requests == Some(0) {
+            return Err("--keepalive-max-requests should be greater than 0".into());
+        }
+        let compression_preference = param
+            .compression
+            .split(',')
+            .map(str::trim)
+            .filter(|algo| !algo.is_empty())
+            .map(str::parse)
+            .collect::<Result<Vec<Compression>, _>>()?;
+        let (mut gzip_level, mut brotli_level) =
+            (Compression::Gzip.default_level(), Compression::Brotli.default_level());
+        for raw in &param.compression_level {
+            match parse_compression_level(raw)? {
+                (Compression::Gzip, level) => gzip_level = level,
+                (Compression::Brotli, level) => brotli_level = level,
+            }
+        }
+        let rewrite_user_agent = match param.rewrite_user_agent {
+            Some(ref value) => value.parse()?,
+            None => UserAgentRewrite::Passthrough,
+        };
+
         // 处理静态文件托管控制
         // 1. 如果设置了prohibit_serving，则禁止所有静态文件托管
         // 2. 如果会主动询问用户鉴权，且没有设置never_ask_for_auth，也禁止所有静态文件托管
@@ -147,6 +1235,11 @@ impl TryFrom<Param> for Config {
             }
         }
 
+        let serve_embedded = param.serve_embedded && !crate::embedded_content::is_empty();
+        if param.serve_embedded && !serve_embedded {
+            warn!("no web content is embedded at build time, fall back to \"{}\"", param.web_content_path);
+        }
+
         Ok(Config {
             cert: param.cert,
             key: param.key,
@@ -157,20 +1250,140 @@ impl TryFrom<Param> for Config {
             serving_control: ServingControl {
                 prohibit_serving,
                 allowed_networks,
+                auth_static: param.auth_static,
             },
             over_tls: param.over_tls,
-            port: param.port,
+            tls_ticket_lifetime: param.tls_ticket_lifetime,
+            tls_enable_0rtt: param.tls_enable_0rtt,
+            max_tls_handshakes: param.max_tls_handshakes,
+            tls_handshake_queue: param.tls_handshake_queue,
+            port,
+            ignore_bind_errors: param.ignore_bind_errors,
+            unix_listen: param.unix_listen,
             reverse_proxy_config,
+            reverse_proxy_config_url: param.reverse_proxy_config_url,
+            socket_buffer_size: SocketBufferSize {
+                send: param.so_sndbuf,
+                recv: param.so_rcvbuf,
+            },
+            traffic_report_period: param.traffic_report_period,
+            rewrite_user_agent,
+            strict_host_check: param.strict_host_check,
+            follow_symlinks: param.follow_symlinks,
+            static_file_filter: StaticFileFilter::new(&param.static_allow_extension, &param.static_deny_extension)?,
+            serve_embedded,
+            log_format: param.log_format,
+            access_log_file: param.access_log_file,
+            access_decision_log: param.access_decision_log,
+            access_decision_to_access_log: param.access_decision_to_access_log,
+            log_dir: param.log_dir,
+            listener_modes,
+            max_tunnels: param.max_tunnels,
+            retry_after: param.retry_after,
+            global_rate_limit: param.global_rate_limit,
+            global_rate_burst: param.global_rate_burst,
+            tunnel_buffer_size: param.tunnel_buffer_size,
+            allow_connect_ports: param.allow_connect_port,
+            allow_methods,
+            keepalive_max_requests: param.keepalive_max_requests,
+            close_on_status: param
+                .close_on_status
+                .iter()
+                .map(|status| status.parse())
+                .collect::<Result<_, _>>()?,
+            max_uri_length: param.max_uri_length,
+            allow_trace: param.allow_trace,
+            tunnel_timing: param.tunnel_timing,
+            max_headers: param.max_headers,
+            use_webpki_roots: param.use_webpki_roots,
+            upstream_ca: UpstreamCa::load(param.upstream_ca_dir.as_deref(), param.upstream_ca_mode)?,
+            host_overrides: HostOverrides::new(&param.host_override)?,
+            passthrough_connect_timeout: param.passthrough_connect_timeout,
+            passthrough_response_timeout: param.passthrough_response_timeout,
+            passthrough_retries: param.passthrough_retries,
+            passthrough_follow_redirects: param.passthrough_follow_redirects,
+            max_redirect_hops: param.max_redirect_hops,
+            pool_max_idle_per_host: param.pool_max_idle_per_host,
+            forward_pool_max_idle: param.forward_pool_max_idle,
+            forward_pool_idle_timeout: param.forward_pool_idle_timeout,
+            srv_nameserver: param.srv_nameserver,
+            #[cfg(feature = "statsd")]
+            statsd: param.statsd_addr.map(|addr| crate::statsd::StatsdConfig {
+                addr,
+                interval: std::time::Duration::from_secs(param.statsd_interval),
+                prefix: param.statsd_prefix.trim_end_matches('.').to_string(),
+                flavor: param.statsd_flavor,
+            }),
+            immutable_asset_pattern,
+            immutable_max_age: param.immutable_max_age,
+            default_charset,
+            served_by,
+            request_id_header,
+            upload_progress_min_bytes: param.upload_progress_min_bytes,
+            root_response,
+            proxy_hostnames,
+            real_ip_header,
+            trusted_proxies,
+            forwarded_headers: param.forwarded_headers,
+            host_port_policy: param.host_port_policy,
+            forward_upgrade: param.forward_upgrade,
+            admin_port: param.admin_port,
+            max_compressions: param.max_compressions,
+            buffer_response_threshold: param.buffer_response_threshold,
+            compression_preference,
+            strip_response_headers: crate::response_headers::parse_names(&param.strip_response_header)
+                .map_err(|e| format!("invalid --strip-response-header: {e}"))?,
+            gzip_level,
+            brotli_level,
+            run_as_user: param.run_as_user,
+            run_as_group: param.run_as_group,
         })
     }
 }
 
-pub(crate) fn load_config() -> Result<Config, DynError> {
+/// 解析命令行参数并初始化日志
+pub fn load_config() -> Result<Config, DynError> {
     let param = Param::parse();
     if let Err(log_init_error) = init_log(&param.log_dir, &param.log_file, "info") {
         return Err(format!("init log error:{log_init_error}").into());
     }
     info!("build time: {}", crate::BUILD_TIME);
+    let config = Config::try_from(param)?;
+    info!("auto close connection after idle for {IDLE_TIMEOUT:?}");
+    if let Some(lifetime) = config.tls_ticket_lifetime {
+        info!("issue tls session tickets valid for {lifetime}s");
+    }
+    if config.tls_enable_0rtt {
+        warn!("accept tls 0-RTT early data, non-idempotent requests in early data are answered with 425");
+    }
+    for (name, ip) in config.host_overrides.iter() {
+        info!("override dns of {name} to {ip}");
+    }
+    if let Some(max) = config.max_headers {
+        info!("reject requests with more than {max} headers");
+    }
+    if let Some(max) = config.keepalive_max_requests {
+        info!("close keep-alive connection after {max} requests");
+    }
+    if !config.close_on_status.is_empty() {
+        info!("close keep-alive connection after responses of {:?}", config.close_on_status);
+    }
+    if let Some(root_response) = &config.root_response {
+        info!("respond to / of {:?} and IP hosts with {root_response:?}", config.proxy_hostnames);
+    }
+    Ok(config)
+}
+
+/// 编译时选择的crypto provider，见Prometheus指标 `crypto_provider_info`
+pub(crate) const CRYPTO_PROVIDER: &str = if cfg!(all(feature = "ring", not(feature = "aws_lc_rs"))) {
+    "ring"
+} else if cfg!(all(feature = "aws_lc_rs", not(feature = "ring"))) {
+    "aws_lc_rs"
+} else {
+    "none"
+};
+
+pub(crate) fn install_crypto_provider() {
     #[cfg(all(feature = "ring", not(feature = "aws_lc_rs")))]
     {
         info!("use ring as default crypto provider");
@@ -181,17 +1394,17 @@ pub(crate) fn load_config() -> Result<Config, DynError> {
         info!("use aws_lc_rs as default crypto provider");
         let _ = tokio_rustls::rustls::crypto::aws_lc_rs::default_provider().install_default();
     }
-    let config = Config::try_from(param)?;
-    log_config(&config);
-    info!("auto close connection after idle for {IDLE_TIMEOUT:?}");
-    Ok(config)
 }
 
-fn log_config(config: &Config) {
+pub(crate) fn log_config(config: &Config) {
     if config.serving_control.prohibit_serving {
         warn!("do not serve web content to avoid being detected!");
     } else {
-        info!("serve web content of \"{}\"", config.web_content_path);
+        if config.serve_embedded {
+            info!("serve {} embedded files", crate::embedded_content::len());
+        } else {
+            info!("serve web content of \"{}\"", config.web_content_path);
+        }
         if !config.serving_control.allowed_networks.is_empty() {
             info!("Only allowing static content access from networks: {:?}", config.serving_control.allowed_networks);
         } else {
@@ -202,20 +1415,120 @@ fn log_config(config: &Config) {
         }
     }
     info!("basic auth is {:?}", config.basic_auth);
-    if !config.reverse_proxy_config.locations.is_empty() {
+    if config.rewrite_user_agent != UserAgentRewrite::Passthrough {
+        info!("rewrite User-Agent of forward proxy requests: {:?}", config.rewrite_user_agent);
+    }
+    for (port, mode) in &config.listener_modes {
+        info!("listener mode of port {port}: {mode:?}");
+    }
+    if !config.socket_buffer_size.is_default() {
+        info!("socket buffer size: {:?}", config.socket_buffer_size);
+    }
+    if let Some(url) = &config.reverse_proxy_config_url {
+        info!("reverse proxy config will be fetched from {url}");
+    }
+    log_reverse_proxy_config(&config.reverse_proxy_config);
+}
+
+pub(crate) fn log_reverse_proxy_config(reverse_proxy_config: &ReverseProxyConfig) {
+    if !reverse_proxy_config.locations.is_empty() {
         info!("reverse proxy config: ");
     }
-    config
-        .reverse_proxy_config
-        .locations
-        .iter()
-        .for_each(|reverse_proxy_config| {
-            for ele in reverse_proxy_config.1 {
+    reverse_proxy_config.locations.iter().for_each(|reverse_proxy_config| {
+        for ele in reverse_proxy_config.1 {
+            info!(
+                "    {:<70} -> {}**",
+                format!("http(s)://{}:port{}**", reverse_proxy_config.0, ele.route_key()),
+                ele.upstream.url_base,
+            );
+            if let Some(canary) = &ele.canary {
                 info!(
-                    "    {:<70} -> {}**",
-                    format!("http(s)://{}:port{}**", reverse_proxy_config.0, ele.location),
-                    ele.upstream.url_base,
+                    "    {:<70} -> {}** ({}%, sticky: {})",
+                    "canary", canary.upstream.url_base, canary.percent, canary.sticky
                 );
             }
-        });
+            if let Some(fault) = &ele.fault {
+                warn!(
+                    "    fault injection is configured for {}**: {:?}, toggle it by POST /fault_injection",
+                    ele.location, fault
+                );
+            }
+            if ele.opaque_upstream_body {
+                warn!(
+                    "    opaque_upstream_body is enabled for {}**, chunked framing will not be decoded",
+                    ele.location
+                );
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_listener_mode() {
+        assert_eq!(parse_listener_mode("443=reverse-only").ok(), Some((443, ListenerMode::ReverseOnly)));
+        assert_eq!(parse_listener_mode("80=static-only").ok(), Some((80, ListenerMode::StaticOnly)));
+        #[cfg(target_os = "linux")]
+        assert_eq!(parse_listener_mode("12345=transparent").ok(), Some((12345, ListenerMode::Transparent)));
+        assert!(parse_listener_mode("443").is_err());
+        assert!(parse_listener_mode("443=reverse").is_err());
+        assert!(parse_listener_mode("http=static-only").is_err());
+    }
+
+    #[test]
+    fn test_parse_status_pattern() -> Result<(), DynError> {
+        let class: StatusPattern = "5XX".parse()?;
+        assert!(class.matches(http::StatusCode::BAD_GATEWAY));
+        assert!(!class.matches(http::StatusCode::NOT_FOUND));
+        let exact: StatusPattern = "502".parse()?;
+        assert!(exact.matches(http::StatusCode::BAD_GATEWAY));
+        assert!(!exact.matches(http::StatusCode::SERVICE_UNAVAILABLE));
+        assert!("6xx".parse::<StatusPattern>().is_err());
+        assert!("99".parse::<StatusPattern>().is_err());
+        assert!("5x".parse::<StatusPattern>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_compression_level() {
+        assert_eq!(parse_compression_level("gzip=9").ok(), Some((Compression::Gzip, 9)));
+        assert_eq!(parse_compression_level("br=0").ok(), Some((Compression::Brotli, 0)));
+        assert!(parse_compression_level("gzip=0").is_err());
+        assert!(parse_compression_level("br=12").is_err());
+        assert!(parse_compression_level("zstd=3").is_err());
+        assert!(parse_compression_level("gzip").is_err());
+    }
+
+    /// 按命令行参数构造配置
+    fn config_of(args: &[&str]) -> Result<Config, DynError> {
+        let param = Param::try_parse_from(std::iter::once("rust_http_proxy").chain(args.iter().copied()))?;
+        Config::try_from(param)
+    }
+
+    #[test]
+    fn test_served_by() -> Result<(), DynError> {
+        assert!(config_of(&[])?.served_by.is_none());
+        let config = config_of(&["--served-by", "node-1"])?;
+        assert_eq!(config.served_by, Some(HeaderValue::from_static("node-1")));
+        // 不指定VALUE时使用主机名或本机IP
+        let config = config_of(&["--served-by"])?;
+        assert!(config.served_by.is_some_and(|value| !value.is_empty()));
+        assert!(config_of(&["--served-by", "bad\nvalue"]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_socket_buffer_size() -> Result<(), DynError> {
+        let config = config_of(&["--so-sndbuf", "4194304", "--so-rcvbuf", "2147483647"])?;
+        assert_eq!(config.socket_buffer_size.send, Some(4194304));
+        assert_eq!(config.socket_buffer_size.recv, Some(MAX_SOCKET_BUFFER_SIZE));
+        // 超过i32的值设置到socket时会被截断
+        assert!(config_of(&["--so-sndbuf", "4294967297"]).is_err());
+        assert!(config_of(&["--so-rcvbuf", "2147483648"]).is_err());
+        assert!(config_of(&["--so-sndbuf", "0"]).is_err());
+        Ok(())
+    }
 }
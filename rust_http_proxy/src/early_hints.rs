@@ -0,0 +1,84 @@
+//! 反向代理时处理上游返回的 `103 Early Hints`
+//!
+//! hyper的server端不支持发送1xx响应，无法将103原样转发给客户端。这里收集103中的 `Link` 响应头，
+//! 合并到最终响应中，浏览器同样会根据最终响应的 `Link` 预加载资源。只有HTTP/1.1的上游会回调1xx响应。
+
+use std::sync::{Arc, Mutex};
+
+use http::{header, HeaderValue, Request, Response};
+
+#[derive(Clone, Default)]
+pub(crate) struct EarlyHints(Arc<Mutex<Vec<HeaderValue>>>);
+
+impl EarlyHints {
+    /// 在发送给上游的请求上注册1xx响应的回调
+    pub(crate) fn capture<B>(req: &mut Request<B>) -> Self {
+        let hints = EarlyHints::default();
+        let links = hints.0.clone();
+        hyper::ext::on_informational(req, move |resp| {
+            // http crate没有定义103的常量
+            if resp.status().as_u16() != 103 {
+                return;
+            }
+            if let Ok(mut links) = links.lock() {
+                links.extend(resp.headers().get_all(header::LINK).iter().cloned());
+            }
+        });
+        hints
+    }
+
+    /// 将103中出现、最终响应中没有的 `Link` 添加到最终响应
+    pub(crate) fn apply<B>(&self, resp: &mut Response<B>) {
+        let Ok(links) = self.0.lock() else {
+            return;
+        };
+        for link in links.iter() {
+            if !resp.headers().get_all(header::LINK).iter().any(|value| value == link) {
+                resp.headers_mut().append(header::LINK, link.clone());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::StatusCode;
+    use http_body_util::{BodyExt, Empty};
+    use hyper::body::Bytes;
+    use hyper_util::{client::legacy, rt::TokioExecutor};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_early_hints() -> Result<(), crate::DynError> {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            if let Ok((mut stream, _)) = listener.accept().await {
+                let mut buf = [0u8; 1024];
+                let _ = stream.read(&mut buf).await;
+                let _ = stream
+                    .write_all(
+                        b"HTTP/1.1 103 Early Hints\r\nLink: </a.css>; rel=preload; as=style\r\n\r\n\
+                        HTTP/1.1 200 OK\r\nLink: </b.js>; rel=preload; as=script\r\nContent-Length: 2\r\n\r\nok",
+                    )
+                    .await;
+            }
+        });
+        let client: legacy::Client<_, Empty<Bytes>> = legacy::Client::builder(TokioExecutor::new()).build_http();
+        let mut req = Request::get(format!("http://{addr}/")).body(Empty::new())?;
+        let hints = EarlyHints::capture(&mut req);
+        let mut resp = client.request(req).await?;
+        hints.apply(&mut resp);
+        assert_eq!(resp.status(), StatusCode::OK);
+        let links = resp
+            .headers()
+            .get_all(header::LINK)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .collect::<Vec<_>>();
+        assert_eq!(links, vec!["</b.js>; rel=preload; as=script", "</a.css>; rel=preload; as=style"]);
+        assert_eq!(resp.into_body().collect().await?.to_bytes(), Bytes::from_static(b"ok"));
+        Ok(())
+    }
+}
@@ -0,0 +1,85 @@
+//! 构建时通过 `RUST_HTTP_PROXY_EMBED_DIR` 嵌入的静态文件，见build.rs
+//!
+//! `--serve-embedded` 时只从这里查找文件，不再访问web_content_path；构建时没有嵌入文件则仍使用磁盘。
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub(crate) struct EmbeddedFile {
+    pub(crate) path: &'static str,
+    pub(crate) data: &'static [u8],
+    modified_secs: u64,
+}
+
+impl EmbeddedFile {
+    /// 构建时文件的修改时间，用于Last-Modified和ETag
+    pub(crate) fn modified(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(self.modified_secs)
+    }
+}
+
+static EMBEDDED: &[EmbeddedFile] = include!(concat!(env!("OUT_DIR"), "/embedded_web_content.rs"));
+
+pub(crate) fn is_empty() -> bool {
+    EMBEDDED.is_empty()
+}
+
+pub(crate) fn len() -> usize {
+    EMBEDDED.len()
+}
+
+/// 与磁盘上一样，以 `/` 结尾或者是目录时查找其下的index.html
+pub(crate) fn find(url_path: &str) -> Option<&'static EmbeddedFile> {
+    find_in(EMBEDDED, url_path)
+}
+
+/// 精确查找，用于预压缩的 `.br`、`.gz` 文件
+pub(crate) fn get(path: &str) -> Option<&'static EmbeddedFile> {
+    get_in(EMBEDDED, path)
+}
+
+fn get_in<'a>(files: &'a [EmbeddedFile], path: &str) -> Option<&'a EmbeddedFile> {
+    files
+        .binary_search_by(|file| file.path.cmp(path))
+        .ok()
+        .and_then(|i| files.get(i))
+}
+
+fn find_in<'a>(files: &'a [EmbeddedFile], url_path: &str) -> Option<&'a EmbeddedFile> {
+    if url_path.ends_with('/') {
+        return get_in(files, &format!("{url_path}index.html"));
+    }
+    get_in(files, url_path).or_else(|| get_in(files, &format!("{url_path}/index.html")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find() {
+        let files = [
+            EmbeddedFile {
+                path: "/docs/index.html",
+                data: b"docs",
+                modified_secs: 0,
+            },
+            EmbeddedFile {
+                path: "/index.html",
+                data: b"index",
+                modified_secs: 0,
+            },
+            EmbeddedFile {
+                path: "/index.html.gz",
+                data: b"gz",
+                modified_secs: 0,
+            },
+        ];
+        let data = |url_path| find_in(&files, url_path).map(|file| file.data);
+        assert_eq!(data("/"), Some(&b"index"[..]));
+        assert_eq!(data("/index.html"), Some(&b"index"[..]));
+        assert_eq!(data("/docs"), Some(&b"docs"[..]));
+        assert_eq!(data("/docs/"), Some(&b"docs"[..]));
+        assert_eq!(data("/missing"), None);
+        assert_eq!(get_in(&files, "/index.html.gz").map(|file| file.data), Some(&b"gz"[..]));
+    }
+}
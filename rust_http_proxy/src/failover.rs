@@ -0,0 +1,134 @@
+//! location的主备上游：`upstream` 为主上游，`backup_upstreams` 按顺序为备用上游
+//!
+//! 不同于按权重分流，主上游可用时所有请求都发送到主上游，只有前面的上游都不可用时才使用后面的备用上游。
+//! 可用性由被动健康检查决定：连续 `max_fails` 次失败（连接错误、超时或502/503/504）后，
+//! 该上游在 `fail_timeout_secs` 内视为不可用，之后的请求会再次尝试它，成功即恢复。
+//! 所有上游都不可用时仍然使用主上游。失败的请求不会在同一个请求内重试到备用上游。
+
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use http::StatusCode;
+use log::{info, warn};
+use prom_label::LabelImpl;
+
+use crate::proxy::LocationLabel;
+use crate::reverse::Upstream;
+use crate::METRICS;
+
+pub(crate) const DEFAULT_MAX_FAILS: u32 = 3;
+pub(crate) const DEFAULT_FAIL_TIMEOUT_SECS: u64 = 10;
+
+/// 运行时状态，不参与配置的比较
+#[derive(Default)]
+pub(crate) struct UpstreamHealth {
+    consecutive_fails: AtomicU32,
+    down_until: Mutex<Option<Instant>>,
+}
+
+impl PartialEq for UpstreamHealth {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for UpstreamHealth {}
+
+impl PartialOrd for UpstreamHealth {
+    fn partial_cmp(&self, _: &Self) -> Option<std::cmp::Ordering> {
+        Some(std::cmp::Ordering::Equal)
+    }
+}
+
+impl UpstreamHealth {
+    /// 不可用的时间过去之后重新视为可用，由下一个请求验证
+    pub(crate) fn is_available(&self, now: Instant) -> bool {
+        self.down_until
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .map_or(true, |until| now >= until)
+    }
+
+    /// 记录一次请求的结果
+    pub(crate) fn record(&self, ok: bool, max_fails: u32, fail_timeout: Duration, url_base: &str) {
+        let mut down_until = self.down_until.lock().unwrap_or_else(|e| e.into_inner());
+        if ok {
+            self.consecutive_fails.store(0, Ordering::Relaxed);
+            if down_until.take().is_some() {
+                info!("[failover] upstream {url_base} recovered");
+            }
+            return;
+        }
+        let fails = self.consecutive_fails.fetch_add(1, Ordering::Relaxed) + 1;
+        if fails >= max_fails {
+            let now = Instant::now();
+            if down_until.map_or(true, |until| now >= until) {
+                warn!(
+                    "[failover] upstream {url_base} failed {fails} times in a row, mark it down for {fail_timeout:?}"
+                );
+            }
+            *down_until = Some(now + fail_timeout);
+        }
+    }
+}
+
+/// 依次返回第一个可用的上游及其层级（0为主上游），都不可用时返回主上游
+pub(crate) fn select<'a>(primary: &'a Upstream, backups: &'a [Upstream], origin: &str) -> (usize, &'a Upstream) {
+    let now = Instant::now();
+    let (tier, upstream) = std::iter::once(primary)
+        .chain(backups)
+        .enumerate()
+        .find(|(_, upstream)| upstream.health.is_available(now))
+        .unwrap_or((0, primary));
+    METRICS
+        .reverse_proxy_failover_tier
+        .get_or_create(&LabelImpl::new(LocationLabel {
+            origin: origin.to_string(),
+        }))
+        .set(tier as i64);
+    (tier, upstream)
+}
+
+/// 上游返回这些状态码时也计为失败，通常表示它自己的后端不可用
+pub(crate) fn is_unavailable_status(status: StatusCode) -> bool {
+    matches!(status, StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE | StatusCode::GATEWAY_TIMEOUT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mark_down_and_recover() {
+        let health = UpstreamHealth::default();
+        let now = Instant::now();
+        health.record(false, 2, Duration::from_secs(10), "http://primary");
+        assert!(health.is_available(now));
+        health.record(false, 2, Duration::from_secs(10), "http://primary");
+        assert!(!health.is_available(Instant::now()));
+        assert!(health.is_available(Instant::now() + Duration::from_secs(11)));
+        health.record(true, 2, Duration::from_secs(10), "http://primary");
+        assert!(health.is_available(Instant::now()));
+        // 恢复后重新计数
+        health.record(false, 2, Duration::from_secs(10), "http://primary");
+        assert!(health.is_available(Instant::now()));
+    }
+
+    #[test]
+    fn test_retry_after_fail_timeout_fails_again() {
+        let health = UpstreamHealth::default();
+        health.record(false, 1, Duration::ZERO, "http://primary");
+        // fail_timeout过去后再次尝试，仍然失败则立即重新标记为不可用
+        assert!(health.is_available(Instant::now()));
+        health.record(false, 1, Duration::from_secs(10), "http://primary");
+        assert!(!health.is_available(Instant::now()));
+    }
+
+    #[test]
+    fn test_is_unavailable_status() {
+        assert!(is_unavailable_status(StatusCode::BAD_GATEWAY));
+        assert!(!is_unavailable_status(StatusCode::INTERNAL_SERVER_ERROR));
+        assert!(!is_unavailable_status(StatusCode::NOT_FOUND));
+    }
+}
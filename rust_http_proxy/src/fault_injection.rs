@@ -0,0 +1,157 @@
+//! 反向代理的故障注入，用于测试客户端的容错能力
+//!
+//! 只对配置了 `fault` 的location生效，并且需要先通过 `POST /fault_injection?enabled=true` 打开全局开关。
+//! 启动时开关总是关闭的，避免在生产环境中被意外开启。
+
+use std::{
+    io,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use http::{Response, StatusCode};
+use http_body_util::combinators::BoxBody;
+use hyper::body::Bytes;
+use log::warn;
+use prom_label::LabelImpl;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::proxy::{full_body, FaultInjectedLabel};
+use crate::METRICS;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+pub(crate) fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+pub(crate) fn set_enabled(enabled: bool) {
+    let previous = ENABLED.swap(enabled, Ordering::Relaxed);
+    if previous != enabled {
+        warn!("fault injection is {}", if enabled { "ENABLED" } else { "disabled" });
+    }
+}
+
+#[derive(Serialize, Deserialize, Eq, PartialEq, Debug)]
+pub(crate) struct FaultInjection {
+    #[serde(default)]
+    pub(crate) delay_ms: u64, // 注入的延迟
+    #[serde(default)]
+    pub(crate) delay_percent: u32, // 0-100，注入延迟的请求百分比
+    #[serde(default = "default_abort_status")]
+    pub(crate) abort_status: u16, // 直接返回的状态码
+    #[serde(default)]
+    pub(crate) abort_percent: u32, // 0-100，直接返回abort_status的请求百分比
+}
+
+fn default_abort_status() -> u16 {
+    503
+}
+
+impl FaultInjection {
+    pub(crate) fn validate(&self, location: &str) -> Result<(), crate::DynError> {
+        if self.delay_percent > 100 || self.abort_percent > 100 {
+            return Err(format!("fault injection percent of location {location} should be in 0-100").into());
+        }
+        StatusCode::from_u16(self.abort_status)
+            .map_err(|e| format!("invalid abort_status of location {location}: {e}"))?;
+        Ok(())
+    }
+
+    /// 按配置的概率延迟或中止请求，中止时返回响应
+    pub(crate) async fn inject(&self, origin: &str) -> Option<Response<BoxBody<Bytes, io::Error>>> {
+        if !is_enabled() {
+            return None;
+        }
+        if hit(self.abort_percent) {
+            warn!("[fault injection] abort {origin} with {}", self.abort_status);
+            record(origin, "abort");
+            let mut resp = Response::new(full_body("fault injected"));
+            *resp.status_mut() = StatusCode::from_u16(self.abort_status).unwrap_or(StatusCode::SERVICE_UNAVAILABLE);
+            return Some(resp);
+        }
+        if self.delay_ms > 0 && hit(self.delay_percent) {
+            warn!("[fault injection] delay {origin} for {}ms", self.delay_ms);
+            record(origin, "delay");
+            tokio::time::sleep(Duration::from_millis(self.delay_ms)).await;
+        }
+        None
+    }
+}
+
+fn hit(percent: u32) -> bool {
+    percent > 0 && rand::rng().random_range(0..100) < percent
+}
+
+fn record(origin: &str, kind: &'static str) {
+    METRICS
+        .reverse_proxy_fault_injected
+        .get_or_create(&LabelImpl::new(FaultInjectedLabel {
+            origin: origin.to_string(),
+            kind,
+        }))
+        .inc();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fault(delay_ms: u64, delay_percent: u32, abort_status: u16, abort_percent: u32) -> FaultInjection {
+        FaultInjection {
+            delay_ms,
+            delay_percent,
+            abort_status,
+            abort_percent,
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(fault(10, 100, 503, 100).validate("/").is_ok());
+        assert!(fault(10, 101, 503, 0).validate("/").is_err());
+        assert!(fault(0, 0, 503, 101).validate("/").is_err());
+        assert!(fault(0, 0, 1000, 0).validate("/").is_err());
+    }
+
+    #[test]
+    fn test_hit() {
+        assert!((0..1000).all(|_| !hit(0)));
+        assert!((0..1000).all(|_| hit(100)));
+        let hits = (0..10000).filter(|_| hit(30)).count();
+        assert!((2500..3500).contains(&hits), "{hits}");
+    }
+
+    // 全局开关只在这个测试中打开
+    #[tokio::test]
+    async fn test_inject() {
+        let abort = fault(0, 0, 418, 100);
+        set_enabled(false);
+        assert!(abort.inject("test_inject").await.is_none());
+
+        set_enabled(true);
+        let resp = abort.inject("test_inject").await;
+        assert_eq!(resp.map(|resp| resp.status()), Some(StatusCode::IM_A_TEAPOT));
+
+        let delay = fault(50, 100, 503, 0);
+        let started = std::time::Instant::now();
+        assert!(delay.inject("test_inject").await.is_none());
+        assert!(started.elapsed() >= Duration::from_millis(50));
+
+        let never = fault(1000, 0, 503, 0);
+        let started = std::time::Instant::now();
+        assert!(never.inject("test_inject").await.is_none());
+        assert!(started.elapsed() < Duration::from_millis(500));
+
+        let partial = fault(0, 0, 503, 30);
+        let mut aborted = 0;
+        for _ in 0..1000 {
+            if partial.inject("test_inject").await.is_some() {
+                aborted += 1;
+            }
+        }
+        assert!((200..400).contains(&aborted), "{aborted}");
+        set_enabled(false);
+    }
+}
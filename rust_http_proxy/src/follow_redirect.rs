@@ -0,0 +1,373 @@
+//! location的 `follow_redirects`：由代理跟随上游返回的重定向，将最终的响应流式返回给客户端
+//!
+//! 用于上游重定向到签名的存储URL等客户端无法通过代理访问的地址。只跟随没有请求体的GET、HEAD请求，
+//! 其他请求的重定向原样返回给客户端。跳转到其他host时不再发送 `Authorization`、`Cookie` 等凭证。
+//! 超过最大跳转次数（不超过 `--max-redirect-hops`）或者跳转回访问过的URL时返回 [`TooManyRedirects`]，
+//! 由调用方返回508 Loop Detected。
+//!
+//! 为了避免SSRF，默认不跟随到回环、私有、链路本地（例如 `169.254.169.254`）等内部地址的重定向，
+//! 域名会先解析后检查，这样的重定向原样返回给客户端；与原始上游相同的host:port不受限制。
+
+use std::fmt::Write;
+use std::future::Future;
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use http::header::{AUTHORIZATION, COOKIE, HOST, LOCATION, PROXY_AUTHORIZATION};
+use http::{HeaderMap, Method, Request, Response, StatusCode, Uri, Version};
+use http_body_util::combinators::BoxBody;
+use hyper::body::{Body, Bytes};
+use log::{debug, warn};
+
+use crate::proxy::empty_body;
+
+#[derive(Debug)]
+pub(crate) struct TooManyRedirects(String);
+
+impl std::fmt::Display for TooManyRedirects {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for TooManyRedirects {}
+
+impl TooManyRedirects {
+    fn error(msg: String) -> io::Error {
+        io::Error::new(ErrorKind::InvalidData, TooManyRedirects(msg))
+    }
+
+    pub(crate) fn is(e: &io::Error) -> bool {
+        e.get_ref().is_some_and(|inner| inner.is::<TooManyRedirects>())
+    }
+}
+
+/// 跟随重定向时重新构造请求所需的信息
+pub(crate) struct RedirectTemplate {
+    method: Method,
+    uri: Uri,
+    version: Version,
+    headers: HeaderMap,
+}
+
+impl RedirectTemplate {
+    /// 只有没有请求体的GET、HEAD请求可以跟随重定向
+    pub(crate) fn capture<B: Body>(req: &Request<B>) -> Option<Self> {
+        if !matches!(*req.method(), Method::GET | Method::HEAD) || !req.body().is_end_stream() {
+            return None;
+        }
+        Some(RedirectTemplate {
+            method: req.method().clone(),
+            uri: req.uri().clone(),
+            version: req.version(),
+            headers: req.headers().clone(),
+        })
+    }
+}
+
+/// 从 `resp` 开始跟随最多 `max_hops` 次重定向，返回最终的响应。`allow_internal` 为true时也跟随到内部地址
+pub(crate) async fn follow<B, F, Fut>(
+    mut resp: Response<B>, template: RedirectTemplate, max_hops: u32, allow_internal: bool, send: F,
+) -> io::Result<Response<B>>
+where
+    F: Fn(Request<BoxBody<Bytes, io::Error>>) -> Fut,
+    Fut: Future<Output = io::Result<Response<B>>>,
+{
+    let RedirectTemplate {
+        method,
+        mut uri,
+        version,
+        mut headers,
+    } = template;
+    headers.remove(HOST);
+    let upstream_authority = uri.authority().cloned();
+    let mut visited = vec![uri.clone()];
+    loop {
+        let Some(next) = redirect_target(&resp, &uri) else {
+            return Ok(resp);
+        };
+        if visited.contains(&next) {
+            return Err(TooManyRedirects::error(format!("redirect loop: {next} was visited")));
+        }
+        if visited.len() > max_hops as usize {
+            return Err(TooManyRedirects::error(format!("too many redirects, stop at {next} after {max_hops} hops")));
+        }
+        if !allow_internal && next.authority() != upstream_authority.as_ref() && is_internal(&next).await {
+            warn!("[reverse] do not follow redirect {uri} -> {next}: internal address");
+            return Ok(resp);
+        }
+        debug!("[reverse] follow redirect {} {uri} -> {next}", resp.status().as_u16());
+        if next.authority() != uri.authority() {
+            for name in [AUTHORIZATION, COOKIE, PROXY_AUTHORIZATION] {
+                headers.remove(name);
+            }
+        }
+        let mut builder = Request::builder()
+            .method(method.clone())
+            .uri(next.clone())
+            .version(version);
+        if let Some(builder_headers) = builder.headers_mut() {
+            builder_headers.extend(headers.clone());
+        }
+        let req = builder
+            .body(empty_body())
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+        visited.push(next.clone());
+        uri = next;
+        resp = send(req).await?;
+    }
+}
+
+/// 跟随的重定向状态码及其 `Location` 相对于 `current` 解析后的URL，不是重定向时返回None
+fn redirect_target<B>(resp: &Response<B>, current: &Uri) -> Option<Uri> {
+    if !matches!(
+        resp.status(),
+        StatusCode::MOVED_PERMANENTLY
+            | StatusCode::FOUND
+            | StatusCode::SEE_OTHER
+            | StatusCode::TEMPORARY_REDIRECT
+            | StatusCode::PERMANENT_REDIRECT
+    ) {
+        return None;
+    }
+    let location = resp.headers().get(LOCATION)?.to_str().ok()?;
+    let resolved = resolve(current, location);
+    if resolved.is_none() {
+        debug!("[reverse] can not follow redirect to {location:?}");
+    }
+    resolved
+}
+
+/// 重定向的目标是否为内部地址，域名解析到的任意一个地址是内部地址即视为内部地址。解析失败时由后续的请求报错
+async fn is_internal(uri: &Uri) -> bool {
+    let Some(host) = uri.host() else {
+        return true;
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return is_internal_ip(ip);
+    }
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    if host == "localhost" || host.ends_with(".localhost") {
+        return true;
+    }
+    let port = uri
+        .port_u16()
+        .unwrap_or(if uri.scheme_str() == Some("https") { 443 } else { 80 });
+    let addrs = tokio::net::lookup_host((host.as_str(), port)).await;
+    addrs.is_ok_and(|mut addrs| addrs.any(|addr| is_internal_ip(addr.ip())))
+}
+
+fn is_internal_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_internal_ipv4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_internal_ipv4(ip),
+            None => is_internal_ipv6(ip),
+        },
+    }
+}
+
+fn is_internal_ipv4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        || (a == 100 && (b & 0xc0) == 64) // 100.64.0.0/10，运营商级NAT
+}
+
+fn is_internal_ipv6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    ip.is_loopback()
+        || ip.is_unspecified()
+        || (first & 0xfe00) == 0xfc00 // fc00::/7，唯一本地地址
+        || (first & 0xffc0) == 0xfe80 // fe80::/10，链路本地地址
+}
+
+/// 按RFC 3986解析绝对URL、`//host/path`、`/path` 和相对路径，只支持http和https
+fn resolve(base: &Uri, location: &str) -> Option<Uri> {
+    let scheme = base.scheme_str()?;
+    let authority = base.authority()?;
+    let mut absolute = String::new();
+    if location.starts_with("//") {
+        write!(absolute, "{scheme}:{location}").ok()?;
+    } else if location
+        .split_once("://")
+        .is_some_and(|(prefix, _)| !prefix.contains('/'))
+    {
+        absolute.push_str(location);
+    } else if location.starts_with('/') {
+        write!(absolute, "{scheme}://{authority}{location}").ok()?;
+    } else {
+        let dir = base.path().rsplit_once('/').map_or("", |(dir, _)| dir);
+        write!(absolute, "{scheme}://{authority}{dir}/{location}").ok()?;
+    }
+    let uri: Uri = absolute.parse().ok()?;
+    match (uri.scheme_str(), uri.authority()) {
+        (Some("http" | "https"), Some(_)) => Some(uri),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::Empty;
+
+    fn redirect(status: StatusCode, location: &str) -> Result<Response<Empty<Bytes>>, http::Error> {
+        Response::builder()
+            .status(status)
+            .header(LOCATION, location)
+            .body(Empty::new())
+    }
+
+    fn template(uri: &str) -> Result<RedirectTemplate, Box<dyn std::error::Error>> {
+        let req = Request::get(uri)
+            .header(AUTHORIZATION, "Bearer secret")
+            .body(empty_body())?;
+        Ok(RedirectTemplate::capture(&req).ok_or("not captured")?)
+    }
+
+    #[test]
+    fn test_resolve() -> Result<(), http::uri::InvalidUri> {
+        let base: Uri = "https://github.com/owner/repo/releases/download/v1/app.zip".parse()?;
+        let resolve = |location| resolve(&base, location).map(|uri| uri.to_string());
+        assert_eq!(
+            resolve("https://objects.example.com/a?sig=1"),
+            Some("https://objects.example.com/a?sig=1".to_string())
+        );
+        assert_eq!(resolve("//cdn.example.com/a"), Some("https://cdn.example.com/a".to_string()));
+        assert_eq!(resolve("/login"), Some("https://github.com/login".to_string()));
+        assert_eq!(
+            resolve("v2/app.zip"),
+            Some("https://github.com/owner/repo/releases/download/v1/v2/app.zip".to_string())
+        );
+        assert_eq!(resolve("ftp://example.com/a"), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_capture() -> Result<(), http::Error> {
+        assert!(RedirectTemplate::capture(&Request::head("http://a/").body(empty_body())?).is_some());
+        assert!(RedirectTemplate::capture(&Request::post("http://a/").body(empty_body())?).is_none());
+        assert!(RedirectTemplate::capture(&Request::get("http://a/").body(crate::proxy::full_body("x"))?).is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_follow() -> Result<(), Box<dyn std::error::Error>> {
+        let send = |req: Request<BoxBody<Bytes, io::Error>>| async move {
+            let resp = match req.uri().path() {
+                "/storage" => {
+                    // 跳转到其他host时不发送凭证
+                    assert!(!req.headers().contains_key(AUTHORIZATION));
+                    Response::new(Empty::new())
+                }
+                "/loop" => redirect(StatusCode::FOUND, "http://a/start").map_err(io::Error::other)?,
+                path => redirect(StatusCode::FOUND, &format!("{path}/next")).map_err(io::Error::other)?,
+            };
+            Ok::<_, io::Error>(resp)
+        };
+        let first = redirect(StatusCode::FOUND, "http://storage.example.com/storage")?;
+        let resp = follow(first, template("http://a/start")?, 3, false, send).await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let not_redirect = Response::new(Empty::new());
+        assert_eq!(
+            follow(not_redirect, template("http://a/start")?, 3, false, send)
+                .await?
+                .status(),
+            StatusCode::OK
+        );
+
+        let first = redirect(StatusCode::TEMPORARY_REDIRECT, "/loop")?;
+        assert!(follow(first, template("http://a/start")?, 3, false, send)
+            .await
+            .is_err_and(|e| TooManyRedirects::is(&e)));
+
+        let first = redirect(StatusCode::MOVED_PERMANENTLY, "/hop")?;
+        assert!(follow(first, template("http://a/start")?, 3, false, send)
+            .await
+            .is_err_and(|e| TooManyRedirects::is(&e)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_follow_internal_address() -> Result<(), Box<dyn std::error::Error>> {
+        let send = |req: Request<BoxBody<Bytes, io::Error>>| async move {
+            let resp = match req.uri().path() {
+                "/same-upstream" => Response::new(Empty::new()),
+                _ => {
+                    redirect(StatusCode::FOUND, "http://169.254.169.254/latest/meta-data/").map_err(io::Error::other)?
+                }
+            };
+            Ok::<_, io::Error>(resp)
+        };
+        for location in [
+            "http://169.254.169.254/latest/meta-data/",
+            "http://127.0.0.1:8080/admin",
+            "http://10.0.0.1/",
+            "http://[::1]/",
+            "http://[::ffff:192.168.1.1]/",
+            "http://[fd00::1]/",
+            "http://localhost/",
+        ] {
+            let first = redirect(StatusCode::FOUND, location)?;
+            let resp = follow(first, template("http://203.0.113.1/start")?, 3, false, send).await?;
+            assert_eq!(resp.status(), StatusCode::FOUND, "{location}");
+            assert_eq!(resp.headers().get(LOCATION).and_then(|v| v.to_str().ok()), Some(location));
+        }
+        // 上游本身是内部地址时，跳转到同一个host:port不受限制
+        let first = redirect(StatusCode::FOUND, "/same-upstream")?;
+        let resp = follow(first, template("http://10.0.0.1/start")?, 3, false, send).await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+        // 公网地址跳转到内部地址时也不跟随
+        let first = redirect(StatusCode::FOUND, "http://203.0.113.2/public")?;
+        let resp = follow(first, template("http://203.0.113.1/start")?, 3, false, send).await?;
+        assert_eq!(
+            resp.headers().get(LOCATION).and_then(|v| v.to_str().ok()),
+            Some("http://169.254.169.254/latest/meta-data/")
+        );
+        // 明确允许时跟随
+        let first = redirect(StatusCode::FOUND, "http://10.0.0.2/same-upstream")?;
+        let resp = follow(first, template("http://203.0.113.1/start")?, 3, true, send).await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_follow_loop_terminates() -> Result<(), Box<dyn std::error::Error>> {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        // a和b互相跳转
+        let sent = AtomicUsize::new(0);
+        let send = |req: Request<BoxBody<Bytes, io::Error>>| {
+            sent.fetch_add(1, Ordering::Relaxed);
+            let location = match req.uri().host() {
+                Some("a") => "http://b/bounce",
+                _ => "http://a/start",
+            };
+            async move { redirect(StatusCode::FOUND, location).map_err(io::Error::other) }
+        };
+        let first = redirect(StatusCode::FOUND, "http://b/bounce")?;
+        let result = follow(first, template("http://a/start")?, 5, false, send).await;
+        assert!(result.is_err_and(|e| TooManyRedirects::is(&e) && e.to_string().contains("loop")));
+        assert_eq!(sent.load(Ordering::Relaxed), 1);
+
+        // 每次都跳转到新的URL时，在上限处停止
+        let sent = AtomicUsize::new(0);
+        let send = |req: Request<BoxBody<Bytes, io::Error>>| {
+            sent.fetch_add(1, Ordering::Relaxed);
+            let location = format!("{}/next", req.uri().path());
+            async move { redirect(StatusCode::FOUND, &location).map_err(io::Error::other) }
+        };
+        let first = redirect(StatusCode::FOUND, "/next")?;
+        assert!(follow(first, template("http://a/start")?, 5, false, send)
+            .await
+            .is_err_and(|e| TooManyRedirects::is(&e)));
+        assert_eq!(sent.load(Ordering::Relaxed), 5);
+        Ok(())
+    }
+}
@@ -9,7 +9,7 @@ use std::{
     time::{Duration, Instant},
 };
 
-use http::{header, HeaderMap, HeaderValue, Version};
+use http::{header, HeaderMap, HeaderValue, StatusCode, Version};
 use hyper::{
     body::{self, Body},
     client::conn::http1,
@@ -23,14 +23,17 @@ use lru_time_cache::LruCache;
 use prom_label::LabelImpl;
 use tokio::{net::TcpStream, sync::Mutex};
 
-use crate::proxy::AccessLabel;
-
-const CONNECTION_EXPIRE_DURATION: Duration = Duration::from_secs(if !cfg!(debug_assertions) { 30 } else { 10 });
+use crate::proxy::{AccessLabel, ForwardPoolLabel};
+use crate::METRICS;
 
 /// ForwardProxyClient, supporting HTTP/1.1 and H2, HTTPS.
 pub struct ForwardProxyClient<B> {
     #[allow(clippy::type_complexity)]
     cache_conn: Arc<Mutex<LruCache<AccessLabel, VecDeque<(HttpConnection<B>, Instant)>>>>,
+    /// --forward-pool-idle-timeout
+    idle_timeout: Duration,
+    /// --forward-pool-max-idle，None表示不限制
+    max_idle: Option<usize>,
 }
 
 impl<B> ForwardProxyClient<B>
@@ -41,23 +44,37 @@ where
 {
     /// Create a new HttpClient
     pub fn new() -> ForwardProxyClient<B> {
+        Self::with_idle_timeout(
+            Duration::from_secs(crate::CONFIG.forward_pool_idle_timeout),
+            crate::CONFIG.forward_pool_max_idle,
+        )
+    }
+
+    pub(crate) fn with_idle_timeout(idle_timeout: Duration, max_idle: Option<usize>) -> ForwardProxyClient<B> {
         ForwardProxyClient {
-            cache_conn: Arc::new(Mutex::new(LruCache::with_expiry_duration(CONNECTION_EXPIRE_DURATION))),
+            cache_conn: Arc::new(Mutex::new(LruCache::with_expiry_duration(idle_timeout))),
+            idle_timeout,
+            max_idle,
         }
     }
 
     /// Make HTTP requests
+    ///
+    /// `upgrade` 为true时连接可能升级为隧道：总是新建连接，空闲超时与隧道相同（IDLE_TIMEOUT），用完也不放回连接池
     #[inline]
     pub async fn send_request(
-        &self, req: Request<B>, access_label: &AccessLabel,
+        &self, req: Request<B>, access_label: &AccessLabel, upgrade: bool,
         stream_map_func: impl FnOnce(TcpStream, AccessLabel) -> CounterIO<TcpStream, LabelImpl<AccessLabel>>,
     ) -> Result<Response<body::Incoming>, std::io::Error> {
         // 1. Check if there is an available client
-        if let Some(c) = self.get_cached_connection(access_label).await {
-            debug!("HTTP client for host: {} taken from cache", &access_label);
-            match self.send_request_conn(access_label, c, req).await {
-                Ok(o) => return Ok(o),
-                Err(err) => return Err(io::Error::new(io::ErrorKind::InvalidData, err)),
+        if !upgrade {
+            if let Some(c) = self.get_cached_connection(access_label).await {
+                debug!("HTTP client for host: {} taken from cache", &access_label);
+                record_pool_event("reused");
+                match self.send_request_conn(access_label, c, req, true).await {
+                    Ok(o) => return Ok(o),
+                    Err(err) => return Err(io::Error::new(io::ErrorKind::InvalidData, err)),
+                }
             }
         }
 
@@ -67,15 +84,23 @@ where
             None => &Scheme::HTTP,
         };
 
-        let c = match HttpConnection::connect(scheme, access_label, stream_map_func).await {
-            Ok(c) => c,
+        let idle_timeout = if upgrade {
+            crate::IDLE_TIMEOUT
+        } else {
+            self.idle_timeout
+        };
+        let c = match HttpConnection::connect(scheme, access_label, stream_map_func, idle_timeout).await {
+            Ok(c) => {
+                record_pool_event("connected");
+                c
+            }
             Err(err) => {
                 error!("failed to connect to host: {}, error: {}", &access_label.target, err);
                 return Err(io::Error::new(io::ErrorKind::InvalidData, err));
             }
         };
 
-        self.send_request_conn(access_label, c, req)
+        self.send_request_conn(access_label, c, req, !upgrade)
             .await
             .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
     }
@@ -85,13 +110,15 @@ where
             debug!("HTTP client for host: {} found in cache, len: {}", access_label, q.len());
             while let Some((c, inst)) = q.pop_front() {
                 let now = Instant::now();
-                if now - inst >= CONNECTION_EXPIRE_DURATION {
+                if now - inst >= self.idle_timeout {
                     debug!("HTTP connection for host: {access_label} expired",);
+                    record_pool_event("expired");
                     continue;
                 }
                 if c.is_closed() {
                     // true at once after connection.await return
                     debug!("HTTP connection for host: {access_label} is closed",);
+                    record_pool_event("closed");
                     continue;
                 }
                 return Some(c);
@@ -103,27 +130,40 @@ where
     }
 
     async fn send_request_conn(
-        &self, access_label: &AccessLabel, mut c: HttpConnection<B>, req: Request<B>,
+        &self, access_label: &AccessLabel, mut c: HttpConnection<B>, req: Request<B>, reusable: bool,
     ) -> hyper::Result<Response<body::Incoming>> {
         trace!("HTTP making request to host: {access_label}, request: {req:?}");
         let response = c.send_request(req).await?;
         trace!("HTTP received response from host: {access_label}, response: {response:?}");
 
-        // Check keep-alive
-        if check_keep_alive(response.version(), response.headers(), false) {
+        // Check keep-alive，101之后的连接用于升级后的协议，不能放回连接池
+        if reusable
+            && response.status() != StatusCode::SWITCHING_PROTOCOLS
+            && check_keep_alive(response.version(), response.headers(), false)
+        {
             trace!("HTTP connection keep-alive for host: {access_label}, response: {response:?}");
-            self.cache_conn
-                .lock()
-                .await
-                .entry(access_label.clone())
-                .or_insert_with(VecDeque::new)
-                .push_back((c, Instant::now()));
+            let mut cache_conn = self.cache_conn.lock().await;
+            let idle = cache_conn.entry(access_label.clone()).or_insert_with(VecDeque::new);
+            if self.max_idle.is_some_and(|max_idle| idle.len() >= max_idle) {
+                debug!("HTTP connection for host: {access_label} is not kept, --forward-pool-max-idle reached");
+                record_pool_event("full");
+            } else {
+                idle.push_back((c, Instant::now()));
+            }
         }
 
         Ok(response)
     }
 }
 
+/// reused和connected之比即为连接的复用率
+fn record_pool_event(event: &'static str) {
+    METRICS
+        .forward_proxy_pool
+        .get_or_create(&LabelImpl::new(ForwardPoolLabel { event }))
+        .inc();
+}
+
 pub fn check_keep_alive(version: Version, headers: &HeaderMap<HeaderValue>, check_proxy: bool) -> bool {
     // HTTP/1.1, HTTP/2, HTTP/3 keeps alive by default
     let mut conn_keep_alive = !matches!(version, Version::HTTP_09 | Version::HTTP_10);
@@ -182,22 +222,25 @@ where
     async fn connect(
         scheme: &Scheme, access_label: &AccessLabel,
         stream_map_func: impl FnOnce(TcpStream, AccessLabel) -> CounterIO<TcpStream, LabelImpl<AccessLabel>>,
+        idle_timeout: Duration,
     ) -> io::Result<HttpConnection<B>> {
         if *scheme != Scheme::HTTP && *scheme != Scheme::HTTPS {
             return Err(io::Error::new(ErrorKind::InvalidInput, "invalid scheme"));
         }
 
-        let stream = TcpStream::connect(&access_label.target).await?;
+        let target = crate::CONFIG.host_overrides.rewrite(&access_label.target);
+        let stream = crate::CONFIG.socket_buffer_size.connect(target.as_ref()).await?;
         let stream: CounterIO<TcpStream, LabelImpl<AccessLabel>> = stream_map_func(stream, access_label.clone());
 
-        HttpConnection::connect_http_http1(scheme, access_label, stream).await
+        HttpConnection::connect_http_http1(scheme, access_label, stream, idle_timeout).await
     }
 
     async fn connect_http_http1(
         scheme: &Scheme, access_label: &AccessLabel, stream: CounterIO<TcpStream, LabelImpl<AccessLabel>>,
+        idle_timeout: Duration,
     ) -> io::Result<HttpConnection<B>> {
         trace!("HTTP making new HTTP/1.1 connection to host: {access_label}, scheme: {scheme}");
-        let stream = TimeoutIO::new(stream, CONNECTION_EXPIRE_DURATION);
+        let stream = TimeoutIO::new(stream, idle_timeout);
 
         // HTTP/1.x
         let (send_request, connection) = match http1::Builder::new()
@@ -212,7 +255,7 @@ where
 
         let access_label = access_label.clone();
         tokio::spawn(async move {
-            if let Err(err) = connection.await {
+            if let Err(err) = connection.with_upgrades().await {
                 handle_http1_connection_error(err, access_label);
             }
         });
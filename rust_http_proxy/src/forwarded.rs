@@ -0,0 +1,148 @@
+//! 反向代理转发给上游的 `X-Forwarded-*` 请求头，由 `--forwarded-headers` 控制
+//!
+//! 客户端可以随意伪造 `X-Forwarded-For` 等请求头，上游如果据此判断客户端IP就会被欺骗。
+//! 默认（`strip`）只有来自 `--trusted-proxy` 的请求才保留这些请求头，其他请求先删除，
+//! 再由本代理追加对端IP到 `X-Forwarded-For`，并设置 `X-Forwarded-Proto`、`X-Forwarded-Host`。
+//! `passthrough` 为原来的行为：原样转发，不追加。正向代理不受影响。
+
+use std::net::{IpAddr, SocketAddr};
+
+use clap::ValueEnum;
+use http::{HeaderMap, HeaderName, HeaderValue};
+use ipnetwork::IpNetwork;
+
+use crate::proxy::SchemeHostPort;
+
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+const X_FORWARDED_PROTO: HeaderName = HeaderName::from_static("x-forwarded-proto");
+const X_FORWARDED_HOST: HeaderName = HeaderName::from_static("x-forwarded-host");
+
+/// 不可信的对端发送的这些请求头会被删除
+const FORWARDING_HEADERS: [&str; 6] = [
+    "forwarded",
+    "x-forwarded-for",
+    "x-forwarded-proto",
+    "x-forwarded-host",
+    "x-forwarded-port",
+    "x-real-ip",
+];
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum ForwardedHeaders {
+    /// 删除不可信对端发送的转发请求头，并追加本代理的
+    #[default]
+    Strip,
+    /// 原样转发，不追加
+    Passthrough,
+}
+
+/// 连接的对端地址，不受--real-ip-header影响
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct PeerAddr(pub(crate) SocketAddr);
+
+impl ForwardedHeaders {
+    /// `real_ip_header` 为--real-ip-header，不可信的对端发送的该请求头同样会被删除
+    pub(crate) fn apply(
+        self, headers: &mut HeaderMap, peer: IpAddr, original: &SchemeHostPort, trusted_proxies: &[IpNetwork],
+        real_ip_header: Option<&HeaderName>,
+    ) {
+        if self == ForwardedHeaders::Passthrough {
+            return;
+        }
+        let peer = peer.to_canonical();
+        let trusted = trusted_proxies.iter().any(|network| network.contains(peer));
+        if !trusted {
+            for name in FORWARDING_HEADERS.iter().copied() {
+                headers.remove(name);
+            }
+            if let Some(name) = real_ip_header {
+                headers.remove(name);
+            }
+        }
+        let chain = headers
+            .get_all(X_FORWARDED_FOR)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .chain(std::iter::once(peer.to_string().as_str()))
+            .collect::<Vec<_>>()
+            .join(", ");
+        if let Ok(value) = HeaderValue::from_str(&chain) {
+            headers.insert(X_FORWARDED_FOR, value);
+        }
+        // 可信的前置代理设置的值更接近客户端看到的
+        if !headers.contains_key(X_FORWARDED_PROTO) {
+            if let Ok(value) = HeaderValue::from_str(&original.scheme) {
+                headers.insert(X_FORWARDED_PROTO, value);
+            }
+        }
+        if !headers.contains_key(X_FORWARDED_HOST) {
+            let host = match original.port {
+                Some(port) => format!("{}:{port}", original.host),
+                None => original.host.clone(),
+            };
+            if let Ok(value) = HeaderValue::from_str(&host) {
+                headers.insert(X_FORWARDED_HOST, value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn original() -> SchemeHostPort {
+        SchemeHostPort {
+            scheme: "https".to_string(),
+            host: "example.com".to_string(),
+            port: None,
+        }
+    }
+
+    #[test]
+    fn test_strip_untrusted() -> Result<(), Box<dyn std::error::Error>> {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", HeaderValue::from_static("6.6.6.6"));
+        headers.insert("forwarded", HeaderValue::from_static("for=6.6.6.6"));
+        headers.insert("x-forwarded-proto", HeaderValue::from_static("http"));
+        headers.insert("cf-connecting-ip", HeaderValue::from_static("6.6.6.6"));
+        let trusted = [IpNetwork::from_str("10.0.0.0/8")?];
+        let real_ip_header = HeaderName::from_static("cf-connecting-ip");
+        ForwardedHeaders::Strip.apply(
+            &mut headers,
+            IpAddr::from([1, 2, 3, 4]),
+            &original(),
+            &trusted,
+            Some(&real_ip_header),
+        );
+        assert_eq!(headers.get("x-forwarded-for"), Some(&HeaderValue::from_static("1.2.3.4")));
+        assert_eq!(headers.get("x-forwarded-proto"), Some(&HeaderValue::from_static("https")));
+        assert_eq!(headers.get("x-forwarded-host"), Some(&HeaderValue::from_static("example.com")));
+        assert!(headers.get("forwarded").is_none());
+        assert!(headers.get("cf-connecting-ip").is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_preserve_trusted_chain() -> Result<(), Box<dyn std::error::Error>> {
+        let mut headers = HeaderMap::new();
+        headers.append("x-forwarded-for", HeaderValue::from_static("1.2.3.4"));
+        headers.append("x-forwarded-for", HeaderValue::from_static("5.6.7.8"));
+        headers.insert("x-forwarded-proto", HeaderValue::from_static("http"));
+        let trusted = [IpNetwork::from_str("10.0.0.0/8")?];
+        ForwardedHeaders::Strip.apply(&mut headers, IpAddr::from([10, 0, 0, 1]), &original(), &trusted, None);
+        assert_eq!(headers.get("x-forwarded-for"), Some(&HeaderValue::from_static("1.2.3.4, 5.6.7.8, 10.0.0.1")));
+        assert_eq!(headers.get("x-forwarded-proto"), Some(&HeaderValue::from_static("http")));
+        Ok(())
+    }
+
+    #[test]
+    fn test_passthrough() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", HeaderValue::from_static("6.6.6.6"));
+        ForwardedHeaders::Passthrough.apply(&mut headers, IpAddr::from([1, 2, 3, 4]), &original(), &[], None);
+        assert_eq!(headers.get("x-forwarded-for"), Some(&HeaderValue::from_static("6.6.6.6")));
+        assert!(headers.get("x-forwarded-host").is_none());
+    }
+}
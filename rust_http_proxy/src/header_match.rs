@@ -0,0 +1,103 @@
+//! location的请求头匹配条件，用于按 `X-Api-Version` 等请求头将同一path分流到不同的upstream
+//!
+//! 一个location的所有条件都满足时才匹配；同名请求头出现多次时，任意一个值满足即可。
+
+use std::fmt;
+
+use http::{HeaderMap, HeaderName};
+use regex::Regex;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+#[derive(Serialize, Deserialize, Eq, PartialEq, Debug)]
+pub(crate) struct HeaderMatch {
+    pub(crate) name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) value: Option<String>, // 精确匹配
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) regex: Option<HeaderRegex>, // 正则匹配，不自动添加^$
+}
+
+impl HeaderMatch {
+    pub(crate) fn validate(&self, location: &str) -> Result<(), crate::DynError> {
+        HeaderName::from_bytes(self.name.as_bytes())
+            .map_err(|e| format!("invalid header name {:?} of location {location}: {e}", self.name))?;
+        if self.value.is_some() == self.regex.is_some() {
+            return Err(format!(
+                "header match {} of location {location} should set exactly one of value and regex",
+                self.name
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    pub(crate) fn matches(&self, headers: &HeaderMap) -> bool {
+        headers
+            .get_all(self.name.as_str())
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .any(|value| match (&self.value, &self.regex) {
+                (Some(expected), _) => value == expected,
+                (None, Some(regex)) => regex.0.is_match(value),
+                (None, None) => false,
+            })
+    }
+}
+
+impl fmt::Display for HeaderMatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.value, &self.regex) {
+            (Some(value), _) => write!(f, "{}={value}", self.name),
+            (None, Some(regex)) => write!(f, "{}~{}", self.name, regex.0.as_str()),
+            (None, None) => write!(f, "{}", self.name),
+        }
+    }
+}
+
+/// 解析配置时编译的正则
+#[derive(Debug)]
+pub(crate) struct HeaderRegex(Regex);
+
+impl PartialEq for HeaderRegex {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+impl Eq for HeaderRegex {}
+
+impl Serialize for HeaderRegex {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.0.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for HeaderRegex {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let pattern = String::deserialize(deserializer)?;
+        Regex::new(&pattern).map(HeaderRegex).map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::HeaderValue;
+
+    #[test]
+    fn test_matches() -> Result<(), toml::de::Error> {
+        let exact: HeaderMatch = toml::from_str("name = \"X-Api-Version\"\nvalue = \"2\"")?;
+        let regex: HeaderMatch = toml::from_str("name = \"user-agent\"\nregex = \"(?i)mobile\"")?;
+        assert!(exact.validate("/").is_ok() && regex.validate("/").is_ok());
+        let mut headers = HeaderMap::new();
+        assert!(!exact.matches(&headers));
+        headers.append("x-api-version", HeaderValue::from_static("1"));
+        assert!(!exact.matches(&headers));
+        headers.append("x-api-version", HeaderValue::from_static("2"));
+        assert!(exact.matches(&headers));
+        headers.insert(http::header::USER_AGENT, HeaderValue::from_static("Foo Mobile Safari"));
+        assert!(regex.matches(&headers));
+        assert!(toml::from_str::<HeaderMatch>("name = \"a\"\nregex = \"(\"").is_err());
+        Ok(())
+    }
+}
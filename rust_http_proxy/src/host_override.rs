@@ -0,0 +1,141 @@
+//! `--host-override name=ip`：只在代理内部将指定的域名解析为固定的IP，类似 `/etc/hosts`
+//!
+//! 用于测试和分离DNS（split-horizon）的场景，优先于系统的DNS解析，对CONNECT隧道、正向代理和反向代理的上游都生效。
+
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    future::Future,
+    io,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use hyper_util::client::legacy::connect::dns::{GaiResolver, Name};
+use log::debug;
+use tower_service::Service;
+
+use crate::DynError;
+
+/// 使用 `--host-override` 的连接器，替代 `hyper_util` 默认的 `HttpConnector<GaiResolver>`
+pub(crate) type HttpConnector = hyper_util::client::legacy::connect::HttpConnector<HostOverrideResolver>;
+
+/// 域名均为小写且不带末尾的 `.`
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct HostOverrides(Arc<HashMap<String, IpAddr>>);
+
+impl HostOverrides {
+    /// 解析 `name=ip`，IPv6地址可以带方括号
+    pub(crate) fn new(values: &[String]) -> Result<Self, DynError> {
+        let mut overrides = HashMap::new();
+        for value in values {
+            let invalid = || format!("invalid --host-override {value:?}, should be name=ip");
+            let (name, ip) = value.split_once('=').ok_or_else(invalid)?;
+            let name = normalize(name);
+            let ip = ip.trim().trim_start_matches('[').trim_end_matches(']');
+            if name.is_empty() || name.contains([':', '/']) {
+                return Err(invalid().into());
+            }
+            let ip: IpAddr = ip.parse().map_err(|_| invalid())?;
+            if overrides.insert(name, ip).is_some() {
+                return Err(format!("duplicated --host-override {value:?}").into());
+            }
+        }
+        Ok(HostOverrides(Arc::new(overrides)))
+    }
+
+    pub(crate) fn get(&self, host: &str) -> Option<IpAddr> {
+        if self.0.is_empty() {
+            return None;
+        }
+        self.0.get(&normalize(host)).copied()
+    }
+
+    /// 将 `host:port` 形式的连接目标中被覆盖的域名替换为IP
+    pub(crate) fn rewrite<'a>(&self, target: &'a str) -> Cow<'a, str> {
+        let Some((host, port)) = target.rsplit_once(':') else {
+            return Cow::Borrowed(target);
+        };
+        match (self.get(host), port.parse::<u16>()) {
+            (Some(ip), Ok(port)) => {
+                let addr = SocketAddr::new(ip, port);
+                debug!("[host-override] {target} -> {addr}");
+                Cow::Owned(addr.to_string())
+            }
+            _ => Cow::Borrowed(target),
+        }
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&String, &IpAddr)> {
+        self.0.iter()
+    }
+}
+
+fn normalize(name: &str) -> String {
+    name.trim().trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// 先查找 `--host-override`，没有覆盖时使用系统的DNS解析
+#[derive(Clone)]
+pub(crate) struct HostOverrideResolver {
+    overrides: HostOverrides,
+    gai: GaiResolver,
+}
+
+impl HostOverrideResolver {
+    pub(crate) fn new(overrides: HostOverrides) -> Self {
+        HostOverrideResolver {
+            overrides,
+            gai: GaiResolver::new(),
+        }
+    }
+}
+
+impl Service<Name> for HostOverrideResolver {
+    type Response = std::vec::IntoIter<SocketAddr>;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.gai.poll_ready(cx)
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        if let Some(ip) = self.overrides.get(name.as_str()) {
+            debug!("[host-override] {} -> {ip}", name.as_str());
+            // 端口由HttpConnector设置
+            return Box::pin(std::future::ready(Ok(vec![SocketAddr::new(ip, 0)].into_iter())));
+        }
+        let resolving = self.gai.call(name);
+        Box::pin(async move { Ok(resolving.await?.collect::<Vec<_>>().into_iter()) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[tokio::test]
+    async fn test_host_overrides() -> Result<(), DynError> {
+        let overrides = HostOverrides::new(&[
+            "API.example.com.=10.0.0.1".to_string(),
+            "v6.example.com=[::1]".to_string(),
+        ])?;
+        assert_eq!(overrides.rewrite("api.example.com:443"), "10.0.0.1:443");
+        assert_eq!(overrides.rewrite("v6.example.com:80"), "[::1]:80");
+        assert_eq!(overrides.rewrite("other.example.com:443"), "other.example.com:443");
+        assert!(HostOverrides::new(&["api.example.com".to_string()]).is_err());
+        assert!(HostOverrides::new(&["api.example.com=not-an-ip".to_string()]).is_err());
+
+        let mut resolver = HostOverrideResolver::new(overrides);
+        let resolved = resolver
+            .call(Name::from_str("Api.Example.com")?)
+            .await?
+            .collect::<Vec<_>>();
+        assert_eq!(resolved, vec![SocketAddr::from(([10, 0, 0, 1], 0))]);
+        Ok(())
+    }
+}
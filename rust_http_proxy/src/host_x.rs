@@ -0,0 +1,246 @@
+//! 解析 `Host` 请求头和URI中的host、port
+//!
+//! 支持带方括号的IPv6、省略的端口以及非ASCII的域名（按UTS #46转换为punycode），host统一转为小写，
+//! 其他格式错误的值返回 `InvalidData`，由调用方返回400。反向代理配置中的host也按同样的方式规范化。
+
+use std::io::{self, ErrorKind};
+use std::net::Ipv6Addr;
+
+use http::Uri;
+
+/// 域名的最大长度，见RFC 1035
+const MAX_HOST_LEN: usize = 253;
+const MAX_LABEL_LEN: usize = 63;
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct HostPort {
+    /// 小写的域名、IPv4地址，或者带方括号的IPv6地址
+    pub(crate) host: String,
+    pub(crate) port: Option<u16>,
+}
+
+/// 解析 `Host` 请求头的值，即 `host [ ":" port ]`
+pub(crate) fn parse_host(value: &[u8]) -> io::Result<HostPort> {
+    let value = std::str::from_utf8(value)
+        .map_err(|_| invalid("host is not valid UTF-8"))?
+        .trim_matches([' ', '\t']);
+    if value.is_empty() {
+        return Err(invalid("host is empty"));
+    }
+    if let Some(rest) = value.strip_prefix('[') {
+        let (ip, rest) = rest
+            .split_once(']')
+            .ok_or_else(|| invalid(format!("unclosed IPv6 literal in host {value:?}")))?;
+        let ip = ip
+            .parse::<Ipv6Addr>()
+            .map_err(|_| invalid(format!("invalid IPv6 literal in host {value:?}")))?;
+        let port = match rest {
+            "" => None,
+            rest => match rest.strip_prefix(':') {
+                Some(port) => parse_port(port)?,
+                None => return Err(invalid(format!("unexpected characters after IPv6 literal in host {value:?}"))),
+            },
+        };
+        return Ok(HostPort {
+            host: format!("[{ip}]"),
+            port,
+        });
+    }
+    if value.matches(':').count() > 1 {
+        return Err(invalid(format!("too many colons in host {value:?}, IPv6 address should be bracketed")));
+    }
+    let (host, port) = match value.split_once(':') {
+        Some((host, port)) => (host, parse_port(port)?),
+        None => (value, None),
+    };
+    Ok(HostPort {
+        host: normalize_domain(host)?,
+        port,
+    })
+}
+
+/// 从已经解析过的URI中取host和port，同样做规范化
+pub(crate) fn from_uri(uri: &Uri) -> io::Result<HostPort> {
+    let host = uri.host().ok_or_else(|| invalid("host is absent in uri"))?;
+    let host = parse_host(host.as_bytes())?.host;
+    Ok(HostPort {
+        host,
+        port: uri.port_u16(),
+    })
+}
+
+/// 规范化反向代理配置中的host，使其与 [`parse_host`] 的结果可以直接比较
+pub(crate) fn normalize_config_host(value: &str) -> io::Result<String> {
+    let parsed = parse_host(value.as_bytes())?;
+    if parsed.port.is_some() {
+        return Err(invalid(format!("host {value:?} should not contain port")));
+    }
+    Ok(parsed.host)
+}
+
+/// 空的端口视为省略，见RFC 3986
+fn parse_port(port: &str) -> io::Result<Option<u16>> {
+    if port.is_empty() {
+        return Ok(None);
+    }
+    if port.len() > 5 || !port.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(invalid(format!("invalid port {port:?}")));
+    }
+    match port.parse::<u16>() {
+        Ok(0) | Err(_) => Err(invalid(format!("invalid port {port:?}"))),
+        Ok(port) => Ok(Some(port)),
+    }
+}
+
+/// 转为小写，非ASCII的域名转换为punycode，并检查字符和长度。允许一个表示FQDN的结尾的点
+fn normalize_domain(host: &str) -> io::Result<String> {
+    let trimmed = host.strip_suffix('.').unwrap_or(host);
+    if trimmed.is_empty() {
+        return Err(invalid("host is empty"));
+    }
+    let ascii = if trimmed.is_ascii() {
+        trimmed.to_ascii_lowercase()
+    } else {
+        idna::domain_to_ascii(trimmed).map_err(|_| invalid(format!("can not encode host {host:?}")))?
+    };
+    for label in ascii.split('.') {
+        if label.is_empty() || label.len() > MAX_LABEL_LEN {
+            return Err(invalid(format!("invalid label length in host {host:?}")));
+        }
+        if !label
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+        {
+            return Err(invalid(format!("invalid characters in host {host:?}")));
+        }
+    }
+    if ascii.len() > MAX_HOST_LEN {
+        return Err(invalid(format!("host is longer than {MAX_HOST_LEN} bytes")));
+    }
+    Ok(ascii)
+}
+
+fn invalid(msg: impl Into<String>) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, msg.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::Rng;
+
+    fn host_port(host: &str, port: Option<u16>) -> HostPort {
+        HostPort {
+            host: host.to_string(),
+            port,
+        }
+    }
+
+    #[test]
+    fn test_parse_host() -> io::Result<()> {
+        assert_eq!(parse_host(b"www.Example.com")?, host_port("www.example.com", None));
+        assert_eq!(parse_host(b"www.example.com:8080")?, host_port("www.example.com", Some(8080)));
+        assert_eq!(parse_host(b"www.example.com:")?, host_port("www.example.com", None));
+        assert_eq!(parse_host(b"www.example.com.")?, host_port("www.example.com", None));
+        assert_eq!(parse_host(b" 127.0.0.1:80 ")?, host_port("127.0.0.1", Some(80)));
+        assert_eq!(parse_host(b"[::1]")?, host_port("[::1]", None));
+        assert_eq!(parse_host(b"[2001:DB8:0:0::1]:443")?, host_port("[2001:db8::1]", Some(443)));
+        assert_eq!(parse_host("München.DE".as_bytes())?, host_port("xn--mnchen-3ya.de", None));
+        assert_eq!(parse_host("例子.测试:8443".as_bytes())?, host_port("xn--fsqu00a.xn--0zwm56d", Some(8443)));
+        assert_eq!(parse_host(b"xn--mnchen-3ya.de")?, host_port("xn--mnchen-3ya.de", None));
+        for value in [
+            &b""[..],
+            b"   ",
+            b":80",
+            b"::1",
+            b"[::1",
+            b"[::1]x",
+            b"[example.com]",
+            b"[fe80::1%eth0]",
+            b"a:b:c",
+            b"example.com:0",
+            b"example.com:65536",
+            b"example.com:+80",
+            b"example.com: 80",
+            b"example.com:80:80",
+            b"exa mple.com",
+            b"example..com",
+            b".example.com",
+            b"user@example.com",
+            b"example.com/path",
+            b"\xff\xfe",
+        ] {
+            assert!(parse_host(value).is_err(), "{:?}", String::from_utf8_lossy(value));
+        }
+        assert_eq!(parse_host(b"[::1]:")?, host_port("[::1]", None));
+        assert!(parse_host("a".repeat(64).as_bytes()).is_err());
+        assert!(parse_host(vec!["a".repeat(63); 5].join(".").as_bytes()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_normalize_config_host() -> io::Result<()> {
+        assert_eq!(normalize_config_host("WWW.Example.com.")?, "www.example.com");
+        assert_eq!(normalize_config_host("München.de")?, "xn--mnchen-3ya.de");
+        assert_eq!(normalize_config_host("default_host")?, "default_host");
+        assert!(normalize_config_host("example.com:8080").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_from_uri() -> Result<(), crate::DynError> {
+        let uri: Uri = "http://WWW.Example.com:8080/path".parse()?;
+        assert_eq!(from_uri(&uri)?, host_port("www.example.com", Some(8080)));
+        let uri: Uri = "http://[::1]/".parse()?;
+        assert_eq!(from_uri(&uri)?, host_port("[::1]", None));
+        assert!(from_uri(&"/path".parse()?).is_err());
+        Ok(())
+    }
+
+    /// 随机生成由容易出问题的字符组成的输入，不应panic，解析成功的结果再次解析应不变
+    #[test]
+    fn test_parse_host_random() -> io::Result<()> {
+        const ALPHABET: &[&str] = &[
+            "a",
+            "Z",
+            "0",
+            "9",
+            "-",
+            "_",
+            ".",
+            ":",
+            "[",
+            "]",
+            " ",
+            "\t",
+            "%",
+            "@",
+            "/",
+            "ü",
+            "例",
+            "\u{10FFFF}",
+            "::",
+            "[::1]",
+            "65535",
+            "65536",
+            "xn--",
+        ];
+        let mut rng = rand::rng();
+        for _ in 0..20000 {
+            let len = rng.random_range(0..12);
+            let value = (0..len)
+                .map(|_| ALPHABET[rng.random_range(0..ALPHABET.len())])
+                .collect::<String>();
+            let Ok(parsed) = parse_host(value.as_bytes()) else {
+                continue;
+            };
+            let formatted = match parsed.port {
+                Some(port) => format!("{}:{port}", parsed.host),
+                None => parsed.host.clone(),
+            };
+            assert_eq!(parse_host(formatted.as_bytes())?, parsed, "{value:?}");
+            assert!(formatted.parse::<http::uri::Authority>().is_ok(), "{value:?}");
+        }
+        Ok(())
+    }
+}
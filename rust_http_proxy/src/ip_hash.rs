@@ -0,0 +1,152 @@
+//! location的 `ip_hash_upstreams`：按客户端IP一致性哈希选择上游，实现会话保持
+//!
+//! `upstream` 和 `ip_hash_upstreams` 一起组成哈希环，每个上游按 `url_base` 放置多个虚拟节点，
+//! 同一个客户端IP（IPv4映射的IPv6地址按IPv4处理）总是落到同一个上游；增删上游时只有少部分客户端会换到别的上游。
+//! 选中的上游被动健康检查不可用时（规则同 `backup_upstreams`），沿环顺时针使用下一个可用的上游，
+//! 所有上游都不可用时仍然使用选中的上游。Prometheus指标 `reverse_proxy_ip_hash_req` 为各上游分到的请求数。
+
+use std::net::IpAddr;
+
+use prom_label::LabelImpl;
+
+use crate::proxy::IpHashLabel;
+use crate::METRICS;
+
+/// 每个上游在环上的虚拟节点数，越多分布越均匀
+const VIRTUAL_NODES: usize = 160;
+
+/// 运行时状态，在校验配置时生成，不参与配置的比较
+#[derive(Default)]
+pub(crate) struct HashRing {
+    /// (哈希值, 上游下标)，按哈希值排序
+    points: Vec<(u64, usize)>,
+    len: usize,
+}
+
+impl PartialEq for HashRing {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for HashRing {}
+
+impl PartialOrd for HashRing {
+    fn partial_cmp(&self, _: &Self) -> Option<std::cmp::Ordering> {
+        Some(std::cmp::Ordering::Equal)
+    }
+}
+
+impl HashRing {
+    /// 虚拟节点只由url_base决定，与上游的顺序无关
+    pub(crate) fn new<'a>(url_bases: impl IntoIterator<Item = &'a str>) -> Self {
+        let mut points = vec![];
+        let mut len = 0;
+        for (index, url_base) in url_bases.into_iter().enumerate() {
+            for replica in 0..VIRTUAL_NODES {
+                points.push((hash(format!("{url_base}#{replica}").as_bytes()), index));
+            }
+            len = index + 1;
+        }
+        points.sort_unstable();
+        HashRing { points, len }
+    }
+
+    /// 返回client_ip对应的上游下标，跳过 `available` 返回false的上游；环为空时返回None
+    pub(crate) fn select(&self, client_ip: IpAddr, available: impl Fn(usize) -> bool) -> Option<usize> {
+        let key = match client_ip.to_canonical() {
+            IpAddr::V4(ip) => hash(&ip.octets()),
+            IpAddr::V6(ip) => hash(&ip.octets()),
+        };
+        let start = self.points.partition_point(|(point, _)| *point < key);
+        let mut visited = vec![false; self.len];
+        let mut chosen = None;
+        for (_, index) in self.points[start..].iter().chain(&self.points[..start]) {
+            if visited[*index] {
+                continue;
+            }
+            visited[*index] = true;
+            chosen.get_or_insert(*index);
+            if available(*index) {
+                return Some(*index);
+            }
+        }
+        chosen
+    }
+}
+
+/// 记录上游分到的请求，用于验证分布是否均衡
+pub(crate) fn record(origin: &str, url_base: &str) {
+    METRICS
+        .reverse_proxy_ip_hash_req
+        .get_or_create(&LabelImpl::new(IpHashLabel {
+            origin: origin.to_string(),
+            upstream: url_base.to_string(),
+        }))
+        .inc();
+}
+
+/// FNV-1a，再经过splitmix64的混合使结果在环上分布均匀；不使用随机种子，保证多个实例的映射一致
+fn hash(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d049bb133111eb);
+    hash ^ (hash >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv6Addr;
+
+    fn clients() -> impl Iterator<Item = IpAddr> {
+        (0..10000u32).map(|i| IpAddr::from((0x0a00_0000 + i).to_be_bytes()))
+    }
+
+    #[test]
+    fn test_stable_and_balanced() {
+        let ring = HashRing::new(["http://a", "http://b", "http://c"]);
+        let mut counts = [0; 3];
+        for client in clients() {
+            let index = ring.select(client, |_| true).unwrap_or(usize::MAX);
+            assert_eq!(ring.select(client, |_| true), Some(index));
+            counts[index] += 1;
+        }
+        assert!(counts.iter().all(|count| *count > 2500), "{counts:?}");
+        let v4 = IpAddr::from([1, 2, 3, 4]);
+        let mapped = IpAddr::V6(Ipv6Addr::from([0, 0, 0, 0, 0, 0xffff, 0x0102, 0x0304]));
+        assert_eq!(ring.select(v4, |_| true), ring.select(mapped, |_| true));
+    }
+
+    #[test]
+    fn test_adding_upstream_reshuffles_few_clients() {
+        let before = HashRing::new(["http://a", "http://b", "http://c"]);
+        let after = HashRing::new(["http://a", "http://b", "http://c", "http://d"]);
+        let moved = clients()
+            .filter(|client| before.select(*client, |_| true) != after.select(*client, |_| true))
+            .count();
+        // 理想情况下约1/4的客户端换到新的上游，其他客户端不受影响
+        assert!(moved < 3500, "{moved}");
+        for client in clients() {
+            let index = after.select(client, |_| true);
+            assert!(index == Some(3) || index == before.select(client, |_| true));
+        }
+    }
+
+    #[test]
+    fn test_skip_unavailable() {
+        let ring = HashRing::new(["http://a", "http://b", "http://c"]);
+        for client in clients().take(100) {
+            let chosen = ring.select(client, |_| true);
+            let fallback = ring.select(client, |index| Some(index) != chosen);
+            assert!(fallback.is_some() && fallback != chosen);
+            // 都不可用时仍然使用选中的上游
+            assert_eq!(ring.select(client, |_| false), chosen);
+        }
+        assert_eq!(HashRing::default().select(IpAddr::from([1, 2, 3, 4]), |_| true), None);
+    }
+}
@@ -1,3 +1,5 @@
+use http::{HeaderMap, HeaderName};
+use ipnetwork::IpNetwork;
 use std::io;
 use std::net::IpAddr;
 use std::net::Ipv4Addr;
@@ -24,6 +26,33 @@ pub(crate) fn _ipv6_mapped_to_ipv4(addr: IpAddr) -> IpAddr {
     }
 }
 
+/// 对端属于可信代理时，从请求头中取真实的客户端IP。多个值时取最后一个，即离可信代理最近的一跳
+pub(crate) fn real_client_ip(
+    headers: &HeaderMap, peer: IpAddr, header: &HeaderName, trusted_proxies: &[IpNetwork],
+) -> Option<IpAddr> {
+    let peer = peer.to_canonical();
+    if !trusted_proxies.iter().any(|network| network.contains(peer)) {
+        return None;
+    }
+    headers
+        .get_all(header)
+        .iter()
+        .next_back()
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.rsplit(',').next())
+        .and_then(|ip| ip.trim().parse::<IpAddr>().ok())
+        .map(|ip| ip.to_canonical())
+}
+
+/// 依次尝试环境变量HOSTNAME和/etc/hostname
+pub(crate) fn hostname() -> Option<String> {
+    std::env::var("HOSTNAME")
+        .ok()
+        .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+}
+
 #[cfg(not(feature = "pnet"))]
 pub fn local_ip() -> io::Result<String> {
     let socket = std::net::UdpSocket::bind("0.0.0.0:0")?;
@@ -66,3 +95,23 @@ impl std::fmt::Display for SocketAddrFormat<'_> {
         write!(f, "https://ip.im/{} {}", self.0.ip().to_canonical(), self.0.port())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_real_client_ip() -> Result<(), crate::DynError> {
+        let header = HeaderName::from_static("x-forwarded-for");
+        let trusted = ["10.0.0.0/8".parse::<IpNetwork>()?];
+        let mut headers = HeaderMap::new();
+        headers.insert(&header, "1.1.1.1, 2.2.2.2".parse()?);
+        let trusted_peer: IpAddr = "::ffff:10.0.0.1".parse()?;
+        assert_eq!(real_client_ip(&headers, trusted_peer, &header, &trusted), "2.2.2.2".parse().ok());
+        let untrusted_peer: IpAddr = "192.168.0.1".parse()?;
+        assert_eq!(real_client_ip(&headers, untrusted_peer, &header, &trusted), None);
+        headers.insert(&header, "garbage".parse()?);
+        assert_eq!(real_client_ip(&headers, trusted_peer, &header, &trusted), None);
+        Ok(())
+    }
+}
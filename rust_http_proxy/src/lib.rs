@@ -0,0 +1,118 @@
+#![deny(warnings)]
+#![deny(clippy::unwrap_used)]
+#![deny(clippy::expect_used)]
+//! A HTTP proxy server based on Hyper and Rustls, which features TLS proxy and static file serving.
+//!
+//! 除了作为可执行文件运行，也可以通过 [`ProxyServer`] 嵌入到其他程序中。
+mod access_decision;
+mod access_log;
+mod address;
+mod axum_handler;
+mod body_snippet;
+mod buffered_response;
+mod concurrency_limit;
+mod config;
+mod early_hints;
+#[cfg(all(target_os = "linux", feature = "bpf"))]
+mod ebpf;
+mod embedded_content;
+mod failover;
+mod fault_injection;
+mod follow_redirect;
+mod forward_proxy_client;
+mod forwarded;
+mod header_match;
+mod host_override;
+mod host_x;
+mod ip_hash;
+mod ip_x;
+mod limited_body;
+#[cfg(target_os = "linux")]
+mod linux_axum_handler;
+#[cfg(target_os = "linux")]
+mod linux_monitor;
+mod metrics;
+mod mirror;
+mod opaque_upstream;
+mod passthrough;
+#[cfg(unix)]
+mod privilege;
+mod proxy;
+mod rate_limit;
+mod raw_serve;
+mod remote_config;
+mod request_compression;
+mod request_id;
+mod response_headers;
+mod reverse;
+mod root_response;
+mod server;
+mod socket_x;
+mod srv_upstream;
+mod static_filter;
+#[cfg(feature = "statsd")]
+mod statsd;
+mod sub_filter;
+#[cfg(test)]
+mod test_server;
+#[cfg(test)]
+mod test_tls;
+mod tls_handshake_limit;
+mod tls_session;
+mod traffic_report;
+mod trailer_filter;
+mod tunnel_timing;
+mod upload_progress;
+mod upstream_ca;
+mod warmup;
+
+pub use crate::access_decision::AccessDecisionLog;
+pub use crate::access_log::LogFormat;
+pub use crate::config::{
+    load_config, Compression, Config, ForwardUpgrade, HostPortPolicy, ListenerMode, ServingControl, UpstreamCaMode,
+    UserAgentRewrite,
+};
+pub use crate::reverse::ReverseProxyConfig;
+pub use crate::root_response::RootResponse;
+pub use crate::server::ProxyServer;
+pub use crate::socket_x::SocketBufferSize;
+pub use crate::traffic_report::TrafficReportPeriod;
+
+use crate::metrics::METRICS;
+
+use std::error::Error as stdError;
+use std::sync::{LazyLock, OnceLock};
+use std::time::Duration;
+
+pub(crate) const IDLE_TIMEOUT: Duration = Duration::from_secs(if !cfg!(debug_assertions) { 600 } else { 10 }); // 3 minutes
+
+pub type DynError = Box<dyn stdError + Send + Sync>; // wrapper for dyn Error
+
+/// 供 `fuzz/` 下的cargo-fuzz目标调用，只在 `--cfg fuzzing` 时编译
+#[cfg(fuzzing)]
+#[doc(hidden)]
+pub mod fuzz {
+    /// 不应panic，解析成功的结果再次解析应不变，并且是合法的authority
+    pub fn parse_host(data: &[u8]) {
+        let Ok(parsed) = crate::host_x::parse_host(data) else {
+            return;
+        };
+        let formatted = match parsed.port {
+            Some(port) => format!("{}:{port}", parsed.host),
+            None => parsed.host.clone(),
+        };
+        assert_eq!(crate::host_x::parse_host(formatted.as_bytes()).ok(), Some(parsed));
+        assert!(formatted.parse::<http::uri::Authority>().is_ok(), "{formatted:?}");
+    }
+}
+
+// 由ProxyServer::new设置
+static CONFIG_CELL: OnceLock<Config> = OnceLock::new();
+static CONFIG: LazyLock<&'static Config> = LazyLock::new(|| {
+    #[allow(clippy::expect_used)]
+    CONFIG_CELL
+        .get()
+        .expect("config is not set, call ProxyServer::new first")
+});
+
+pub const BUILD_TIME: &str = build_time::build_time_local!("%Y-%m-%d %H:%M:%S %:z");
@@ -0,0 +1,141 @@
+//! 限制反向代理请求体的大小和读取时间，边转发边检查，不缓存整个请求体
+
+use std::{
+    error::Error as StdError,
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{Arc, OnceLock},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use hyper::body::{Body, Bytes, Frame, SizeHint};
+use pin_project_lite::pin_project;
+use tokio::time::{sleep, Sleep};
+
+/// 请求体被拒绝的原因
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum BodyRejection {
+    TooLarge,
+    Timeout,
+}
+
+impl BodyRejection {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            BodyRejection::TooLarge => "too_large",
+            BodyRejection::Timeout => "timeout",
+        }
+    }
+}
+
+pin_project! {
+    /// 超过`max_bytes`或在`timeout`内未读完时返回错误，并将原因记录在`rejection`中，
+    /// 以便在上游请求失败后返回413/408
+    pub(crate) struct LimitedBody<B> {
+        #[pin]
+        inner: B,
+        remaining: Option<u64>,
+        deadline: Option<Pin<Box<Sleep>>>,
+        rejection: Arc<OnceLock<BodyRejection>>,
+    }
+}
+
+impl<B> LimitedBody<B> {
+    pub(crate) fn new(
+        inner: B, max_bytes: Option<u64>, timeout: Option<Duration>, rejection: Arc<OnceLock<BodyRejection>>,
+    ) -> Self {
+        LimitedBody {
+            inner,
+            remaining: max_bytes,
+            deadline: timeout.map(|timeout| Box::pin(sleep(timeout))),
+            rejection,
+        }
+    }
+}
+
+impl<B> Body for LimitedBody<B>
+where
+    B: Body<Data = Bytes>,
+    B::Error: Into<Box<dyn StdError + Send + Sync>>,
+{
+    type Data = Bytes;
+    type Error = io::Error;
+
+    fn poll_frame(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = self.project();
+        match this.inner.poll_frame(cx) {
+            Poll::Ready(Some(Ok(frame))) => {
+                if let (Some(remaining), Some(data)) = (this.remaining.as_mut(), frame.data_ref()) {
+                    match remaining.checked_sub(data.len() as u64) {
+                        Some(left) => *remaining = left,
+                        None => return Poll::Ready(Some(Err(reject(this.rejection, BodyRejection::TooLarge)))),
+                    }
+                }
+                Poll::Ready(Some(Ok(frame)))
+            }
+            Poll::Ready(Some(Err(e))) => Poll::Ready(Some(Err(io::Error::new(io::ErrorKind::InvalidData, e)))),
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => {
+                if let Some(deadline) = this.deadline.as_mut() {
+                    if deadline.as_mut().poll(cx).is_ready() {
+                        return Poll::Ready(Some(Err(reject(this.rejection, BodyRejection::Timeout))));
+                    }
+                }
+                Poll::Pending
+            }
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+fn reject(rejection: &OnceLock<BodyRejection>, reason: BodyRejection) -> io::Error {
+    let _ = rejection.set(reason);
+    io::Error::new(io::ErrorKind::InvalidData, format!("request body rejected: {}", reason.as_str()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::stream;
+    use http_body_util::{BodyExt, StreamBody};
+
+    fn chunks(parts: &[&'static str]) -> impl Body<Data = Bytes, Error = io::Error> {
+        StreamBody::new(stream::iter(
+            parts
+                .iter()
+                .map(|part| Ok::<_, io::Error>(Frame::data(Bytes::from_static(part.as_bytes()))))
+                .collect::<Vec<_>>(),
+        ))
+    }
+
+    #[tokio::test]
+    async fn test_limited_body() {
+        let rejection = Arc::new(OnceLock::new());
+        let body = LimitedBody::new(chunks(&["hello", "world"]), Some(10), None, rejection.clone());
+        assert_eq!(body.collect().await.map(|b| b.to_bytes()).ok(), Some(Bytes::from_static(b"helloworld")));
+        assert_eq!(rejection.get(), None);
+
+        let rejection = Arc::new(OnceLock::new());
+        let body = LimitedBody::new(chunks(&["hello", "world!"]), Some(10), None, rejection.clone());
+        assert!(body.collect().await.is_err());
+        assert_eq!(rejection.get(), Some(&BodyRejection::TooLarge));
+    }
+
+    #[tokio::test]
+    async fn test_limited_body_timeout() {
+        let rejection = Arc::new(OnceLock::new());
+        let pending = StreamBody::new(stream::pending::<Result<Frame<Bytes>, io::Error>>());
+        let body = LimitedBody::new(pending, None, Some(Duration::from_millis(50)), rejection.clone());
+        assert!(body.collect().await.is_err());
+        assert_eq!(rejection.get(), Some(&BodyRejection::Timeout));
+    }
+}
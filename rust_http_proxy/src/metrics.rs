@@ -1,9 +1,17 @@
-use crate::proxy::{AccessLabel, ReqLabels, ReverseProxyReqLabel};
+use crate::proxy::{
+    AccessLabel, BodyRejectedLabel, CanaryRespLabel, FaultInjectedLabel, ForwardPoolLabel, IpHashLabel,
+    ListenerPortLabel, LocationLabel, MirrorLabel, PassthroughLabel, ReqLabels, ReverseProxyReqLabel, StaticRespLabel,
+    TlsCipherLabel, TlsConnectionLabel, TunnelPhaseLabel, TunnelUserLabel,
+};
 use log::info;
 use prom_label::{Label, LabelImpl};
 use prometheus_client::metrics::counter::Counter;
 use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::metrics::histogram::Histogram;
+use prometheus_client::metrics::info::Info;
 use prometheus_client::registry::Registry;
+use std::sync::atomic::AtomicI64;
 use std::sync::LazyLock;
 use std::time::Duration;
 
@@ -13,8 +21,183 @@ pub(crate) static METRICS: LazyLock<Metrics> = LazyLock::new(|| {
     registry.register("req_from_out", "Number of HTTP requests received", http_req_counter.clone());
     let reverse_proxy_req = Family::<LabelImpl<ReverseProxyReqLabel>, Counter>::default();
     registry.register("reverse_proxy_req", "Number of reverse proxy requests", reverse_proxy_req.clone());
+    let reverse_proxy_body_rejected = Family::<LabelImpl<BodyRejectedLabel>, Counter>::default();
+    registry.register(
+        "reverse_proxy_body_rejected",
+        "Number of reverse proxy requests rejected for oversized or slow request bodies",
+        reverse_proxy_body_rejected.clone(),
+    );
+    let reverse_proxy_canary_resp = Family::<LabelImpl<CanaryRespLabel>, Counter>::default();
+    registry.register(
+        "reverse_proxy_canary_resp",
+        "Number of responses of locations with canary, by variant and status class",
+        reverse_proxy_canary_resp.clone(),
+    );
+    let reverse_proxy_fault_injected = Family::<LabelImpl<FaultInjectedLabel>, Counter>::default();
+    registry.register(
+        "reverse_proxy_fault_injected",
+        "Number of reverse proxy requests delayed or aborted by fault injection",
+        reverse_proxy_fault_injected.clone(),
+    );
+    let reverse_proxy_mirror = Family::<LabelImpl<MirrorLabel>, Counter>::default();
+    registry.register(
+        "reverse_proxy_mirror",
+        "Number of reverse proxy requests mirrored to shadow upstreams, by result",
+        reverse_proxy_mirror.clone(),
+    );
+    let reverse_proxy_in_flight = Family::<LabelImpl<LocationLabel>, Gauge<i64, AtomicI64>>::default();
+    registry.register(
+        "reverse_proxy_in_flight",
+        "Number of in-flight reverse proxy requests of locations with max_concurrent_requests",
+        reverse_proxy_in_flight.clone(),
+    );
+    let reverse_proxy_failover_tier = Family::<LabelImpl<LocationLabel>, Gauge<i64, AtomicI64>>::default();
+    registry.register(
+        "reverse_proxy_failover_tier",
+        "Tier of the upstream in use by locations with backup_upstreams, 0 for the primary upstream",
+        reverse_proxy_failover_tier.clone(),
+    );
+    let reverse_proxy_ip_hash_req = Family::<LabelImpl<IpHashLabel>, Counter>::default();
+    registry.register(
+        "reverse_proxy_ip_hash_req",
+        "Number of requests of locations with ip_hash_upstreams, by selected upstream",
+        reverse_proxy_ip_hash_req.clone(),
+    );
+    let reverse_proxy_draining_requests = Gauge::<i64, AtomicI64>::default();
+    registry.register(
+        "reverse_proxy_draining_requests",
+        "Number of in-flight reverse proxy requests still served by configs replaced by a reload",
+        reverse_proxy_draining_requests.clone(),
+    );
+    let reverse_proxy_concurrency_rejected = Family::<LabelImpl<LocationLabel>, Counter>::default();
+    registry.register(
+        "reverse_proxy_concurrency_rejected",
+        "Number of reverse proxy requests rejected with 503 for exceeding max_concurrent_requests",
+        reverse_proxy_concurrency_rejected.clone(),
+    );
+    let reverse_proxy_passthrough = Family::<LabelImpl<PassthroughLabel>, Counter>::default();
+    registry.register(
+        "reverse_proxy_passthrough",
+        "Number of requests of --append-upstream-url and github proxy locations, by result, retries included",
+        reverse_proxy_passthrough.clone(),
+    );
+    let active_tunnels = Gauge::<i64, AtomicI64>::default();
+    registry.register("active_tunnels", "Number of active CONNECT tunnels", active_tunnels.clone());
+    let tunnel_rejected = Counter::default();
+    registry.register(
+        "tunnel_rejected",
+        "Number of CONNECT requests rejected for exceeding --max-tunnels",
+        tunnel_rejected.clone(),
+    );
+    let global_rate_limited = Counter::default();
+    registry.register(
+        "global_rate_limited",
+        "Number of requests rejected with 503 for exceeding --global-rate-limit",
+        global_rate_limited.clone(),
+    );
+    let tunnel_duration_seconds =
+        Family::<LabelImpl<TunnelUserLabel>, Histogram, fn() -> Histogram>::new_with_constructor(
+            tunnel_duration_buckets,
+        );
+    registry.register(
+        "tunnel_duration_seconds",
+        "Duration of CONNECT tunnels by username, from upgrade to close",
+        tunnel_duration_seconds.clone(),
+    );
+    let tunnel_phase_seconds =
+        Family::<LabelImpl<TunnelPhaseLabel>, Histogram, fn() -> Histogram>::new_with_constructor(tunnel_phase_buckets);
+    registry.register(
+        "tunnel_phase_seconds",
+        "Duration of CONNECT tunnel establishment phases (dns, connect, first_byte), recorded with --tunnel-timing",
+        tunnel_phase_seconds.clone(),
+    );
+    let forward_proxy_pool = Family::<LabelImpl<ForwardPoolLabel>, Counter>::default();
+    registry.register(
+        "forward_proxy_pool",
+        "Connection pool events of the forward proxy (non-CONNECT): reused, connected, expired, closed, full",
+        forward_proxy_pool.clone(),
+    );
+    let tunnel_linger_closed = Counter::default();
+    registry.register(
+        "tunnel_linger_closed",
+        "Number of tunnels closed because the remaining direction stayed idle for the linger timeout after one direction finished",
+        tunnel_linger_closed.clone(),
+    );
+    let upload_in_progress = Gauge::<i64, AtomicI64>::default();
+    registry.register(
+        "upload_in_progress",
+        "Number of request bodies being uploaded that are tracked by --upload-progress-min-bytes",
+        upload_in_progress.clone(),
+    );
+    let upload_bytes = Gauge::<i64, AtomicI64>::default();
+    registry.register(
+        "upload_bytes",
+        "Bytes received so far of in-progress request bodies tracked by --upload-progress-min-bytes",
+        upload_bytes.clone(),
+    );
+    let static_resp = Family::<LabelImpl<StaticRespLabel>, Counter>::default();
+    registry.register(
+        "static_resp",
+        "Number of static file responses by status, fs_error for filesystem errors other than not found",
+        static_resp.clone(),
+    );
+    let access_log_dropped = Counter::default();
+    registry.register(
+        "access_log_dropped",
+        "Number of access log lines dropped because writing to --access-log-file could not keep up",
+        access_log_dropped.clone(),
+    );
+    let compression_skipped = Counter::default();
+    registry.register(
+        "compression_skipped",
+        "Number of static responses served uncompressed because --max-compressions was reached",
+        compression_skipped.clone(),
+    );
+    let uri_too_long = Counter::default();
+    registry.register(
+        "uri_too_long",
+        "Number of requests rejected for exceeding --max-uri-length",
+        uri_too_long.clone(),
+    );
+    let too_many_headers = Counter::default();
+    registry.register(
+        "too_many_headers",
+        "Number of requests rejected for exceeding --max-headers, including HTTP/1.x requests rejected by the parser for oversized heads",
+        too_many_headers.clone(),
+    );
+    let tls_handshake_queued = Gauge::<i64, AtomicI64>::default();
+    registry.register(
+        "tls_handshake_queued",
+        "Number of TLS connections waiting for a handshake slot of --max-tls-handshakes",
+        tls_handshake_queued.clone(),
+    );
+    let tls_handshake_rejected = Counter::default();
+    registry.register(
+        "tls_handshake_rejected",
+        "Number of TLS connections closed because the handshake queue of --max-tls-handshakes was full",
+        tls_handshake_rejected.clone(),
+    );
+    let tls_connections = Family::<LabelImpl<TlsConnectionLabel>, Counter>::default();
+    registry.register(
+        "tls_connections",
+        "Number of accepted TLS connections by negotiated ALPN protocol",
+        tls_connections.clone(),
+    );
+    let tls_cipher_suites = Family::<LabelImpl<TlsCipherLabel>, Counter>::default();
+    registry.register(
+        "tls_cipher_suites",
+        "Number of accepted TLS connections by negotiated cipher suite",
+        tls_cipher_suites.clone(),
+    );
+    registry.register(
+        "crypto_provider",
+        "The rustls crypto provider selected at build time",
+        Info::new(vec![("provider", crate::config::CRYPTO_PROVIDER)]),
+    );
     let proxy_traffic = Family::<LabelImpl<AccessLabel>, Counter>::default();
     registry.register("proxy_traffic", "num proxy_traffic", proxy_traffic.clone());
+    let req_per_port = Family::<LabelImpl<ListenerPortLabel>, Counter>::default();
+    registry.register("req_per_port", "Number of requests served by each listener port", req_per_port.clone());
     #[cfg(all(target_os = "linux", feature = "bpf"))]
     let net_bytes = Family::<LabelImpl<crate::proxy::NetDirectionLabel>, Counter>::default();
     #[cfg(all(target_os = "linux", feature = "bpf"))]
@@ -32,6 +215,35 @@ pub(crate) static METRICS: LazyLock<Metrics> = LazyLock::new(|| {
         http_req_counter,
         proxy_traffic,
         reverse_proxy_req,
+        reverse_proxy_body_rejected,
+        reverse_proxy_canary_resp,
+        reverse_proxy_fault_injected,
+        reverse_proxy_mirror,
+        reverse_proxy_in_flight,
+        reverse_proxy_failover_tier,
+        reverse_proxy_ip_hash_req,
+        reverse_proxy_draining_requests,
+        reverse_proxy_concurrency_rejected,
+        reverse_proxy_passthrough,
+        active_tunnels,
+        tunnel_rejected,
+        global_rate_limited,
+        tunnel_duration_seconds,
+        tunnel_phase_seconds,
+        forward_proxy_pool,
+        tunnel_linger_closed,
+        uri_too_long,
+        too_many_headers,
+        compression_skipped,
+        access_log_dropped,
+        upload_in_progress,
+        upload_bytes,
+        static_resp,
+        req_per_port,
+        tls_handshake_queued,
+        tls_handshake_rejected,
+        tls_connections,
+        tls_cipher_suites,
         #[cfg(all(target_os = "linux", feature = "bpf"))]
         net_bytes,
         #[cfg(all(target_os = "linux", feature = "bpf"))]
@@ -39,11 +251,50 @@ pub(crate) static METRICS: LazyLock<Metrics> = LazyLock::new(|| {
     }
 });
 
+/// 从1秒到1天，隧道多为长连接
+fn tunnel_duration_buckets() -> Histogram {
+    Histogram::new([1.0, 10.0, 60.0, 300.0, 1800.0, 3600.0, 4.0 * 3600.0, 24.0 * 3600.0])
+}
+
+/// 从1毫秒到10秒
+fn tunnel_phase_buckets() -> Histogram {
+    Histogram::new([0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0])
+}
+
 pub(crate) struct Metrics {
     pub(crate) registry: Registry,
     pub(crate) http_req_counter: Family<LabelImpl<ReqLabels>, Counter>,
     pub(crate) proxy_traffic: Family<LabelImpl<AccessLabel>, Counter>,
     pub(crate) reverse_proxy_req: Family<LabelImpl<ReverseProxyReqLabel>, Counter>,
+    pub(crate) reverse_proxy_body_rejected: Family<LabelImpl<BodyRejectedLabel>, Counter>,
+    pub(crate) reverse_proxy_canary_resp: Family<LabelImpl<CanaryRespLabel>, Counter>,
+    pub(crate) reverse_proxy_fault_injected: Family<LabelImpl<FaultInjectedLabel>, Counter>,
+    pub(crate) reverse_proxy_mirror: Family<LabelImpl<MirrorLabel>, Counter>,
+    pub(crate) reverse_proxy_in_flight: Family<LabelImpl<LocationLabel>, Gauge<i64, AtomicI64>>,
+    pub(crate) reverse_proxy_failover_tier: Family<LabelImpl<LocationLabel>, Gauge<i64, AtomicI64>>,
+    pub(crate) reverse_proxy_ip_hash_req: Family<LabelImpl<IpHashLabel>, Counter>,
+    pub(crate) reverse_proxy_draining_requests: Gauge<i64, AtomicI64>,
+    pub(crate) reverse_proxy_concurrency_rejected: Family<LabelImpl<LocationLabel>, Counter>,
+    pub(crate) reverse_proxy_passthrough: Family<LabelImpl<PassthroughLabel>, Counter>,
+    pub(crate) active_tunnels: Gauge<i64, AtomicI64>,
+    pub(crate) tunnel_rejected: Counter,
+    pub(crate) global_rate_limited: Counter,
+    pub(crate) tunnel_duration_seconds: Family<LabelImpl<TunnelUserLabel>, Histogram, fn() -> Histogram>,
+    pub(crate) tunnel_phase_seconds: Family<LabelImpl<TunnelPhaseLabel>, Histogram, fn() -> Histogram>,
+    pub(crate) forward_proxy_pool: Family<LabelImpl<ForwardPoolLabel>, Counter>,
+    pub(crate) tunnel_linger_closed: Counter,
+    pub(crate) uri_too_long: Counter,
+    pub(crate) too_many_headers: Counter,
+    pub(crate) compression_skipped: Counter,
+    pub(crate) access_log_dropped: Counter,
+    pub(crate) upload_in_progress: Gauge<i64, AtomicI64>,
+    pub(crate) upload_bytes: Gauge<i64, AtomicI64>,
+    pub(crate) static_resp: Family<LabelImpl<StaticRespLabel>, Counter>,
+    pub(crate) req_per_port: Family<LabelImpl<ListenerPortLabel>, Counter>,
+    pub(crate) tls_handshake_queued: Gauge<i64, AtomicI64>,
+    pub(crate) tls_handshake_rejected: Counter,
+    pub(crate) tls_connections: Family<LabelImpl<TlsConnectionLabel>, Counter>,
+    pub(crate) tls_cipher_suites: Family<LabelImpl<TlsCipherLabel>, Counter>,
     #[cfg(all(target_os = "linux", feature = "bpf"))]
     pub(crate) net_bytes: Family<LabelImpl<crate::proxy::NetDirectionLabel>, Counter>,
     #[cfg(all(target_os = "linux", feature = "bpf"))]
@@ -62,3 +313,37 @@ fn register_metric_cleaner<T: Label + Send + Sync>(counter: Family<T, Counter>,
         }
     });
 }
+
+/// 收到SIGUSR1时，将当前的指标以Prometheus文本格式写入 `{log_dir}/metrics-%Y%m%d-%H%M%S.prom`，
+/// 用于没有Prometheus抓取的环境
+#[cfg(unix)]
+pub(crate) fn spawn_snapshot_on_signal(log_dir: String) {
+    use log::warn;
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut user_signal = match signal(SignalKind::user_defined1()) {
+        Ok(s) => s,
+        Err(e) => {
+            warn!("register SIGUSR1 error: {e}");
+            return;
+        }
+    };
+    tokio::spawn(async move {
+        while user_signal.recv().await.is_some() {
+            match write_snapshot(std::path::Path::new(&log_dir), chrono::Local::now()) {
+                Ok(path) => info!("metrics snapshot is written to {}", path.display()),
+                Err(e) => warn!("write metrics snapshot error: {e}"),
+            }
+        }
+    });
+}
+
+#[cfg(unix)]
+fn write_snapshot(
+    log_dir: &std::path::Path, now: chrono::DateTime<chrono::Local>,
+) -> std::io::Result<std::path::PathBuf> {
+    let mut buffer = String::new();
+    prometheus_client::encoding::text::encode(&mut buffer, &METRICS.registry).map_err(std::io::Error::other)?;
+    let path = log_dir.join(format!("metrics-{}.prom", now.format("%Y%m%d-%H%M%S")));
+    std::fs::write(&path, buffer)?;
+    Ok(path)
+}
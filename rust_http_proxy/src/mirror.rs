@@ -0,0 +1,78 @@
+//! 将部分反向代理请求复制一份发送到shadow upstream，用于用生产流量测试新版本的后端
+//!
+//! shadow upstream的响应会被丢弃，失败也不会影响客户端。为了复制请求体，只复制带有
+//! `Content-Length` 且不超过 `max_body_bytes` 的请求，请求体会被完整读取后再转发。
+
+use std::time::Duration;
+
+use prom_label::LabelImpl;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::proxy::MirrorLabel;
+use crate::reverse::Upstream;
+use crate::METRICS;
+
+/// shadow请求的超时时间，避免堆积
+pub(crate) const MIRROR_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Serialize, Deserialize, Eq, PartialEq)]
+pub(crate) struct Mirror {
+    pub(crate) upstream: Upstream,
+    #[serde(default = "default_percent")]
+    pub(crate) percent: u32, // 0-100，复制的请求百分比
+    #[serde(default = "default_max_body_bytes")]
+    pub(crate) max_body_bytes: u64, // 请求体超过该大小（或没有Content-Length）时不复制
+}
+
+fn default_percent() -> u32 {
+    100
+}
+
+fn default_max_body_bytes() -> u64 {
+    64 * 1024
+}
+
+impl Mirror {
+    pub(crate) fn hit(&self) -> bool {
+        self.percent > 0 && rand::rng().random_range(0..100) < self.percent
+    }
+
+    /// 请求体的大小已知且不超过限制时才能复制
+    pub(crate) fn accepts_body(&self, exact_len: Option<u64>, is_end_stream: bool) -> bool {
+        is_end_stream || exact_len.is_some_and(|len| len <= self.max_body_bytes)
+    }
+}
+
+/// result为 `ok`、`error`、`timeout` 或 `skipped`
+pub(crate) fn record(origin: &str, result: &'static str) {
+    METRICS
+        .reverse_proxy_mirror
+        .get_or_create(&LabelImpl::new(MirrorLabel {
+            origin: origin.to_string(),
+            result,
+        }))
+        .inc();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_accepts_body() -> Result<(), toml::de::Error> {
+        let mirror: Mirror = toml::from_str(
+            r#"
+            max_body_bytes = 10
+            [upstream]
+            url_base = "http://127.0.0.1:8081"
+            "#,
+        )?;
+        assert_eq!(mirror.percent, 100);
+        assert!(mirror.accepts_body(None, true));
+        assert!(mirror.accepts_body(Some(10), false));
+        assert!(!mirror.accepts_body(Some(11), false));
+        assert!(!mirror.accepts_body(None, false));
+        Ok(())
+    }
+}
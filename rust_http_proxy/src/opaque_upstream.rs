@@ -0,0 +1,272 @@
+//! 兼容模式：不解析上游响应的chunked编码，将响应体当作透明的字节流（直到上游关闭连接）
+//!
+//! 用于发送了畸形chunked编码、会被hyper拒绝的老旧upstream。
+
+use std::{
+    error::Error as StdError,
+    io::{self, ErrorKind},
+};
+
+use futures_util::{stream, StreamExt, TryStreamExt};
+use http::{header, HeaderMap, HeaderName, HeaderValue, Request, Response, StatusCode};
+use http_body_util::{combinators::BoxBody, BodyExt, StreamBody};
+use hyper::body::{Body, Bytes, Frame};
+use hyper_util::rt::TokioIo;
+
+use crate::host_override::HttpConnector;
+use log::debug;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio_util::io::ReaderStream;
+use tower_service::Service;
+
+const MAX_RESPONSE_HEAD_SIZE: usize = 64 * 1024;
+const MAX_RESPONSE_HEADERS: usize = 128;
+
+/// 以 `Connection: close` 发送请求，响应体不做任何framing解析，原样转发给客户端。
+///
+/// 请求体会被完整读取后以 `Content-Length` 发送。
+pub(crate) async fn send_request<B>(
+    mut connector: hyper_rustls::HttpsConnector<HttpConnector>, req: Request<B>,
+) -> io::Result<Response<BoxBody<Bytes, io::Error>>>
+where
+    B: Body<Data = Bytes>,
+    B::Error: Into<Box<dyn StdError + Send + Sync>>,
+{
+    let (parts, body) = req.into_parts();
+    let body = body
+        .collect()
+        .await
+        .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?
+        .to_bytes();
+    let head = encode_request_head(&parts.method, &parts.uri, &parts.headers, body.len())?;
+
+    let stream = connector.call(parts.uri.clone()).await.map_err(io::Error::other)?;
+    let mut stream = TokioIo::new(stream);
+    stream.write_all(&head).await?;
+    stream.write_all(&body).await?;
+    stream.flush().await?;
+
+    let mut buf = Vec::with_capacity(4096);
+    let (response, head_len) = loop {
+        let mut chunk = [0u8; 4096];
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(io::Error::new(ErrorKind::UnexpectedEof, "upstream closed before response head"));
+        }
+        buf.extend_from_slice(&chunk[..n]);
+        if let Some(parsed) = parse_response_head(&buf)? {
+            break parsed;
+        }
+        if buf.len() > MAX_RESPONSE_HEAD_SIZE {
+            return Err(io::Error::new(ErrorKind::InvalidData, "upstream response head too large"));
+        }
+    };
+    debug!("opaque upstream response: {:?} {:?}", response.status(), response.headers());
+    // 没有响应体，不等待上游关闭连接
+    if parts.method == http::Method::HEAD || is_bodiless(response.status()) {
+        return Ok(response.map(|_| crate::proxy::empty_body()));
+    }
+    let leftover = Bytes::copy_from_slice(&buf[head_len..]);
+    let body = stream::once(async move { Ok::<_, io::Error>(leftover) })
+        .chain(ReaderStream::new(stream))
+        .try_filter(|bytes| std::future::ready(!bytes.is_empty()))
+        .map_ok(Frame::data);
+    Ok(response.map(|_| BodyExt::boxed(StreamBody::new(body))))
+}
+
+fn is_bodiless(status: StatusCode) -> bool {
+    status.is_informational() || status == StatusCode::NO_CONTENT || status == StatusCode::NOT_MODIFIED
+}
+
+fn encode_request_head(
+    method: &http::Method, uri: &http::Uri, headers: &HeaderMap, body_len: usize,
+) -> io::Result<Vec<u8>> {
+    let path_and_query = uri.path_and_query().map(|p| p.as_str()).unwrap_or("/");
+    let mut head = format!("{method} {path_and_query} HTTP/1.1\r\n").into_bytes();
+    if !headers.contains_key(header::HOST) {
+        let authority = uri
+            .authority()
+            .ok_or(io::Error::new(ErrorKind::InvalidInput, "upstream url has no authority"))?;
+        head.extend_from_slice(format!("host: {authority}\r\n").as_bytes());
+    }
+    for (name, value) in headers {
+        if is_framing_header(name) {
+            continue;
+        }
+        head.extend_from_slice(name.as_str().as_bytes());
+        head.extend_from_slice(b": ");
+        head.extend_from_slice(value.as_bytes());
+        head.extend_from_slice(b"\r\n");
+    }
+    if body_len > 0 {
+        head.extend_from_slice(format!("content-length: {body_len}\r\n").as_bytes());
+    }
+    head.extend_from_slice(b"connection: close\r\n\r\n");
+    Ok(head)
+}
+
+fn is_framing_header(name: &HeaderName) -> bool {
+    name == header::CONNECTION
+        || name == header::TRANSFER_ENCODING
+        || name == header::CONTENT_LENGTH
+        || name == header::TE
+        || name == header::UPGRADE
+        || name.as_str().eq_ignore_ascii_case("keep-alive")
+        || name.as_str().eq_ignore_ascii_case("proxy-connection")
+}
+
+/// 解析响应头，返回响应（不含body）和响应头的长度。数据不完整时返回None
+fn parse_response_head(buf: &[u8]) -> io::Result<Option<(Response<()>, usize)>> {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_RESPONSE_HEADERS];
+    let mut parsed = httparse::Response::new(&mut headers);
+    let head_len = match parsed
+        .parse(buf)
+        .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?
+    {
+        httparse::Status::Complete(len) => len,
+        httparse::Status::Partial => return Ok(None),
+    };
+    let status =
+        StatusCode::from_u16(parsed.code.unwrap_or_default()).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+    let mut response = Response::new(());
+    *response.status_mut() = status;
+    for h in parsed.headers.iter() {
+        let name = HeaderName::from_bytes(h.name.as_bytes()).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+        // 响应体不再是chunked，也不一定符合Content-Length，由hyper重新分帧
+        if is_framing_header(&name) {
+            continue;
+        }
+        let value = HeaderValue::from_bytes(h.value).map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+        response.headers_mut().append(name, value);
+    }
+    Ok(Some((response, head_len)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::Empty;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_nonconforming_chunked_body_reaches_client() -> Result<(), crate::DynError> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            if let Ok((mut socket, _)) = listener.accept().await {
+                let mut buf = [0u8; 1024];
+                let _ = socket.read(&mut buf).await;
+                // chunk size不是合法的十六进制，且没有结尾的0\r\n\r\n
+                let _ = socket
+                    .write_all(
+                        b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nX-Legacy: 1\r\n\r\nzz\r\nhello legacy\r\n",
+                    )
+                    .await;
+            }
+        });
+
+        let req = Request::builder()
+            .uri(format!("http://{addr}/legacy"))
+            .body(Empty::<Bytes>::new())?;
+        let resp = send_request(
+            crate::proxy::build_http1_connector(
+                Default::default(),
+                false,
+                &Default::default(),
+                None,
+                Default::default(),
+            )?,
+            req,
+        )
+        .await?;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers().get("x-legacy"), Some(&HeaderValue::from_static("1")));
+        assert!(!resp.headers().contains_key(header::TRANSFER_ENCODING));
+        let body = resp.into_body().collect().await?.to_bytes();
+        assert_eq!(&body[..], b"zz\r\nhello legacy\r\n");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_not_modified_without_body() -> Result<(), crate::DynError> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let (closed_tx, closed_rx) = tokio::sync::oneshot::channel::<()>();
+        tokio::spawn(async move {
+            if let Ok((mut socket, _)) = listener.accept().await {
+                let mut buf = [0u8; 1024];
+                let _ = socket.read(&mut buf).await;
+                let _ = socket
+                    .write_all(b"HTTP/1.1 304 Not Modified\r\nETag: \"v1\"\r\n\r\n")
+                    .await;
+                // 不关闭连接
+                let _ = closed_rx.await;
+            }
+        });
+
+        let req = Request::builder()
+            .uri(format!("http://{addr}/cached"))
+            .header(header::IF_NONE_MATCH, "\"v1\"")
+            .body(Empty::<Bytes>::new())?;
+        let resp = send_request(
+            crate::proxy::build_http1_connector(
+                Default::default(),
+                false,
+                &Default::default(),
+                None,
+                Default::default(),
+            )?,
+            req,
+        )
+        .await?;
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(resp.headers().get(header::ETAG), Some(&HeaderValue::from_static("\"v1\"")));
+        let body = tokio::time::timeout(std::time::Duration::from_secs(5), resp.into_body().collect()).await??;
+        assert!(body.to_bytes().is_empty());
+        drop(closed_tx);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_h2_capable_upstream_negotiates_http1() -> Result<(), crate::DynError> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let acceptor = tokio_rustls::TlsAcceptor::from(crate::test_tls::server_config(&[b"h2", b"http/1.1"])?);
+        tokio::spawn(async move {
+            if let Ok((socket, _)) = listener.accept().await {
+                let Ok(mut tls) = acceptor.accept(socket).await else {
+                    return;
+                };
+                let alpn = tls
+                    .get_ref()
+                    .1
+                    .alpn_protocol()
+                    .map_or("none".to_string(), |alpn| String::from_utf8_lossy(alpn).to_string());
+                let mut buf = [0u8; 1024];
+                let _ = tls.read(&mut buf).await;
+                let head = format!("HTTP/1.1 200 OK\r\nX-Alpn: {alpn}\r\n\r\nhello");
+                let _ = tls.write_all(head.as_bytes()).await;
+                let _ = tls.shutdown().await;
+            }
+        });
+
+        let req = Request::builder()
+            .uri(format!("https://127.0.0.1:{}/", addr.port()))
+            .body(Empty::<Bytes>::new())?;
+        let resp = send_request(
+            crate::proxy::build_http1_connector(
+                Default::default(),
+                false,
+                &crate::test_tls::upstream_ca()?,
+                None,
+                Default::default(),
+            )?,
+            req,
+        )
+        .await?;
+        assert_eq!(resp.headers().get("x-alpn"), Some(&HeaderValue::from_static("none")));
+        let body = resp.into_body().collect().await?.to_bytes();
+        assert_eq!(&body[..], b"hello");
+        Ok(())
+    }
+}
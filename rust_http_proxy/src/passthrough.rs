@@ -0,0 +1,102 @@
+//! `--append-upstream-url` 和 `--enable-github-proxy` 生成的location（passthrough）访问任意的外部站点，
+//! 与用户定义的location分开，使用单独的连接超时、响应超时和重试，避免缓慢的CDN一直占用客户端请求
+//!
+//! 只重试没有请求体的GET、HEAD、OPTIONS请求；响应超时指收到响应头之前的时间，不限制响应体的下载。
+
+use std::io::{self, ErrorKind};
+use std::time::Duration;
+
+use http::{Method, Request, Response};
+use http_body_util::combinators::BoxBody;
+use hyper::body::{Body, Bytes, Incoming};
+use hyper_util::client::legacy;
+use log::warn;
+use prom_label::LabelImpl;
+
+use crate::host_override::HttpConnector;
+use crate::proxy::{empty_body, PassthroughLabel};
+use crate::METRICS;
+
+/// 两次尝试之间的间隔
+const RETRY_BACKOFF: Duration = Duration::from_millis(200);
+
+/// 响应超时时返回 [`ErrorKind::TimedOut`]
+pub(crate) async fn send(
+    client: &legacy::Client<hyper_rustls::HttpsConnector<HttpConnector>, BoxBody<Bytes, io::Error>>,
+    req: Request<BoxBody<Bytes, io::Error>>, upstream: &str,
+) -> io::Result<Response<Incoming>> {
+    let response_timeout = Duration::from_secs(crate::CONFIG.passthrough_response_timeout);
+    let retries = if is_replayable(&req) {
+        crate::CONFIG.passthrough_retries
+    } else {
+        0
+    };
+    let (parts, body) = req.into_parts();
+    let mut body = Some(body);
+    let mut attempt = 0;
+    loop {
+        let mut builder = Request::builder()
+            .method(parts.method.clone())
+            .uri(parts.uri.clone())
+            .version(parts.version);
+        if let Some(headers) = builder.headers_mut() {
+            headers.extend(parts.headers.clone());
+        }
+        // 只有空的请求体会被重试，之后的尝试使用新的空请求体
+        let req = builder
+            .body(body.take().unwrap_or_else(empty_body))
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+        let result = match tokio::time::timeout(response_timeout, client.request(req)).await {
+            Ok(Ok(resp)) => Ok(resp),
+            Ok(Err(e)) => Err(io::Error::new(ErrorKind::InvalidData, e)),
+            Err(_) => {
+                Err(io::Error::new(ErrorKind::TimedOut, format!("{upstream} did not respond in {response_timeout:?}")))
+            }
+        };
+        let outcome = match &result {
+            Ok(_) => "ok",
+            Err(e) if e.kind() == ErrorKind::TimedOut => "timeout",
+            Err(_) => "error",
+        };
+        if result.is_ok() || attempt >= retries {
+            record(upstream, outcome);
+            return result;
+        }
+        if let Err(e) = &result {
+            warn!("[passthrough] request to {} failed, retry {}/{}: {e}", parts.uri, attempt + 1, retries);
+        }
+        record(upstream, "retry");
+        attempt += 1;
+        tokio::time::sleep(RETRY_BACKOFF).await;
+    }
+}
+
+/// 幂等且没有请求体，可以安全地重新发送
+fn is_replayable<B: Body>(req: &Request<B>) -> bool {
+    matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS) && req.body().is_end_stream()
+}
+
+fn record(upstream: &str, result: &'static str) {
+    METRICS
+        .reverse_proxy_passthrough
+        .get_or_create(&LabelImpl::new(PassthroughLabel {
+            upstream: upstream.to_string(),
+            result,
+        }))
+        .inc();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy::full_body;
+
+    #[test]
+    fn test_is_replayable() -> Result<(), http::Error> {
+        assert!(is_replayable(&Request::get("/").body(empty_body())?));
+        assert!(is_replayable(&Request::head("/").body(empty_body())?));
+        assert!(!is_replayable(&Request::get("/").body(full_body("body"))?));
+        assert!(!is_replayable(&Request::post("/").body(empty_body())?));
+        Ok(())
+    }
+}
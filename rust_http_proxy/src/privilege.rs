@@ -0,0 +1,120 @@
+//! 以root绑定端口后切换到非特权用户，见 `--run-as-user` 和 `--run-as-group`
+//!
+//! 用户和组在绑定端口之前解析，不存在时直接退出；切换发生在绑定之后、处理任何请求之前。
+
+use std::{ffi::CString, io};
+
+use log::info;
+
+use crate::DynError;
+
+/// getpwnam_r和getgrnam_r的缓冲区大小
+const BUF_SIZE: usize = 16 * 1024;
+
+pub(crate) struct RunAs {
+    uid: libc::uid_t,
+    gid: libc::gid_t,
+}
+
+/// 只指定用户时使用该用户的主组；只指定组时保持当前用户
+pub(crate) fn resolve(user: Option<&str>, group: Option<&str>) -> Result<Option<RunAs>, DynError> {
+    if user.is_none() && group.is_none() {
+        return Ok(None);
+    }
+    // SAFETY: getuid和getgid总是成功
+    let (uid, user_gid) = match user {
+        Some(user) => lookup_user(user)?,
+        None => unsafe { (libc::getuid(), libc::getgid()) },
+    };
+    let gid = match group {
+        Some(group) => lookup_group(group)?,
+        None => user_gid,
+    };
+    Ok(Some(RunAs { uid, gid }))
+}
+
+pub(crate) fn drop_privileges(run_as: &RunAs) -> io::Result<()> {
+    // SAFETY: 以下函数只读取传入的值，失败时通过errno返回错误
+    unsafe {
+        if libc::geteuid() != 0 {
+            return match (libc::getuid(), libc::getgid()) == (run_as.uid, run_as.gid) {
+                true => Ok(()),
+                false => Err(io::Error::new(io::ErrorKind::PermissionDenied, "switching user requires root")),
+            };
+        }
+        // 先清空附加组并切换组，切换用户之后就没有权限了
+        if libc::setgroups(1, &run_as.gid) != 0 || libc::setgid(run_as.gid) != 0 || libc::setuid(run_as.uid) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    info!("dropped privileges to uid {} gid {}", run_as.uid, run_as.gid);
+    Ok(())
+}
+
+fn lookup_user(name: &str) -> Result<(libc::uid_t, libc::gid_t), DynError> {
+    let c_name = CString::new(name)?;
+    let mut buf = vec![0 as libc::c_char; BUF_SIZE];
+    // SAFETY: passwd是只包含整数和指针的C结构体，全零是合法的值
+    let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut result = std::ptr::null_mut();
+    // SAFETY: 所有指针在调用期间都有效，buf的长度与传入的一致
+    let ret = unsafe { libc::getpwnam_r(c_name.as_ptr(), &mut pwd, buf.as_mut_ptr(), buf.len(), &mut result) };
+    if ret != 0 {
+        return Err(format!("lookup user {name} error: {}", io::Error::from_raw_os_error(ret)).into());
+    }
+    if result.is_null() {
+        return Err(format!("user {name} does not exist").into());
+    }
+    Ok((pwd.pw_uid, pwd.pw_gid))
+}
+
+fn lookup_group(name: &str) -> Result<libc::gid_t, DynError> {
+    let c_name = CString::new(name)?;
+    let mut buf = vec![0 as libc::c_char; BUF_SIZE];
+    // SAFETY: group是只包含整数和指针的C结构体，全零是合法的值
+    let mut grp: libc::group = unsafe { std::mem::zeroed() };
+    let mut result = std::ptr::null_mut();
+    // SAFETY: 所有指针在调用期间都有效，buf的长度与传入的一致
+    let ret = unsafe { libc::getgrnam_r(c_name.as_ptr(), &mut grp, buf.as_mut_ptr(), buf.len(), &mut result) };
+    if ret != 0 {
+        return Err(format!("lookup group {name} error: {}", io::Error::from_raw_os_error(ret)).into());
+    }
+    if result.is_null() {
+        return Err(format!("group {name} does not exist").into());
+    }
+    Ok(grp.gr_gid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve() -> Result<(), DynError> {
+        assert!(resolve(None, None)?.is_none());
+        let root = resolve(Some("root"), None)?.ok_or("should resolve root")?;
+        assert_eq!((root.uid, root.gid), (0, 0));
+        // 只指定组时保持当前用户
+        let group_only = resolve(None, Some("root"))?.ok_or("should resolve group")?;
+        // SAFETY: getuid总是成功
+        assert_eq!((group_only.uid, group_only.gid), (unsafe { libc::getuid() }, 0));
+        assert!(resolve(Some("no_such_user_rust_http_proxy"), None).is_err());
+        assert!(resolve(Some("root"), Some("no_such_group_rust_http_proxy")).is_err());
+        assert!(resolve(Some("bad\0name"), None).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_drop_privileges() -> io::Result<()> {
+        // SAFETY: 这些函数总是成功
+        let (uid, gid, euid) = unsafe { (libc::getuid(), libc::getgid(), libc::geteuid()) };
+        // 切换到当前的用户和组总是成功的，不会改变测试进程的权限
+        drop_privileges(&RunAs { uid, gid })?;
+        if euid != 0 {
+            let other = RunAs { uid: 0, gid: 0 };
+            let err = drop_privileges(&other).err().map(|e| e.kind());
+            assert_eq!(err, Some(io::ErrorKind::PermissionDenied));
+        }
+        Ok(())
+    }
+}
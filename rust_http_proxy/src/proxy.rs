@@ -1,41 +1,66 @@
 use std::{
     borrow::Cow,
+    collections::HashMap,
     fmt::{Display, Formatter},
     io::{self, ErrorKind},
     net::SocketAddr,
-    sync::LazyLock,
-    time::Duration,
+    pin::Pin,
+    sync::{Arc, LazyLock, Mutex},
+    time::{Duration, Instant},
 };
 
 use crate::{
-    address::host_addr,
-    axum_handler::{self, AppProxyError, AXUM_PATHS},
+    access_decision::{AccessDecision, Rule},
+    address::{host_addr, Address},
+    axum_handler::{self, AXUM_PATHS},
+    config::{ForwardUpgrade, HostPortPolicy, ListenerMode, UserAgentRewrite},
     forward_proxy_client::ForwardProxyClient,
-    ip_x::local_ip,
+    forwarded::PeerAddr,
+    host_override::{HostOverrideResolver, HostOverrides, HttpConnector},
+    ip_x::{local_ip, SocketAddrFormat},
+    rate_limit::TokenBucket,
     raw_serve,
+    request_id::{RequestId, RequestIdFormat},
     reverse::DEFAULT_HOST,
+    root_response::RootResponse,
+    socket_x::SocketBufferSize,
+    traffic_report::TRAFFIC_REPORT,
+    tunnel_timing,
+    upload_progress::{self, UploadProgressBody},
+    upstream_ca::UpstreamCa,
     METRICS,
 };
 use {io_x::CounterIO, io_x::TimeoutIO, prom_label::LabelImpl};
 
 use axum::extract::Request;
-use axum_bootstrap::InterceptResult;
-use http::{header::HOST, Uri};
+use futures_util::future::try_join;
+use http::{header::HOST, HeaderMap, Uri};
 use http_body_util::{combinators::BoxBody, BodyExt, Empty, Full};
 use hyper::body::Incoming;
-use hyper::{body::Bytes, header::HeaderValue, http, upgrade::Upgraded, Method, Response, Version};
-use hyper_util::client::legacy::{self, connect::HttpConnector};
+use hyper::upgrade::OnUpgrade;
+use hyper::{body::Bytes, header::HeaderValue, http, Method, Response, Version};
+use hyper_util::client::legacy;
 use hyper_util::rt::TokioExecutor;
 use hyper_util::rt::TokioIo;
 use log::{debug, info, warn};
 use percent_encoding::percent_decode_str;
+use pin_project_lite::pin_project;
 use prometheus_client::encoding::EncodeLabelSet;
 use rand::Rng;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::{Notify, OwnedSemaphorePermit, Semaphore, TryAcquireError};
 use tokio::{net::TcpStream, pin};
 static LOCAL_IP: LazyLock<String> = LazyLock::new(|| local_ip().unwrap_or("0.0.0.0".to_string()));
 pub struct ProxyHandler {
-    forwad_proxy_client: ForwardProxyClient<Incoming>,
-    reverse_proxy_client: legacy::Client<hyper_rustls::HttpsConnector<HttpConnector>, Incoming>,
+    forwad_proxy_client: ForwardProxyClient<UploadProgressBody<Incoming>>,
+    reverse_proxy_client: legacy::Client<hyper_rustls::HttpsConnector<HttpConnector>, BoxBody<Bytes, io::Error>>,
+    reverse_proxy_connector: hyper_rustls::HttpsConnector<HttpConnector>, // 设置了pool_max_idle_per_host的location使用
+    opaque_upstream_connector: hyper_rustls::HttpsConnector<HttpConnector>, // opaque_upstream_body的location直接使用，只支持HTTP/1.1
+    passthrough_client: legacy::Client<hyper_rustls::HttpsConnector<HttpConnector>, BoxBody<Bytes, io::Error>>, // --append-upstream-url生成的location使用，连接超时不同
+    passthrough_connector: hyper_rustls::HttpsConnector<HttpConnector>, // 设置了pool_max_idle_per_host的passthrough location使用
+    sized_reverse_clients: Mutex<HashMap<(bool, usize), ReverseClient>>, // 设置了pool_max_idle_per_host的location使用，按是否passthrough和大小共用
+    tunnel_limit: Option<Arc<Semaphore>>,
+    global_rate_limit: Option<TokenBucket>, // --global-rate-limit，所有端口共用
 }
 
 pub(crate) enum InterceptResultAdapter {
@@ -44,74 +69,228 @@ pub(crate) enum InterceptResultAdapter {
     Continue(Request<Incoming>),
 }
 
-impl From<InterceptResultAdapter> for InterceptResult<AppProxyError> {
-    fn from(val: InterceptResultAdapter) -> Self {
-        match val {
-            InterceptResultAdapter::Return(resp) => {
-                let (parts, body) = resp.into_parts();
-                axum_bootstrap::InterceptResult::Return(Response::from_parts(parts, axum::body::Body::new(body)))
-            }
-            InterceptResultAdapter::Drop => InterceptResult::Drop,
-            InterceptResultAdapter::Continue(req) => InterceptResult::Continue(req),
-        }
-    }
-}
-
 #[allow(unused)]
 use hyper_rustls::HttpsConnectorBuilder;
 impl ProxyHandler {
     #[allow(clippy::expect_used)]
     pub fn new() -> Result<Self, crate::DynError> {
-        let reverse_client = build_hyper_legacy_client();
-        let http1_client = ForwardProxyClient::<Incoming>::new();
+        let reverse_connector = build_reverse_proxy_connector(
+            crate::CONFIG.socket_buffer_size,
+            crate::CONFIG.use_webpki_roots,
+            &crate::CONFIG.upstream_ca,
+            None,
+            crate::CONFIG.host_overrides.clone(),
+        )?;
+        let reverse_client = build_hyper_legacy_client(reverse_connector.clone(), crate::CONFIG.pool_max_idle_per_host);
+        let passthrough_connector = build_https_connector(
+            crate::CONFIG.socket_buffer_size,
+            crate::CONFIG.use_webpki_roots,
+            &crate::CONFIG.upstream_ca,
+            Some(Duration::from_secs(crate::CONFIG.passthrough_connect_timeout)),
+            crate::CONFIG.host_overrides.clone(),
+        )?;
+        let passthrough_client =
+            build_hyper_legacy_client(passthrough_connector.clone(), crate::CONFIG.pool_max_idle_per_host);
+        let opaque_upstream_connector = build_http1_connector(
+            crate::CONFIG.socket_buffer_size,
+            crate::CONFIG.use_webpki_roots,
+            &crate::CONFIG.upstream_ca,
+            None,
+            crate::CONFIG.host_overrides.clone(),
+        )?;
+        let http1_client = ForwardProxyClient::<UploadProgressBody<Incoming>>::new();
 
         Ok(ProxyHandler {
             reverse_proxy_client: reverse_client,
+            reverse_proxy_connector: reverse_connector,
+            opaque_upstream_connector,
+            passthrough_client,
+            passthrough_connector,
+            sized_reverse_clients: Mutex::new(HashMap::new()),
             forwad_proxy_client: http1_client,
+            tunnel_limit: crate::CONFIG.max_tunnels.map(|max| Arc::new(Semaphore::new(max))),
+            global_rate_limit: crate::CONFIG
+                .global_rate_limit
+                .map(|rate| TokenBucket::new(rate, crate::CONFIG.global_rate_burst.unwrap_or(rate))),
         })
     }
     pub async fn handle(
-        &self, req: Request<hyper::body::Incoming>, client_socket_addr: SocketAddr,
+        &self, mut req: Request<hyper::body::Incoming>, client_socket_addr: SocketAddr, listener_port: u16,
     ) -> Result<InterceptResultAdapter, io::Error> {
+        METRICS
+            .req_per_port
+            .get_or_create(&LabelImpl::new(ListenerPortLabel { port: listener_port }))
+            .inc();
+        // 可能由axum处理的管理接口，确定没有匹配反向代理的location之后才能豁免
+        let admin_req = is_admin_req(&req, crate::CONFIG.admin_port);
+        if !admin_req {
+            if let Some(resp) = self.over_global_rate_limit(client_socket_addr) {
+                return Ok(resp);
+            }
+        }
         let config_basic_auth = &crate::CONFIG.basic_auth;
         let never_ask_for_auth = crate::CONFIG.never_ask_for_auth;
+        if uri_len(req.uri()) > crate::CONFIG.max_uri_length {
+            warn!(
+                "reject request from {}: uri is longer than {} bytes",
+                SocketAddrFormat(&client_socket_addr),
+                crate::CONFIG.max_uri_length
+            );
+            METRICS.uri_too_long.inc();
+            let mut resp = Response::new(full_body("URI Too Long"));
+            *resp.status_mut() = http::StatusCode::URI_TOO_LONG;
+            return Ok(InterceptResultAdapter::Return(resp));
+        }
+        let listener_mode = crate::CONFIG
+            .listener_modes
+            .get(&listener_port)
+            .copied()
+            .unwrap_or_default();
+        if is_asterisk_form(&req) {
+            // OPTIONS * 询问的是服务器本身的能力，不转发
+            let mut resp = Response::new(empty_body());
+            *resp.status_mut() = http::StatusCode::NO_CONTENT;
+            resp.headers_mut().insert(
+                http::header::ALLOW,
+                allowed_methods(listener_mode, &crate::CONFIG.allow_methods, crate::CONFIG.allow_trace),
+            );
+            return Ok(InterceptResultAdapter::Return(resp));
+        }
+        if !crate::CONFIG.allow_trace {
+            let allow = allowed_methods(listener_mode, &crate::CONFIG.allow_methods, false);
+            if let Some(resp) = reject_trace(&req, allow) {
+                warn!(
+                    "reject {} request from {}, use --allow-trace to forward it",
+                    req.method(),
+                    SocketAddrFormat(&client_socket_addr)
+                );
+                return Ok(InterceptResultAdapter::Return(resp));
+            }
+        }
+        if listener_mode != ListenerMode::ForwardProxy && is_forward_proxy_req(&req) {
+            AccessDecision::deny(
+                Rule::ListenerMode,
+                client_socket_addr.ip(),
+                &format_args!("{} {}", req.method(), req.uri()),
+                &format!("forward proxy is not allowed on {listener_mode:?} port {listener_port}"),
+            );
+            let mut resp = Response::new(full_body("Forward proxy is not allowed on this port"));
+            *resp.status_mut() = http::StatusCode::BAD_REQUEST;
+            return Ok(InterceptResultAdapter::Return(resp));
+        }
 
         // 对于非CONNECT请求，检查是否需要反向代理或服务
         if Method::CONNECT != req.method() {
-            let (original_scheme_host_port, req_domain) = extract_scheme_host_port(
+            let scheme_host_port = extract_scheme_host_port(
                 &req,
                 match crate::CONFIG.over_tls {
                     true => "https",
                     false => "http",
                 },
-            )?;
+            );
+            let (mut original_scheme_host_port, req_domain) = match scheme_host_port {
+                Ok(scheme_host_port) => scheme_host_port,
+                Err(e) => {
+                    warn!("reject request from {}: {e}", SocketAddrFormat(&client_socket_addr));
+                    let mut resp = Response::new(full_body("Bad Request: invalid Host"));
+                    *resp.status_mut() = http::StatusCode::BAD_REQUEST;
+                    return Ok(InterceptResultAdapter::Return(resp));
+                }
+            };
+            // 正向代理请求中的端口是目标的端口；unix socket没有端口
+            if !is_forward_proxy_req(&req) && listener_port != 0 {
+                if let Err(e) = apply_host_port_policy(
+                    &mut original_scheme_host_port,
+                    listener_port,
+                    crate::CONFIG.host_port_policy,
+                ) {
+                    warn!("reject request from {}: {e}", SocketAddrFormat(&client_socket_addr));
+                    let mut resp = Response::new(full_body("Bad Request: Host port mismatch"));
+                    *resp.status_mut() = http::StatusCode::BAD_REQUEST;
+                    return Ok(InterceptResultAdapter::Return(resp));
+                }
+            }
 
             // 尝试找到匹配的反向代理配置
-            let location_config_of_host = crate::CONFIG
-                .reverse_proxy_config
+            let reverse_proxy_config = crate::reverse::current_config();
+            let location_config_of_host = reverse_proxy_config
                 .locations
                 .get(&req_domain.0)
-                .or(crate::CONFIG.reverse_proxy_config.locations.get(DEFAULT_HOST));
+                .or(reverse_proxy_config.locations.get(DEFAULT_HOST));
 
-            if let Some(locations) = location_config_of_host {
-                if let Some(location_config) = locations
-                    .iter()
-                    .find(|&ele| req.uri().path().starts_with(&ele.location))
-                // 用请求的path和location做前缀匹配
-                {
+            if let Some(locations) = location_config_of_host.filter(|_| listener_mode != ListenerMode::StaticOnly) {
+                // 用请求的path和location做前缀匹配，并检查请求头条件，第一个完全匹配的生效
+                if let Some(location_config) = locations.iter().find(|&ele| ele.matches(&req)) {
+                    if admin_req {
+                        if let Some(resp) = self.over_global_rate_limit(client_socket_addr) {
+                            return Ok(resp);
+                        }
+                    }
+                    let peer = req
+                        .extensions()
+                        .get::<PeerAddr>()
+                        .map_or(client_socket_addr, |peer| peer.0);
+                    crate::CONFIG.forwarded_headers.apply(
+                        req.headers_mut(),
+                        peer.ip(),
+                        &original_scheme_host_port,
+                        &crate::CONFIG.trusted_proxies,
+                        crate::CONFIG.real_ip_header.as_ref(),
+                    );
+                    // 只有反向代理把ID转发给上游，替换客户端发送的不合法的值
+                    if let Some(RequestId { name, value }) = req.extensions().get::<RequestId>().cloned() {
+                        req.headers_mut().insert(name, value);
+                    }
+                    let reverse_client = self.reverse_client_for(location_config);
                     return location_config
-                        .handle(req, client_socket_addr, &original_scheme_host_port, &self.reverse_proxy_client)
+                        .handle(
+                            req,
+                            client_socket_addr,
+                            &original_scheme_host_port,
+                            &reverse_client,
+                            &self.opaque_upstream_connector,
+                        )
                         .await
+                        .map(|resp| crate::reverse::hold_config(resp, reverse_proxy_config.clone()))
+                        .map(|resp| with_served_by(resp, crate::CONFIG.served_by.as_ref()))
                         .map(InterceptResultAdapter::Return);
                 }
             }
 
+            // 没有匹配的location时，直接访问代理自身根路径的请求返回--root-response
+            if let Some(root_response) = &crate::CONFIG.root_response {
+                if !is_forward_proxy_req(&req)
+                    && RootResponse::applies(&req, &req_domain.0, &crate::CONFIG.proxy_hostnames)
+                {
+                    return Ok(InterceptResultAdapter::Return(with_served_by(
+                        root_response.build(&req),
+                        crate::CONFIG.served_by.as_ref(),
+                    )));
+                }
+            }
+
+            if listener_mode == ListenerMode::ReverseOnly {
+                if admin_req {
+                    if let Some(resp) = self.over_global_rate_limit(client_socket_addr) {
+                        return Ok(resp);
+                    }
+                }
+                let mut resp = Response::new(full_body("Not Found"));
+                *resp.status_mut() = http::StatusCode::NOT_FOUND;
+                return Ok(InterceptResultAdapter::Return(resp));
+            }
+
             // 对于HTTP/2请求或URI中不包含host的请求，处理为普通服务请求
             if req.version() == Version::HTTP_2 || req.uri().host().is_none() {
                 // 检查是否允许提供静态文件服务
                 if crate::CONFIG.serving_control.prohibit_serving {
                     // 全局禁止静态文件托管
-                    info!("Dropping request from {client_socket_addr} due to global prohibit_serving setting");
+                    AccessDecision::deny(
+                        Rule::ProhibitServing,
+                        client_socket_addr.ip(),
+                        &req.uri().path(),
+                        "static file serving is prohibited, dropping",
+                    );
                     return Ok(InterceptResultAdapter::Drop);
                 }
 
@@ -124,9 +303,21 @@ impl ProxyHandler {
                     let ip_allowed = allowed_networks.iter().any(|network| network.contains(client_ip));
 
                     if !ip_allowed {
-                        info!("Dropping request from {client_ip} as it's not in allowed networks");
+                        AccessDecision::deny(
+                            Rule::AllowedNetworks,
+                            client_ip,
+                            &req.uri().path(),
+                            "client is not in --allow-serving-network, dropping",
+                        );
+                        raw_serve::record_static_resp("403");
                         return Ok(InterceptResultAdapter::Drop);
                     }
+                    AccessDecision::allow(
+                        Rule::AllowedNetworks,
+                        client_ip,
+                        &req.uri().path(),
+                        "client is in --allow-serving-network",
+                    );
                 }
 
                 // IP检查通过，提供静态文件服务
@@ -135,7 +326,10 @@ impl ProxyHandler {
                         if res.status() == http::StatusCode::NOT_FOUND {
                             return Ok(InterceptResultAdapter::Continue(req));
                         } else {
-                            return Ok(InterceptResultAdapter::Return(res));
+                            return Ok(InterceptResultAdapter::Return(with_served_by(
+                                res,
+                                crate::CONFIG.served_by.as_ref(),
+                            )));
                         }
                     }
                     Err(err) => {
@@ -148,16 +342,34 @@ impl ProxyHandler {
         // 2. proxy stage
         match axum_handler::check_auth(req.headers(), http::header::PROXY_AUTHORIZATION, config_basic_auth) {
             Ok(username_option) => {
+                if let Some(username) = &username_option {
+                    AccessDecision::allow(
+                        Rule::ProxyAuth,
+                        client_socket_addr.ip(),
+                        &format_args!("{} {}", req.method(), req.uri()),
+                        &format!("authorized as {username}"),
+                    );
+                }
                 let username = username_option.unwrap_or("unknown".to_owned());
                 info!(
-                    "{:>29} {:<5} {:^8} {:^7} {:?} {:?} ",
+                    "{:>29} {:<5} {:^8} {:^7} {:?} {:?}{} ",
                     "https://ip.im/".to_owned() + &client_socket_addr.ip().to_canonical().to_string(),
                     client_socket_addr.port(),
                     username,
                     req.method().as_str(),
                     req.uri(),
                     req.version(),
+                    RequestIdFormat(req.extensions().get::<RequestId>().and_then(RequestId::as_str)),
                 );
+                if let Some(resp) = check_forward_method(&req, &crate::CONFIG.allow_methods) {
+                    AccessDecision::deny(
+                        Rule::AllowMethod,
+                        client_socket_addr.ip(),
+                        &format_args!("{} {}", req.method(), req.uri()),
+                        "method is not in --allow-method",
+                    );
+                    return Ok(InterceptResultAdapter::Return(resp));
+                }
                 if Method::CONNECT == req.method() {
                     self.tunnel_proxy(req, client_socket_addr, username)
                         .map(InterceptResultAdapter::Return)
@@ -168,7 +380,12 @@ impl ProxyHandler {
                 }
             }
             Err(e) => {
-                warn!("auth check from {} error: {}", { client_socket_addr }, e);
+                AccessDecision::deny(
+                    Rule::ProxyAuth,
+                    client_socket_addr.ip(),
+                    &format_args!("{} {}", req.method(), req.uri()),
+                    &e.to_string(),
+                );
                 if never_ask_for_auth {
                     Err(io::Error::new(ErrorKind::PermissionDenied, "wrong basic auth, closing socket..."))
                 } else {
@@ -183,22 +400,67 @@ impl ProxyHandler {
     async fn simple_proxy(
         &self, mut req: Request<Incoming>, client_socket_addr: SocketAddr, username: String,
     ) -> Result<Response<BoxBody<Bytes, io::Error>>, io::Error> {
+        if crate::CONFIG.strict_host_check {
+            if let Err(e) = check_host_header(req.method(), req.uri(), req.headers()) {
+                return Ok(build_host_mismatch_resp(&req, client_socket_addr, e));
+            }
+        }
         let access_label = build_access_label(&req, client_socket_addr, username)?;
-        mod_http1_proxy_req(&mut req)?;
+        // 只有HTTP/1.1可以升级
+        let upgrade = is_upgrade_request(req.headers()).then(|| match req.version() {
+            Version::HTTP_11 => crate::CONFIG.forward_upgrade,
+            _ => ForwardUpgrade::Strip,
+        });
+        let mut upgrade_tunnel = None;
+        match upgrade {
+            Some(ForwardUpgrade::Reject) => {
+                let mut resp = Response::new(full_body("Upgrade is not allowed"));
+                *resp.status_mut() = http::StatusCode::BAD_REQUEST;
+                return Ok(resp);
+            }
+            Some(ForwardUpgrade::Strip) => strip_upgrade(req.headers_mut()),
+            Some(ForwardUpgrade::Tunnel) => {
+                let Ok(permit) = self.tunnel_permit(client_socket_addr, &access_label.target) else {
+                    return Ok(build_over_capacity_resp("Too many tunnels", crate::CONFIG.retry_after));
+                };
+                upgrade_tunnel = Some((hyper::upgrade::on(&mut req), permit));
+            }
+            None => {}
+        }
+        let http10_keep_alive = (req.version() == Version::HTTP_10).then(|| wants_keep_alive(req.headers()));
+        mod_http1_proxy_req(&mut req, &crate::CONFIG.rewrite_user_agent)?;
+        let req = upload_progress::track(req, client_socket_addr);
         match self
             .forwad_proxy_client
-            .send_request(req, &access_label, |stream: TcpStream, access_label: AccessLabel| {
-                CounterIO::new(stream, METRICS.proxy_traffic.clone(), LabelImpl::new(access_label))
-            })
+            .send_request(
+                req,
+                &access_label,
+                upgrade_tunnel.is_some(),
+                |stream: TcpStream, access_label: AccessLabel| {
+                    let user_traffic = TRAFFIC_REPORT.counter_for(&access_label.username);
+                    CounterIO::new(stream, METRICS.proxy_traffic.clone(), LabelImpl::new(access_label))
+                        .with_extra_counter(user_traffic)
+                },
+            )
             .await
         {
-            Ok(resp) => Ok(resp.map(|body| {
-                body.map_err(|e| {
-                    let e = e;
-                    io::Error::new(ErrorKind::InvalidData, e)
-                })
-                .boxed()
-            })),
+            Ok(mut resp) => {
+                if let Some((client_upgrade, permit)) = upgrade_tunnel {
+                    if resp.status() == http::StatusCode::SWITCHING_PROTOCOLS {
+                        spawn_upgrade_tunnel(client_upgrade, hyper::upgrade::on(&mut resp), permit, access_label);
+                    }
+                }
+                if let Some(keep_alive) = http10_keep_alive {
+                    set_http10_resp_keep_alive(resp.headers_mut(), keep_alive);
+                }
+                Ok(resp.map(|body| {
+                    body.map_err(|e| {
+                        let e = e;
+                        io::Error::new(ErrorKind::InvalidData, e)
+                    })
+                    .boxed()
+                }))
+            }
             Err(e) => Err(e),
         }
     }
@@ -221,18 +483,49 @@ impl ProxyHandler {
         // Note: only after client received an empty body with STATUS_OK can the
         // connection be upgraded, so we can't return a response inside
         // `on_upgrade` future.
+        if crate::CONFIG.strict_host_check {
+            if let Err(e) = check_host_header(req.method(), req.uri(), req.headers()) {
+                return Ok(build_host_mismatch_resp(&req, client_socket_addr, e));
+            }
+        }
         if let Some(addr) = host_addr(req.uri()) {
+            if let Some(resp) = reject_connect_port(&addr, &crate::CONFIG.allow_connect_ports, client_socket_addr) {
+                return Ok(resp);
+            }
+            let Ok(permit) = self.tunnel_permit(client_socket_addr, &addr) else {
+                return Ok(build_over_capacity_resp("Too many tunnels", crate::CONFIG.retry_after));
+            };
             let proxy_traffic = METRICS.proxy_traffic.clone();
             tokio::task::spawn(async move {
+                let _tunnel_guard = TunnelGuard::new(permit);
                 match hyper::upgrade::on(req).await {
                     Ok(src_upgraded) => {
+                        let upgraded_at = Instant::now();
+                        let duration_label = LabelImpl::new(TunnelUserLabel {
+                            username: username.clone(),
+                        });
                         let access_label = AccessLabel {
                             client: client_socket_addr.ip().to_canonical().to_string(),
                             target: addr.clone().to_string(),
                             username,
                         };
                         // Connect to remote server
-                        match TcpStream::connect(addr.to_string()).await {
+                        let target = addr.to_string();
+                        let target = crate::CONFIG.host_overrides.rewrite(&target);
+                        let connected = if crate::CONFIG.tunnel_timing {
+                            crate::CONFIG
+                                .socket_buffer_size
+                                .connect_timed(target.as_ref())
+                                .await
+                                .map(|(stream, dns, connect)| {
+                                    tunnel_timing::observe("dns", &target, dns);
+                                    tunnel_timing::observe("connect", &target, connect);
+                                    stream
+                                })
+                        } else {
+                            crate::CONFIG.socket_buffer_size.connect(target.as_ref()).await
+                        };
+                        match connected {
                             Ok(target_stream) => {
                                 // if the DST server did not respond the FIN(shutdown) from the SRC client, then you will see a pair of FIN-WAIT-2 and CLOSE_WAIT in the proxy server
                                 // which two socketAddrs are in the true path.
@@ -240,6 +533,7 @@ impl ProxyHandler {
                                 // netstat -ntp|grep -E "CLOSE_WAIT|FIN_WAIT"|sort
                                 // The DST server should answer for this problem, becasue it ignores the FIN
                                 // Dont worry, after the FIN_WAIT_2 timeout, the CLOSE_WAIT connection will close.
+                                // tunnel() also closes the whole tunnel once the remaining direction is idle for TUNNEL_LINGER after one direction finishes.
                                 debug!(
                                     "[tunnel {}], [true path: {} -> {}]",
                                     access_label,
@@ -254,11 +548,22 @@ impl ProxyHandler {
                                         .unwrap_or("failed".to_owned())
                                 );
                                 let access_tag = access_label.to_string();
-                                let dst_stream =
-                                    CounterIO::new(target_stream, proxy_traffic, LabelImpl::new(access_label));
-                                if let Err(e) = tunnel(src_upgraded, dst_stream).await {
+                                let user_traffic = TRAFFIC_REPORT.counter_for(&access_label.username);
+                                // 从升级开始计时，包含了DNS解析和建连的耗时
+                                let first_byte = crate::CONFIG.tunnel_timing.then(|| (upgraded_at, target.to_string()));
+                                let dst_stream = CounterIO::new(
+                                    tunnel_timing::FirstByteIO::new(target_stream, first_byte),
+                                    proxy_traffic,
+                                    LabelImpl::new(access_label),
+                                )
+                                .with_extra_counter(user_traffic);
+                                if let Err(e) = tunnel(TokioIo::new(src_upgraded), dst_stream, TUNNEL_LINGER).await {
                                     warn!("[tunnel io error] [{}]: [{}] {} ", access_tag, e.kind(), e);
                                 };
+                                METRICS
+                                    .tunnel_duration_seconds
+                                    .get_or_create(&duration_label)
+                                    .observe(upgraded_at.elapsed().as_secs_f64());
                             }
                             Err(e) => {
                                 warn!("[tunnel establish error] [{}]: [{}] {} ", access_label, e.kind(), e)
@@ -286,6 +591,84 @@ impl ProxyHandler {
             Ok(resp)
         }
     }
+    /// 设置了 `pool_max_idle_per_host` 的location使用单独的连接池，相同大小的location共用一个client
+    ///
+    /// passthrough的location连接超时不同，与其他location分开共用
+    pub(crate) fn reverse_client_for(&self, location_config: &crate::reverse::LocationConfig) -> ReverseClient {
+        let passthrough = location_config.passthrough;
+        let (client, connector) = match passthrough {
+            true => (&self.passthrough_client, &self.passthrough_connector),
+            false => (&self.reverse_proxy_client, &self.reverse_proxy_connector),
+        };
+        match location_config.pool_max_idle_per_host {
+            Some(size) if size != crate::CONFIG.pool_max_idle_per_host => {
+                let mut clients = self.sized_reverse_clients.lock().unwrap_or_else(|e| e.into_inner());
+                clients
+                    .entry((passthrough, size))
+                    .or_insert_with(|| build_hyper_legacy_client(connector.clone(), size))
+                    .clone()
+            }
+            _ => client.clone(),
+        }
+    }
+
+    /// 超过 `--global-rate-limit` 时返回503
+    fn over_global_rate_limit(&self, client_socket_addr: SocketAddr) -> Option<InterceptResultAdapter> {
+        if self.global_rate_limit.as_ref().map_or(true, TokenBucket::try_acquire) {
+            return None;
+        }
+        debug!("reject request from {}: exceeding --global-rate-limit", SocketAddrFormat(&client_socket_addr));
+        METRICS.global_rate_limited.inc();
+        Some(InterceptResultAdapter::Return(build_over_capacity_resp("Service Unavailable", crate::CONFIG.retry_after)))
+    }
+
+    /// 达到 `--max-tunnels` 时返回Err
+    fn tunnel_permit(
+        &self, client_socket_addr: SocketAddr, target: &dyn Display,
+    ) -> Result<Option<OwnedSemaphorePermit>, TryAcquireError> {
+        let Some(tunnel_limit) = &self.tunnel_limit else {
+            return Ok(None);
+        };
+        tunnel_limit.clone().try_acquire_owned().map(Some).inspect_err(|_| {
+            warn!("reject tunnel from {} to {}: too many tunnels", SocketAddrFormat(&client_socket_addr), target);
+            METRICS.tunnel_rejected.inc();
+        })
+    }
+
+    /// 透明代理：将连接原样转发到 `SO_ORIGINAL_DST`，用户名记为transparent
+    pub(crate) async fn transparent_tunnel(&self, conn: TcpStream, client_socket_addr: SocketAddr) {
+        let addr = match crate::socket_x::original_dst(&conn) {
+            Ok(addr) => addr,
+            Err(e) => {
+                warn!("[transparent] get original destination of {} error: {e}", SocketAddrFormat(&client_socket_addr));
+                return;
+            }
+        };
+        let Ok(permit) = self.tunnel_permit(client_socket_addr, &addr) else {
+            return;
+        };
+        let _tunnel_guard = TunnelGuard::new(permit);
+        let access_label = AccessLabel {
+            client: client_socket_addr.ip().to_canonical().to_string(),
+            target: addr.to_string(),
+            username: "transparent".to_string(),
+        };
+        match crate::CONFIG.socket_buffer_size.connect(addr).await {
+            Ok(target_stream) => {
+                debug!("[transparent] [{access_label}]");
+                let access_tag = access_label.to_string();
+                let user_traffic = TRAFFIC_REPORT.counter_for(&access_label.username);
+                let dst_stream =
+                    CounterIO::new(target_stream, METRICS.proxy_traffic.clone(), LabelImpl::new(access_label))
+                        .with_extra_counter(user_traffic);
+                if let Err(e) = tunnel(conn, dst_stream, TUNNEL_LINGER).await {
+                    warn!("[tunnel io error] [{}]: [{}] {} ", access_tag, e.kind(), e);
+                }
+            }
+            Err(e) => warn!("[tunnel establish error] [{}]: [{}] {} ", access_label, e.kind(), e),
+        }
+    }
+
     async fn serve_request(
         &self, req: &Request<Incoming>, client_socket_addr: SocketAddr,
     ) -> Result<Response<BoxBody<Bytes, io::Error>>, io::Error> {
@@ -297,16 +680,34 @@ impl ProxyHandler {
         if AXUM_PATHS.contains(&path) {
             return raw_serve::not_found().map_err(|e| io::Error::new(ErrorKind::InvalidData, e));
         }
+        if crate::CONFIG.serving_control.auth_static {
+            match axum_handler::check_auth(req.headers(), http::header::AUTHORIZATION, &crate::CONFIG.basic_auth) {
+                Ok(Some(username)) => AccessDecision::allow(
+                    Rule::StaticAuth,
+                    client_socket_addr.ip(),
+                    &raw_path,
+                    &format!("authorized as {username}"),
+                ),
+                Ok(None) => {}
+                Err(e) => {
+                    AccessDecision::deny(Rule::StaticAuth, client_socket_addr.ip(), &raw_path, &e.to_string());
+                    raw_serve::record_static_resp("401");
+                    return Ok(build_authenticate_resp(false));
+                }
+            }
+        }
         raw_serve::serve_http_request(req, client_socket_addr, path)
             .await
             .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))
     }
 }
 
-fn mod_http1_proxy_req(req: &mut Request<Incoming>) -> io::Result<()> {
+fn mod_http1_proxy_req<B>(req: &mut Request<B>, user_agent_rewrite: &UserAgentRewrite) -> io::Result<()> {
     // 删除代理特有的请求头
     req.headers_mut().remove(http::header::PROXY_AUTHORIZATION.to_string());
     req.headers_mut().remove("Proxy-Connection");
+    upgrade_http10_req(req);
+    rewrite_user_agent(req.headers_mut(), user_agent_rewrite);
     // set host header
     let uri = req.uri().clone();
     let hostname = uri
@@ -332,6 +733,259 @@ fn mod_http1_proxy_req(req: &mut Request<Incoming>) -> io::Result<()> {
     Ok(())
 }
 
+/// HTTP/1.0的请求以HTTP/1.1转发给上游，以便复用连接池中的连接。
+/// 与客户端之间的keep-alive由hyper按HTTP/1.0的语义处理：客户端未声明`Connection: keep-alive`时，响应后关闭连接
+fn upgrade_http10_req<B>(req: &mut Request<B>) {
+    if req.version() != Version::HTTP_10 {
+        return;
+    }
+    *req.version_mut() = Version::HTTP_11;
+    let headers = req.headers_mut();
+    headers.remove("Keep-Alive");
+    if wants_keep_alive(headers) {
+        // HTTP/1.1默认就是持久连接
+        headers.remove(http::header::CONNECTION);
+    }
+}
+
+/// `Connection` 中有 `upgrade` 且带有 `Upgrade` 请求头
+fn is_upgrade_request(headers: &HeaderMap) -> bool {
+    headers.contains_key(http::header::UPGRADE)
+        && connection_tokens(headers).any(|token| token.eq_ignore_ascii_case("upgrade"))
+}
+
+/// 删除 `Upgrade` 请求头和 `Connection` 中的 `upgrade`
+fn strip_upgrade(headers: &mut HeaderMap) {
+    headers.remove(http::header::UPGRADE);
+    let tokens = connection_tokens(headers)
+        .filter(|token| !token.eq_ignore_ascii_case("upgrade"))
+        .collect::<Vec<_>>()
+        .join(", ");
+    headers.remove(http::header::CONNECTION);
+    if let Ok(value) = HeaderValue::from_str(&tokens) {
+        if !tokens.is_empty() {
+            headers.insert(http::header::CONNECTION, value);
+        }
+    }
+}
+
+fn connection_tokens(headers: &HeaderMap) -> impl Iterator<Item = &str> {
+    headers
+        .get_all(http::header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|token| !token.is_empty())
+}
+
+/// 客户端和上游都升级后，在两者之间转发数据，与CONNECT隧道共用 `--max-tunnels` 和相关指标
+fn spawn_upgrade_tunnel(
+    client_upgrade: OnUpgrade, upstream_upgrade: OnUpgrade, permit: Option<OwnedSemaphorePermit>,
+    access_label: AccessLabel,
+) {
+    tokio::spawn(async move {
+        let _tunnel_guard = TunnelGuard::new(permit);
+        let (client_upgraded, upstream_upgraded) = match try_join(client_upgrade, upstream_upgrade).await {
+            Ok(upgraded) => upgraded,
+            Err(e) => {
+                warn!("[upgrade error] [{access_label}]: {e}");
+                return;
+            }
+        };
+        let upgraded_at = Instant::now();
+        debug!("[upgrade tunnel {access_label}]");
+        // 上游的连接已经计入了流量
+        if let Err(e) = tunnel(TokioIo::new(client_upgraded), TokioIo::new(upstream_upgraded), TUNNEL_LINGER).await {
+            warn!("[tunnel io error] [{}]: [{}] {} ", access_label, e.kind(), e);
+        }
+        METRICS
+            .tunnel_duration_seconds
+            .get_or_create(&LabelImpl::new(TunnelUserLabel {
+                username: access_label.username,
+            }))
+            .observe(upgraded_at.elapsed().as_secs_f64());
+    });
+}
+
+fn wants_keep_alive(headers: &HeaderMap) -> bool {
+    connection_tokens(headers).any(|token| token.eq_ignore_ascii_case("keep-alive"))
+}
+
+/// HTTP/1.0的客户端只有在响应中看到`Connection: keep-alive`时才会复用连接，hyper也据此决定是否关闭连接
+fn set_http10_resp_keep_alive(headers: &mut HeaderMap, keep_alive: bool) {
+    headers.remove("Keep-Alive");
+    headers.insert(http::header::CONNECTION, HeaderValue::from_static(if keep_alive { "keep-alive" } else { "close" }));
+}
+
+fn rewrite_user_agent(headers: &mut HeaderMap, user_agent_rewrite: &UserAgentRewrite) {
+    match user_agent_rewrite {
+        UserAgentRewrite::Passthrough => {}
+        UserAgentRewrite::Remove => {
+            headers.remove(http::header::USER_AGENT);
+        }
+        UserAgentRewrite::Fixed(value) => {
+            headers.insert(http::header::USER_AGENT, value.clone());
+        }
+    }
+}
+
+/// 校验Host请求头与请求URI中的authority是否一致，用于防止请求走私和路由混淆
+///
+/// 缺少Host头时视为一致；存在多个Host头时视为不一致。端口缺省时按scheme取80或443。
+fn check_host_header(method: &Method, uri: &Uri, headers: &HeaderMap) -> io::Result<()> {
+    let mut host_headers = headers.get_all(HOST).iter();
+    let Some(host_header) = host_headers.next() else {
+        return Ok(());
+    };
+    if host_headers.next().is_some() {
+        return Err(io::Error::new(ErrorKind::InvalidInput, "multiple Host headers"));
+    }
+    let uri_host = uri
+        .host()
+        .ok_or(io::Error::new(ErrorKind::InvalidInput, "host is absent in request uri"))?;
+    let header_authority = host_header
+        .to_str()
+        .ok()
+        .and_then(|host| host.parse::<http::uri::Authority>().ok())
+        .ok_or(io::Error::new(ErrorKind::InvalidInput, format!("invalid Host header: {host_header:?}")))?;
+    // CONNECT的URI是authority-form，没有scheme，通常是到443的隧道
+    let default_port = if *method == Method::CONNECT || is_schema_secure(uri) {
+        443
+    } else {
+        80
+    };
+    let uri_port = uri.port_u16().unwrap_or(default_port);
+    let header_port = header_authority.port_u16().unwrap_or(default_port);
+    if !header_authority.host().eq_ignore_ascii_case(uri_host) || header_port != uri_port {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("Host header {header_authority} mismatches request authority {uri_host}:{uri_port}"),
+        ));
+    }
+    Ok(())
+}
+
+/// 隧道结束时释放 `--max-tunnels` 的名额，并更新 `active_tunnels`
+struct TunnelGuard {
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl TunnelGuard {
+    fn new(permit: Option<OwnedSemaphorePermit>) -> Self {
+        METRICS.active_tunnels.inc();
+        TunnelGuard { _permit: permit }
+    }
+}
+
+impl Drop for TunnelGuard {
+    fn drop(&mut self) {
+        METRICS.active_tunnels.dec();
+    }
+}
+
+/// 不重新拼接URI，按各部分的长度计算
+fn uri_len(uri: &Uri) -> usize {
+    let scheme_len = uri.scheme_str().map(|scheme| scheme.len() + "://".len()).unwrap_or(0);
+    let authority_len = uri.authority().map(|authority| authority.as_str().len()).unwrap_or(0);
+    let path_and_query_len = uri
+        .path_and_query()
+        .map(|path_and_query| path_and_query.as_str().len())
+        .unwrap_or(0);
+    scheme_len + authority_len + path_and_query_len
+}
+
+/// `OPTIONS *` 和拒绝TRACE时Allow的值：端口可用于正向代理且--allow-method允许时才有CONNECT，--allow-trace时才有TRACE
+fn allowed_methods(listener_mode: ListenerMode, allow_methods: &[Method], allow_trace: bool) -> HeaderValue {
+    let mut methods = vec!["GET", "HEAD", "POST", "PUT", "DELETE", "PATCH", "OPTIONS"];
+    if allow_trace {
+        methods.push("TRACE");
+    }
+    if listener_mode == ListenerMode::ForwardProxy
+        && (allow_methods.is_empty() || allow_methods.contains(&Method::CONNECT))
+    {
+        methods.push("CONNECT");
+    }
+    HeaderValue::from_str(&methods.join(", ")).unwrap_or(HeaderValue::from_static("GET, HEAD"))
+}
+
+/// asterisk-form只用于 `OPTIONS *`
+fn is_asterisk_form<B>(req: &Request<B>) -> bool {
+    req.method() == Method::OPTIONS && req.uri().authority().is_none() && req.uri().path() == "*"
+}
+
+/// TRACE会回显请求头（包括Cookie），TRACK是IIS的同类方法，除非--allow-trace都返回405而不转发，`allow` 为响应中的Allow
+fn reject_trace<B>(req: &Request<B>, allow: HeaderValue) -> Option<Response<BoxBody<Bytes, io::Error>>> {
+    if !(req.method() == Method::TRACE || req.method().as_str() == "TRACK") {
+        return None;
+    }
+    let mut resp = Response::new(full_body("Method Not Allowed"));
+    *resp.status_mut() = http::StatusCode::METHOD_NOT_ALLOWED;
+    resp.headers_mut().insert(http::header::ALLOW, allow);
+    Some(resp)
+}
+
+/// `allowed` 为--allow-method，不为空时正向代理只允许其中的方法，其他返回405
+fn check_forward_method<B>(req: &Request<B>, allowed: &[Method]) -> Option<Response<BoxBody<Bytes, io::Error>>> {
+    if allowed.is_empty() || allowed.contains(req.method()) {
+        return None;
+    }
+    let mut resp = Response::new(full_body("Method Not Allowed"));
+    *resp.status_mut() = http::StatusCode::METHOD_NOT_ALLOWED;
+    let allow = allowed.iter().map(Method::as_str).collect::<Vec<_>>().join(", ");
+    if let Ok(allow) = HeaderValue::from_str(&allow) {
+        resp.headers_mut().insert(http::header::ALLOW, allow);
+    }
+    Some(resp)
+}
+
+/// `allowed_ports` 为--allow-connect-port，为空时不限制，不在其中的端口返回403
+fn reject_connect_port(
+    addr: &Address, allowed_ports: &[u16], client_socket_addr: SocketAddr,
+) -> Option<Response<BoxBody<Bytes, io::Error>>> {
+    if allowed_ports.is_empty() || allowed_ports.contains(&addr.port()) {
+        return None;
+    }
+    AccessDecision::deny(
+        Rule::AllowConnectPort,
+        client_socket_addr.ip(),
+        &format_args!("CONNECT {addr}"),
+        "port is not in --allow-connect-port",
+    );
+    let mut resp = Response::new(full_body("CONNECT to this port is not allowed"));
+    *resp.status_mut() = http::StatusCode::FORBIDDEN;
+    Some(resp)
+}
+
+/// CONNECT和HTTP/1.x中absolute-form的请求是正向代理特有的
+fn is_forward_proxy_req<B>(req: &Request<B>) -> bool {
+    req.method() == Method::CONNECT || (req.version() != Version::HTTP_2 && req.uri().scheme().is_some())
+}
+
+/// /metrics等由axum处理的管理接口，不受--global-rate-limit限制
+///
+/// 设置了 `--admin-port` 时，代理端口上的请求都不豁免
+fn is_admin_req<B>(req: &Request<B>, admin_port: Option<u16>) -> bool {
+    admin_port.is_none() && !is_forward_proxy_req(req) && AXUM_PATHS.contains(&req.uri().path())
+}
+
+/// 按--served-by添加X-Served-By响应头
+fn with_served_by<B>(mut resp: Response<B>, served_by: Option<&HeaderValue>) -> Response<B> {
+    if let Some(value) = served_by {
+        resp.headers_mut().insert("x-served-by", value.clone());
+    }
+    resp
+}
+
+fn build_host_mismatch_resp(
+    req: &Request<Incoming>, client_socket_addr: SocketAddr, e: io::Error,
+) -> Response<BoxBody<Bytes, io::Error>> {
+    warn!("reject {} {} from {}: {}", req.method(), req.uri(), SocketAddrFormat(&client_socket_addr), e);
+    let mut resp = Response::new(full_body("Host header mismatches request authority"));
+    *resp.status_mut() = http::StatusCode::BAD_REQUEST;
+    resp
+}
+
 fn build_access_label(
     req: &Request<Incoming>, client_socket_addr: SocketAddr, username: String,
 ) -> Result<AccessLabel, io::Error> {
@@ -363,90 +1017,172 @@ impl Display for SchemeHostPort {
 #[derive(Clone, Debug, Hash, PartialEq, Eq)]
 struct RequestDomain(String);
 
-fn extract_scheme_host_port(
-    req: &Request<Incoming>, default_scheme: &str,
-) -> io::Result<(SchemeHostPort, RequestDomain)> {
+fn extract_scheme_host_port<B>(req: &Request<B>, default_scheme: &str) -> io::Result<(SchemeHostPort, RequestDomain)> {
     let uri = req.uri();
     let scheme = uri.scheme_str().unwrap_or(default_scheme);
     if req.version() == Version::HTTP_2 {
         //H2，信息全在uri中
-        let host_in_url = uri
-            .host()
-            .ok_or(io::Error::new(ErrorKind::InvalidData, "authority is absent in HTTP/2"))?
-            .to_string();
+        let host_in_url = crate::host_x::from_uri(uri)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, format!("invalid authority in HTTP/2: {e}")))?;
         let host_in_header = req
             .headers()
             .get(http::header::HOST)
-            .and_then(|host| host.to_str().ok())
-            .and_then(|host_str| host_str.split(':').next())
-            .map(str::to_string);
+            .map(|host| crate::host_x::parse_host(host.as_bytes()))
+            .transpose()?;
         Ok((
             SchemeHostPort {
                 scheme: scheme.to_owned(),
-                host: host_in_url.clone(),
-                port: uri.port_u16(),
+                host: host_in_url.host.clone(),
+                port: host_in_url.port,
             },
-            RequestDomain(if let Some(host_in_header) = host_in_header {
-                host_in_header
-            } else {
-                host_in_url
+            RequestDomain(match host_in_header {
+                Some(host_in_header) => host_in_header.host,
+                None => host_in_url.host,
             }),
         ))
+    } else if req.version() == Version::HTTP_10 && !req.headers().contains_key(http::header::HOST) {
+        //HTTP/1.0可以不带Host，从absolute-form的uri中获取
+        let host = crate::host_x::from_uri(uri)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, format!("Host is absent in HTTP/1.0: {e}")))?;
+        Ok((
+            SchemeHostPort {
+                scheme: scheme.to_owned(),
+                host: host.host.clone(),
+                port: host.port,
+            },
+            RequestDomain(host.host),
+        ))
     } else {
-        let mut split = req
+        let host_header = req
             .headers()
             .get(http::header::HOST)
-            .ok_or(io::Error::new(ErrorKind::InvalidData, "Host Header is absent in HTTP/1.1"))?
-            .to_str()
-            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?
-            .split(':');
-        let host = split
-            .next()
-            .ok_or(io::Error::new(ErrorKind::InvalidData, "host not in header"))?
-            .to_string();
-        let port = match split.next() {
-            Some(port) => Some(
-                port.parse::<u16>()
-                    .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?,
-            ),
-            None => None,
-        };
+            .ok_or(io::Error::new(ErrorKind::InvalidData, "Host Header is absent in HTTP/1.1"))?;
+        let host = crate::host_x::parse_host(host_header.as_bytes())?;
         Ok((
             SchemeHostPort {
                 scheme: scheme.to_owned(),
-                host: host.clone(),
-                port,
+                host: host.host.clone(),
+                port: host.port,
             },
-            RequestDomain(host),
+            RequestDomain(host.host),
         ))
     }
 }
 
+/// Host中的端口（没有时为scheme的默认端口）与监听端口不一致时，按 `--host-port-policy` 处理
+fn apply_host_port_policy(
+    scheme_host_port: &mut SchemeHostPort, listener_port: u16, policy: HostPortPolicy,
+) -> Result<(), String> {
+    let default_port = match scheme_host_port.scheme.as_str() {
+        "https" => 443,
+        _ => 80,
+    };
+    let host_port = scheme_host_port.port.unwrap_or(default_port);
+    if host_port == listener_port {
+        return Ok(());
+    }
+    match policy {
+        HostPortPolicy::Header => Ok(()),
+        HostPortPolicy::Listener => {
+            scheme_host_port.port = (listener_port != default_port).then_some(listener_port);
+            Ok(())
+        }
+        HostPortPolicy::Reject => Err(format!(
+            "port {host_port} of Host {} does not match the listener port {listener_port}",
+            scheme_host_port.host
+        )),
+    }
+}
+
 fn is_schema_secure(uri: &Uri) -> bool {
     uri.scheme_str()
         .map(|scheme_str| matches!(scheme_str, "wss" | "https"))
         .unwrap_or_default()
 }
 
-fn build_hyper_legacy_client() -> legacy::Client<hyper_rustls::HttpsConnector<HttpConnector>, Incoming> {
-    let pool_idle_timeout = Duration::from_secs(90);
-    // 创建一个 HttpConnector
-    let mut http_connector = HttpConnector::new();
-    http_connector.enforce_http(false);
-    http_connector.set_keepalive(Some(pool_idle_timeout));
+const POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+/// 隧道的一个方向结束后，另一个方向空闲超过该时间时关闭隧道
+const TUNNEL_LINGER: Duration = Duration::from_secs(5);
+/// 与 `tokio::io::copy` 内部的缓冲区大小相同
+pub(crate) const DEFAULT_TUNNEL_BUFFER_SIZE: usize = 8 * 1024;
+
+/// `use_webpki_roots` 为true时使用内置的Mozilla根证书，适用于没有系统证书的最小化镜像，
+/// `upstream_ca` 不为空时按 `--upstream-ca-mode` 信任其中的证书。SNI为url中的host
+pub(crate) fn build_https_connector(
+    socket_buffer_size: SocketBufferSize, use_webpki_roots: bool, upstream_ca: &UpstreamCa,
+    connect_timeout: Option<Duration>, host_overrides: HostOverrides,
+) -> io::Result<hyper_rustls::HttpsConnector<HttpConnector>> {
+    let (builder, http_connector) =
+        https_connector_builder(socket_buffer_size, use_webpki_roots, upstream_ca, connect_timeout, host_overrides)?;
+    Ok(builder
+        .https_or_http()
+        .enable_all_versions()
+        .wrap_connector(http_connector))
+}
 
-    let https_connector = HttpsConnectorBuilder::new()
-        .with_platform_verifier()
+/// 与 [`build_https_connector`] 相同，但SNI按反向代理配置中上游的sni，只用于反向代理location的上游
+pub(crate) fn build_reverse_proxy_connector(
+    socket_buffer_size: SocketBufferSize, use_webpki_roots: bool, upstream_ca: &UpstreamCa,
+    connect_timeout: Option<Duration>, host_overrides: HostOverrides,
+) -> io::Result<hyper_rustls::HttpsConnector<HttpConnector>> {
+    let (builder, http_connector) =
+        https_connector_builder(socket_buffer_size, use_webpki_roots, upstream_ca, connect_timeout, host_overrides)?;
+    Ok(builder
         .https_or_http()
+        .with_server_name_resolver(crate::reverse::UpstreamServerNameResolver)
         .enable_all_versions()
-        .wrap_connector(http_connector);
+        .wrap_connector(http_connector))
+}
+
+/// 不发送ALPN，上游只能使用HTTP/1.1，用于自行编码HTTP/1.1请求的opaque_upstream_body。SNI与反向代理相同
+pub(crate) fn build_http1_connector(
+    socket_buffer_size: SocketBufferSize, use_webpki_roots: bool, upstream_ca: &UpstreamCa,
+    connect_timeout: Option<Duration>, host_overrides: HostOverrides,
+) -> io::Result<hyper_rustls::HttpsConnector<HttpConnector>> {
+    let (builder, http_connector) =
+        https_connector_builder(socket_buffer_size, use_webpki_roots, upstream_ca, connect_timeout, host_overrides)?;
+    Ok(builder
+        .https_or_http()
+        .with_server_name_resolver(crate::reverse::UpstreamServerNameResolver)
+        .enable_http1()
+        .wrap_connector(http_connector))
+}
+
+fn https_connector_builder(
+    socket_buffer_size: SocketBufferSize, use_webpki_roots: bool, upstream_ca: &UpstreamCa,
+    connect_timeout: Option<Duration>, host_overrides: HostOverrides,
+) -> io::Result<(HttpsConnectorBuilder<hyper_rustls::builderstates::WantsSchemes>, HttpConnector)> {
+    // 创建一个 HttpConnector，先按--host-override解析
+    let mut http_connector = HttpConnector::new_with_resolver(HostOverrideResolver::new(host_overrides));
+    http_connector.enforce_http(false);
+    http_connector.set_connect_timeout(connect_timeout);
+    http_connector.set_keepalive(Some(POOL_IDLE_TIMEOUT));
+    http_connector.set_send_buffer_size(socket_buffer_size.send);
+    http_connector.set_recv_buffer_size(socket_buffer_size.recv);
+
     // 创建一个 HttpsConnector，使用 rustls 作为后端
-    let client: legacy::Client<hyper_rustls::HttpsConnector<HttpConnector>, Incoming> =
-        legacy::Client::builder(TokioExecutor::new())
-            .pool_idle_timeout(pool_idle_timeout)
-            .pool_max_idle_per_host(5)
-            .pool_timer(hyper_util::rt::TokioTimer::new())
-            .build(https_connector);
+    let builder = HttpsConnectorBuilder::new();
+    let builder = if !upstream_ca.is_empty() {
+        builder.with_tls_config(upstream_ca.client_config(use_webpki_roots)?)
+    } else if use_webpki_roots {
+        builder.with_webpki_roots()
+    } else {
+        builder.with_platform_verifier()
+    };
+    Ok((builder, http_connector))
+}
+
+pub(crate) type ReverseClient = legacy::Client<hyper_rustls::HttpsConnector<HttpConnector>, BoxBody<Bytes, io::Error>>;
+
+fn build_hyper_legacy_client(
+    https_connector: hyper_rustls::HttpsConnector<HttpConnector>, pool_max_idle_per_host: usize,
+) -> ReverseClient {
+    let pool_idle_timeout = POOL_IDLE_TIMEOUT;
+    let client: ReverseClient = legacy::Client::builder(TokioExecutor::new())
+        .pool_idle_timeout(pool_idle_timeout)
+        .pool_max_idle_per_host(pool_max_idle_per_host)
+        .pool_timer(hyper_util::rt::TokioTimer::new())
+        .build(https_connector);
     client
 }
 
@@ -468,15 +1204,103 @@ fn origin_form(uri: &mut Uri) -> io::Result<()> {
 
 // Create a TCP connection to host:port, build a tunnel between the connection and
 // the upgraded connection
-async fn tunnel(upgraded: Upgraded, target_io: CounterIO<TcpStream, LabelImpl<AccessLabel>>) -> io::Result<()> {
-    let mut upgraded = TokioIo::new(upgraded);
+async fn tunnel<C, T>(upgraded: C, target_io: T, linger: Duration) -> io::Result<()>
+where
+    C: AsyncRead + AsyncWrite + Send,
+    T: AsyncRead + AsyncWrite + Send,
+{
     let timed_target_io = TimeoutIO::new(target_io, crate::IDLE_TIMEOUT);
-    pin!(timed_target_io);
     // https://github.com/sfackler/tokio-io-timeout/issues/12
     // timed_target_io.as_mut() // 一定要as_mut()，否则会move所有权
     // ._set_timeout_pinned(Duration::from_secs(crate::IDLE_SECONDS));
-    let (_from_client, _from_server) = tokio::io::copy_bidirectional(&mut upgraded, &mut timed_target_io).await?;
-    Ok(())
+    let half_closed = Arc::new(Notify::new());
+    let activity = Arc::new(Notify::new());
+    let client = TunnelNotify::new(upgraded, half_closed.clone(), activity.clone());
+    let target = TunnelNotify::new(timed_target_io, half_closed.clone(), activity.clone());
+    pin!(client, target);
+    // 读到EOF后shutdown写端，将FIN传递给另一侧。缓冲区大小为--tunnel-buffer-size
+    let buffer_size = crate::CONFIG.tunnel_buffer_size;
+    let copy = tokio::io::copy_bidirectional_with_sizes(&mut client, &mut target, buffer_size, buffer_size);
+    pin!(copy);
+    tokio::select! {
+        result = &mut copy => result.map(|_| ()),
+        _ = half_closed.notified() => {
+            // 一个方向已经结束并half-close了另一侧，对端可能不响应FIN，另一个方向空闲linger后关闭隧道
+            loop {
+                tokio::select! {
+                    result = &mut copy => return result.map(|_| ()),
+                    _ = activity.notified() => {}
+                    _ = tokio::time::sleep(linger) => {
+                        debug!("tunnel closed after idle for linger timeout {linger:?}");
+                        METRICS.tunnel_linger_closed.inc();
+                        return Ok(());
+                    }
+                }
+            }
+        }
+    }
+}
+
+pin_project! {
+    /// 隧道的一个方向结束、shutdown写端时通知 `half_closed`，用于开始linger计时；读到数据时通知 `activity`，重新开始计时
+    struct TunnelNotify<T> {
+        #[pin]
+        inner: T,
+        half_closed: Arc<Notify>,
+        activity: Arc<Notify>,
+    }
+}
+
+impl<T> TunnelNotify<T> {
+    fn new(inner: T, half_closed: Arc<Notify>, activity: Arc<Notify>) -> Self {
+        TunnelNotify {
+            inner,
+            half_closed,
+            activity,
+        }
+    }
+}
+
+impl<T: AsyncRead> AsyncRead for TunnelNotify<T> {
+    fn poll_read(
+        self: Pin<&mut Self>, cx: &mut std::task::Context<'_>, buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<io::Result<()>> {
+        let this = self.project();
+        let filled = buf.filled().len();
+        let poll = this.inner.poll_read(cx, buf);
+        if buf.filled().len() > filled {
+            this.activity.notify_one();
+        }
+        poll
+    }
+}
+
+impl<T: AsyncWrite> AsyncWrite for TunnelNotify<T> {
+    fn poll_write(
+        self: Pin<&mut Self>, cx: &mut std::task::Context<'_>, buf: &[u8],
+    ) -> std::task::Poll<io::Result<usize>> {
+        self.project().inner.poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> std::task::Poll<io::Result<()>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> std::task::Poll<io::Result<()>> {
+        let this = self.project();
+        this.half_closed.notify_one();
+        this.inner.poll_shutdown(cx)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>, cx: &mut std::task::Context<'_>, bufs: &[io::IoSlice<'_>],
+    ) -> std::task::Poll<io::Result<usize>> {
+        self.project().inner.poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
 }
 
 #[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
@@ -494,6 +1318,29 @@ pub struct AccessLabel {
     pub username: String,
 }
 
+/// username只会是--users中的用户或unknown，基数有限
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct TunnelUserLabel {
+    pub username: String,
+}
+
+/// event为reused、connected、expired、closed或full
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct ForwardPoolLabel {
+    pub event: &'static str,
+}
+
+/// phase为dns、connect或first_byte
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct TunnelPhaseLabel {
+    pub phase: &'static str,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct ListenerPortLabel {
+    pub port: u16,
+}
+
 #[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet, PartialOrd, Ord)]
 pub struct ReverseProxyReqLabel {
     pub client: String,
@@ -501,6 +1348,65 @@ pub struct ReverseProxyReqLabel {
     pub upstream: String,
 }
 
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct StaticRespLabel {
+    pub status: &'static str,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct BodyRejectedLabel {
+    pub origin: String,
+    pub reason: &'static str,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct CanaryRespLabel {
+    pub origin: String,
+    pub variant: &'static str,
+    pub status: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct LocationLabel {
+    pub origin: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct IpHashLabel {
+    pub origin: String,
+    pub upstream: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct PassthroughLabel {
+    pub upstream: String,
+    pub result: &'static str,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct MirrorLabel {
+    pub origin: String,
+    pub result: &'static str,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct FaultInjectedLabel {
+    pub origin: String,
+    pub kind: &'static str,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct TlsConnectionLabel {
+    pub alpn: &'static str,
+}
+
+/// cipher为rustls支持的套件名，基数有限
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+pub struct TlsCipherLabel {
+    pub cipher: &'static str,
+}
+
+#[cfg(all(target_os = "linux", feature = "bpf"))]
 #[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
 pub struct NetDirectionLabel {
     pub direction: &'static str,
@@ -530,6 +1436,17 @@ pub(crate) fn build_authenticate_resp(for_proxy: bool) -> Response<BoxBody<Bytes
     resp
 }
 
+/// 过载时的503响应，`retry_after` 为--retry-after，提示客户端稍后重试，为0时不返回Retry-After
+pub(crate) fn build_over_capacity_resp(message: &'static str, retry_after: u64) -> Response<BoxBody<Bytes, io::Error>> {
+    let mut resp = Response::new(full_body(message));
+    *resp.status_mut() = http::StatusCode::SERVICE_UNAVAILABLE;
+    if retry_after > 0 {
+        resp.headers_mut()
+            .insert(http::header::RETRY_AFTER, HeaderValue::from(retry_after));
+    }
+    resp
+}
+
 pub fn empty_body() -> BoxBody<Bytes, io::Error> {
     Empty::<Bytes>::new().map_err(|never| match never {}).boxed()
 }
@@ -540,6 +1457,627 @@ pub fn full_body<T: Into<Bytes>>(chunk: T) -> BoxBody<Bytes, io::Error> {
 
 #[cfg(test)]
 mod test {
+    use super::*;
+
+    #[test]
+    fn test_is_admin_req() -> Result<(), http::Error> {
+        let req = Request::get("/metrics").body(())?;
+        assert!(is_admin_req(&req, None));
+        assert!(!is_admin_req(&req, Some(9000)));
+        assert!(!is_admin_req(&Request::get("/index.html").body(())?, None));
+        assert!(!is_admin_req(&Request::get("http://example.com/metrics").body(())?, None));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_global_rate_limit_reverse_admin_path() -> Result<(), crate::DynError> {
+        use crate::reverse::{parse_reverse_proxy_config, ReverseProxyConfigSource};
+        crate::test_server::init_config();
+        let _reverse_config = crate::test_server::REVERSE_CONFIG_LOCK.lock().await;
+        let upstream =
+            crate::test_server::serve(|_req: Request<Incoming>| async { Ok(Response::new(full_body("upstream"))) })
+                .await?;
+        let source = ReverseProxyConfigSource {
+            files: vec![],
+            append_upstream_url: vec![],
+            enable_github_proxy: false,
+        };
+        let toml = format!(
+            r#"
+            [["ratelimit.test"]]
+            location = "/metrics"
+            upstream = {{ url_base = "http://{upstream}" }}
+            "#
+        );
+        crate::reverse::store_config(parse_reverse_proxy_config(source, Some(("test", &toml)))?);
+        let handler = Arc::new(ProxyHandler {
+            global_rate_limit: Some(TokenBucket::new(1, 1)),
+            ..ProxyHandler::new()?
+        });
+        let proxy = crate::test_server::serve(move |req: Request<Incoming>| {
+            let handler = handler.clone();
+            async move {
+                let peer = SocketAddr::from(([127, 0, 0, 1], 40000));
+                match handler.handle(req, peer, 0).await? {
+                    InterceptResultAdapter::Return(resp) => Ok(resp),
+                    InterceptResultAdapter::Continue(_) => Ok(Response::new(full_body("axum"))),
+                    InterceptResultAdapter::Drop => Err(io::Error::other("dropped")),
+                }
+            }
+        })
+        .await?;
+        let get = |host: &'static str| async move {
+            let stream = TcpStream::connect(proxy).await?;
+            let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+            tokio::spawn(conn);
+            let req = http::Request::get("/metrics")
+                .header(HOST, host)
+                .body(Empty::<Bytes>::new())?;
+            let resp = sender.send_request(req).await?;
+            let status = resp.status();
+            let body = resp.into_body().collect().await?.to_bytes();
+            Ok::<_, crate::DynError>((status, body))
+        };
+        let result = async {
+            assert_eq!(get("ratelimit.test").await?, (http::StatusCode::OK, Bytes::from("upstream")));
+            // 令牌已经用完：转发给上游的/metrics仍然受限制，由axum处理的/metrics不受限制
+            assert_eq!(get("ratelimit.test").await?.0, http::StatusCode::SERVICE_UNAVAILABLE);
+            assert_eq!(get("other.test").await?, (http::StatusCode::OK, Bytes::from("axum")));
+            Ok::<_, crate::DynError>(())
+        }
+        .await;
+        crate::reverse::store_config(crate::reverse::ReverseProxyConfig::default());
+        result
+    }
+
+    #[tokio::test]
+    async fn test_reverse_client_for() -> Result<(), crate::DynError> {
+        crate::test_server::init_config();
+        let handler = ProxyHandler::new()?;
+        let size = crate::CONFIG.pool_max_idle_per_host + 1;
+        let mut location_config: crate::reverse::LocationConfig = toml::from_str(&format!(
+            r#"
+            location = "/"
+            upstream = {{ url_base = "http://127.0.0.1:8080" }}
+            pool_max_idle_per_host = {size}
+            "#
+        ))?;
+        handler.reverse_client_for(&location_config);
+        location_config.passthrough = true;
+        handler.reverse_client_for(&location_config);
+        handler.reverse_client_for(&location_config);
+        let mut keys: Vec<_> = handler
+            .sized_reverse_clients
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .keys()
+            .copied()
+            .collect();
+        keys.sort();
+        assert_eq!(keys, [(false, size), (true, size)]);
+
+        location_config.pool_max_idle_per_host = None;
+        handler.reverse_client_for(&location_config);
+        assert_eq!(
+            handler
+                .sized_reverse_clients
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .len(),
+            2
+        );
+        Ok(())
+    }
+
+    fn headers_with_host(hosts: &[&'static str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for host in hosts {
+            headers.append(HOST, HeaderValue::from_static(host));
+        }
+        headers
+    }
+
+    /// 返回上游收到的SNI
+    async fn sni_sent(connector: hyper_rustls::HttpsConnector<HttpConnector>) -> Result<String, crate::DynError> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let acceptor = tokio_rustls::TlsAcceptor::from(crate::test_tls::server_config(&[b"http/1.1"])?);
+        tokio::spawn(async move {
+            if let Ok((socket, _)) = listener.accept().await {
+                let Ok(mut tls) = acceptor.accept(socket).await else {
+                    return;
+                };
+                let sni = tls.get_ref().1.server_name().unwrap_or("none").to_string();
+                let mut buf = [0u8; 1024];
+                let _ = tls.read(&mut buf).await;
+                let resp = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{sni}", sni.len());
+                let _ = tls.write_all(resp.as_bytes()).await;
+            }
+        });
+        let key = format!("127.0.0.1:{}", addr.port());
+        let mut config = crate::reverse::ReverseProxyConfig::default();
+        config
+            .server_names
+            .insert(key.clone(), tokio_rustls::rustls::pki_types::ServerName::try_from("upstream.test")?);
+        crate::reverse::store_config(config);
+        let client: legacy::Client<_, Empty<Bytes>> = legacy::Client::builder(TokioExecutor::new()).build(connector);
+        let req = http::Request::get(format!("https://{key}/")).body(Empty::new())?;
+        let resp = tokio::time::timeout(Duration::from_secs(10), client.request(req)).await??;
+        Ok(String::from_utf8(resp.into_body().collect().await?.to_bytes().to_vec())?)
+    }
+
+    #[tokio::test]
+    async fn test_upstream_sni_only_for_reverse_proxy() -> Result<(), crate::DynError> {
+        crate::test_server::init_config();
+        let _reverse_config = crate::test_server::REVERSE_CONFIG_LOCK.lock().await;
+        let ca = crate::test_tls::upstream_ca()?;
+        let reverse = build_reverse_proxy_connector(Default::default(), false, &ca, None, Default::default())?;
+        assert_eq!(sni_sent(reverse).await?, "upstream.test");
+        // IP不发送SNI
+        let shared = build_https_connector(Default::default(), false, &ca, None, Default::default())?;
+        assert_eq!(sni_sent(shared).await?, "none");
+        Ok(())
+    }
+
+    /// 目标返回101后回显收到的字节
+    async fn serve_echo_upgrade() -> io::Result<SocketAddr> {
+        crate::test_server::serve(|mut req: Request<Incoming>| async move {
+            let upgrade = hyper::upgrade::on(&mut req);
+            tokio::spawn(async move {
+                if let Ok(upgraded) = upgrade.await {
+                    let (mut read, mut write) = tokio::io::split(TokioIo::new(upgraded));
+                    let _ = tokio::io::copy(&mut read, &mut write).await;
+                }
+            });
+            Response::builder()
+                .status(http::StatusCode::SWITCHING_PROTOCOLS)
+                .header(http::header::CONNECTION, "upgrade")
+                .header(http::header::UPGRADE, "echo")
+                .body(empty_body())
+                .map_err(io::Error::other)
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_spawn_upgrade_tunnel() -> Result<(), crate::DynError> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        crate::test_server::init_config();
+        let target = serve_echo_upgrade().await?;
+        let tunnel_limit = Arc::new(Semaphore::new(1));
+        // 连接池的空闲超时很短，升级后的隧道不受它影响
+        let pool_idle_timeout = Duration::from_millis(300);
+        let handler = Arc::new(ProxyHandler {
+            forwad_proxy_client: ForwardProxyClient::with_idle_timeout(pool_idle_timeout, None),
+            tunnel_limit: Some(tunnel_limit.clone()),
+            ..ProxyHandler::new()?
+        });
+        let proxy = crate::test_server::serve(move |req: Request<Incoming>| {
+            let handler = handler.clone();
+            async move {
+                let peer = SocketAddr::from(([127, 0, 0, 1], 40000));
+                match handler.handle(req, peer, 0).await? {
+                    InterceptResultAdapter::Return(resp) => Ok(resp),
+                    _ => Err(io::Error::other("should be handled by ProxyHandler")),
+                }
+            }
+        })
+        .await?;
+
+        let mut client = TcpStream::connect(proxy).await?;
+        client
+            .write_all(
+                format!(
+                    "GET http://{target}/ HTTP/1.1\r\nHost: {target}\r\nConnection: upgrade\r\nUpgrade: echo\r\n\r\n"
+                )
+                .as_bytes(),
+            )
+            .await?;
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            let mut byte = [0u8; 1];
+            client.read_exact(&mut byte).await?;
+            head.push(byte[0]);
+        }
+        let head = String::from_utf8(head)?.to_ascii_lowercase();
+        assert!(head.starts_with("http/1.1 101"), "{head}");
+        assert!(head.contains("upgrade: echo"), "{head}");
+
+        for (i, message) in [&b"ping"[..], b"pong"].into_iter().enumerate() {
+            if i > 0 {
+                tokio::time::sleep(pool_idle_timeout * 3).await;
+            }
+            client.write_all(message).await?;
+            let mut echoed = vec![0u8; message.len()];
+            tokio::time::timeout(Duration::from_secs(5), client.read_exact(&mut echoed)).await??;
+            assert_eq!(echoed, message);
+        }
+        assert_eq!(tunnel_limit.available_permits(), 0);
+
+        client.shutdown().await?;
+        let mut rest = Vec::new();
+        tokio::time::timeout(Duration::from_secs(5), client.read_to_end(&mut rest)).await??;
+        assert!(rest.is_empty());
+        // 隧道结束后归还--max-tunnels的许可
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while tunnel_limit.available_permits() == 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_tunnel_half_close() -> io::Result<()> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        crate::test_server::init_config();
+        let (client, mut client_peer) = tokio::io::duplex(64);
+        let (target, mut target_peer) = tokio::io::duplex(64);
+        let tunnel = tokio::spawn(tunnel(client, target, TUNNEL_LINGER));
+
+        client_peer.write_all(b"ping").await?;
+        client_peer.shutdown().await?;
+        let mut received = Vec::new();
+        target_peer.read_to_end(&mut received).await?;
+        assert_eq!(received, b"ping");
+
+        // 客户端half-close之后，目标仍可以继续发送
+        target_peer.write_all(b"pong").await?;
+        target_peer.shutdown().await?;
+        let mut received = Vec::new();
+        client_peer.read_to_end(&mut received).await?;
+        assert_eq!(received, b"pong");
+        tunnel.await.map_err(io::Error::other)?
+    }
+
+    #[tokio::test]
+    async fn test_tunnel_linger() -> io::Result<()> {
+        use tokio::io::AsyncWriteExt;
+        crate::test_server::init_config();
+        let (client, mut client_peer) = tokio::io::duplex(64);
+        let (target, _target_peer) = tokio::io::duplex(64);
+        let linger = Duration::from_millis(200);
+        let tunnel = tokio::spawn(tunnel(client, target, linger));
+        client_peer.shutdown().await?;
+        // 目标不响应FIN时，空闲linger之后关闭隧道
+        tokio::time::timeout(linger * 10, tunnel)
+            .await
+            .map_err(io::Error::other)?
+            .map_err(io::Error::other)?
+    }
+
+    #[tokio::test]
+    async fn test_tunnel_linger_reset_by_data() -> io::Result<()> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        crate::test_server::init_config();
+        let (client, mut client_peer) = tokio::io::duplex(64);
+        let (target, mut target_peer) = tokio::io::duplex(64);
+        let linger = Duration::from_millis(200);
+        let tunnel = tokio::spawn(tunnel(client, target, linger));
+        // 客户端发送请求后half-close，目标持续发送的时间远超linger
+        client_peer.shutdown().await?;
+        let download = tokio::spawn(async move {
+            let mut received = Vec::new();
+            client_peer.read_to_end(&mut received).await.map(|_| received)
+        });
+        for _ in 0..10 {
+            target_peer.write_all(b"data").await?;
+            tokio::time::sleep(linger / 2).await;
+        }
+        target_peer.shutdown().await?;
+        assert_eq!(download.await.map_err(io::Error::other)??, b"data".repeat(10));
+        tunnel.await.map_err(io::Error::other)?
+    }
+
+    #[test]
+    fn test_check_host_header() {
+        let uri: Uri = "http://www.example.com/index.html".parse().unwrap_or_default();
+        assert!(check_host_header(&Method::GET, &uri, &headers_with_host(&[])).is_ok());
+        assert!(check_host_header(&Method::GET, &uri, &headers_with_host(&["www.example.com"])).is_ok());
+        assert!(check_host_header(&Method::GET, &uri, &headers_with_host(&["WWW.Example.com:80"])).is_ok());
+        assert!(check_host_header(&Method::GET, &uri, &headers_with_host(&["evil.com"])).is_err());
+        assert!(check_host_header(&Method::GET, &uri, &headers_with_host(&["www.example.com:8080"])).is_err());
+        assert!(check_host_header(&Method::GET, &uri, &headers_with_host(&["www.example.com", "evil.com"])).is_err());
+        assert!(check_host_header(&Method::GET, &uri, &headers_with_host(&["www.example.com/path"])).is_err());
+
+        let uri: Uri = "https://[::1]/".parse().unwrap_or_default();
+        assert!(check_host_header(&Method::GET, &uri, &headers_with_host(&["[::1]:443"])).is_ok());
+
+        // CONNECT请求的URI是authority-form
+        let uri: Uri = "www.example.com:443".parse().unwrap_or_default();
+        assert!(check_host_header(&Method::CONNECT, &uri, &headers_with_host(&["www.example.com:443"])).is_ok());
+        assert!(check_host_header(&Method::CONNECT, &uri, &headers_with_host(&["www.example.com"])).is_ok());
+        assert!(check_host_header(&Method::CONNECT, &uri, &headers_with_host(&["other.example.com:443"])).is_err());
+        let uri: Uri = "www.example.com:8443".parse().unwrap_or_default();
+        assert!(check_host_header(&Method::CONNECT, &uri, &headers_with_host(&["www.example.com"])).is_err());
+    }
+
+    #[test]
+    fn test_asterisk_form() -> Result<(), http::Error> {
+        let req = Request::builder().method(Method::OPTIONS).uri("*").body(())?;
+        assert!(is_asterisk_form(&req));
+        let req = Request::builder().method(Method::OPTIONS).uri("/").body(())?;
+        assert!(!is_asterisk_form(&req));
+        let req = Request::builder().method(Method::GET).uri("*").body(())?;
+        assert!(!is_asterisk_form(&req));
+        Ok(())
+    }
+
+    #[test]
+    fn test_with_served_by() {
+        let node = HeaderValue::from_static("node-1");
+        let resp = with_served_by(Response::new(()), Some(&node));
+        assert_eq!(resp.headers().get("x-served-by"), Some(&node));
+        // 上游返回的X-Served-By被替换，不会暴露上游的节点
+        let mut upstream = Response::new(());
+        upstream
+            .headers_mut()
+            .insert("x-served-by", HeaderValue::from_static("upstream"));
+        let resp = with_served_by(upstream, Some(&node));
+        assert_eq!(resp.headers().get_all("x-served-by").iter().collect::<Vec<_>>(), [&node]);
+        assert!(!with_served_by(Response::new(()), None)
+            .headers()
+            .contains_key("x-served-by"));
+    }
+
+    #[test]
+    fn test_reject_connect_port() {
+        crate::test_server::init_config();
+        let client = SocketAddr::from(([127, 0, 0, 1], 40000));
+        let status = |authority: &str, allowed: &[u16]| {
+            let uri = authority.parse::<Uri>().unwrap_or_default();
+            host_addr(&uri).and_then(|addr| reject_connect_port(&addr, allowed, client).map(|resp| resp.status()))
+        };
+        assert_eq!(status("smtp.example.com:25", &[80, 443]), Some(http::StatusCode::FORBIDDEN));
+        assert_eq!(status("1.2.3.4:25", &[80, 443]), Some(http::StatusCode::FORBIDDEN));
+        assert_eq!(status("www.example.com:443", &[80, 443]), None);
+        assert_eq!(status("smtp.example.com:25", &[]), None);
+    }
+
+    #[test]
+    fn test_reject_trace() -> Result<(), http::Error> {
+        for method in [Method::TRACE, Method::from_bytes(b"TRACK").unwrap_or_default()] {
+            let req = Request::builder()
+                .method(method)
+                .uri("http://www.example.com/")
+                .body(())?;
+            let resp = reject_trace(&req, HeaderValue::from_static("GET"));
+            assert!(resp.is_some_and(|resp| resp.status() == http::StatusCode::METHOD_NOT_ALLOWED
+                && resp.headers().get(http::header::ALLOW) == Some(&HeaderValue::from_static("GET"))));
+        }
+        let req = Request::builder().method(Method::GET).uri("/").body(())?;
+        assert!(reject_trace(&req, HeaderValue::from_static("GET")).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_allowed_methods() {
+        let forward = ListenerMode::ForwardProxy;
+        assert_eq!(allowed_methods(forward, &[], false), "GET, HEAD, POST, PUT, DELETE, PATCH, OPTIONS, CONNECT");
+        assert_eq!(
+            allowed_methods(ListenerMode::ReverseOnly, &[], false),
+            "GET, HEAD, POST, PUT, DELETE, PATCH, OPTIONS"
+        );
+        // --allow-method不包含CONNECT时隧道不可用
+        assert_eq!(allowed_methods(forward, &[Method::GET], false), "GET, HEAD, POST, PUT, DELETE, PATCH, OPTIONS");
+        assert_eq!(
+            allowed_methods(forward, &[Method::CONNECT], true),
+            "GET, HEAD, POST, PUT, DELETE, PATCH, OPTIONS, TRACE, CONNECT"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_trace_not_forwarded() -> Result<(), crate::DynError> {
+        crate::test_server::init_config();
+        let seen = Arc::new(Mutex::new(Vec::<Method>::new()));
+        let upstream_seen = seen.clone();
+        let upstream = crate::test_server::serve(move |req: Request<Incoming>| {
+            let seen = upstream_seen.clone();
+            async move {
+                seen.lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .push(req.method().clone());
+                Ok(Response::new(full_body("upstream")))
+            }
+        })
+        .await?;
+        let handler = Arc::new(ProxyHandler::new()?);
+        let proxy = crate::test_server::serve(move |req: Request<Incoming>| {
+            let handler = handler.clone();
+            async move {
+                let peer = SocketAddr::from(([127, 0, 0, 1], 40000));
+                match handler.handle(req, peer, 0).await? {
+                    InterceptResultAdapter::Return(resp) => Ok(resp),
+                    _ => Err(io::Error::other("should be handled by ProxyHandler")),
+                }
+            }
+        })
+        .await?;
+        // 正向代理请求：连接代理，请求行为目标的绝对URI
+        let send = |method: Method| async move {
+            let stream = TcpStream::connect(proxy).await?;
+            let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+            tokio::spawn(conn);
+            let req = http::Request::builder()
+                .method(method)
+                .uri(format!("http://{upstream}/"))
+                .header(HOST, upstream.to_string())
+                .body(Empty::<Bytes>::new())?;
+            let resp = tokio::time::timeout(Duration::from_secs(10), sender.send_request(req)).await??;
+            Ok::<_, crate::DynError>(resp.status())
+        };
+        for method in [Method::TRACE, Method::from_bytes(b"TRACK")?] {
+            assert_eq!(send(method).await?, http::StatusCode::METHOD_NOT_ALLOWED);
+        }
+        assert!(seen.lock().unwrap_or_else(|e| e.into_inner()).is_empty());
+        // 同样的请求换成GET会被转发，说明TRACE是在ProxyHandler中被拒绝的
+        assert_eq!(send(Method::GET).await?, http::StatusCode::OK);
+        assert_eq!(*seen.lock().unwrap_or_else(|e| e.into_inner()), [Method::GET]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_forward_method() -> Result<(), Box<dyn std::error::Error>> {
+        let allowed = [Method::GET, Method::POST, Method::CONNECT];
+        let req = Request::builder()
+            .method(Method::from_bytes(b"PROPFIND")?)
+            .uri("http://www.example.com/")
+            .body(())?;
+        let resp = check_forward_method(&req, &allowed).ok_or("PROPFIND is not rejected")?;
+        assert_eq!(resp.status(), http::StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(resp.headers().get(http::header::ALLOW), Some(&HeaderValue::from_static("GET, POST, CONNECT")));
+        // 不设置时允许所有方法
+        assert!(check_forward_method(&req, &[]).is_none());
+        let req = Request::builder()
+            .method(Method::CONNECT)
+            .uri("www.example.com:443")
+            .body(())?;
+        assert!(check_forward_method(&req, &allowed).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_rewrite_user_agent() {
+        let mut headers = HeaderMap::new();
+        headers.insert(http::header::USER_AGENT, HeaderValue::from_static("curl/8.0"));
+        rewrite_user_agent(&mut headers, &UserAgentRewrite::Passthrough);
+        assert_eq!(headers.get(http::header::USER_AGENT), Some(&HeaderValue::from_static("curl/8.0")));
+        rewrite_user_agent(&mut headers, &UserAgentRewrite::Fixed(HeaderValue::from_static("Mozilla/5.0")));
+        assert_eq!(headers.get(http::header::USER_AGENT), Some(&HeaderValue::from_static("Mozilla/5.0")));
+        rewrite_user_agent(&mut headers, &UserAgentRewrite::Remove);
+        assert!(!headers.contains_key(http::header::USER_AGENT));
+    }
+
+    #[test]
+    fn test_http10_proxy_req() -> io::Result<()> {
+        let mut req = Request::builder()
+            .method(Method::GET)
+            .uri("http://www.example.com:8080/path?a=1")
+            .version(Version::HTTP_10)
+            .header(http::header::CONNECTION, "Keep-Alive")
+            .header("Keep-Alive", "timeout=5")
+            .body(())
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+        assert!(wants_keep_alive(req.headers()));
+        let (scheme_host_port, domain) = extract_scheme_host_port(&req, "http")?;
+        assert_eq!(scheme_host_port.to_string(), "http://www.example.com:8080");
+        assert_eq!(domain.0, "www.example.com");
+
+        mod_http1_proxy_req(&mut req, &UserAgentRewrite::Passthrough)?;
+        assert_eq!(req.version(), Version::HTTP_11);
+        assert_eq!(req.uri().to_string(), "/path?a=1");
+        assert_eq!(req.headers().get(HOST), Some(&HeaderValue::from_static("www.example.com:8080")));
+        assert!(!req.headers().contains_key(http::header::CONNECTION));
+        assert!(!req.headers().contains_key("Keep-Alive"));
+
+        let mut resp_headers = HeaderMap::new();
+        resp_headers.insert("Keep-Alive", HeaderValue::from_static("timeout=5"));
+        set_http10_resp_keep_alive(&mut resp_headers, true);
+        assert_eq!(resp_headers.get(http::header::CONNECTION), Some(&HeaderValue::from_static("keep-alive")));
+        assert!(!resp_headers.contains_key("Keep-Alive"));
+
+        // origin-form且没有Host时无法确定目标
+        let req = Request::builder()
+            .uri("/path")
+            .version(Version::HTTP_10)
+            .body(())
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+        assert!(extract_scheme_host_port(&req, "http").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_host_port_policy() -> io::Result<()> {
+        let apply = |host: &str, scheme: &str, listener_port: u16, policy: HostPortPolicy| {
+            let req = Request::builder()
+                .uri("/")
+                .header(HOST, host)
+                .body(())
+                .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+            let (mut scheme_host_port, _) = extract_scheme_host_port(&req, scheme)?;
+            Ok::<_, io::Error>(
+                apply_host_port_policy(&mut scheme_host_port, listener_port, policy)
+                    .map(|_| scheme_host_port.to_string()),
+            )
+        };
+        // 默认使用Host中的端口
+        assert_eq!(
+            apply("example.com:8080", "https", 443, HostPortPolicy::Header)?,
+            Ok("https://example.com:8080".to_string())
+        );
+        assert_eq!(
+            apply("example.com:8080", "https", 443, HostPortPolicy::Listener)?,
+            Ok("https://example.com".to_string())
+        );
+        assert_eq!(
+            apply("example.com", "http", 8080, HostPortPolicy::Listener)?,
+            Ok("http://example.com:8080".to_string())
+        );
+        assert!(apply("example.com:8080", "https", 443, HostPortPolicy::Reject)?.is_err());
+        assert!(apply("example.com", "http", 8080, HostPortPolicy::Reject)?.is_err());
+        // 端口一致时不做处理，省略的默认端口视为一致
+        assert_eq!(
+            apply("example.com:443", "https", 443, HostPortPolicy::Reject)?,
+            Ok("https://example.com:443".to_string())
+        );
+        assert_eq!(apply("example.com", "https", 443, HostPortPolicy::Reject)?, Ok("https://example.com".to_string()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_forward_proxy_req() -> Result<(), http::Error> {
+        let req = Request::builder().uri("http://www.example.com/").body(())?;
+        assert!(is_forward_proxy_req(&req));
+        let req = Request::builder()
+            .method(Method::CONNECT)
+            .uri("www.example.com:443")
+            .body(())?;
+        assert!(is_forward_proxy_req(&req));
+        let req = Request::builder().uri("/index.html").body(())?;
+        assert!(!is_forward_proxy_req(&req));
+        // HTTP/2的uri总是带有authority
+        let req = Request::builder()
+            .uri("https://www.example.com/")
+            .version(Version::HTTP_2)
+            .body(())?;
+        assert!(!is_forward_proxy_req(&req));
+        Ok(())
+    }
+
+    #[test]
+    fn test_uri_len() -> Result<(), http::uri::InvalidUri> {
+        for uri in [
+            "/",
+            "/index.html?a=1",
+            "https://www.example.com/https://cdnjs.cloudflare.com/ajax?x=1",
+            "www.example.com:443",
+        ] {
+            let parsed: Uri = uri.parse()?;
+            assert_eq!(uri_len(&parsed), parsed.to_string().len(), "{uri}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_strip_upgrade() {
+        let mut headers = HeaderMap::new();
+        headers.insert(http::header::UPGRADE, HeaderValue::from_static("websocket"));
+        headers.insert(http::header::CONNECTION, HeaderValue::from_static("keep-alive, Upgrade"));
+        assert!(is_upgrade_request(&headers));
+        strip_upgrade(&mut headers);
+        assert!(!is_upgrade_request(&headers));
+        assert!(!headers.contains_key(http::header::UPGRADE));
+        assert_eq!(headers.get(http::header::CONNECTION), Some(&HeaderValue::from_static("keep-alive")));
+
+        // 没有Upgrade请求头时不是升级请求
+        headers.insert(http::header::CONNECTION, HeaderValue::from_static("upgrade"));
+        assert!(!is_upgrade_request(&headers));
+        strip_upgrade(&mut headers);
+        assert!(!headers.contains_key(http::header::CONNECTION));
+    }
+
     #[test]
     fn test_aa() {
         let host = "www.arloor.com";
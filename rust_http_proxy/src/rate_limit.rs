@@ -0,0 +1,72 @@
+//! `--global-rate-limit`：整个服务每秒请求数的上限，超过时返回503
+//!
+//! 所有端口的正向代理、反向代理和静态文件托管共用 `ProxyHandler` 中的一个令牌桶，在 `handle()` 的最开始检查，是最粗粒度的过载保护。
+//! 桶的容量为 `--global-rate-burst`，默认等于每秒的速率。由axum处理的 `/metrics`、`/ip` 等管理接口不受限制，
+//! 但匹配了反向代理location的同名路径仍然受限制，设置了 `--admin-port` 时代理端口上的请求也都受限制。
+//! 被拒绝的请求计入Prometheus指标 `global_rate_limited`。
+
+use std::{sync::Mutex, time::Instant};
+
+pub(crate) struct TokenBucket {
+    /// 每秒补充的令牌数
+    rate: f64,
+    burst: f64,
+    state: Mutex<BucketState>,
+}
+
+struct BucketState {
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    /// 初始时桶是满的
+    pub(crate) fn new(rate: u32, burst: u32) -> Self {
+        TokenBucket {
+            rate: rate as f64,
+            burst: burst as f64,
+            state: Mutex::new(BucketState {
+                tokens: burst as f64,
+                last: Instant::now(),
+            }),
+        }
+    }
+
+    pub(crate) fn try_acquire(&self) -> bool {
+        self.try_acquire_at(Instant::now())
+    }
+
+    fn try_acquire_at(&self, now: Instant) -> bool {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let elapsed = now.saturating_duration_since(state.last).as_secs_f64();
+        state.tokens = (state.tokens + elapsed * self.rate).min(self.burst);
+        state.last = now;
+        if state.tokens >= 1.0 {
+            state.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_token_bucket() {
+        let bucket = TokenBucket::new(10, 3);
+        let start = Instant::now();
+        assert!((0..3).all(|_| bucket.try_acquire_at(start)));
+        assert!(!bucket.try_acquire_at(start));
+        // 每100毫秒补充一个令牌
+        assert!(bucket.try_acquire_at(start + Duration::from_millis(100)));
+        assert!(!bucket.try_acquire_at(start + Duration::from_millis(150)));
+        // 不超过桶的容量
+        let later = start + Duration::from_secs(10);
+        assert!((0..3).all(|_| bucket.try_acquire_at(later)));
+        assert!(!bucket.try_acquire_at(later));
+    }
+}
@@ -1,9 +1,13 @@
+use crate::buffered_response::buffer_small_response;
+use crate::config::Compression;
+use crate::embedded_content;
 use crate::ip_x::SocketAddrFormat;
 use crate::proxy::empty_body;
 use crate::proxy::full_body;
 use crate::proxy::ReqLabels;
+use crate::proxy::StaticRespLabel;
 use crate::METRICS;
-use async_compression::tokio::bufread::GzipEncoder;
+use async_compression::tokio::bufread::{BrotliEncoder, GzipEncoder};
 use futures_util::TryStreamExt;
 use http::response::Builder;
 use http::{Error, HeaderValue};
@@ -13,27 +17,68 @@ use httpdate::fmt_http_date;
 use hyper::body::{Body, Bytes, Frame};
 use hyper::header::{CONTENT_ENCODING, REFERER};
 use hyper::{http, Method, Request, Response, StatusCode};
-use log::{info, warn};
+use log::{debug, info, warn};
 use mime_guess::from_path;
 use prom_label::LabelImpl;
 use prometheus_client::metrics::counter::Counter;
 use prometheus_client::metrics::family::Family;
 use regex::Regex;
+use std::fs::Metadata;
 use std::io;
 use std::net::SocketAddr;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::pin;
-use std::sync::LazyLock;
+use std::sync::{Arc, LazyLock};
 use std::time::SystemTime;
-use tokio::fs::{metadata, File};
+use tokio::fs::{canonicalize, metadata, File};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, BufReader};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
 use tokio_util::io::ReaderStream;
 
 pub(crate) static GZIP: &str = "gzip";
+static BR: &str = "br";
 pub(crate) const SERVER_NAME: &str = "The Bad Server";
+/// 限制同时进行实时压缩的响应数，为None时不限制
+static COMPRESSION_PERMITS: LazyLock<Option<Arc<Semaphore>>> =
+    LazyLock::new(|| crate::CONFIG.max_compressions.map(|max| Arc::new(Semaphore::new(max))));
 
+/// 静态文件托管的结果按状态计入 `static_resp`
 pub async fn serve_http_request(
     req: &Request<impl Body>, client_socket_addr: SocketAddr, path: &str,
+) -> Result<Response<BoxBody<Bytes, io::Error>>, Error> {
+    let mut result = serve(req, client_socket_addr, path).await;
+    if let Ok(resp) = result {
+        result = Ok(buffer_small_response(resp, req.method(), crate::CONFIG.buffer_response_threshold).await);
+    }
+    if let Ok(resp) = &result {
+        let status = match resp.extensions().get::<StaticRespStatus>() {
+            Some(StaticRespStatus(status)) => status,
+            None => match resp.status().as_u16() {
+                200..=299 => "2xx",
+                304 => "304",
+                404 => "404",
+                400..=499 => "4xx",
+                _ => "5xx",
+            },
+        };
+        record_static_resp(status);
+    }
+    result
+}
+
+/// 响应状态码无法区分的结果，例如以404返回的Referer拒绝和文件系统错误
+#[derive(Clone, Copy)]
+struct StaticRespStatus(&'static str);
+
+pub(crate) fn record_static_resp(status: &'static str) {
+    METRICS
+        .static_resp
+        .get_or_create(&LabelImpl::new(StaticRespLabel { status }))
+        .inc();
+}
+
+async fn serve(
+    req: &Request<impl Body>, client_socket_addr: SocketAddr, path: &str,
 ) -> Result<Response<BoxBody<Bytes, io::Error>>, Error> {
     let web_content_path = &crate::CONFIG.web_content_path;
     let referer_keywords_to_self = &crate::CONFIG.referer_keywords_to_self;
@@ -53,14 +98,14 @@ pub async fn serve_http_request(
                 referer_header,
                 SocketAddrFormat(&client_socket_addr)
             );
-            return not_found();
+            return not_found().map(|resp| with_static_resp_status(resp, "403"));
         }
     }
     let accept_encoding = req
         .headers()
         .get(http::header::ACCEPT_ENCODING)
         .map_or("", |h| h.to_str().unwrap_or(""));
-    let can_gzip = accept_encoding.contains(GZIP);
+    let compression = negotiate_compression(accept_encoding);
 
     #[allow(clippy::needless_return)]
     return match (req.method(), path) {
@@ -85,12 +130,12 @@ pub async fn serve_http_request(
                     "".to_string()
                 },
             );
-            let r = serve_path(web_content_path, path, req, can_gzip, true).await;
+            let r = serve_path(web_content_path, path, req, compression, true).await;
             let is_shell = path.ends_with(".sh");
             incr_counter_if_need(&r, is_outer_view_html, is_shell, &METRICS.http_req_counter, referer_header, path);
             r
         }
-        (&Method::HEAD, path) => serve_path(web_content_path, path, req, false, false).await,
+        (&Method::HEAD, path) => serve_path(web_content_path, path, req, None, false).await,
         _ => not_found(),
     };
 }
@@ -135,14 +180,14 @@ fn extract_search_engine_from_referer(referer: &str) -> Result<String, regex::Er
 }
 
 async fn serve_path(
-    web_content_path: &String, url_path: &str, req: &Request<impl Body>, can_gzip: bool, need_body: bool,
+    web_content_path: &String, url_path: &str, req: &Request<impl Body>, compression: Option<Compression>,
+    need_body: bool,
 ) -> Result<Response<BoxBody<Bytes, io::Error>>, Error> {
     if String::from(url_path).contains("/..") {
         return not_found();
     }
-    // 禁止访问.git目录
-    if String::from(url_path).starts_with("/.git/") {
-        return not_found();
+    if crate::CONFIG.serve_embedded {
+        return serve_embedded(url_path, req, compression, need_body);
     }
     let path = if String::from(url_path).ends_with('/') {
         format!("{web_content_path}{url_path}index.html")
@@ -158,87 +203,341 @@ async fn serve_path(
                 path = PathBuf::from(format!("{web_content_path}{url_path}/index.html"));
                 match metadata(&path).await {
                     Ok(m) => m,
-                    Err(_) => return not_found(),
+                    Err(e) => return fs_error(&path, e),
                 }
             }
         }
-        Err(_) => {
+        Err(e) => {
             if url_path == "/favicon.ico" {
                 return serve_favico(req, need_body);
             };
-            return not_found();
+            return fs_error(&path, e);
         }
     };
 
+    if !crate::CONFIG.static_file_filter.permits(url_path, &path) {
+        return denied(url_path);
+    }
+
+    let follow_symlinks = crate::CONFIG.follow_symlinks;
+    if !follow_symlinks && !is_within_root(web_content_path, &path).await {
+        warn!("{} resolves outside of {}, rejected", path.display(), web_content_path);
+        return not_found();
+    }
+
+    // 内容类型以原始文件为准，预压缩文件只决定Content-Encoding
+    let content_type = guess_content_type(&path, crate::CONFIG.default_charset.as_deref());
+    let cache_control = cache_control(
+        &path,
+        &content_type,
+        crate::CONFIG.immutable_asset_pattern.as_ref(),
+        crate::CONFIG.immutable_max_age,
+    );
+    let precompressed = match find_precompressed(&path, precompressed_accept_encoding(req)).await {
+        Some((precompressed_path, _, _))
+            if !follow_symlinks && !is_within_root(web_content_path, &precompressed_path).await =>
+        {
+            None
+        }
+        other => other,
+    };
+    let (path, meta, precompressed_encoding) = match precompressed {
+        Some((precompressed_path, precompressed_meta, encoding)) => {
+            (precompressed_path, precompressed_meta, Some(encoding))
+        }
+        None => (path, meta, None),
+    };
+
     let last_modified: SystemTime = match meta.modified() {
         Ok(time) => time,
-        Err(_) => return not_found(),
+        Err(e) => return fs_error(&path, e),
     };
     let file_len = meta.len();
     let file_etag = cal_file_etag(last_modified, file_len);
     if let Some(response) = return_304_if_not_modified(req, &file_etag, last_modified) {
         return response;
     }
-    let mime_type = from_path(&path).first_or_octet_stream();
-    let content_type = mime_type.as_ref();
-    let content_type = if !content_type.to_ascii_lowercase().contains("charset") && !content_type.contains("wasm") {
-        format!("{}{}", &content_type, "; charset=utf-8")
-    } else {
-        String::from(content_type)
-    };
-    let mut builder = Response::builder()
-        .header(http::header::CONTENT_TYPE, content_type.as_str())
-        .header(http::header::LAST_MODIFIED, fmt_http_date(last_modified))
-        .header(http::header::ETAG, file_etag)
-        .header(http::header::ACCEPT_RANGES, "bytes")
-        .header(http::header::SERVER, SERVER_NAME);
-
-    // 判断客户端是否支持gzip
-    let content_type = content_type.as_str();
-    let need_gzip = can_gzip
-        && (content_type.starts_with("text/html")
-            || content_type.starts_with("text/css")
-            || content_type.starts_with("application/javascript")
-            || content_type.starts_with("application/json")
-            || content_type.starts_with("text/xml")
-            || content_type.starts_with("application/xml")
-            || content_type.starts_with("text/plain")
-            || content_type.starts_with("text/markdown"));
-    if need_gzip {
-        builder = builder.header(CONTENT_ENCODING, GZIP)
-    };
+    let (builder, compression, compression_permit) =
+        response_head(&content_type, cache_control, last_modified, file_etag, precompressed_encoding, compression);
     if !need_body {
         return builder.body(empty_body());
     }
 
-    let mut file = match File::open(path).await {
+    let mut file = match File::open(&path).await {
         Ok(file) => file,
-        Err(_) => return not_found(),
+        Err(e) => return fs_error(&path, e),
     };
+    // 空文件没有合法的字节范围，忽略Range
+    if file_len == 0 {
+        return final_build(compression, file, builder, compression_permit);
+    }
 
     let (start, end, builder) = match parse_range(req.headers().get(http::header::RANGE), file_len, builder) {
         Ok((start, end, builder)) => (start, end, builder),
-        Err(e) => {
-            return Response::builder()
-                .status(StatusCode::RANGE_NOT_SATISFIABLE)
-                .header(http::header::SERVER, SERVER_NAME)
-                .body(full_body(e.to_string()));
-        }
+        Err(e) => return range_not_satisfiable(e),
     };
 
     if start != 0 {
         if let Err(e) = file.seek(io::SeekFrom::Start(start)).await {
             warn!("seek file error: {e}");
-            return Ok(build_500_resp());
+            return Ok(with_static_resp_status(build_500_resp(), "fs_error"));
         };
     }
     if end != file_len - 1 {
-        final_build(need_gzip, file.take(end - start + 1), builder)
+        final_build(compression, file.take(end - start + 1), builder, compression_permit)
     } else {
-        final_build(need_gzip, file, builder)
+        final_build(compression, file, builder, compression_permit)
     }
 }
 
+/// 与磁盘文件相同的ETag、Range和压缩处理，区别只是内容来自构建时嵌入的数据
+fn serve_embedded(
+    url_path: &str, req: &Request<impl Body>, compression: Option<Compression>, need_body: bool,
+) -> Result<Response<BoxBody<Bytes, io::Error>>, Error> {
+    let Some(file) = embedded_content::find(url_path) else {
+        if url_path == "/favicon.ico" {
+            return serve_favico(req, need_body);
+        }
+        return not_found();
+    };
+    let path = Path::new(file.path);
+    if !crate::CONFIG.static_file_filter.permits(url_path, path) {
+        return denied(url_path);
+    }
+    let content_type = guess_content_type(path, crate::CONFIG.default_charset.as_deref());
+    let cache_control = cache_control(
+        path,
+        &content_type,
+        crate::CONFIG.immutable_asset_pattern.as_ref(),
+        crate::CONFIG.immutable_max_age,
+    );
+    let accept_encoding = precompressed_accept_encoding(req);
+    let precompressed = [(BR, "br"), (GZIP, "gz")]
+        .into_iter()
+        .filter(|(encoding, _)| accepts_encoding(accept_encoding, encoding))
+        .find_map(|(encoding, suffix)| {
+            embedded_content::get(&format!("{}.{suffix}", file.path)).map(|precompressed| (precompressed, encoding))
+        });
+    let (file, precompressed_encoding) = match precompressed {
+        Some((precompressed, encoding)) => (precompressed, Some(encoding)),
+        None => (file, None),
+    };
+    let file_len = file.data.len() as u64;
+    let file_etag = cal_file_etag(file.modified(), file_len);
+    if let Some(response) = return_304_if_not_modified(req, &file_etag, file.modified()) {
+        return response;
+    }
+    let (builder, compression, compression_permit) =
+        response_head(&content_type, cache_control, file.modified(), file_etag, precompressed_encoding, compression);
+    if !need_body {
+        return builder.body(empty_body());
+    }
+    embedded_body(file.data, req.headers().get(http::header::RANGE), builder, compression, compression_permit)
+}
+
+/// 空文件没有合法的字节范围，忽略Range返回空的200
+fn embedded_body(
+    data: &'static [u8], range_header: Option<&HeaderValue>, builder: Builder, compression: Option<Compression>,
+    compression_permit: Option<OwnedSemaphorePermit>,
+) -> Result<Response<BoxBody<Bytes, io::Error>>, Error> {
+    if data.is_empty() {
+        return final_build(compression, data, builder, compression_permit);
+    }
+    let (start, end, builder) = match parse_range(range_header, data.len() as u64, builder) {
+        Ok((start, end, builder)) => (start, end, builder),
+        Err(e) => return range_not_satisfiable(e),
+    };
+    final_build(compression, &data[start as usize..=end as usize], builder, compression_permit)
+}
+
+/// 构建200/206响应的响应头，并决定是否实时压缩
+fn response_head(
+    content_type: &str, cache_control: Option<String>, last_modified: SystemTime, file_etag: String,
+    precompressed_encoding: Option<&'static str>, compression: Option<Compression>,
+) -> (Builder, Option<Compression>, Option<OwnedSemaphorePermit>) {
+    let mut builder = Response::builder()
+        .header(http::header::CONTENT_TYPE, content_type)
+        .header(http::header::LAST_MODIFIED, fmt_http_date(last_modified))
+        .header(http::header::ETAG, file_etag)
+        .header(http::header::ACCEPT_RANGES, "bytes")
+        .header(http::header::SERVER, SERVER_NAME);
+    if let Some(cache_control) = cache_control {
+        builder = builder.header(http::header::CACHE_CONTROL, cache_control);
+    }
+
+    // 客户端支持且没有预压缩文件时实时压缩
+    let mut compression = compression.filter(|_| precompressed_encoding.is_none() && is_compressible(content_type));
+    let compression_permit = match COMPRESSION_PERMITS.as_ref() {
+        Some(permits) if compression.is_some() => match permits.clone().try_acquire_owned() {
+            Ok(permit) => Some(permit),
+            Err(_) => {
+                // 压缩的并发已满，返回未压缩的响应而不是排队
+                METRICS.compression_skipped.inc();
+                compression = None;
+                None
+            }
+        },
+        _ => None,
+    };
+    if let Some(encoding) = precompressed_encoding {
+        builder = builder
+            .header(CONTENT_ENCODING, encoding)
+            .header(http::header::VARY, "accept-encoding");
+    } else if let Some(compression) = compression {
+        builder = builder
+            .header(CONTENT_ENCODING, compression.encoding())
+            .header(http::header::VARY, "accept-encoding");
+    };
+    (builder, compression, compression_permit)
+}
+
+fn range_not_satisfiable(e: io::Error) -> Result<Response<BoxBody<Bytes, io::Error>>, Error> {
+    Response::builder()
+        .status(StatusCode::RANGE_NOT_SATISFIABLE)
+        .header(http::header::SERVER, SERVER_NAME)
+        .body(full_body(e.to_string()))
+}
+
+/// 文件名带有内容hash的静态资源可以长期缓存，HTML需要每次向服务器确认，其他文件不设置
+fn cache_control(
+    path: &Path, content_type: &str, immutable_asset_pattern: Option<&Regex>, immutable_max_age: u64,
+) -> Option<String> {
+    if content_type.starts_with("text/html") {
+        return Some("no-cache".to_string());
+    }
+    let file_name = path.file_name()?.to_str()?;
+    if immutable_asset_pattern?.is_match(file_name) {
+        return Some(format!("public, max-age={immutable_max_age}, immutable"));
+    }
+    None
+}
+
+/// 解析符号链接后，判断文件是否仍在web_content_path之下
+async fn is_within_root(web_content_path: &str, path: &Path) -> bool {
+    let (root, path) = match (canonicalize(web_content_path).await, canonicalize(path).await) {
+        (Ok(root), Ok(path)) => (root, path),
+        _ => return false,
+    };
+    path.starts_with(root)
+}
+
+fn accept_encoding(req: &Request<impl Body>) -> &str {
+    req.headers()
+        .get(http::header::ACCEPT_ENCODING)
+        .map_or("", |h| h.to_str().unwrap_or(""))
+}
+
+/// 选择预压缩文件时使用的 `Accept-Encoding`。Range针对原始文件的字节，带Range的请求不使用预压缩文件
+fn precompressed_accept_encoding(req: &Request<impl Body>) -> &str {
+    if req.headers().contains_key(http::header::RANGE) {
+        ""
+    } else {
+        accept_encoding(req)
+    }
+}
+
+/// mime_guess对部分现代类型的映射与浏览器预期不一致，这里优先覆盖
+///
+/// 文本类型加上 `charset`（--default-charset），避免浏览器猜错编码
+fn guess_content_type(path: &Path, charset: Option<&str>) -> String {
+    let extension = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or("")
+        .to_ascii_lowercase();
+    let mime_type = from_path(path).first_or_octet_stream();
+    let content_type = match extension.as_str() {
+        "wasm" => "application/wasm",
+        "mjs" | "js" => "text/javascript",
+        "webmanifest" => "application/manifest+json",
+        "avif" => "image/avif",
+        "webp" => "image/webp",
+        "woff2" => "font/woff2",
+        "woff" => "font/woff",
+        "map" => "application/json",
+        _ => mime_type.as_ref(),
+    };
+    match charset {
+        Some(charset) if is_text(content_type) && !content_type.to_ascii_lowercase().contains("charset") => {
+            format!("{content_type}; charset={charset}")
+        }
+        _ => content_type.to_string(),
+    }
+}
+
+/// html、css、js、纯文本、json、xml等文本类型
+fn is_text(content_type: &str) -> bool {
+    let essence = content_type.split(';').next().unwrap_or("").trim();
+    essence.starts_with("text/")
+        || matches!(essence, "application/javascript" | "application/json" | "application/xml")
+        || essence.ends_with("+json")
+        || essence.ends_with("+xml")
+}
+
+fn is_compressible(content_type: &str) -> bool {
+    content_type.starts_with("text/html")
+        || content_type.starts_with("text/css")
+        || content_type.starts_with("text/javascript")
+        || content_type.starts_with("application/javascript")
+        || content_type.starts_with("application/json")
+        || content_type.starts_with("application/manifest+json")
+        || content_type.starts_with("application/wasm")
+        || content_type.starts_with("image/svg+xml")
+        || content_type.starts_with("text/xml")
+        || content_type.starts_with("application/xml")
+        || content_type.starts_with("text/plain")
+        || content_type.starts_with("text/markdown")
+}
+
+/// 查找预压缩的同名文件，例如app.wasm.br、app.wasm.gz，优先br
+async fn find_precompressed(path: &Path, accept_encoding: &str) -> Option<(PathBuf, Metadata, &'static str)> {
+    for (encoding, suffix) in [(BR, "br"), (GZIP, "gz")] {
+        if !accepts_encoding(accept_encoding, encoding) {
+            continue;
+        }
+        let mut precompressed = path.as_os_str().to_owned();
+        precompressed.push(".");
+        precompressed.push(suffix);
+        let precompressed = PathBuf::from(precompressed);
+        if let Ok(meta) = metadata(&precompressed).await {
+            if meta.is_file() {
+                return Some((precompressed, meta, encoding));
+            }
+        }
+    }
+    None
+}
+
+/// 按 `--compression` 的优先级选择客户端支持的算法
+fn negotiate_compression(accept_encoding: &str) -> Option<Compression> {
+    crate::CONFIG
+        .compression_preference
+        .iter()
+        .find(|compression| accepts_encoding(accept_encoding, compression.encoding()))
+        .copied()
+}
+
+/// `Accept-Encoding` 是否接受 `encoding`，按RFC 9110处理q值和 `*`，`q=0` 或无法解析的q值表示不接受
+pub(crate) fn accepts_encoding(accept_encoding: &str, encoding: &str) -> bool {
+    let mut wildcard = false;
+    for item in accept_encoding.split(',') {
+        let mut params = item.split(';');
+        let coding = params.next().unwrap_or("").trim();
+        let q = params
+            .map(str::trim)
+            .find_map(|param| param.strip_prefix("q=").or_else(|| param.strip_prefix("Q=")))
+            .map_or(Some(1.0), |q| q.trim().parse::<f32>().ok());
+        let accepted = q.is_some_and(|q| q > 0.0);
+        if coding.eq_ignore_ascii_case(encoding) {
+            return accepted;
+        }
+        if coding == "*" {
+            wildcard = accepted;
+        }
+    }
+    wildcard
+}
+
 fn return_304_if_not_modified(
     req: &Request<impl Body>, file_etag: &str, last_modified: SystemTime,
 ) -> Option<Result<Response<BoxBody<Bytes, io::Error>>, Error>> {
@@ -282,28 +581,36 @@ fn cal_file_etag(last_modified: SystemTime, file_len: u64) -> String {
     format!("\"{last_modified_secs:x}-{file_len:x}\"")
 }
 
+/// `compression_permit` 随响应体一起释放
 fn final_build<T>(
-    need_gzip: bool, async_read: T, builder: Builder,
+    compression: Option<Compression>, async_read: T, builder: Builder, compression_permit: Option<OwnedSemaphorePermit>,
 ) -> Result<Response<BoxBody<Bytes, io::Error>>, Error>
 where
     T: AsyncRead + Send + Sync + Unpin + 'static,
 {
-    let stream_body = StreamBody::new(build_reader_stream(async_read, need_gzip).map_ok(Frame::data));
+    let stream_body = StreamBody::new(build_reader_stream(async_read, compression).map_ok(move |data| {
+        let _ = &compression_permit;
+        Frame::data(data)
+    }));
     builder.body(stream_body.boxed())
 }
 
 fn build_reader_stream<T>(
-    async_read: T, need_gzip: bool,
+    async_read: T, compression: Option<Compression>,
 ) -> ReaderStream<pin::Pin<Box<dyn AsyncRead + Send + Sync + Unpin>>>
 where
     T: AsyncRead + Send + Sync + Unpin + 'static,
 {
-    let dyn_async_read: pin::Pin<Box<dyn AsyncRead + Send + Sync + Unpin>> = if need_gzip {
-        let buf_stream = BufReader::new(async_read);
-        let encoder = GzipEncoder::with_quality(buf_stream, async_compression::Level::Best);
-        Box::pin(encoder)
-    } else {
-        Box::pin(async_read)
+    let dyn_async_read: pin::Pin<Box<dyn AsyncRead + Send + Sync + Unpin>> = match compression {
+        Some(Compression::Gzip) => Box::pin(GzipEncoder::with_quality(
+            BufReader::new(async_read),
+            async_compression::Level::Precise(crate::CONFIG.gzip_level as i32),
+        )),
+        Some(Compression::Brotli) => Box::pin(BrotliEncoder::with_quality(
+            BufReader::new(async_read),
+            async_compression::Level::Precise(crate::CONFIG.brotli_level as i32),
+        )),
+        None => Box::pin(async_read),
     };
     ReaderStream::new(dyn_async_read)
 }
@@ -397,6 +704,26 @@ pub(crate) fn not_found() -> Result<Response<BoxBody<Bytes, io::Error>>, Error>
         .status(StatusCode::NOT_FOUND)
         .body(empty_body())
 }
+
+/// 被--static-allow-extension、--static-deny-extension等拒绝的文件，以404返回
+fn denied(url_path: &str) -> Result<Response<BoxBody<Bytes, io::Error>>, Error> {
+    debug!("static file {url_path} is denied");
+    not_found().map(|resp| with_static_resp_status(resp, "denied"))
+}
+
+/// 文件不存在以外的错误（例如没有权限）同样返回404，但单独计数并打印日志
+fn fs_error(path: &Path, e: io::Error) -> Result<Response<BoxBody<Bytes, io::Error>>, Error> {
+    if matches!(e.kind(), io::ErrorKind::NotFound | io::ErrorKind::NotADirectory) {
+        return not_found();
+    }
+    warn!("read {} error: {e}", path.display());
+    not_found().map(|resp| with_static_resp_status(resp, "fs_error"))
+}
+
+fn with_static_resp_status<B>(mut resp: Response<B>, status: &'static str) -> Response<B> {
+    resp.extensions_mut().insert(StaticRespStatus(status));
+    resp
+}
 const FAV_ICO: &[u8] = include_bytes!("../html/favicon.ico");
 static BOOTUP_TIME: LazyLock<SystemTime> = LazyLock::new(SystemTime::now);
 
@@ -461,6 +788,122 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_accepts_encoding() {
+        assert!(accepts_encoding("gzip, deflate, br", "br"));
+        assert!(accepts_encoding("GZIP;q=0.5", "gzip"));
+        assert!(!accepts_encoding("gzip;q=0, br", "gzip"));
+        assert!(!accepts_encoding("gzip; q=0.000", "gzip"));
+        assert!(!accepts_encoding("x-gzip", "gzip"));
+        assert!(!accepts_encoding("gzip;q=abc", "gzip"));
+        assert!(accepts_encoding("*", "br"));
+        assert!(!accepts_encoding("br;q=0, *", "br"));
+        assert!(!accepts_encoding("*;q=0", "br"));
+        assert!(!accepts_encoding("", "gzip"));
+    }
+
+    #[test]
+    fn test_guess_content_type() {
+        let guess = |path: &str| guess_content_type(Path::new(path), Some("utf-8"));
+        assert_eq!(guess("/a/app.wasm"), "application/wasm");
+        assert_eq!(guess("/a/app.MJS"), "text/javascript; charset=utf-8");
+        assert_eq!(guess("/a/site.webmanifest"), "application/manifest+json; charset=utf-8");
+        assert_eq!(guess("/a/photo.avif"), "image/avif");
+        assert_eq!(guess("/a/photo.png"), "image/png");
+        assert_eq!(guess("/a/index.html"), "text/html; charset=utf-8");
+        assert_eq!(guess("/a/data.json"), "application/json; charset=utf-8");
+        assert_eq!(guess("/a/icon.svg"), "image/svg+xml; charset=utf-8");
+        assert_eq!(guess("/a/archive.bin"), "application/octet-stream");
+        assert_eq!(guess_content_type(Path::new("/a/index.html"), Some("gbk")), "text/html; charset=gbk");
+        assert_eq!(guess_content_type(Path::new("/a/index.html"), None), "text/html");
+    }
+
+    #[test]
+    fn test_cache_control() -> Result<(), regex::Error> {
+        let pattern = Regex::new(crate::config::DEFAULT_IMMUTABLE_ASSET_PATTERN)?;
+        let cache_control = |path: &str| {
+            let path = Path::new(path);
+            cache_control(path, &guess_content_type(path, Some("utf-8")), Some(&pattern), 31536000)
+        };
+        assert_eq!(cache_control("/a/app.abc123.js").as_deref(), Some("public, max-age=31536000, immutable"));
+        assert_eq!(cache_control("/a/index.html").as_deref(), Some("no-cache"));
+        assert_eq!(cache_control("/a/app.js"), None);
+        assert_eq!(cache_control("/a/jquery.min.js"), None);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_is_within_root() -> io::Result<()> {
+        let base = std::env::temp_dir().join(format!("raw_serve_symlink_{}", std::process::id()));
+        let root = base.join("root");
+        let outside = base.join("outside");
+        std::fs::create_dir_all(root.join("shared"))?;
+        std::fs::create_dir_all(&outside)?;
+        std::fs::write(root.join("index.html"), "index")?;
+        std::fs::write(root.join("shared/app.js"), "app")?;
+        std::fs::write(outside.join("secret.txt"), "secret")?;
+        std::os::unix::fs::symlink(root.join("shared/app.js"), root.join("app.js"))?;
+        std::os::unix::fs::symlink(outside.join("secret.txt"), root.join("secret.txt"))?;
+        std::os::unix::fs::symlink(&outside, root.join("outside_dir"))?;
+
+        let root_str = root.to_string_lossy().to_string();
+        assert!(is_within_root(&root_str, &root.join("index.html")).await);
+        assert!(is_within_root(&root_str, &root.join("app.js")).await);
+        assert!(!is_within_root(&root_str, &root.join("secret.txt")).await);
+        assert!(!is_within_root(&root_str, &root.join("outside_dir/secret.txt")).await);
+        assert!(!is_within_root(&root_str, &root.join("missing.html")).await);
+        std::fs::remove_dir_all(&base)
+    }
+
+    #[tokio::test]
+    async fn test_find_precompressed() -> Result<(), Box<dyn std::error::Error>> {
+        let base = std::env::temp_dir().join(format!("raw_serve_precompressed_{}", std::process::id()));
+        std::fs::create_dir_all(&base)?;
+        let path = base.join("app.js");
+        std::fs::write(&path, "app")?;
+        std::fs::write(base.join("app.js.gz"), compress_string("app")?)?;
+
+        let req = Request::get("/app.js")
+            .header(http::header::ACCEPT_ENCODING, "gzip, br")
+            .body(empty_body())?;
+        let found = find_precompressed(&path, precompressed_accept_encoding(&req)).await;
+        assert_eq!(found.map(|(path, _, encoding)| (path, encoding)), Some((base.join("app.js.gz"), GZIP)));
+
+        // 带Range时使用原始文件，Range和ETag都对应原始文件的字节
+        let req = Request::get("/app.js")
+            .header(http::header::ACCEPT_ENCODING, "gzip, br")
+            .header(http::header::RANGE, "bytes=0-1")
+            .body(empty_body())?;
+        assert!(find_precompressed(&path, precompressed_accept_encoding(&req))
+            .await
+            .is_none());
+        std::fs::remove_dir_all(&base)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_denied() -> Result<(), http::Error> {
+        let resp = denied("/.env")?;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        assert_eq!(resp.extensions().get::<StaticRespStatus>().map(|status| status.0), Some("denied"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_embedded_body() -> Result<(), Box<dyn std::error::Error>> {
+        let range = HeaderValue::from_static("bytes=0-");
+        let resp = embedded_body(b"", Some(&range), Response::builder(), None, None)?;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(resp.into_body().collect().await?.to_bytes().is_empty());
+
+        let range = HeaderValue::from_static("bytes=1-2");
+        let resp = embedded_body(b"hello", Some(&range), Response::builder(), None, None)?;
+        assert_eq!(resp.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(resp.into_body().collect().await?.to_bytes(), "el");
+        Ok(())
+    }
+
     use flate2::write::GzEncoder;
     use flate2::Compression;
 
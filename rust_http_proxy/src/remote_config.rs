@@ -0,0 +1,251 @@
+//! 从 `--reverse-proxy-config-url` 获取反向代理配置，并在收到SIGHUP时重新加载
+//!
+//! 获取到的内容校验通过后才会生效，并缓存到日志目录下；获取失败或校验失败时使用缓存。
+//! SIGHUP或 `POST /admin/reload` 同时会重新读取 `--reverse-proxy-config-file`，任何一步出错都保留当前的配置。
+
+use std::{collections::HashMap, io, path::PathBuf, time::Duration};
+
+use http::{Request, StatusCode};
+use http_body_util::{BodyExt, Empty};
+use hyper::body::Bytes;
+use hyper_util::{client::legacy, rt::TokioExecutor};
+use log::{info, warn};
+use serde::Serialize;
+
+use crate::reverse::{current_config, parse_reverse_proxy_config, store_config, LocationConfig, ReverseProxyConfig};
+use crate::{DynError, CONFIG};
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+const CACHE_FILE: &str = "reverse_proxy_config.cache.toml";
+
+/// 启动时获取远程配置，获取失败且没有可用的缓存时返回错误
+pub(crate) async fn init(url: Option<&str>) -> Result<(), DynError> {
+    if let Some(url) = url {
+        let config = load(url).await?;
+        crate::config::log_reverse_proxy_config(&config);
+        store_config(config);
+    }
+    #[cfg(unix)]
+    spawn_reload_on_signal(url.map(str::to_string));
+    Ok(())
+}
+
+#[cfg(unix)]
+fn spawn_reload_on_signal(url: Option<String>) {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(s) => s,
+        Err(e) => {
+            warn!("register SIGHUP error: {e}");
+            return;
+        }
+    };
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            info!("receive SIGHUP, reloading reverse proxy config");
+            if let Err(e) = reload(url.as_deref()).await {
+                warn!("reload reverse proxy config error, keep the current one: {e}");
+            }
+        }
+    });
+}
+
+/// 重新加载后相对之前配置的变化，元素为 `host` + `location`（以及请求头条件）
+#[derive(Serialize, Default, Debug, PartialEq)]
+pub(crate) struct ReloadSummary {
+    pub(crate) added: Vec<String>,
+    pub(crate) removed: Vec<String>,
+    pub(crate) changed: Vec<String>,
+}
+
+impl ReloadSummary {
+    fn diff(old: &HashMap<String, Vec<LocationConfig>>, new: &HashMap<String, Vec<LocationConfig>>) -> Self {
+        let mut summary = ReloadSummary::default();
+        for (host, configs) in new {
+            for config in configs {
+                let key = format!("{host}{}", config.route_key());
+                match find(old, host, &config.route_key()) {
+                    None => summary.added.push(key),
+                    Some(old_config) if old_config != config => summary.changed.push(key),
+                    Some(_) => {}
+                }
+            }
+        }
+        for (host, configs) in old {
+            for config in configs {
+                if find(new, host, &config.route_key()).is_none() {
+                    summary.removed.push(format!("{host}{}", config.route_key()));
+                }
+            }
+        }
+        summary.added.sort();
+        summary.removed.sort();
+        summary.changed.sort();
+        summary
+    }
+}
+
+fn find<'a>(
+    locations: &'a HashMap<String, Vec<LocationConfig>>, host: &str, route_key: &str,
+) -> Option<&'a LocationConfig> {
+    locations
+        .get(host)
+        .and_then(|configs| configs.iter().find(|config| config.route_key() == route_key))
+}
+
+/// 重新获取或读取反向代理配置，校验通过后替换当前的配置
+pub(crate) async fn reload(url: Option<&str>) -> Result<ReloadSummary, DynError> {
+    let config = match url {
+        Some(url) => load(url).await?,
+        None => parse_reverse_proxy_config(current_config().source.clone(), None)?,
+    };
+    crate::config::log_reverse_proxy_config(&config);
+    let summary = ReloadSummary::diff(&current_config().locations, &config.locations);
+    store_config(config);
+    info!("reverse proxy config reloaded: {summary:?}");
+    Ok(summary)
+}
+
+/// 获取并校验远程配置，失败时使用缓存
+async fn load(url: &str) -> Result<ReverseProxyConfig, DynError> {
+    let source = current_config().source.clone();
+    let err: DynError = match fetch(url).await {
+        Ok(content) => match parse_reverse_proxy_config(source.clone(), Some((url, &content))) {
+            Ok(config) => {
+                if let Err(e) = std::fs::write(cache_path(), &content) {
+                    warn!("write reverse proxy config cache error: {e}");
+                }
+                return Ok(config);
+            }
+            Err(e) => e,
+        },
+        Err(e) => e.into(),
+    };
+    warn!("load reverse proxy config from {url} error: {err}, fallback to cache");
+    let cache = cache_path();
+    let content = std::fs::read_to_string(&cache)
+        .map_err(|e| format!("read reverse proxy config cache {} error: {e}", cache.display()))?;
+    let cache = cache.to_string_lossy();
+    parse_reverse_proxy_config(source, Some((&cache, &content)))
+}
+
+async fn fetch(url: &str) -> io::Result<String> {
+    let client: legacy::Client<_, Empty<Bytes>> =
+        legacy::Client::builder(TokioExecutor::new()).build(crate::proxy::build_https_connector(
+            CONFIG.socket_buffer_size,
+            CONFIG.use_webpki_roots,
+            &CONFIG.upstream_ca,
+            None,
+            CONFIG.host_overrides.clone(),
+        )?);
+    let req = Request::get(url)
+        .body(Empty::new())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let resp = tokio::time::timeout(FETCH_TIMEOUT, client.request(req))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "fetch reverse proxy config timeout"))?
+        .map_err(io::Error::other)?;
+    if resp.status() != StatusCode::OK {
+        return Err(io::Error::other(format!("unexpected status {}", resp.status())));
+    }
+    let body = tokio::time::timeout(FETCH_TIMEOUT, resp.into_body().collect())
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "fetch reverse proxy config timeout"))?
+        .map_err(io::Error::other)?
+        .to_bytes();
+    String::from_utf8(body.to_vec()).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn cache_path() -> PathBuf {
+    PathBuf::from(&CONFIG.log_dir).join(CACHE_FILE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reload_summary() -> Result<(), toml::de::Error> {
+        let old: HashMap<String, Vec<LocationConfig>> = toml::from_str(
+            r#"
+            [[default_host]]
+            location = "/a/"
+            upstream = { url_base = "http://127.0.0.1:8081/" }
+            [[default_host]]
+            location = "/b/"
+            upstream = { url_base = "http://127.0.0.1:8082/" }
+            "#,
+        )?;
+        let new: HashMap<String, Vec<LocationConfig>> = toml::from_str(
+            r#"
+            [[default_host]]
+            location = "/a/"
+            upstream = { url_base = "http://127.0.0.1:8081/" }
+            [[default_host]]
+            location = "/b/"
+            upstream = { url_base = "http://127.0.0.1:9092/" }
+            [["example.com"]]
+            location = "/"
+            upstream = { url_base = "http://127.0.0.1:8083/" }
+            "#,
+        )?;
+        assert_eq!(
+            ReloadSummary::diff(&old, &new),
+            ReloadSummary {
+                added: vec!["example.com/".to_string()],
+                removed: vec![],
+                changed: vec!["default_host/b/".to_string()],
+            }
+        );
+        assert_eq!(ReloadSummary::diff(&new, &old).removed, vec!["example.com/".to_string()]);
+        Ok(())
+    }
+
+    /// 返回 `body` 和状态码的配置服务
+    async fn serve_config(status: StatusCode, body: &'static str) -> io::Result<String> {
+        let addr = crate::test_server::serve(move |_| async move {
+            let mut resp = http::Response::new(crate::proxy::full_body(body));
+            *resp.status_mut() = status;
+            Ok(resp)
+        })
+        .await?;
+        Ok(format!("http://{addr}/config.toml"))
+    }
+
+    const REMOTE_CONFIG: &str = r#"
+        [["remote.example.com"]]
+        location = "/"
+        upstream = { url_base = "http://127.0.0.1:8081/" }
+        "#;
+
+    fn has_remote_location(config: &ReverseProxyConfig) -> bool {
+        config.locations.contains_key("remote.example.com")
+    }
+
+    // 缓存文件只在这个测试中读写
+    #[tokio::test]
+    async fn test_load() -> Result<(), DynError> {
+        crate::test_server::init_config();
+        let _ = std::fs::remove_file(cache_path());
+
+        // 没有缓存时获取失败或内容无效都返回错误，不替换当前的配置
+        let closed = format!("http://{}/config.toml", crate::test_server::closed_port().await?);
+        assert!(reload(Some(&closed)).await.is_err());
+        let invalid = serve_config(StatusCode::OK, "[[default_host]]\nlocation = ").await?;
+        assert!(load(&invalid).await.is_err());
+        let not_found = serve_config(StatusCode::NOT_FOUND, REMOTE_CONFIG).await?;
+        assert!(reload(Some(&not_found)).await.is_err());
+        assert!(!has_remote_location(&current_config()));
+
+        let valid = serve_config(StatusCode::OK, REMOTE_CONFIG).await?;
+        let config = load(&valid).await?;
+        assert!(has_remote_location(&config));
+        assert_eq!(std::fs::read_to_string(cache_path())?, REMOTE_CONFIG);
+
+        // 之后获取失败或内容无效时使用缓存
+        assert!(has_remote_location(&load(&closed).await?));
+        assert!(has_remote_location(&load(&invalid).await?));
+        std::fs::remove_file(cache_path())?;
+        Ok(())
+    }
+}
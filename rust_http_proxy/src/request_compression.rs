@@ -0,0 +1,98 @@
+//! 反向代理时以gzip压缩发送给上游的请求体，节省内网的带宽，需要上游支持 `Content-Encoding: gzip` 的请求
+//!
+//! 只压缩匹配 `types`、未压缩且大于 `min_bytes` 的请求体；没有 `Content-Length` 的流式请求体总是压缩。
+//! 压缩后改为chunked编码发送。
+
+use std::io;
+
+use async_compression::tokio::bufread::GzipEncoder;
+use futures_util::TryStreamExt;
+use http::{header, HeaderMap, HeaderValue};
+use http_body_util::{combinators::BoxBody, BodyExt, StreamBody};
+use hyper::body::{Bytes, Frame};
+use serde::{Deserialize, Serialize};
+use tokio_util::io::{ReaderStream, StreamReader};
+
+#[derive(Serialize, Deserialize, Eq, PartialEq, Debug)]
+pub(crate) struct RequestCompression {
+    #[serde(default = "default_min_bytes")]
+    pub(crate) min_bytes: u64, // 小于该大小的请求体不压缩
+    #[serde(default = "default_types")]
+    pub(crate) types: Vec<String>, // 只压缩这些Content-Type的请求体
+}
+
+fn default_min_bytes() -> u64 {
+    1024
+}
+
+fn default_types() -> Vec<String> {
+    ["application/json", "application/xml", "text/plain", "text/csv"]
+        .iter()
+        .map(|t| t.to_string())
+        .collect()
+}
+
+impl RequestCompression {
+    pub(crate) fn applies(&self, headers: &HeaderMap, exact_len: Option<u64>) -> bool {
+        if headers.contains_key(header::CONTENT_ENCODING) || exact_len.is_some_and(|len| len < self.min_bytes) {
+            return false;
+        }
+        let Some(content_type) = headers.get(header::CONTENT_TYPE).and_then(|value| value.to_str().ok()) else {
+            return false;
+        };
+        let mime = content_type.split(';').next().unwrap_or("").trim();
+        self.types.iter().any(|t| t.eq_ignore_ascii_case(mime))
+    }
+
+    /// 设置 `Content-Encoding` 并去掉 `Content-Length`
+    pub(crate) fn set_headers(headers: &mut HeaderMap) {
+        headers.remove(header::CONTENT_LENGTH);
+        headers.insert(header::CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+    }
+
+    pub(crate) fn compress(body: BoxBody<Bytes, io::Error>) -> BoxBody<Bytes, io::Error> {
+        // trailers会被丢弃
+        let reader = StreamReader::new(body.into_data_stream());
+        let stream = ReaderStream::new(GzipEncoder::new(reader));
+        StreamBody::new(stream.map_ok(Frame::data)).boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_compression::tokio::bufread::GzipDecoder;
+    use tokio::io::AsyncReadExt;
+
+    #[test]
+    fn test_applies() {
+        let compression = RequestCompression {
+            min_bytes: default_min_bytes(),
+            types: default_types(),
+        };
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("application/json; charset=utf-8"));
+        assert!(compression.applies(&headers, Some(1024)));
+        assert!(compression.applies(&headers, None));
+        assert!(!compression.applies(&headers, Some(1023)));
+        headers.insert(header::CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+        assert!(!compression.applies(&headers, Some(4096)));
+        headers.remove(header::CONTENT_ENCODING);
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("image/png"));
+        assert!(!compression.applies(&headers, Some(4096)));
+    }
+
+    #[tokio::test]
+    async fn test_compress() -> io::Result<()> {
+        let json = "{\"key\":\"value\"}".repeat(100);
+        let body = RequestCompression::compress(crate::proxy::full_body(json.clone()));
+        let compressed = body.collect().await?.to_bytes();
+        assert!(compressed.len() < json.len());
+        let mut decompressed = String::new();
+        GzipDecoder::new(&compressed[..])
+            .read_to_string(&mut decompressed)
+            .await?;
+        assert_eq!(decompressed, json);
+        Ok(())
+    }
+}
@@ -0,0 +1,127 @@
+//! `--request-id-header`：为每个请求分配一个ID，用于在代理和上游的日志之间关联同一个请求
+//!
+//! 请求到达时分配，客户端（或前置代理）已经带有该请求头时保留它的值，否则生成一个UUID v4。
+//! 所有请求的ID都在响应中返回，并出现在代理的请求日志和访问日志中；
+//! 只有反向代理会把ID写入转发给上游的请求头，正向代理的请求原样转发给目标。
+
+use std::fmt;
+
+use http::{HeaderMap, HeaderName, HeaderValue, Response};
+use log::debug;
+
+/// 客户端发送的ID超过该长度时重新生成，避免日志被过长的值污染
+const MAX_REQUEST_ID_LEN: usize = 200;
+
+/// 请求使用的ID，请求到达时放入请求的extensions中
+#[derive(Clone, Debug)]
+pub(crate) struct RequestId {
+    pub(crate) name: HeaderName,
+    pub(crate) value: HeaderValue,
+}
+
+impl RequestId {
+    pub(crate) fn as_str(&self) -> Option<&str> {
+        self.value.to_str().ok()
+    }
+}
+
+/// 保留请求中合法的ID，否则生成新的
+pub(crate) fn assign(headers: &HeaderMap, name: &HeaderName) -> RequestId {
+    let value = match headers.get(name) {
+        Some(value) if is_valid(value) => value.clone(),
+        Some(value) => {
+            debug!("replace invalid {name} {value:?}");
+            generate()
+        }
+        None => generate(),
+    };
+    RequestId {
+        name: name.clone(),
+        value,
+    }
+}
+
+fn is_valid(value: &HeaderValue) -> bool {
+    !value.is_empty() && value.len() <= MAX_REQUEST_ID_LEN && value.to_str().is_ok()
+}
+
+/// 随机生成的UUID v4
+fn generate() -> HeaderValue {
+    let mut bytes: [u8; 16] = rand::random();
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex: String = bytes.iter().map(|b| format!("{b:02x}")).collect();
+    let uuid = format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..]);
+    HeaderValue::from_str(&uuid).unwrap_or(HeaderValue::from_static("-"))
+}
+
+/// 响应中返回请求使用的ID，覆盖上游返回的同名响应头
+pub(crate) fn with_request_id<B>(mut resp: Response<B>, request_id: Option<RequestId>) -> Response<B> {
+    if let Some(RequestId { name, value }) = request_id {
+        resp.headers_mut().insert(name, value);
+    }
+    resp
+}
+
+/// 请求中的ID，未开启--request-id-header时为None
+pub(crate) fn of(headers: &HeaderMap) -> Option<&str> {
+    crate::CONFIG
+        .request_id_header
+        .as_ref()
+        .and_then(|name| headers.get(name))
+        .and_then(|value| value.to_str().ok())
+}
+
+/// 在请求日志的末尾输出 ` [request_id]`，没有ID时不输出
+pub(crate) struct RequestIdFormat<'a>(pub(crate) Option<&'a str>);
+
+impl fmt::Display for RequestIdFormat<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(id) => write!(f, " [{id}]"),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_assign() -> Result<(), http::header::InvalidHeaderValue> {
+        let name = HeaderName::from_static("x-request-id");
+        let mut headers = HeaderMap::new();
+        let generated = assign(&headers, &name);
+        assert!(headers.is_empty());
+        assert_eq!(generated.name, name);
+        let uuid = generated.as_str().unwrap_or_default();
+        assert_eq!(uuid.len(), 36);
+        assert_eq!(uuid.as_bytes()[14], b'4');
+        assert_ne!(generate(), generated.value);
+
+        headers.insert(&name, HeaderValue::from_static("abc-123"));
+        assert_eq!(assign(&headers, &name).value, "abc-123");
+
+        headers.insert(&name, HeaderValue::from_str(&"a".repeat(MAX_REQUEST_ID_LEN + 1))?);
+        assert_ne!(assign(&headers, &name).value.len(), MAX_REQUEST_ID_LEN + 1);
+        headers.insert(&name, HeaderValue::from_bytes("中文".as_bytes())?);
+        assert_eq!(assign(&headers, &name).value.len(), 36);
+        Ok(())
+    }
+
+    #[test]
+    fn test_with_request_id() -> Result<(), http::Error> {
+        let name = HeaderName::from_static("x-request-id");
+        let resp = Response::builder().header(&name, "from-upstream").body(())?;
+        let request_id = RequestId {
+            name: name.clone(),
+            value: HeaderValue::from_static("abc-123"),
+        };
+        let resp = with_request_id(resp, Some(request_id));
+        assert_eq!(resp.headers().get_all(&name).iter().collect::<Vec<_>>(), ["abc-123"]);
+        let resp = with_request_id(Response::new(()), None);
+        assert!(resp.headers().is_empty());
+        Ok(())
+    }
+}
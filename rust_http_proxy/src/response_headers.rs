@@ -0,0 +1,90 @@
+//! 删除上游响应中不应发送给客户端的响应头
+//!
+//! `Connection`、`Keep-Alive` 等逐跳响应头（以及 `Connection` 中列出的）总是删除，它们只描述与上游之间的连接。
+//! 另外删除 `--strip-response-header`（默认 `Server`、`X-Powered-By`）和location的 `strip_response_headers`
+//! 中的响应头，避免暴露后端的软件和版本。`Transfer-Encoding` 由hyper处理，trailers见location的 `trailers`。
+
+use http::header::CONNECTION;
+use http::{HeaderMap, HeaderName, StatusCode};
+
+pub(crate) const DEFAULT_STRIP_RESPONSE_HEADERS: [&str; 2] = ["server", "x-powered-by"];
+
+/// 除 `Connection` 以外的逐跳响应头
+const HOP_BY_HOP: [&str; 4] = ["keep-alive", "proxy-connection", "te", "upgrade"];
+
+/// 解析并统一为小写，`none` 表示不删除
+pub(crate) fn parse_names(names: &[String]) -> Result<Vec<HeaderName>, String> {
+    names
+        .iter()
+        .filter(|name| !name.eq_ignore_ascii_case("none"))
+        .map(|name| HeaderName::from_bytes(name.as_bytes()).map_err(|_| format!("invalid header name {name:?}")))
+        .collect()
+}
+
+/// `global` 为 `--strip-response-header`，`location` 为location的 `strip_response_headers`
+pub(crate) fn strip(headers: &mut HeaderMap, status: StatusCode, global: &[HeaderName], location: &[String]) {
+    // 101的Connection和Upgrade用于协议升级
+    if status != StatusCode::SWITCHING_PROTOCOLS {
+        strip_hop_by_hop(headers);
+    }
+    for name in global {
+        headers.remove(name);
+    }
+    for name in location {
+        headers.remove(name.as_str());
+    }
+}
+
+fn strip_hop_by_hop(headers: &mut HeaderMap) {
+    let listed = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .collect::<Vec<_>>();
+    headers.remove(CONNECTION);
+    for name in listed {
+        headers.remove(name);
+    }
+    for name in HOP_BY_HOP {
+        headers.remove(name);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::HeaderValue;
+
+    #[test]
+    fn test_strip() -> Result<(), String> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in [
+            ("server", "nginx/1.18.0"),
+            ("x-powered-by", "PHP/7.4"),
+            ("x-aspnet-version", "4.0.30319"),
+            ("connection", "keep-alive, x-internal"),
+            ("keep-alive", "timeout=5"),
+            ("x-internal", "1"),
+            ("content-type", "text/html"),
+        ] {
+            headers.insert(name, HeaderValue::from_static(value));
+        }
+        let global = parse_names(&DEFAULT_STRIP_RESPONSE_HEADERS.map(str::to_string))?;
+        strip(&mut headers, StatusCode::OK, &global, &["x-aspnet-version".to_string()]);
+        assert_eq!(headers.len(), 1);
+        assert!(headers.contains_key("content-type"));
+
+        // none表示不删除，但逐跳响应头总是删除
+        let mut headers = HeaderMap::new();
+        headers.insert("server", HeaderValue::from_static("nginx"));
+        headers.insert("keep-alive", HeaderValue::from_static("timeout=5"));
+        strip(&mut headers, StatusCode::OK, &parse_names(&["none".to_string()])?, &[]);
+        assert!(headers.contains_key("server"));
+        assert!(!headers.contains_key("keep-alive"));
+
+        assert!(parse_names(&["bad name".to_string()]).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,115 @@
+//! 直接访问代理自身地址的根路径（`GET /`）时返回的响应，由 `--root-response` 开启
+//!
+//! 只对代理自身的host生效：IP地址、localhost、主机名，或者 `--proxy-hostname` 指定的host。
+//! 反向代理的location（包括default_host）优先匹配，不受影响。
+
+use std::io;
+use std::str::FromStr;
+
+use http::{header, HeaderValue, Method, Request, Response, StatusCode};
+use http_body_util::combinators::BoxBody;
+use hyper::body::Bytes;
+
+use crate::proxy::{empty_body, full_body};
+use crate::DynError;
+
+/// 根路径的响应
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RootResponse {
+    /// 简单的状态页，包含版本和构建时间
+    Status,
+    /// 302重定向到指定的URL
+    Redirect(HeaderValue),
+}
+
+impl FromStr for RootResponse {
+    type Err = DynError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "status" {
+            return Ok(RootResponse::Status);
+        }
+        if !s.starts_with("http://") && !s.starts_with("https://") {
+            return Err(format!("invalid --root-response {s:?}: should be \"status\" or a http(s) URL").into());
+        }
+        Ok(RootResponse::Redirect(HeaderValue::from_str(s).map_err(|e| format!("invalid --root-response {s:?}: {e}"))?))
+    }
+}
+
+impl RootResponse {
+    /// 是否是访问代理自身根路径的请求，`host` 为规范化后的host
+    pub(crate) fn applies<B>(req: &Request<B>, host: &str, proxy_hostnames: &[String]) -> bool {
+        matches!(*req.method(), Method::GET | Method::HEAD)
+            && req.uri().path() == "/"
+            && (is_ip_literal(host) || proxy_hostnames.iter().any(|name| name == host))
+    }
+
+    pub(crate) fn build<B>(&self, req: &Request<B>) -> Response<BoxBody<Bytes, io::Error>> {
+        match self {
+            RootResponse::Status => {
+                let body = format!(
+                    "{} {} is running\nbuild time: {}\n",
+                    env!("CARGO_PKG_NAME"),
+                    env!("CARGO_PKG_VERSION"),
+                    crate::BUILD_TIME
+                );
+                let mut resp = Response::new(if req.method() == Method::HEAD {
+                    empty_body()
+                } else {
+                    full_body(body)
+                });
+                resp.headers_mut()
+                    .insert(header::CONTENT_TYPE, HeaderValue::from_static("text/plain; charset=utf-8"));
+                resp.headers_mut()
+                    .insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+                resp
+            }
+            RootResponse::Redirect(location) => {
+                let mut resp = Response::new(empty_body());
+                *resp.status_mut() = StatusCode::FOUND;
+                resp.headers_mut().insert(header::LOCATION, location.clone());
+                resp
+            }
+        }
+    }
+}
+
+/// 通过IP地址访问的总是代理自身
+fn is_ip_literal(host: &str) -> bool {
+    host.parse::<std::net::Ipv4Addr>().is_ok() || (host.starts_with('[') && host.ends_with(']'))
+}
+
+/// `--proxy-hostname` 为空时使用localhost和本机的主机名
+pub(crate) fn default_proxy_hostnames() -> Vec<String> {
+    std::iter::once("localhost".to_string())
+        .chain(crate::ip_x::hostname().map(|name| name.to_ascii_lowercase()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_str() -> Result<(), DynError> {
+        assert_eq!("status".parse::<RootResponse>()?, RootResponse::Status);
+        assert_eq!(
+            "https://example.com/".parse::<RootResponse>()?,
+            RootResponse::Redirect(HeaderValue::from_static("https://example.com/"))
+        );
+        assert!("/relative".parse::<RootResponse>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_applies() -> Result<(), http::Error> {
+        let names = ["localhost".to_string(), "proxy.example.com".to_string()];
+        let get = Request::get("/").body(())?;
+        assert!(RootResponse::applies(&get, "127.0.0.1", &names));
+        assert!(RootResponse::applies(&get, "[::1]", &names));
+        assert!(RootResponse::applies(&get, "proxy.example.com", &names));
+        assert!(!RootResponse::applies(&get, "www.example.com", &names));
+        assert!(!RootResponse::applies(&Request::get("/index.html").body(())?, "localhost", &names));
+        assert!(!RootResponse::applies(&Request::post("/").body(())?, "localhost", &names));
+        Ok(())
+    }
+}
@@ -0,0 +1,792 @@
+//! 监听端口、接受连接并交给 [`ProxyHandler`] 处理
+//!
+//! 可以通过 [`ProxyServer`] 将代理嵌入到其他程序中：
+//!
+//! ```no_run
+//! use rust_http_proxy::{Config, ProxyServer};
+//!
+//! # async fn run() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+//! let config = Config {
+//!     port: vec![8080],
+//!     ..Default::default()
+//! };
+//! ProxyServer::new(config)?.run().await
+//! # }
+//! ```
+
+use std::{
+    convert::Infallible,
+    future::Future,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use axum::{extract::ConnectInfo, response::IntoResponse, Router};
+use futures_util::future::{select_all, FutureExt, Shared};
+use hyper::body::Incoming;
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::{conn::auto, graceful::GracefulShutdown},
+};
+use io_x::TimeoutIO;
+use log::{debug, info, warn};
+use prom_label::LabelImpl;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::{net::TcpListener, sync::broadcast, time};
+use tokio_rustls::rustls::{server::ProducesTickets, ServerConfig, ServerConnection};
+use tower::ServiceExt;
+
+use crate::{
+    access_log::{AccessLogBody, AccessLogEntry, LocationAccessLog, LogFormat},
+    axum_handler::{build_admin_router, build_router, AppProxyError, AppState},
+    config::{Config, ListenerMode},
+    forwarded::PeerAddr,
+    ip_x::SocketAddrFormat,
+    proxy::{InterceptResultAdapter, ProxyHandler, TlsCipherLabel, TlsConnectionLabel},
+    request_id::{with_request_id, RequestId},
+    tls_handshake_limit,
+    tls_session::{allowed_in_early_data, EarlyData, EarlyDataStream},
+    DynError, CONFIG, CONFIG_CELL, METRICS,
+};
+
+const REFRESH_INTERVAL: Duration = Duration::from_secs(60 * 60 * 24);
+const GRACEFUL_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+type ShutdownSignal = Shared<Pin<Box<dyn Future<Output = ()> + Send>>>;
+
+/// 代理服务器，监听 [`Config::port`] 中的所有端口，以及 [`Config::unix_listen`]
+///
+/// 由于配置和指标都是进程级别的全局状态，一个进程只能创建一个 `ProxyServer`。
+pub struct ProxyServer {
+    shutdown_signal: Option<Pin<Box<dyn Future<Output = ()> + Send>>>,
+}
+
+impl ProxyServer {
+    pub fn new(mut config: Config) -> Result<Self, DynError> {
+        crate::config::install_crypto_provider();
+        crate::config::log_config(&config);
+        if CONFIG_CELL.get().is_none() {
+            crate::reverse::store_config(std::mem::take(&mut config.reverse_proxy_config));
+        }
+        CONFIG_CELL
+            .set(config)
+            .map_err(|_| "config is already set, only one ProxyServer is allowed per process")?;
+        Ok(ProxyServer { shutdown_signal: None })
+    }
+
+    /// 自定义优雅关闭的信号，默认为 SIGTERM 或 ctrl_c
+    pub fn with_shutdown_signal(mut self, signal: impl Future<Output = ()> + Send + 'static) -> Self {
+        self.shutdown_signal = Some(Box::pin(signal));
+        self
+    }
+
+    /// 运行服务器，任一端口的监听出错即返回
+    pub async fn run(self) -> Result<(), DynError> {
+        let proxy_handler = Arc::new(ProxyHandler::new()?);
+        #[cfg(all(target_os = "linux", feature = "bpf"))]
+        crate::ebpf::init_once();
+        #[cfg(target_os = "linux")]
+        crate::linux_monitor::init_once();
+        crate::traffic_report::spawn_roll_over_task(CONFIG.traffic_report_period);
+        crate::access_log::init(CONFIG.access_log_file.as_deref())?;
+        crate::remote_config::init(CONFIG.reverse_proxy_config_url.as_deref()).await?;
+        crate::warmup::spawn(proxy_handler.clone());
+        #[cfg(feature = "statsd")]
+        crate::statsd::spawn(CONFIG.statsd.clone());
+        #[cfg(unix)]
+        crate::metrics::spawn_snapshot_on_signal(CONFIG.log_dir.clone());
+        let shutdown_signal: ShutdownSignal = match self.shutdown_signal {
+            Some(signal) => signal,
+            None => Box::pin(handle_signal()),
+        }
+        .shared();
+        #[cfg(unix)]
+        let run_as = crate::privilege::resolve(CONFIG.run_as_user.as_deref(), CONFIG.run_as_group.as_deref())?;
+        #[cfg(not(unix))]
+        if CONFIG.run_as_user.is_some() || CONFIG.run_as_group.is_some() {
+            return Err("--run-as-user and --run-as-group are not supported on this platform".into());
+        }
+        // 先完成所有需要特权的操作：绑定端口、读取证书
+        let ticketer = CONFIG
+            .tls_ticket_lifetime
+            .map(crate::tls_session::ticketer)
+            .transpose()?;
+        let tls_config = match CONFIG.over_tls {
+            true => Some(tls_config(&CONFIG.key, &CONFIG.cert, ticketer.as_ref())?),
+            false => None,
+        };
+        let listeners = create_listeners()?;
+        #[cfg(unix)]
+        let unix_listener = match &CONFIG.unix_listen {
+            Some(path) => Some((path.clone(), create_unix_listener(path)?)),
+            None => None,
+        };
+        #[cfg(not(unix))]
+        if let Some(path) = &CONFIG.unix_listen {
+            return Err(format!("unix socket {path} is not supported on this platform").into());
+        }
+        let admin_listener = match CONFIG.admin_port {
+            Some(port) => Some((port, create_dual_stack_listener(port)?)),
+            None => None,
+        };
+        #[cfg(unix)]
+        if let Some(run_as) = &run_as {
+            crate::privilege::drop_privileges(run_as)?;
+        }
+
+        let mut futures = listeners
+            .into_iter()
+            .map(|(port, listener)| {
+                let proxy_handler = proxy_handler.clone();
+                let shutdown_signal = shutdown_signal.clone();
+                let tls_config = tls_config.clone();
+                let ticketer = ticketer.clone();
+                let future: Pin<Box<dyn Future<Output = Result<(), DynError>> + Send>> = Box::pin(async move {
+                    serve(port, listener, tls_config, ticketer, proxy_handler, shutdown_signal).await
+                });
+                future
+            })
+            .collect::<Vec<_>>();
+        #[cfg(unix)]
+        if let Some((path, listener)) = unix_listener {
+            futures.push(Box::pin(serve_unix(path, listener, proxy_handler.clone(), shutdown_signal.clone())));
+        }
+        if let Some((port, listener)) = admin_listener {
+            futures.push(Box::pin(serve_admin(port, listener, shutdown_signal.clone())));
+        }
+        if futures.is_empty() {
+            return Err("no port or unix socket to listen on".into());
+        }
+        select_all(futures).await.0?;
+        Ok(())
+    }
+}
+
+async fn serve(
+    port: u16, listener: TcpListener, server_tls_config: Option<Arc<ServerConfig>>,
+    ticketer: Option<Arc<dyn ProducesTickets>>, proxy_handler: Arc<ProxyHandler>, shutdown_signal: ShutdownSignal,
+) -> Result<(), DynError> {
+    let config = &crate::CONFIG;
+    let router = build_router(AppState {
+        basic_auth: config.basic_auth.clone(),
+        on_admin_port: false,
+    });
+    let transparent = config.listener_modes.get(&port) == Some(&ListenerMode::Transparent);
+    info!("listening on port {}, use_tls: {}", port, config.over_tls && !transparent);
+    let mut server_tls_config = server_tls_config;
+    let (tx, mut rx) = broadcast::channel::<Arc<ServerConfig>>(10);
+    if config.over_tls {
+        tokio::spawn(async move {
+            info!("update tls config every {REFRESH_INTERVAL:?}");
+            loop {
+                time::sleep(REFRESH_INTERVAL).await;
+                if let Ok(new_config) = tls_config(&CONFIG.key, &CONFIG.cert, ticketer.as_ref()) {
+                    info!("update tls config");
+                    if let Err(e) = tx.send(new_config) {
+                        warn!("send tls config error:{e}");
+                    }
+                }
+            }
+        });
+    }
+    let server = http_server_builder();
+    let graceful = GracefulShutdown::new();
+    let mut shutdown_signal = shutdown_signal;
+    loop {
+        tokio::select! {
+            _ = &mut shutdown_signal => {
+                info!("start graceful shutdown!");
+                drop(listener);
+                break;
+            }
+            Ok(new_config) = rx.recv() => {
+                server_tls_config = Some(new_config);
+                info!("replaced tls config");
+            }
+            conn = listener.accept() => {
+                match conn {
+                    Ok((conn, client_socket_addr)) => {
+                        config.socket_buffer_size.apply(&conn);
+                        if transparent {
+                            let proxy_handler = proxy_handler.clone();
+                            tokio::spawn(async move { proxy_handler.transparent_tunnel(conn, client_socket_addr).await });
+                            continue;
+                        }
+                        let mut conn_ctx = ConnContext {
+                            client_socket_addr,
+                            port,
+                            router: router.clone(),
+                            proxy_handler: proxy_handler.clone(),
+                            early_data: None,
+                        };
+                        match server_tls_config.clone() {
+                            Some(tls_config) => {
+                                let server = server.clone();
+                                let watcher = graceful.watcher();
+                                tokio::spawn(async move {
+                                    let Ok(handshake_permit) = tls_handshake_limit::acquire(&client_socket_addr).await else {
+                                        return;
+                                    };
+                                    let acceptor = tokio_rustls::TlsAcceptor::from(tls_config);
+                                    let handshake = time::timeout(crate::IDLE_TIMEOUT, acceptor.accept(conn)).await;
+                                    drop(handshake_permit);
+                                    match handshake {
+                                        Ok(Ok(tls_stream)) => {
+                                            record_tls_session(tls_stream.get_ref().1, &client_socket_addr);
+                                            if !CONFIG.tls_enable_0rtt {
+                                                return serve_connection(tls_stream, conn_ctx, server, watcher).await;
+                                            }
+                                            match EarlyDataStream::new(tls_stream) {
+                                                Ok((stream, early_data)) => {
+                                                    conn_ctx.early_data = early_data;
+                                                    serve_connection(stream, conn_ctx, server, watcher).await
+                                                }
+                                                Err(e) => debug!(
+                                                    "read tls early data error: {e} from {}",
+                                                    SocketAddrFormat(&client_socket_addr)
+                                                ),
+                                            }
+                                        }
+                                        Ok(Err(e)) => {
+                                            debug!("tls handshake error: {e} from {}", SocketAddrFormat(&client_socket_addr))
+                                        }
+                                        Err(_) => {
+                                            debug!("tls handshake timeout from {}", SocketAddrFormat(&client_socket_addr))
+                                        }
+                                    }
+                                });
+                            }
+                            None => {
+                                let server = server.clone();
+                                let watcher = graceful.watcher();
+                                tokio::spawn(serve_connection(conn, conn_ctx, server, watcher));
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        warn!("accept error:{e}");
+                    }
+                }
+            }
+        }
+    }
+    wait_graceful_shutdown(graceful).await;
+    Ok(())
+}
+
+/// 管理接口的端口，不经过ProxyHandler，只提供 [`build_admin_router`] 中的接口
+async fn serve_admin(port: u16, listener: TcpListener, shutdown_signal: ShutdownSignal) -> Result<(), DynError> {
+    let router = build_admin_router(AppState {
+        basic_auth: CONFIG.basic_auth.clone(),
+        on_admin_port: true,
+    });
+    info!("listening on admin port {port}");
+    axum::serve(listener, router.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(shutdown_signal)
+        .await?;
+    // 由代理端口的优雅关闭决定何时退出
+    std::future::pending().await
+}
+
+/// Unix domain socket没有对端IP，日志和指标中使用该地址代替，端口号为0
+#[cfg(unix)]
+const UNIX_PEER_ADDR: SocketAddr = SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::LOCALHOST), 0);
+
+#[cfg(unix)]
+async fn serve_unix(
+    path: String, listener: tokio::net::UnixListener, proxy_handler: Arc<ProxyHandler>, shutdown_signal: ShutdownSignal,
+) -> Result<(), DynError> {
+    let router = build_router(AppState {
+        basic_auth: CONFIG.basic_auth.clone(),
+        on_admin_port: false,
+    });
+    info!("listening on unix socket {path}");
+    let server = http_server_builder();
+    let graceful = GracefulShutdown::new();
+    let mut shutdown_signal = shutdown_signal;
+    loop {
+        tokio::select! {
+            _ = &mut shutdown_signal => {
+                info!("start graceful shutdown of unix socket {path}!");
+                drop(listener);
+                break;
+            }
+            conn = listener.accept() => {
+                match conn {
+                    Ok((conn, _)) => {
+                        let conn_ctx = ConnContext {
+                            client_socket_addr: UNIX_PEER_ADDR,
+                            port: 0,
+                            router: router.clone(),
+                            proxy_handler: proxy_handler.clone(),
+                            early_data: None,
+                        };
+                        tokio::spawn(serve_connection(conn, conn_ctx, server.clone(), graceful.watcher()));
+                    }
+                    Err(e) => {
+                        warn!("accept error:{e}");
+                    }
+                }
+            }
+        }
+    }
+    if let Err(e) = std::fs::remove_file(&path) {
+        warn!("remove unix socket {path} error: {e}");
+    }
+    wait_graceful_shutdown(graceful).await;
+    Ok(())
+}
+
+/// 删除残留的socket文件后再bind，如果路径存在但不是socket则报错
+#[cfg(unix)]
+fn create_unix_listener(path: &str) -> io::Result<tokio::net::UnixListener> {
+    use std::os::unix::fs::FileTypeExt;
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => {
+            info!("remove stale unix socket {path}");
+            std::fs::remove_file(path)?;
+        }
+        Ok(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{path} already exists and is not a unix socket"),
+            ))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    tokio::net::UnixListener::bind(path)
+}
+
+async fn wait_graceful_shutdown(graceful: GracefulShutdown) {
+    tokio::select! {
+        _ = graceful.shutdown() => {
+            info!("Gracefully shutdown!");
+        },
+        _ = time::sleep(GRACEFUL_SHUTDOWN_TIMEOUT) => {
+            info!("Waited {GRACEFUL_SHUTDOWN_TIMEOUT:?} for graceful shutdown, aborting...");
+        }
+    }
+}
+
+struct ConnContext {
+    client_socket_addr: SocketAddr,
+    port: u16,
+    router: Router,
+    proxy_handler: Arc<ProxyHandler>,
+    /// 开启--tls-enable-0rtt且握手时收到了early data
+    early_data: Option<EarlyData>,
+}
+
+/// 按--max-headers设置HTTP/1.x解析器的请求头数量上限，超过时hyper直接返回431
+fn http_server_builder() -> auto::Builder<TokioExecutor> {
+    let mut builder = auto::Builder::new(TokioExecutor::new());
+    if let Some(max) = CONFIG.max_headers {
+        builder.http1().max_headers(max);
+    }
+    builder
+}
+
+async fn serve_connection<C>(
+    conn: C, conn_ctx: ConnContext, server: auto::Builder<TokioExecutor>,
+    watcher: hyper_util::server::graceful::Watcher,
+) where
+    C: tokio::io::AsyncRead + tokio::io::AsyncWrite + Send + 'static,
+{
+    let client_socket_addr = conn_ctx.client_socket_addr;
+    let conn_ctx = Arc::new(conn_ctx);
+    let stream = TokioIo::new(Box::pin(TimeoutIO::new(conn, crate::IDLE_TIMEOUT)));
+    let served = Arc::new(AtomicUsize::new(0));
+    let hyper_service = hyper::service::service_fn(move |mut req: http::Request<Incoming>| {
+        let conn_ctx = conn_ctx.clone();
+        let close = keepalive_exhausted(&served, &req, CONFIG.keepalive_max_requests);
+        let http1 = req.version() < http::Version::HTTP_2;
+        // 在添加--request-id-header等请求头之前检查，HTTP/1.x超过上限的请求已经被解析器拒绝
+        let too_many_headers = CONFIG.max_headers.is_some_and(|max| req.headers().len() > max);
+        let too_early = match &conn_ctx.early_data {
+            Some(early_data) if early_data.is_early() => {
+                req.headers_mut()
+                    .insert("early-data", http::HeaderValue::from_static("1"));
+                !allowed_in_early_data(req.method())
+            }
+            _ => false,
+        };
+        async move {
+            if too_many_headers {
+                warn!(
+                    "reject request from {}: {} headers exceed --max-headers",
+                    SocketAddrFormat(&client_socket_addr),
+                    req.headers().len()
+                );
+                METRICS.too_many_headers.inc();
+                return Ok(http::StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE.into_response());
+            }
+            if too_early {
+                debug!("{} {} in tls early data is too early", req.method(), req.uri());
+                return Ok(http::StatusCode::TOO_EARLY.into_response());
+            }
+            let mut resp = handle(req, &conn_ctx).await?;
+            // 101的连接已经升级，不再是keep-alive连接
+            let close = close || (http1 && close_on_status(resp.status()));
+            if close && resp.status() != http::StatusCode::SWITCHING_PROTOCOLS {
+                resp.headers_mut()
+                    .insert(http::header::CONNECTION, http::HeaderValue::from_static("close"));
+            }
+            Ok::<_, io::Error>(resp)
+        }
+    });
+    let conn = server.serve_connection_with_upgrades(stream, hyper_service);
+    if let Err(err) = watcher.watch(conn.into_owned()).await {
+        handle_hyper_error(client_socket_addr, err);
+    }
+    debug!("connection dropped: {}", SocketAddrFormat(&client_socket_addr));
+}
+
+/// 计入连接已处理的请求数，达到 `max`（--keepalive-max-requests）时返回true，hyper会在该响应后关闭连接
+///
+/// HTTP/2的请求是多路复用的，CONNECT之后连接成为隧道，都不计入
+fn keepalive_exhausted<B>(served: &AtomicUsize, req: &http::Request<B>, max: Option<usize>) -> bool {
+    let Some(max) = max else {
+        return false;
+    };
+    if req.version() >= http::Version::HTTP_2 || req.method() == http::Method::CONNECT {
+        return false;
+    }
+    served.fetch_add(1, Ordering::Relaxed) + 1 >= max
+}
+
+/// 响应的状态码属于--close-on-status，上游出错后连接的状态不确定，让客户端重新建立连接
+fn close_on_status(status: http::StatusCode) -> bool {
+    CONFIG.close_on_status.iter().any(|pattern| pattern.matches(status))
+}
+
+async fn handle(req: http::Request<Incoming>, conn_ctx: &ConnContext) -> Result<axum::response::Response, io::Error> {
+    handle_with_request_id(req, conn_ctx, CONFIG.request_id_header.as_ref()).await
+}
+
+/// `request_id_header` 为--request-id-header，请求到达时就分配ID，正向代理、静态文件等所有请求都在响应和日志中带有ID
+async fn handle_with_request_id(
+    mut req: http::Request<Incoming>, conn_ctx: &ConnContext, request_id_header: Option<&http::HeaderName>,
+) -> Result<axum::response::Response, io::Error> {
+    let client_socket_addr = client_addr(req.headers(), conn_ctx.client_socket_addr);
+    req.extensions_mut().insert(PeerAddr(conn_ctx.client_socket_addr));
+    let request_id = request_id_header.map(|name| crate::request_id::assign(req.headers(), name));
+    if let Some(request_id) = &request_id {
+        req.extensions_mut().insert(request_id.clone());
+    }
+    let log_format = CONFIG.log_format;
+    if log_format == LogFormat::Default {
+        return Ok(with_request_id(handle_inner(req, conn_ctx, client_socket_addr).await?, request_id));
+    }
+    let entry = AccessLogEntry::new(
+        client_socket_addr.ip(),
+        request_username(req.headers()),
+        req.method(),
+        req.uri(),
+        req.version(),
+        req.headers(),
+        request_id.as_ref().and_then(RequestId::as_str),
+    );
+    let resp = with_request_id(handle_inner(req, conn_ctx, client_socket_addr).await?, request_id);
+    let log_format = match resp.extensions().get::<LocationAccessLog>() {
+        Some(access_log) => access_log.format(log_format),
+        None => log_format,
+    };
+    if log_format == LogFormat::Default {
+        return Ok(resp);
+    }
+    let status = resp.status().as_u16();
+    Ok(resp.map(|body| axum::body::Body::new(AccessLogBody::new(body, entry, log_format, status))))
+}
+
+/// 访问日志中的%u，取自Proxy-Authorization或Authorization
+fn request_username(headers: &http::HeaderMap) -> Option<String> {
+    [http::header::PROXY_AUTHORIZATION, http::header::AUTHORIZATION]
+        .iter()
+        .filter_map(|name| headers.get(name).and_then(|v| v.to_str().ok()))
+        .find_map(|value| CONFIG.basic_auth.get(value).cloned())
+}
+
+/// 按--real-ip-header替换客户端IP，端口保持为对端的
+fn client_addr(headers: &http::HeaderMap, peer: SocketAddr) -> SocketAddr {
+    let Some(header) = &CONFIG.real_ip_header else {
+        return peer;
+    };
+    match crate::ip_x::real_client_ip(headers, peer.ip(), header, &CONFIG.trusted_proxies) {
+        Some(ip) => SocketAddr::new(ip, peer.port()),
+        None => peer,
+    }
+}
+
+async fn handle_inner(
+    req: http::Request<Incoming>, conn_ctx: &ConnContext, client_socket_addr: SocketAddr,
+) -> Result<axum::response::Response, io::Error> {
+    match conn_ctx
+        .proxy_handler
+        .handle(req, client_socket_addr, conn_ctx.port)
+        .await
+    {
+        Ok(InterceptResultAdapter::Return(resp)) => Ok(resp.map(axum::body::Body::new)),
+        Ok(InterceptResultAdapter::Drop) => Err(io::Error::other("Request dropped by interceptor")),
+        Ok(InterceptResultAdapter::Continue(mut req)) => {
+            req.extensions_mut().insert(ConnectInfo(client_socket_addr));
+            let resp: Result<_, Infallible> = conn_ctx.router.clone().oneshot(req).await;
+            resp.map_err(|never| match never {})
+        }
+        Err(err) => Ok(AppProxyError::new(err).into_response()),
+    }
+}
+
+fn handle_hyper_error(client_socket_addr: SocketAddr, http_err: DynError) {
+    use std::error::Error;
+    match http_err.downcast_ref::<hyper::Error>() {
+        Some(hyper_err) => {
+            let level = if hyper_err.is_user() {
+                log::Level::Warn
+            } else {
+                log::Level::Debug
+            };
+            if hyper_err.is_parse_too_large() && CONFIG.max_headers.is_some() {
+                METRICS.too_many_headers.inc();
+            }
+            let source = hyper_err.source().unwrap_or(hyper_err);
+            log::log!(
+                level,
+                "[hyper {}]: {:?} from {}",
+                if hyper_err.is_user() { "user" } else { "system" },
+                source,
+                SocketAddrFormat(&client_socket_addr)
+            );
+        }
+        None => match http_err.downcast_ref::<io::Error>() {
+            Some(io_err) => {
+                warn!("[hyper io]: [{}] {} from {}", io_err.kind(), io_err, SocketAddrFormat(&client_socket_addr));
+            }
+            None => {
+                warn!("[hyper]: {} from {}", http_err, SocketAddrFormat(&client_socket_addr));
+            }
+        },
+    }
+}
+
+/// `--ignore-bind-errors` 时跳过无法监听的端口，但至少要有一个端口监听成功
+fn create_listeners() -> Result<Vec<(u16, TcpListener)>, DynError> {
+    let mut listeners = vec![];
+    let mut skipped = vec![];
+    for &port in &CONFIG.port {
+        match create_dual_stack_listener(port) {
+            Ok(listener) => listeners.push((port, listener)),
+            Err(e) if CONFIG.ignore_bind_errors => {
+                warn!("skip port {port}, listen error: {e}");
+                skipped.push(port);
+            }
+            Err(e) => return Err(format!("listen on port {port} error: {e}").into()),
+        }
+    }
+    if !skipped.is_empty() {
+        if listeners.is_empty() {
+            return Err(format!("none of the ports {skipped:?} can be listened on").into());
+        }
+        let bound = listeners.iter().map(|(port, _)| *port).collect::<Vec<_>>();
+        warn!("listen on ports {bound:?}, skipped ports {skipped:?}");
+    }
+    Ok(listeners)
+}
+
+fn create_dual_stack_listener(port: u16) -> io::Result<TcpListener> {
+    // 创建一个IPv6的socket
+    let socket = Socket::new(Domain::IPV6, Type::STREAM, Some(Protocol::TCP))?;
+    #[cfg(not(windows))]
+    socket.set_reuse_address(true)?; // 设置reuse_address以支持快速重启
+
+    // 支持ipv4 + ipv6双栈
+    socket.set_only_v6(false)?;
+    let addr = SocketAddr::from(([0, 0, 0, 0, 0, 0, 0, 0], port));
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?; // 监听，1024为backlog的大小
+
+    let std_listener = std::net::TcpListener::from(socket);
+    std_listener.set_nonblocking(true)?;
+    TcpListener::from_std(std_listener)
+}
+
+/// 握手完成后记录协商的ALPN和SNI，便于排查为什么有的客户端使用h2、有的使用HTTP/1.1
+fn record_tls_session(session: &ServerConnection, client_socket_addr: &SocketAddr) {
+    let alpn = alpn_label(session.alpn_protocol());
+    let cipher = session
+        .negotiated_cipher_suite()
+        .map(|suite| suite.suite().as_str().unwrap_or("other"))
+        .unwrap_or("none");
+    debug!(
+        "tls handshake done, alpn: {alpn}, sni: {}, version: {:?}, cipher: {cipher} from {}",
+        session.server_name().unwrap_or("-"),
+        session.protocol_version(),
+        SocketAddrFormat(client_socket_addr)
+    );
+    METRICS
+        .tls_connections
+        .get_or_create(&LabelImpl::new(TlsConnectionLabel { alpn }))
+        .inc();
+    METRICS
+        .tls_cipher_suites
+        .get_or_create(&LabelImpl::new(TlsCipherLabel { cipher }))
+        .inc();
+}
+
+/// 只区分我们通告的两种协议，避免客户端发送任意的ALPN导致指标的label无限增长
+fn alpn_label(alpn: Option<&[u8]>) -> &'static str {
+    match alpn {
+        Some(b"h2") => "h2",
+        Some(b"http/1.1") => "http/1.1",
+        Some(_) => "other",
+        None => "none",
+    }
+}
+
+fn tls_config(
+    key: &str, cert: &str, ticketer: Option<&Arc<dyn ProducesTickets>>,
+) -> Result<Arc<ServerConfig>, DynError> {
+    use std::io::BufReader;
+    let key_file = std::fs::File::open(key).map_err(|_| "open private key failed")?;
+    let cert_file = std::fs::File::open(cert).map_err(|_| "open cert failed")?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(cert_file)).collect::<io::Result<Vec<_>>>()?;
+    let key = rustls_pemfile::private_key(&mut BufReader::new(key_file))?.ok_or("can not find any pem in key file")?;
+
+    let mut config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    config.alpn_protocols = vec![
+        b"h2".to_vec(),       // http2
+        b"http/1.1".to_vec(), // http1.1
+    ];
+    crate::tls_session::apply(&mut config, ticketer, CONFIG.tls_enable_0rtt);
+    Ok(Arc::new(config))
+}
+
+#[cfg(unix)]
+async fn handle_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut terminate_signal = match signal(SignalKind::terminate()) {
+        Ok(s) => s,
+        Err(e) => {
+            warn!("register terminate signal error: {e}");
+            let _ = tokio::signal::ctrl_c().await;
+            info!("ctrl_c => shutdowning");
+            return;
+        }
+    };
+    tokio::select! {
+        _ = terminate_signal.recv() => {
+            info!("receive terminate signal, shutdowning");
+        },
+        _ = tokio::signal::ctrl_c() => {
+            info!("ctrl_c => shutdowning");
+        },
+    };
+}
+
+#[cfg(windows)]
+async fn handle_signal() {
+    let _ = tokio::signal::ctrl_c().await;
+    info!("ctrl_c => shutdowning");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keepalive_exhausted() -> Result<(), http::Error> {
+        let get = http::Request::get("/").body(())?;
+        let served = AtomicUsize::new(0);
+        let closes = (0..4)
+            .map(|_| keepalive_exhausted(&served, &get, Some(2)))
+            .collect::<Vec<_>>();
+        assert_eq!(closes, [false, true, true, true]);
+
+        // HTTP/2和CONNECT不计入
+        let served = AtomicUsize::new(0);
+        let h2 = http::Request::get("/").version(http::Version::HTTP_2).body(())?;
+        let connect = http::Request::connect("example.com:443").body(())?;
+        assert!(!keepalive_exhausted(&served, &h2, Some(1)));
+        assert!(!keepalive_exhausted(&served, &connect, Some(1)));
+        assert!(keepalive_exhausted(&served, &get, Some(1)));
+
+        let served = AtomicUsize::new(0);
+        assert!((0..100).all(|_| !keepalive_exhausted(&served, &get, None)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_request_id() -> Result<(), DynError> {
+        use http_body_util::{BodyExt, Empty};
+        use hyper::body::Bytes;
+        crate::test_server::init_config();
+        let name = http::HeaderName::from_static("x-request-id");
+        // 目标返回收到的ID
+        let target_name = name.clone();
+        let target = crate::test_server::serve(move |req: http::Request<Incoming>| {
+            let seen = req
+                .headers()
+                .get(&target_name)
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or("none")
+                .to_string();
+            async move { Ok(http::Response::new(crate::proxy::full_body(seen))) }
+        })
+        .await?;
+        let conn_ctx = Arc::new(ConnContext {
+            client_socket_addr: SocketAddr::from(([127, 0, 0, 1], 40000)),
+            port: 0,
+            router: build_router(AppState {
+                basic_auth: Default::default(),
+                on_admin_port: false,
+            }),
+            proxy_handler: Arc::new(ProxyHandler::new()?),
+            early_data: None,
+        });
+        let proxy_name = name.clone();
+        let proxy = crate::test_server::serve(move |req: http::Request<Incoming>| {
+            let conn_ctx = conn_ctx.clone();
+            let name = proxy_name.clone();
+            async move {
+                let (parts, body) = handle_with_request_id(req, &conn_ctx, Some(&name)).await?.into_parts();
+                let body = body.collect().await.map_err(io::Error::other)?.to_bytes();
+                Ok(http::Response::from_parts(parts, crate::proxy::full_body(body)))
+            }
+        })
+        .await?;
+        let send = |uri: String, request_id: Option<&'static str>| async move {
+            let stream = tokio::net::TcpStream::connect(proxy).await?;
+            let (mut sender, conn) = hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+            tokio::spawn(conn);
+            let mut req = http::Request::get(uri).header(http::header::HOST, "localhost");
+            if let Some(request_id) = request_id {
+                req = req.header("x-request-id", request_id);
+            }
+            let resp = sender.send_request(req.body(Empty::<Bytes>::new())?).await?;
+            let request_id = resp.headers().get("x-request-id").cloned();
+            let body = resp.into_body().collect().await?.to_bytes();
+            Ok::<_, DynError>((request_id, String::from_utf8(body.to_vec())?))
+        };
+
+        // 正向代理：响应中带有分配的ID，但不添加到转发给目标的请求中
+        let (request_id, seen) = send(format!("http://{target}/"), None).await?;
+        assert_eq!(request_id.map(|id| id.len()), Some(36));
+        assert_eq!(seen, "none");
+        // 客户端自带的ID原样转发，并在响应中返回
+        let (request_id, seen) = send(format!("http://{target}/"), Some("abc-123")).await?;
+        assert_eq!(request_id.as_ref().map(|id| id.as_bytes()), Some(&b"abc-123"[..]));
+        assert_eq!(seen, "abc-123");
+        // 由axum处理的请求
+        let (request_id, _) = send("/ip".to_string(), None).await?;
+        assert_eq!(request_id.map(|id| id.len()), Some(36));
+        Ok(())
+    }
+}
@@ -0,0 +1,137 @@
+//! socket选项，用于 `--so-sndbuf` 、 `--so-rcvbuf` 和透明代理的 `SO_ORIGINAL_DST`
+
+use std::{
+    io,
+    net::SocketAddr,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
+
+use log::warn;
+use socket2::SockRef;
+use tokio::net::{lookup_host, TcpSocket, TcpStream, ToSocketAddrs};
+
+/// 发送、接收缓冲区大小，None表示使用系统默认值
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SocketBufferSize {
+    pub send: Option<usize>,
+    pub recv: Option<usize>,
+}
+
+/// setsockopt的参数是int
+pub(crate) const MAX_SOCKET_BUFFER_SIZE: usize = i32::MAX as usize;
+
+static SNDBUF_CLAMP_WARNED: AtomicBool = AtomicBool::new(false);
+static RCVBUF_CLAMP_WARNED: AtomicBool = AtomicBool::new(false);
+
+impl SocketBufferSize {
+    pub(crate) fn is_default(&self) -> bool {
+        self.send.is_none() && self.recv.is_none()
+    }
+
+    /// 对已建立的连接设置缓冲区大小，例如accept得到的连接
+    pub(crate) fn apply(&self, stream: &TcpStream) {
+        let sock_ref = SockRef::from(stream);
+        if let Some(size) = self.send {
+            match sock_ref.set_send_buffer_size(size) {
+                Ok(_) => warn_if_clamped("SO_SNDBUF", size, sock_ref.send_buffer_size(), &SNDBUF_CLAMP_WARNED),
+                Err(e) => warn!("set SO_SNDBUF to {size} error: {e}"),
+            }
+        }
+        if let Some(size) = self.recv {
+            match sock_ref.set_recv_buffer_size(size) {
+                Ok(_) => warn_if_clamped("SO_RCVBUF", size, sock_ref.recv_buffer_size(), &RCVBUF_CLAMP_WARNED),
+                Err(e) => warn!("set SO_RCVBUF to {size} error: {e}"),
+            }
+        }
+    }
+
+    /// 建立连接，在connect之前设置缓冲区大小，以便TCP window scale生效
+    pub(crate) async fn connect<A: ToSocketAddrs>(&self, addr: A) -> io::Result<TcpStream> {
+        if self.is_default() {
+            return TcpStream::connect(addr).await;
+        }
+        self.connect_addrs(lookup_host(addr).await?).await
+    }
+
+    /// 与 [`Self::connect`] 相同，另外返回DNS解析和TCP建连各自的耗时，用于 `--tunnel-timing`
+    pub(crate) async fn connect_timed<A: ToSocketAddrs>(&self, addr: A) -> io::Result<(TcpStream, Duration, Duration)> {
+        let start = Instant::now();
+        let addrs: Vec<SocketAddr> = lookup_host(addr).await?.collect();
+        let dns = start.elapsed();
+        let stream = self.connect_addrs(addrs).await?;
+        Ok((stream, dns, start.elapsed().saturating_sub(dns)))
+    }
+
+    /// 依次尝试解析得到的地址，返回第一个建立成功的连接
+    async fn connect_addrs(&self, addrs: impl IntoIterator<Item = SocketAddr>) -> io::Result<TcpStream> {
+        let mut last_err = None;
+        for socket_addr in addrs {
+            if self.is_default() {
+                match TcpStream::connect(socket_addr).await {
+                    Ok(stream) => return Ok(stream),
+                    Err(e) => last_err = Some(e),
+                }
+                continue;
+            }
+            let socket = match socket_addr {
+                SocketAddr::V4(_) => TcpSocket::new_v4()?,
+                SocketAddr::V6(_) => TcpSocket::new_v6()?,
+            };
+            if let Some(size) = self.send {
+                socket.set_send_buffer_size(buffer_size(size)?)?;
+                warn_if_clamped("SO_SNDBUF", size, socket.send_buffer_size().map(|s| s as usize), &SNDBUF_CLAMP_WARNED);
+            }
+            if let Some(size) = self.recv {
+                socket.set_recv_buffer_size(buffer_size(size)?)?;
+                warn_if_clamped("SO_RCVBUF", size, socket.recv_buffer_size().map(|s| s as usize), &RCVBUF_CLAMP_WARNED);
+            }
+            match socket.connect(socket_addr).await {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last_err = Some(e),
+            }
+        }
+        Err(last_err.unwrap_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "could not resolve to any address")))
+    }
+}
+
+fn buffer_size(size: usize) -> io::Result<u32> {
+    u32::try_from(size)
+        .ok()
+        .filter(|size| *size as usize <= MAX_SOCKET_BUFFER_SIZE)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("socket buffer size {size} is too large")))
+}
+
+// 只告警一次，避免日志刷屏
+fn warn_if_clamped(name: &str, requested: usize, actual: io::Result<usize>, warned: &AtomicBool) {
+    if let Ok(actual) = actual {
+        if actual < requested && !warned.swap(true, Ordering::Relaxed) {
+            warn!("{name} requested {requested} but OS clamped it to {actual}, check net.core.wmem_max/rmem_max");
+        }
+    }
+}
+
+/// 读取iptables REDIRECT之前的目标地址，IPv4（含IPv4-mapped）和IPv6分别使用对应的sockopt
+#[cfg(target_os = "linux")]
+pub(crate) fn original_dst(stream: &TcpStream) -> io::Result<std::net::SocketAddr> {
+    let sock_ref = SockRef::from(stream);
+    let is_ipv4 = stream.peer_addr()?.ip().to_canonical().is_ipv4();
+    let addr = match is_ipv4 {
+        true => sock_ref.original_dst(),
+        false => sock_ref.original_dst_ipv6(),
+    }?;
+    let addr = addr
+        .as_socket()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "SO_ORIGINAL_DST is not an inet address"))?;
+    // 没有经过REDIRECT的连接，原始目标就是本机的监听地址，转发会形成环路
+    let local_addr = stream.local_addr()?;
+    if addr.port() == local_addr.port() && addr.ip().to_canonical() == local_addr.ip().to_canonical() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "connection was not redirected"));
+    }
+    Ok(addr)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn original_dst(_stream: &TcpStream) -> io::Result<std::net::SocketAddr> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "SO_ORIGINAL_DST is only supported on linux"))
+}
@@ -0,0 +1,309 @@
+//! 通过DNS SRV记录发现的upstream，例如 `url_base = "srv+http://_http._tcp.web.service.consul/api"`
+//!
+//! 请求时按SRV记录的TTL解析并缓存，过期后先继续使用旧的结果并在后台刷新；解析失败时保留旧的结果。
+//! 每个请求在priority最小的一组target中按weight随机选择一个，替换url_base中的scheme和authority。
+//! 没有可用的target时location不可用，返回503。
+
+use std::borrow::Cow;
+use std::io::{self, ErrorKind};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, LazyLock, RwLock};
+use std::time::{Duration, Instant};
+
+use hickory_resolver::config::{NameServerConfigGroup, ResolverConfig};
+use hickory_resolver::name_server::TokioConnectionProvider;
+use hickory_resolver::{Resolver, TokioResolver};
+use log::{info, warn};
+use rand::Rng;
+
+const SRV_HTTP: &str = "srv+http://";
+const SRV_HTTPS: &str = "srv+https://";
+const QUERY_TIMEOUT: Duration = Duration::from_secs(3);
+/// TTL为0时也至少缓存这么久，避免每个请求都查询DNS
+const MIN_TTL: Duration = Duration::from_secs(5);
+const MAX_TTL: Duration = Duration::from_secs(300);
+/// 解析失败或者没有target时，间隔这么久再重试
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// 运行时状态，不参与配置的比较
+#[derive(Default)]
+pub(crate) struct SrvUpstream(Option<Arc<SrvState>>);
+
+impl PartialEq for SrvUpstream {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for SrvUpstream {}
+
+impl PartialOrd for SrvUpstream {
+    fn partial_cmp(&self, _: &Self) -> Option<std::cmp::Ordering> {
+        Some(std::cmp::Ordering::Equal)
+    }
+}
+
+pub(crate) struct SrvState {
+    /// SRV记录的名称，例如 `_http._tcp.web.service.consul`
+    name: String,
+    scheme: &'static str,
+    /// url_base中authority之后的部分
+    path: String,
+    cached: RwLock<Option<Resolved>>,
+    refreshing: AtomicBool,
+    first_resolve: tokio::sync::Mutex<()>,
+}
+
+struct Resolved {
+    targets: Vec<SrvTarget>,
+    expires_at: Instant,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct SrvTarget {
+    pub(crate) priority: u16,
+    pub(crate) weight: u16,
+    pub(crate) port: u16,
+    pub(crate) target: String,
+}
+
+impl std::fmt::Display for SrvTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{} priority={} weight={}", self.target, self.port, self.priority, self.weight)
+    }
+}
+
+impl SrvUpstream {
+    /// url_base不是 `srv+http://` 或 `srv+https://` 时返回 `SrvUpstream(None)`
+    pub(crate) fn parse(url_base: &str) -> Result<Self, crate::DynError> {
+        let (scheme, rest) = if let Some(rest) = url_base.strip_prefix(SRV_HTTP) {
+            ("http", rest)
+        } else if let Some(rest) = url_base.strip_prefix(SRV_HTTPS) {
+            ("https", rest)
+        } else {
+            return Ok(SrvUpstream(None));
+        };
+        let (name, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+        if name.is_empty() || name.contains([':', '@']) {
+            return Err(format!(
+                "wrong upstream_url_base: {url_base} --- SRV name should not contain port or userinfo"
+            )
+            .into());
+        }
+        hickory_resolver::Name::from_ascii(name).map_err(|e| format!("wrong upstream_url_base: {url_base} --- {e}"))?;
+        Ok(SrvUpstream(Some(Arc::new(SrvState {
+            name: name.trim_end_matches('.').to_string(),
+            scheme,
+            path: path.to_string(),
+            cached: RwLock::new(None),
+            refreshing: AtomicBool::new(false),
+            first_resolve: tokio::sync::Mutex::new(()),
+        }))))
+    }
+
+    /// 返回实际使用的url_base，没有可用的target时返回 `Err`
+    pub(crate) async fn url_base<'a>(&self, url_base: &'a str) -> io::Result<Cow<'a, str>> {
+        let Some(state) = &self.0 else {
+            return Ok(Cow::Borrowed(url_base));
+        };
+        let target = state.pick().await.ok_or_else(|| {
+            io::Error::new(ErrorKind::NotFound, format!("no available target in SRV record {}", state.name))
+        })?;
+        Ok(Cow::Owned(format!("{}://{}:{}{}", state.scheme, target.target, target.port, state.path)))
+    }
+}
+
+impl SrvState {
+    async fn pick(self: &Arc<Self>) -> Option<SrvTarget> {
+        let expired = match self.cached.read().ok()?.as_ref() {
+            Some(resolved) => Instant::now() >= resolved.expires_at,
+            None => true,
+        };
+        if expired {
+            if self.has_cache() {
+                if !self.refreshing.swap(true, Ordering::AcqRel) {
+                    let state = self.clone();
+                    tokio::spawn(async move {
+                        state.refresh().await;
+                        state.refreshing.store(false, Ordering::Release);
+                    });
+                }
+            } else {
+                // 第一次解析时让并发的请求一起等待同一次查询
+                let _guard = self.first_resolve.lock().await;
+                if !self.has_cache() {
+                    self.refresh().await;
+                }
+            }
+        }
+        let cached = self.cached.read().ok()?;
+        choose(&cached.as_ref()?.targets, rand::rng().random_range(0..u32::MAX)).cloned()
+    }
+
+    fn has_cache(&self) -> bool {
+        self.cached.read().is_ok_and(|cached| cached.is_some())
+    }
+
+    async fn refresh(&self) {
+        let (targets, ttl) = match query_srv(&self.name).await {
+            Ok((targets, _)) if targets.is_empty() => {
+                warn!("[srv] no target in SRV record {}, location is unavailable", self.name);
+                (vec![], RETRY_INTERVAL)
+            }
+            Ok((targets, ttl)) => (targets, ttl.clamp(MIN_TTL, MAX_TTL)),
+            Err(e) => {
+                warn!("[srv] resolve {} error: {e}", self.name);
+                let Ok(mut cached) = self.cached.write() else {
+                    return;
+                };
+                match cached.as_mut() {
+                    Some(resolved) => resolved.expires_at = Instant::now() + RETRY_INTERVAL,
+                    None => {
+                        *cached = Some(Resolved {
+                            targets: vec![],
+                            expires_at: Instant::now() + RETRY_INTERVAL,
+                        })
+                    }
+                }
+                return;
+            }
+        };
+        let Ok(mut cached) = self.cached.write() else {
+            return;
+        };
+        if !targets.is_empty() && cached.as_ref().map_or(true, |resolved| resolved.targets != targets) {
+            let formatted = targets.iter().map(ToString::to_string).collect::<Vec<_>>();
+            info!("[srv] {} resolved to [{}]", self.name, formatted.join(", "));
+        }
+        *cached = Some(Resolved {
+            targets,
+            expires_at: Instant::now() + ttl,
+        });
+    }
+}
+
+/// 在priority最小的target中按weight选择，见RFC 2782。`random` 为均匀分布的随机数
+///
+/// 有weight为0的target时与RFC 2782相同，在 `0..=总weight` 中取随机数，取到0时在weight为0的target中均匀选择，
+/// 所以即使有其他target，weight为0的target也有很小的概率被选中；没有时严格按weight的比例选择
+fn choose(targets: &[SrvTarget], random: u32) -> Option<&SrvTarget> {
+    let priority = targets.iter().map(|target| target.priority).min()?;
+    let (zero_weight, weighted): (Vec<_>, Vec<_>) = targets
+        .iter()
+        .filter(|target| target.priority == priority)
+        .partition(|target| target.weight == 0);
+    let total = weighted.iter().map(|target| target.weight as u64).sum::<u64>();
+    let point = if zero_weight.is_empty() {
+        random as u64 % total.max(1) + 1
+    } else {
+        random as u64 % (total + 1)
+    };
+    if point == 0 {
+        let random = (random as u64 / (total + 1)) as usize;
+        return zero_weight.get(random % zero_weight.len()).copied();
+    }
+    let mut running = 0;
+    weighted.into_iter().find(|target| {
+        running += target.weight as u64;
+        running >= point
+    })
+}
+
+/// `--srv-nameserver`，未设置时使用/etc/resolv.conf中的配置
+static RESOLVER: LazyLock<Result<TokioResolver, String>> = LazyLock::new(|| {
+    let mut builder = match crate::CONFIG.srv_nameserver {
+        Some(nameserver) => Resolver::builder_with_config(
+            ResolverConfig::from_parts(
+                None,
+                vec![],
+                NameServerConfigGroup::from_ips_clear(&[nameserver.ip()], nameserver.port(), true),
+            ),
+            TokioConnectionProvider::default(),
+        ),
+        None => Resolver::builder_tokio().map_err(|e| format!("read system DNS config error: {e}"))?,
+    };
+    builder.options_mut().timeout = QUERY_TIMEOUT;
+    Ok(builder.build())
+});
+
+/// 返回SRV记录和剩余的TTL，没有记录（包括NXDOMAIN）时返回空列表
+async fn query_srv(name: &str) -> io::Result<(Vec<SrvTarget>, Duration)> {
+    let resolver = RESOLVER.as_ref().map_err(|e| io::Error::other(e.clone()))?;
+    // 以点结尾，不追加search域
+    let lookup = match resolver.srv_lookup(format!("{name}.")).await {
+        Ok(lookup) => lookup,
+        Err(e) if e.is_no_records_found() => return Ok((vec![], Duration::ZERO)),
+        Err(e) => return Err(io::Error::other(e)),
+    };
+    let targets = lookup
+        .iter()
+        // target为 "." 表示该服务不可用
+        .filter(|srv| !srv.target().is_root() && srv.port() != 0)
+        .map(|srv| SrvTarget {
+            priority: srv.priority(),
+            weight: srv.weight(),
+            port: srv.port(),
+            target: srv.target().to_lowercase().to_ascii().trim_end_matches('.').to_string(),
+        })
+        .collect();
+    let ttl = lookup
+        .as_lookup()
+        .valid_until()
+        .saturating_duration_since(Instant::now());
+    Ok((targets, ttl))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target(priority: u16, weight: u16, name: &str) -> SrvTarget {
+        SrvTarget {
+            priority,
+            weight,
+            port: 8080,
+            target: name.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_parse() -> Result<(), crate::DynError> {
+        assert!(SrvUpstream::parse("http://example.com")?.0.is_none());
+        let srv = SrvUpstream::parse("srv+https://_https._tcp.web.service.consul/api/")?;
+        let state = srv.0.ok_or("should be SRV")?;
+        assert_eq!(
+            (state.name.as_str(), state.scheme, state.path.as_str()),
+            ("_https._tcp.web.service.consul", "https", "/api/")
+        );
+        assert!(SrvUpstream::parse("srv+http://_http._tcp.web:8080/").is_err());
+        assert!(SrvUpstream::parse("srv+http:///").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_choose() {
+        let targets = [target(10, 1, "a"), target(10, 3, "b"), target(20, 100, "c")];
+        let chosen = (0..4)
+            .filter_map(|random| choose(&targets, random))
+            .map(|target| target.target.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(chosen, ["a", "b", "b", "b"]);
+        let zero_weight = [target(1, 0, "a"), target(1, 0, "b")];
+        assert_eq!(choose(&zero_weight, 1).map(|target| target.target.as_str()), Some("b"));
+        assert!(choose(&[], 0).is_none());
+
+        // weight为0的target在有其他target时只在取到0时被选中
+        let mixed = [
+            target(1, 0, "zero"),
+            target(1, 0, "also_zero"),
+            target(1, 9, "weighted"),
+        ];
+        let chosen = (0..100)
+            .filter_map(|random| choose(&mixed, random))
+            .map(|target| target.target.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(chosen.iter().filter(|name| **name == "zero").count(), 5);
+        assert_eq!(chosen.iter().filter(|name| **name == "also_zero").count(), 5);
+        assert_eq!(chosen.iter().filter(|name| **name == "weighted").count(), 90);
+    }
+}
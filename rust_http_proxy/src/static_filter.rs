@@ -0,0 +1,118 @@
+//! 静态文件托管时按文件名拒绝敏感文件，避免误将 `.env`、`.git`、编辑器备份等文件暴露出去
+//!
+//! 以下文件总是被拒绝：路径中任何一段以 `.` 开头（`/.well-known/` 除外），或文件名以 `~` 结尾。
+//! 另外拒绝 `--static-deny-extension` 中的扩展名；指定了 `--static-allow-extension` 时只允许其中的扩展名。
+//! 被拒绝的请求返回404而不是403，不暴露文件是否存在。
+
+use std::path::Path;
+
+use crate::DynError;
+
+/// `--static-deny-extension` 的默认值：备份、编辑器临时文件和常见的敏感文件
+pub(crate) const DEFAULT_DENY_EXTENSIONS: [&str; 9] = ["bak", "old", "orig", "swp", "swo", "tmp", "sql", "pem", "key"];
+
+/// 扩展名均为小写且不带 `.`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StaticFileFilter {
+    allow: Vec<String>,
+    deny: Vec<String>,
+}
+
+impl Default for StaticFileFilter {
+    fn default() -> Self {
+        StaticFileFilter {
+            allow: vec![],
+            deny: DEFAULT_DENY_EXTENSIONS.iter().map(|ext| ext.to_string()).collect(),
+        }
+    }
+}
+
+impl StaticFileFilter {
+    pub(crate) fn new(allow: &[String], deny: &[String]) -> Result<Self, DynError> {
+        Ok(StaticFileFilter {
+            allow: normalize(allow, "--static-allow-extension")?,
+            deny: normalize(deny, "--static-deny-extension")?,
+        })
+    }
+
+    /// `url_path` 为请求的路径，`file` 为解析后实际要返回的文件（例如目录下的index.html）
+    pub(crate) fn permits(&self, url_path: &str, file: &Path) -> bool {
+        if url_path
+            .split('/')
+            .any(|segment| segment.starts_with('.') && segment != ".well-known")
+        {
+            return false;
+        }
+        let Some(file_name) = file.file_name().and_then(|name| name.to_str()) else {
+            return false;
+        };
+        if file_name.starts_with('.') || file_name.ends_with('~') {
+            return false;
+        }
+        let ext = file
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        if self.deny.contains(&ext) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.contains(&ext)
+    }
+}
+
+fn normalize(extensions: &[String], arg: &str) -> Result<Vec<String>, DynError> {
+    extensions
+        .iter()
+        .map(|ext| {
+            let normalized = ext.trim_start_matches('.').to_ascii_lowercase();
+            if normalized.is_empty() || normalized.contains(['/', '\\']) {
+                return Err(format!("invalid {arg} {ext:?}").into());
+            }
+            Ok(normalized)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn permits(filter: &StaticFileFilter, url_path: &str) -> bool {
+        let file = format!("/var/www{url_path}");
+        filter.permits(url_path, Path::new(&file))
+    }
+
+    #[test]
+    fn test_default_denies_sensitive_files() {
+        let filter = StaticFileFilter::default();
+        assert!(!permits(&filter, "/.git/config"));
+        assert!(!permits(&filter, "/.git/HEAD"));
+        assert!(!permits(&filter, "/.env"));
+        assert!(!permits(&filter, "/app/.env"));
+        assert!(!permits(&filter, "/.htpasswd"));
+        assert!(!permits(&filter, "/index.html~"));
+        assert!(!permits(&filter, "/config.php.bak"));
+        assert!(!permits(&filter, "/dump.SQL"));
+        assert!(!permits(&filter, "/.index.html.swp"));
+        assert!(permits(&filter, "/index.html"));
+        assert!(permits(&filter, "/assets/app.js"));
+        assert!(permits(&filter, "/README"));
+        assert!(permits(&filter, "/.well-known/acme-challenge/token"));
+    }
+
+    #[test]
+    fn test_allowlist() -> Result<(), DynError> {
+        let filter = StaticFileFilter::new(
+            &["html".to_string(), ".CSS".to_string()],
+            &DEFAULT_DENY_EXTENSIONS.map(str::to_string),
+        )?;
+        assert!(permits(&filter, "/index.html"));
+        assert!(permits(&filter, "/style.css"));
+        assert!(!permits(&filter, "/app.js"));
+        assert!(!permits(&filter, "/README"));
+        assert!(!permits(&filter, "/.env.html"));
+        assert!(StaticFileFilter::new(&["".to_string()], &[]).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,314 @@
+//! 以StatsD/DogStatsD格式通过UDP定期推送 `METRICS` 中的指标，由 `statsd` feature和 `--statsd-addr` 开启
+//!
+//! 指标值与 `/metrics` 相同：先编码为Prometheus文本格式再逐行转换。counter推送距上次推送的增量（`|c`），
+//! gauge推送当前值（`|g`），histogram只推送 `_sum` 和 `_count` 的增量，不推送bucket。
+//! `dogstatsd` 格式下标签转换为 `|#key:value` 的tag；`statsd` 格式不支持tag，标签值依次追加到指标名后。
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use clap::ValueEnum;
+use log::{debug, info, warn};
+use tokio::net::UdpSocket;
+
+use crate::METRICS;
+
+/// 单个UDP包的最大长度，避免在常见的MTU下分片
+const MAX_PACKET_SIZE: usize = 1432;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum StatsdFlavor {
+    /// 标签转换为DogStatsD的tag
+    #[default]
+    Dogstatsd,
+    /// 标签值追加到指标名后
+    Statsd,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StatsdConfig {
+    pub addr: String,
+    pub interval: Duration,
+    /// 为空时指标名不加前缀
+    pub prefix: String,
+    pub flavor: StatsdFlavor,
+}
+
+pub(crate) fn spawn(config: Option<StatsdConfig>) {
+    let Some(config) = config else {
+        return;
+    };
+    tokio::spawn(async move {
+        let socket = match UdpSocket::bind(if config.addr.starts_with('[') {
+            "[::]:0"
+        } else {
+            "0.0.0.0:0"
+        })
+        .await
+        {
+            Ok(socket) => socket,
+            Err(e) => {
+                warn!("[statsd] bind udp socket error: {e}");
+                return;
+            }
+        };
+        info!("[statsd] push metrics to {} every {:?}", config.addr, config.interval);
+        let mut encoder = StatsdEncoder::default();
+        let mut interval = tokio::time::interval(config.interval);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            #[cfg(all(target_os = "linux", feature = "bpf"))]
+            crate::ebpf::snapshot_metrics();
+            let mut text = String::new();
+            if let Err(e) = prometheus_client::encoding::text::encode(&mut text, &METRICS.registry) {
+                warn!("[statsd] encode metrics error: {e}");
+                continue;
+            }
+            let lines = encoder.encode(&text, &config);
+            debug!("[statsd] push {} metrics", lines.len());
+            for packet in packets(&lines) {
+                // UDP不保证送达，发送失败只记录日志，下次推送的增量会包含这次的值
+                if let Err(e) = socket.send_to(packet.as_bytes(), &config.addr).await {
+                    warn!("[statsd] send to {} error: {e}", config.addr);
+                    break;
+                }
+            }
+        }
+    });
+}
+
+/// 记录上次推送时counter的值，用于计算增量
+#[derive(Default)]
+struct StatsdEncoder {
+    last: HashMap<String, f64>,
+}
+
+impl StatsdEncoder {
+    fn encode(&mut self, text: &str, config: &StatsdConfig) -> Vec<String> {
+        let mut types = HashMap::new();
+        let mut lines = vec![];
+        let mut current = HashMap::new();
+        for line in text.lines() {
+            if let Some(declaration) = line.strip_prefix("# TYPE ") {
+                if let Some((name, kind)) = declaration.split_once(' ') {
+                    types.insert(name.to_string(), kind.to_string());
+                }
+                continue;
+            }
+            if line.starts_with('#') {
+                continue;
+            }
+            let Some(sample) = Sample::parse(line) else {
+                continue;
+            };
+            let (family, kind) = match family_of(sample.name, &types) {
+                Some(found) => found,
+                None => continue,
+            };
+            let statsd_type = match kind {
+                "counter" => "c",
+                "gauge" => "g",
+                "histogram" if sample.name.ends_with("_sum") || sample.name.ends_with("_count") => "c",
+                _ => continue,
+            };
+            let name = match kind {
+                "counter" => family,
+                _ => sample.name,
+            };
+            let mut value = sample.value;
+            if statsd_type == "c" {
+                let series = line.rsplit_once(' ').map_or(line, |(series, _)| series);
+                let last = self.last.get(series).copied();
+                current.insert(series.to_string(), sample.value);
+                // counter被清空（例如标签清理）后重新计数
+                value = match last {
+                    Some(last) if sample.value >= last => sample.value - last,
+                    _ => sample.value,
+                };
+                if value == 0.0 {
+                    continue;
+                }
+            }
+            lines.push(format_line(name, &sample.labels, value, statsd_type, config));
+        }
+        self.last = current;
+        lines
+    }
+}
+
+/// 指标所属的family及其类型，counter的样本名带有 `_total` 后缀，histogram带有 `_sum` 等后缀
+fn family_of<'a>(name: &'a str, types: &'a HashMap<String, String>) -> Option<(&'a str, &'a str)> {
+    if let Some(kind) = types.get(name) {
+        return Some((name, kind));
+    }
+    ["_total", "_sum", "_count", "_bucket"].iter().find_map(|suffix| {
+        let family = name.strip_suffix(suffix)?;
+        types.get(family).map(|kind| (family, kind.as_str()))
+    })
+}
+
+fn format_line(
+    name: &str, labels: &[(String, String)], value: f64, statsd_type: &str, config: &StatsdConfig,
+) -> String {
+    let mut metric = if config.prefix.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}.{name}", config.prefix)
+    };
+    let mut tags = String::new();
+    match config.flavor {
+        StatsdFlavor::Dogstatsd => {
+            for (key, value) in labels {
+                tags.push(if tags.is_empty() { '#' } else { ',' });
+                tags.push_str(&format!("{key}:{}", sanitize(value, &[',', '|', '#', '\n'])));
+            }
+        }
+        StatsdFlavor::Statsd => {
+            for (_, value) in labels {
+                metric.push('.');
+                metric.push_str(&sanitize(value, &['.', ':', '|', '@', '#', ' ', '\n']));
+            }
+        }
+    }
+    let value = if value.fract() == 0.0 && value.abs() < 1e15 {
+        format!("{}", value as i64)
+    } else {
+        format!("{value}")
+    };
+    if tags.is_empty() {
+        format!("{metric}:{value}|{statsd_type}")
+    } else {
+        format!("{metric}:{value}|{statsd_type}|{tags}")
+    }
+}
+
+fn sanitize(value: &str, reserved: &[char]) -> String {
+    value.replace(reserved, "_")
+}
+
+/// 按换行拼接，每个包不超过MAX_PACKET_SIZE
+fn packets(lines: &[String]) -> Vec<String> {
+    let mut packets = vec![];
+    let mut packet = String::new();
+    for line in lines {
+        if !packet.is_empty() && packet.len() + 1 + line.len() > MAX_PACKET_SIZE {
+            packets.push(std::mem::take(&mut packet));
+        }
+        if !packet.is_empty() {
+            packet.push('\n');
+        }
+        packet.push_str(line);
+    }
+    if !packet.is_empty() {
+        packets.push(packet);
+    }
+    packets
+}
+
+/// Prometheus文本格式的一行样本，例如 `name{key="value"} 1`
+struct Sample<'a> {
+    name: &'a str,
+    labels: Vec<(String, String)>,
+    value: f64,
+}
+
+impl<'a> Sample<'a> {
+    fn parse(line: &'a str) -> Option<Self> {
+        let (series, value) = line.rsplit_once(' ')?;
+        let value = value.parse().ok()?;
+        let Some((name, rest)) = series.split_once('{') else {
+            return Some(Sample {
+                name: series,
+                labels: vec![],
+                value,
+            });
+        };
+        let mut labels = vec![];
+        let mut chars = rest.chars();
+        loop {
+            let key: String = chars.by_ref().take_while(|c| *c != '=').collect();
+            let key = key.trim_start_matches(',');
+            if key.is_empty() || key.starts_with('}') {
+                break;
+            }
+            if chars.next() != Some('"') {
+                return None;
+            }
+            let mut label_value = String::new();
+            loop {
+                match chars.next()? {
+                    '\\' => match chars.next()? {
+                        'n' => label_value.push('\n'),
+                        c => label_value.push(c),
+                    },
+                    '"' => break,
+                    c => label_value.push(c),
+                }
+            }
+            labels.push((key.to_string(), label_value));
+        }
+        Some(Sample { name, labels, value })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT: &str = r#"# HELP proxy_traffic num proxy_traffic.
+# TYPE proxy_traffic counter
+proxy_traffic_total{client="1.2.3.4",target="example.com:443",username="a\"b",relay_over_tls="false"} 100
+# TYPE active_tunnels gauge
+active_tunnels 2
+# TYPE latency histogram
+latency_sum{origin="x"} 1.5
+latency_count{origin="x"} 3
+latency_bucket{origin="x",le="0.1"} 1
+# EOF
+"#;
+
+    fn config(flavor: StatsdFlavor) -> StatsdConfig {
+        StatsdConfig {
+            addr: "127.0.0.1:8125".to_string(),
+            interval: Duration::from_secs(10),
+            prefix: "rust_http_proxy".to_string(),
+            flavor,
+        }
+    }
+
+    #[test]
+    fn test_encode_dogstatsd() {
+        let mut encoder = StatsdEncoder::default();
+        let lines = encoder.encode(TEXT, &config(StatsdFlavor::Dogstatsd));
+        assert_eq!(
+            lines,
+            vec![
+                "rust_http_proxy.proxy_traffic:100|c|#client:1.2.3.4,target:example.com:443,username:a\"b,relay_over_tls:false",
+                "rust_http_proxy.active_tunnels:2|g",
+                "rust_http_proxy.latency_sum:1.5|c|#origin:x",
+                "rust_http_proxy.latency_count:3|c|#origin:x",
+            ]
+        );
+        // 第二次只推送增量，没有变化的counter不推送
+        let text = TEXT.replace("} 100", "} 250");
+        let lines = encoder.encode(&text, &config(StatsdFlavor::Dogstatsd));
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("rust_http_proxy.proxy_traffic:150|c|"));
+    }
+
+    #[test]
+    fn test_encode_statsd() {
+        let mut encoder = StatsdEncoder::default();
+        let lines = encoder.encode(TEXT, &config(StatsdFlavor::Statsd));
+        assert_eq!(lines[0], "rust_http_proxy.proxy_traffic.1_2_3_4.example_com_443.a\"b.false:100|c");
+    }
+
+    #[test]
+    fn test_packets() {
+        let lines = vec!["a".repeat(1000), "b".repeat(1000), "c".to_string()];
+        let packets = packets(&lines);
+        assert_eq!(packets.len(), 2);
+        assert_eq!(packets[1], format!("{}\nc", "b".repeat(1000)));
+    }
+}
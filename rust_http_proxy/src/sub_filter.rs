@@ -0,0 +1,468 @@
+//! 类似nginx的 `sub_filter`，对反向代理的响应体做字符串替换，例如将上游返回的绝对地址替换为对外的域名
+//!
+//! 边转发边替换，只在chunk末尾保留可能跨chunk的部分匹配，因此缓存的字节数小于最长的 `from`。
+//! 上游忽略去掉的 `Accept-Encoding` 仍返回gzip或br压缩的响应时，先解压再替换；其他编码的响应不做替换。
+//! `recompress` 开启时按客户端的 `Accept-Encoding` 重新压缩替换后的响应体。解压和压缩都保留trailers，
+//! 替换后的响应体与上游的不再逐字节相同，因此强ETag改为弱ETag。
+
+use std::{
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+use async_compression::tokio::bufread::{BrotliDecoder, BrotliEncoder, GzipDecoder, GzipEncoder};
+use futures_util::{stream, Stream, StreamExt, TryStreamExt};
+use http::{header, HeaderMap, HeaderValue, Response, StatusCode};
+use http_body_util::{combinators::BoxBody, BodyExt, BodyStream, StreamBody};
+use hyper::body::{Body, Bytes, Frame};
+use log::debug;
+use pin_project_lite::pin_project;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio_util::io::StreamReader;
+
+use crate::config::Compression;
+
+/// 单个 `from` 的最大长度，也就是跨chunk时最多缓存的字节数
+const MAX_PATTERN_LEN: usize = 4096;
+/// 解压、压缩时每次读取的字节数
+const TRANSFORM_BUF_SIZE: usize = 8 * 1024;
+
+#[derive(Serialize, Deserialize, Eq, PartialEq, Debug)]
+pub(crate) struct SubFilter {
+    pub(crate) replace: Vec<SubFilterRule>,
+    #[serde(default = "default_types")]
+    pub(crate) types: Vec<String>, // 只处理这些Content-Type的响应
+    #[serde(default)]
+    pub(crate) recompress: bool, // 替换后按客户端的Accept-Encoding以gzip或br重新压缩
+}
+
+#[derive(Serialize, Deserialize, Eq, PartialEq, Debug, Clone)]
+pub(crate) struct SubFilterRule {
+    pub(crate) from: String,
+    pub(crate) to: String,
+}
+
+fn default_types() -> Vec<String> {
+    vec!["text/html".to_string()]
+}
+
+impl SubFilter {
+    pub(crate) fn validate(&self, location: &str) -> Result<(), crate::DynError> {
+        if self.replace.is_empty() {
+            return Err(format!("sub_filter of location {location} should have at least one replace rule").into());
+        }
+        for rule in &self.replace {
+            if rule.from.is_empty() || rule.from.len() > MAX_PATTERN_LEN {
+                return Err(format!(
+                    "sub_filter from of location {location} should be 1-{MAX_PATTERN_LEN} bytes: {:?}",
+                    rule.from
+                )
+                .into());
+            }
+        }
+        Ok(())
+    }
+
+    /// 对匹配 `types` 的响应做替换，`accept_encoding` 为客户端的 `Accept-Encoding`，用于 `recompress`
+    pub(crate) fn apply(
+        &self, resp: Response<BoxBody<Bytes, io::Error>>, accept_encoding: &str,
+    ) -> Response<BoxBody<Bytes, io::Error>> {
+        // 304、204和HEAD请求的响应没有响应体，不能解压或者重新压缩
+        if resp.status() == StatusCode::NOT_MODIFIED
+            || resp.status() == StatusCode::NO_CONTENT
+            || resp.body().is_end_stream()
+            || !self.matches(resp.headers())
+        {
+            return resp;
+        }
+        let Some(upstream_compression) = content_encoding(resp.headers()) else {
+            debug!(
+                "[sub_filter] skip response with unsupported Content-Encoding {:?}",
+                resp.headers().get(header::CONTENT_ENCODING)
+            );
+            return resp;
+        };
+        let (mut parts, mut body) = resp.into_parts();
+        // 替换后长度会变化
+        parts.headers.remove(header::CONTENT_LENGTH);
+        weaken_etag(&mut parts.headers);
+        if let Some(compression) = upstream_compression {
+            parts.headers.remove(header::CONTENT_ENCODING);
+            body = decompress(body, compression);
+        }
+        body = SubFilterBody::new(body, self.replace.clone()).boxed();
+        if self.recompress {
+            // 优先使用上游原来的压缩算法
+            let compression = upstream_compression
+                .into_iter()
+                .chain([Compression::Brotli, Compression::Gzip])
+                .find(|compression| crate::raw_serve::accepts_encoding(accept_encoding, compression.encoding()));
+            if let Some(compression) = compression {
+                parts
+                    .headers
+                    .insert(header::CONTENT_ENCODING, HeaderValue::from_static(compression.encoding()));
+                parts
+                    .headers
+                    .append(header::VARY, HeaderValue::from_static("accept-encoding"));
+                body = compress(body, compression);
+            }
+        }
+        Response::from_parts(parts, body)
+    }
+
+    fn matches(&self, headers: &HeaderMap) -> bool {
+        let Some(content_type) = headers.get(header::CONTENT_TYPE).and_then(|value| value.to_str().ok()) else {
+            return false;
+        };
+        let mime = content_type.split(';').next().unwrap_or("").trim();
+        self.types.iter().any(|t| t.eq_ignore_ascii_case(mime))
+    }
+}
+
+pin_project! {
+    pub(crate) struct SubFilterBody<B> {
+        #[pin]
+        inner: B,
+        rules: Vec<SubFilterRule>,
+        pending: Vec<u8>,
+        trailers: Option<Frame<Bytes>>,
+        done: bool,
+    }
+}
+
+impl<B> SubFilterBody<B> {
+    pub(crate) fn new(inner: B, rules: Vec<SubFilterRule>) -> Self {
+        SubFilterBody {
+            inner,
+            rules,
+            pending: Vec::new(),
+            trailers: None,
+            done: false,
+        }
+    }
+}
+
+impl<B> Body for SubFilterBody<B>
+where
+    B: Body<Data = Bytes>,
+{
+    type Data = Bytes;
+    type Error = B::Error;
+
+    fn poll_frame(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let mut this = self.project();
+        loop {
+            if *this.done {
+                return Poll::Ready(this.trailers.take().map(Ok));
+            }
+            match this.inner.as_mut().poll_frame(cx) {
+                Poll::Ready(Some(Ok(frame))) => {
+                    let last = match frame.into_data() {
+                        Ok(data) => {
+                            this.pending.extend_from_slice(&data);
+                            false
+                        }
+                        Err(trailers) => {
+                            *this.trailers = Some(trailers);
+                            true
+                        }
+                    };
+                    *this.done = last;
+                    let out = rewrite(this.rules, this.pending, last);
+                    if !out.is_empty() {
+                        return Poll::Ready(Some(Ok(Frame::data(out))));
+                    }
+                }
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(e))),
+                Poll::Ready(None) => {
+                    *this.done = true;
+                    let out = rewrite(this.rules, this.pending, true);
+                    if !out.is_empty() {
+                        return Poll::Ready(Some(Ok(Frame::data(out))));
+                    }
+                }
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.done && self.trailers.is_none()
+    }
+}
+
+/// 未压缩时为 `Some(None)`，不支持的编码（包括多重编码）为None
+fn content_encoding(headers: &HeaderMap) -> Option<Option<Compression>> {
+    let Some(value) = headers.get(header::CONTENT_ENCODING) else {
+        return Some(None);
+    };
+    match value.to_str().ok()?.trim().to_ascii_lowercase().as_str() {
+        "" | "identity" => Some(None),
+        "gzip" | "x-gzip" => Some(Some(Compression::Gzip)),
+        "br" => Some(Some(Compression::Brotli)),
+        _ => None,
+    }
+}
+
+/// 强ETag `"x"` 改为 `W/"x"`
+fn weaken_etag(headers: &mut HeaderMap) {
+    let Some(etag) = headers.get(header::ETAG).and_then(|etag| etag.to_str().ok()) else {
+        return;
+    };
+    if etag.starts_with("W/") {
+        return;
+    }
+    match HeaderValue::from_str(&format!("W/{etag}")) {
+        Ok(weak) => {
+            headers.insert(header::ETAG, weak);
+        }
+        Err(_) => {
+            headers.remove(header::ETAG);
+        }
+    }
+}
+
+type DataReader = StreamReader<Pin<Box<dyn Stream<Item = io::Result<Bytes>> + Send + Sync>>, Bytes>;
+
+/// 以 `transform` 转换响应体的数据部分，原响应体的trailers在转换后的数据之后发送。
+/// 解压时上游的压缩数据结束后解码器不再读取，需要用 `into_inner` 取回原响应体读完剩余的trailers
+fn transform_data<R>(
+    body: BoxBody<Bytes, io::Error>, transform: impl FnOnce(DataReader) -> R, into_inner: fn(R) -> DataReader,
+) -> BoxBody<Bytes, io::Error>
+where
+    R: AsyncRead + Send + Sync + Unpin + 'static,
+{
+    let trailers = Arc::new(Mutex::new(None));
+    let stash = trailers.clone();
+    let data = BodyStream::new(body).try_filter_map(move |frame| {
+        let data = match frame.into_data() {
+            Ok(data) => Some(data),
+            Err(frame) => {
+                if let (Ok(trailers), Ok(mut stash)) = (frame.into_trailers(), stash.lock()) {
+                    *stash = Some(trailers);
+                }
+                None
+            }
+        };
+        std::future::ready(Ok(data))
+    });
+    let reader = transform(StreamReader::new(Box::pin(data)));
+    let frames = stream::unfold(Some(reader), move |reader| {
+        let trailers = trailers.clone();
+        async move {
+            let mut reader = reader?;
+            let mut buf = vec![0; TRANSFORM_BUF_SIZE];
+            match reader.read(&mut buf).await {
+                Ok(0) => {
+                    let mut rest = into_inner(reader).into_inner();
+                    while let Some(Ok(_)) = rest.next().await {}
+                    let trailers = trailers.lock().ok()?.take()?;
+                    Some((Ok(Frame::trailers(trailers)), None))
+                }
+                Ok(n) => {
+                    buf.truncate(n);
+                    Some((Ok(Frame::data(Bytes::from(buf))), Some(reader)))
+                }
+                Err(e) => Some((Err(e), None)),
+            }
+        }
+    });
+    BodyExt::boxed(StreamBody::new(frames))
+}
+
+fn decompress(body: BoxBody<Bytes, io::Error>, compression: Compression) -> BoxBody<Bytes, io::Error> {
+    match compression {
+        Compression::Gzip => transform_data(body, GzipDecoder::new, GzipDecoder::into_inner),
+        Compression::Brotli => transform_data(body, BrotliDecoder::new, BrotliDecoder::into_inner),
+    }
+}
+
+/// 压缩等级与静态文件托管相同，见--compression-level
+fn compress(body: BoxBody<Bytes, io::Error>, compression: Compression) -> BoxBody<Bytes, io::Error> {
+    match compression {
+        Compression::Gzip => transform_data(
+            body,
+            |reader| {
+                GzipEncoder::with_quality(reader, async_compression::Level::Precise(crate::CONFIG.gzip_level as i32))
+            },
+            GzipEncoder::into_inner,
+        ),
+        Compression::Brotli => transform_data(
+            body,
+            |reader| {
+                BrotliEncoder::with_quality(
+                    reader,
+                    async_compression::Level::Precise(crate::CONFIG.brotli_level as i32),
+                )
+            },
+            BrotliEncoder::into_inner,
+        ),
+    }
+}
+
+/// 替换 `pending` 中的内容并返回可以发送的部分，`last` 为false时保留末尾可能与 `from` 部分匹配的字节
+fn rewrite(rules: &[SubFilterRule], pending: &mut Vec<u8>, last: bool) -> Bytes {
+    let mut out = Vec::with_capacity(pending.len());
+    let mut i = 0;
+    'outer: while i < pending.len() {
+        let rest = &pending[i..];
+        for rule in rules {
+            let from = rule.from.as_bytes();
+            if rest.starts_with(from) {
+                out.extend_from_slice(rule.to.as_bytes());
+                i += from.len();
+                continue 'outer;
+            }
+            if !last && from.starts_with(rest) {
+                // 可能跨chunk，等待更多数据
+                break 'outer;
+            }
+        }
+        out.push(rest[0]);
+        i += 1;
+    }
+    pending.drain(..i);
+    Bytes::from(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::stream;
+    use http_body_util::StreamBody;
+
+    fn rules() -> Vec<SubFilterRule> {
+        vec![SubFilterRule {
+            from: "https://backend.internal".to_string(),
+            to: "https://www.example.com".to_string(),
+        }]
+    }
+
+    #[tokio::test]
+    async fn test_rewrite_across_chunks() {
+        let chunks = [
+            "<a href=\"https://back",
+            "end.internal/a\">",
+            "https://backend.internal",
+            " https://back",
+        ];
+        let body = StreamBody::new(stream::iter(
+            chunks
+                .iter()
+                .map(|chunk| Ok::<_, io::Error>(Frame::data(Bytes::from_static(chunk.as_bytes()))))
+                .collect::<Vec<_>>(),
+        ));
+        let collected = SubFilterBody::new(body, rules())
+            .collect()
+            .await
+            .map(|b| b.to_bytes())
+            .ok();
+        assert_eq!(
+            collected,
+            Some(Bytes::from_static(b"<a href=\"https://www.example.com/a\">https://www.example.com https://back"))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_not_modified_passthrough() -> Result<(), Box<dyn std::error::Error>> {
+        let sub_filter = SubFilter {
+            replace: rules(),
+            types: default_types(),
+            recompress: true,
+        };
+        let resp = Response::builder()
+            .status(StatusCode::NOT_MODIFIED)
+            .header(header::CONTENT_TYPE, "text/html")
+            .header(header::CONTENT_ENCODING, "gzip")
+            .header(header::CONTENT_LENGTH, "1024")
+            .header(header::ETAG, "\"v1\"")
+            .body(crate::proxy::empty_body())?;
+        let resp = sub_filter.apply(resp, "gzip, br");
+        assert_eq!(resp.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(resp.headers().get(header::CONTENT_ENCODING), Some(&HeaderValue::from_static("gzip")));
+        assert_eq!(resp.headers().get(header::CONTENT_LENGTH), Some(&HeaderValue::from_static("1024")));
+        assert!(!resp.headers().contains_key(header::VARY));
+        assert!(resp.into_body().collect().await?.to_bytes().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_matches() {
+        let sub_filter = SubFilter {
+            replace: rules(),
+            types: default_types(),
+            recompress: false,
+        };
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_TYPE, http::HeaderValue::from_static("text/html; charset=utf-8"));
+        assert!(sub_filter.matches(&headers));
+        assert_eq!(content_encoding(&headers), Some(None));
+        headers.insert(header::CONTENT_ENCODING, http::HeaderValue::from_static("gzip"));
+        assert_eq!(content_encoding(&headers), Some(Some(Compression::Gzip)));
+        headers.insert(header::CONTENT_ENCODING, http::HeaderValue::from_static("gzip, br"));
+        assert_eq!(content_encoding(&headers), None);
+        headers.remove(header::CONTENT_ENCODING);
+        headers.insert(header::CONTENT_TYPE, http::HeaderValue::from_static("application/json"));
+        assert!(!sub_filter.matches(&headers));
+    }
+
+    fn gzip(data: &[u8]) -> io::Result<Vec<u8>> {
+        use std::io::Write;
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data)?;
+        encoder.finish()
+    }
+
+    /// 上游返回gzip压缩、带有强ETag和trailers的html
+    fn gzip_upstream_resp() -> Result<Response<BoxBody<Bytes, io::Error>>, Box<dyn std::error::Error>> {
+        let html = gzip(b"<a href=\"https://backend.internal/a\">")?;
+        let mut trailers = HeaderMap::new();
+        trailers.insert("x-checksum", HeaderValue::from_static("abc"));
+        let frames = [
+            Ok::<_, io::Error>(Frame::data(Bytes::from(html))),
+            Ok(Frame::trailers(trailers)),
+        ];
+        Ok(Response::builder()
+            .header(header::CONTENT_TYPE, "text/html")
+            .header(header::CONTENT_ENCODING, "gzip")
+            .header(header::ETAG, "\"v1\"")
+            .body(BodyExt::boxed(StreamBody::new(stream::iter(frames))))?)
+    }
+
+    #[tokio::test]
+    async fn test_decompress_rewrite_recompress() -> Result<(), Box<dyn std::error::Error>> {
+        crate::test_server::init_config();
+        let sub_filter = SubFilter {
+            replace: rules(),
+            types: default_types(),
+            recompress: true,
+        };
+        // 客户端不接受gzip，改用br重新压缩
+        let resp = sub_filter.apply(gzip_upstream_resp()?, "gzip;q=0, br");
+        assert_eq!(resp.headers().get(header::CONTENT_ENCODING), Some(&HeaderValue::from_static("br")));
+        assert_eq!(resp.headers().get(header::VARY), Some(&HeaderValue::from_static("accept-encoding")));
+        assert_eq!(resp.headers().get(header::ETAG), Some(&HeaderValue::from_static("W/\"v1\"")));
+        let collected = resp.into_body().collect().await?;
+        assert_eq!(
+            collected.trailers().and_then(|trailers| trailers.get("x-checksum")),
+            Some(&HeaderValue::from_static("abc"))
+        );
+        let mut html = String::new();
+        BrotliDecoder::new(&collected.to_bytes()[..])
+            .read_to_string(&mut html)
+            .await?;
+        assert_eq!(html, "<a href=\"https://www.example.com/a\">");
+
+        // 客户端不接受任何压缩时返回解压后的响应，没有Vary
+        let resp = sub_filter.apply(gzip_upstream_resp()?, "gzip;q=0");
+        assert!(!resp.headers().contains_key(header::CONTENT_ENCODING));
+        assert!(!resp.headers().contains_key(header::VARY));
+        let collected = resp.into_body().collect().await?;
+        assert!(collected.trailers().is_some());
+        assert_eq!(collected.to_bytes(), "<a href=\"https://www.example.com/a\">");
+        Ok(())
+    }
+}
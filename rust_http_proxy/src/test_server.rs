@@ -0,0 +1,46 @@
+//! 测试用的HTTP/1.1服务端，以及需要全局配置的测试使用的默认配置
+
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+
+use http::{Request, Response};
+use http_body_util::combinators::BoxBody;
+use hyper::body::{Bytes, Incoming};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper_util::rt::TokioIo;
+use tokio::net::TcpListener;
+
+/// 修改全局的反向代理配置并依赖它的测试持有该锁，避免互相覆盖
+pub(crate) static REVERSE_CONFIG_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+/// 所有测试共用 [`crate::Config::default`]，一个进程只能设置一次
+pub(crate) fn init_config() {
+    crate::config::install_crypto_provider();
+    let _ = crate::CONFIG_CELL.get_or_init(crate::Config::default);
+}
+
+/// 在127.0.0.1的随机端口上用 `handler` 处理请求，支持协议升级
+pub(crate) async fn serve<F, Fut>(handler: F) -> io::Result<SocketAddr>
+where
+    F: Fn(Request<Incoming>) -> Fut + Clone + Send + Sync + 'static,
+    Fut: Future<Output = io::Result<Response<BoxBody<Bytes, io::Error>>>> + Send + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let conn = http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service_fn(handler.clone()))
+                .with_upgrades();
+            tokio::spawn(conn);
+        }
+    });
+    Ok(addr)
+}
+
+/// 已经关闭的端口，连接会被拒绝
+pub(crate) async fn closed_port() -> io::Result<SocketAddr> {
+    TcpListener::bind("127.0.0.1:0").await?.local_addr()
+}
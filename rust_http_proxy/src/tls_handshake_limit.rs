@@ -0,0 +1,96 @@
+//! `--max-tls-handshakes`：同时进行的TLS握手数的上限，平滑连接洪峰时握手带来的CPU尖峰
+//!
+//! 所有端口共用一个上限。达到上限后新连接排队等待，排队的连接超过 `--tls-handshake-queue` 时直接关闭。
+//! 握手完成（或失败、超时）后立即释放，不限制已建立的连接数。排队数见Prometheus指标 `tls_handshake_queued`，
+//! 被关闭的连接计入 `tls_handshake_rejected`。
+
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, LazyLock,
+    },
+};
+
+use log::warn;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::{ip_x::SocketAddrFormat, METRICS};
+
+static LIMIT: LazyLock<Option<HandshakeLimit>> = LazyLock::new(|| {
+    crate::CONFIG
+        .max_tls_handshakes
+        .map(|max| HandshakeLimit::new(max, crate::CONFIG.tls_handshake_queue))
+});
+
+/// 未设置 `--max-tls-handshakes` 时总是返回 `Ok(None)`，排队已满时返回Err
+pub(crate) async fn acquire(client_socket_addr: &SocketAddr) -> Result<Option<OwnedSemaphorePermit>, ()> {
+    let Some(limit) = LIMIT.as_ref() else {
+        return Ok(None);
+    };
+    limit.acquire().await.map(Some).inspect_err(|_| {
+        warn!(
+            "reject tls connection from {}: too many tls handshakes in progress",
+            SocketAddrFormat(client_socket_addr)
+        );
+        METRICS.tls_handshake_rejected.inc();
+    })
+}
+
+struct HandshakeLimit {
+    semaphore: Arc<Semaphore>,
+    queued: AtomicUsize,
+    max_queue: usize,
+}
+
+impl HandshakeLimit {
+    fn new(max: usize, max_queue: usize) -> Self {
+        HandshakeLimit {
+            semaphore: Arc::new(Semaphore::new(max)),
+            queued: AtomicUsize::new(0),
+            max_queue,
+        }
+    }
+
+    async fn acquire(&self) -> Result<OwnedSemaphorePermit, ()> {
+        if let Ok(permit) = self.semaphore.clone().try_acquire_owned() {
+            return Ok(permit);
+        }
+        if self
+            .queued
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |queued| (queued < self.max_queue).then_some(queued + 1))
+            .is_err()
+        {
+            return Err(());
+        }
+        METRICS.tls_handshake_queued.inc();
+        let permit = self.semaphore.clone().acquire_owned().await.map_err(|_| ());
+        self.queued.fetch_sub(1, Ordering::AcqRel);
+        METRICS.tls_handshake_queued.dec();
+        permit
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_handshake_limit() -> Result<(), ()> {
+        let limit = Arc::new(HandshakeLimit::new(1, 1));
+        let first = limit.acquire().await?;
+        let queued = tokio::spawn({
+            let limit = limit.clone();
+            async move { limit.acquire().await.map(drop) }
+        });
+        while limit.queued.load(Ordering::Acquire) == 0 {
+            tokio::task::yield_now().await;
+        }
+        // 排队已满
+        assert!(limit.acquire().await.is_err());
+        drop(first);
+        queued.await.map_err(|_| ())??;
+        assert!(limit.acquire().await.is_ok());
+        Ok(())
+    }
+}
@@ -0,0 +1,186 @@
+//! HTTPS监听端口的TLS会话恢复：`--tls-ticket-lifetime` 和 `--tls-enable-0rtt`
+//!
+//! tokio-rustls在握手完成后才返回连接，0-RTT的early data此时单独缓存在 `ServerConnection` 中，
+//! 这里把它取出来放在连接的最前面交给hyper。由early data解析出的请求可能被重放，
+//! 只有幂等的方法会被处理（并加上 `Early-Data: 1` 转发给上游），其他请求返回 `425 Too Early`，
+//! 客户端会在握手完成后重试（RFC 8470）。
+//!
+//! rustls只在有状态的会话恢复中接受early data，缓存的会话取出后即删除，同一个ticket不能重复使用0-RTT，
+//! 所以两个参数不能同时开启。
+
+use std::io::{self, Read};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use http::Method;
+use hyper::body::Bytes;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_rustls::rustls::crypto::GetRandomFailed;
+use tokio_rustls::rustls::server::ProducesTickets;
+use tokio_rustls::rustls::ticketer::TicketRotator;
+use tokio_rustls::rustls::ServerConfig;
+
+#[cfg(feature = "aws_lc_rs")]
+use tokio_rustls::rustls::crypto::aws_lc_rs::Ticketer;
+#[cfg(all(feature = "ring", not(feature = "aws_lc_rs")))]
+use tokio_rustls::rustls::crypto::ring::Ticketer;
+
+use crate::DynError;
+
+/// 每个连接最多接受的early data字节数
+const MAX_EARLY_DATA_SIZE: u32 = 16 * 1024;
+/// `--tls-ticket-lifetime` 的上限，与rustls默认ticketer的密钥轮换周期一致
+pub(crate) const MAX_TICKET_LIFETIME: u32 = 6 * 60 * 60;
+
+/// 按配置设置session ticket和early data
+///
+/// `ticketer` 需要在多次重新加载证书之间共用，否则之前签发的ticket都会失效
+pub(crate) fn apply(config: &mut ServerConfig, ticketer: Option<&Arc<dyn ProducesTickets>>, enable_0rtt: bool) {
+    if let Some(ticketer) = ticketer {
+        config.ticketer = ticketer.clone();
+    }
+    if enable_0rtt {
+        config.max_early_data_size = MAX_EARLY_DATA_SIZE;
+    }
+}
+
+/// 有效期为 `lifetime` 秒的session ticket，密钥每 `lifetime / 2` 秒轮换一次，旧密钥保留一个周期
+pub(crate) fn ticketer(lifetime: u32) -> Result<Arc<dyn ProducesTickets>, DynError> {
+    Ok(Arc::new(TicketRotator::new(lifetime.div_ceil(2), new_ticket_keys)?))
+}
+
+fn new_ticket_keys() -> Result<Box<dyn ProducesTickets>, GetRandomFailed> {
+    Ticketer::new()
+        .map(|inner| Box::new(SharedTicketer(inner)) as Box<dyn ProducesTickets>)
+        .map_err(|_| GetRandomFailed)
+}
+
+/// [`TicketRotator`] 需要 `Box<dyn ProducesTickets>`，而crypto provider只提供 `Arc`
+#[derive(Debug)]
+struct SharedTicketer(Arc<dyn ProducesTickets>);
+
+impl ProducesTickets for SharedTicketer {
+    fn enabled(&self) -> bool {
+        self.0.enabled()
+    }
+
+    fn lifetime(&self) -> u32 {
+        self.0.lifetime()
+    }
+
+    fn encrypt(&self, plain: &[u8]) -> Option<Vec<u8>> {
+        self.0.encrypt(plain)
+    }
+
+    fn decrypt(&self, cipher: &[u8]) -> Option<Vec<u8>> {
+        self.0.decrypt(cipher)
+    }
+}
+
+/// 连接中的请求是否还在early data中
+#[derive(Clone, Debug)]
+pub(crate) struct EarlyData(Arc<AtomicBool>);
+
+impl EarlyData {
+    /// hyper解析请求时只读到了early data，这个请求可能被重放
+    pub(crate) fn is_early(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// early data中的请求只处理幂等的方法
+pub(crate) fn allowed_in_early_data(method: &Method) -> bool {
+    matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS)
+}
+
+/// 先读出握手时收到的early data，再读TLS连接
+pub(crate) struct EarlyDataStream<IO> {
+    early: Bytes,
+    inner: IO,
+    state: EarlyData,
+}
+
+impl<IO> EarlyDataStream<tokio_rustls::server::TlsStream<IO>> {
+    /// 取出 `tls_stream` 中的early data，没有则返回的 [`EarlyData`] 为 `None`
+    pub(crate) fn new(mut tls_stream: tokio_rustls::server::TlsStream<IO>) -> io::Result<(Self, Option<EarlyData>)> {
+        let mut early = Vec::new();
+        if let Some(mut reader) = tls_stream.get_mut().1.early_data() {
+            reader.read_to_end(&mut early)?;
+        }
+        let state = EarlyData(Arc::new(AtomicBool::new(!early.is_empty())));
+        let stream = EarlyDataStream {
+            early: Bytes::from(early),
+            inner: tls_stream,
+            state: state.clone(),
+        };
+        Ok((stream, Some(state).filter(EarlyData::is_early)))
+    }
+}
+
+impl<IO: AsyncRead + Unpin> AsyncRead for EarlyDataStream<IO> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if !this.early.is_empty() {
+            let n = this.early.len().min(buf.remaining());
+            buf.put_slice(&this.early.split_to(n));
+            return Poll::Ready(Ok(()));
+        }
+        let filled = buf.filled().len();
+        let result = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if buf.filled().len() > filled {
+            // 读到了握手完成后发送的数据，之后解析的请求不再是early data
+            this.state.0.store(false, Ordering::Relaxed);
+        }
+        result
+    }
+}
+
+impl<IO: AsyncWrite + Unpin> AsyncWrite for EarlyDataStream<IO> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>, cx: &mut Context<'_>, bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ticketer_lifetime() -> Result<(), DynError> {
+        crate::config::install_crypto_provider();
+        let ticketer = ticketer(3600)?;
+        assert!(ticketer.enabled());
+        assert_eq!(ticketer.lifetime(), 3600);
+        let ticket = ticketer.encrypt(b"session").ok_or("encrypt failed")?;
+        assert_eq!(ticketer.decrypt(&ticket).as_deref(), Some(&b"session"[..]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_allowed_in_early_data() {
+        assert!(allowed_in_early_data(&Method::GET));
+        assert!(allowed_in_early_data(&Method::HEAD));
+        assert!(!allowed_in_early_data(&Method::POST));
+        assert!(!allowed_in_early_data(&Method::CONNECT));
+    }
+}
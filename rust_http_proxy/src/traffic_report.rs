@@ -0,0 +1,203 @@
+//! 按用户汇总的正向代理流量，用于导出账单式的报表
+//!
+//! 与 `proxy_traffic` 指标不同，这里不会每天清空，只在统计周期（默认为自然月）切换时重置。
+//! 数据只保存在内存中，进程重启后从零开始。
+
+use std::collections::HashMap;
+use std::sync::{LazyLock, Mutex};
+
+use chrono::{DateTime, Datelike, Duration, Local, NaiveTime, TimeZone};
+use clap::ValueEnum;
+use log::info;
+use prometheus_client::metrics::counter::Counter;
+use serde::Serialize;
+
+pub(crate) static TRAFFIC_REPORT: LazyLock<TrafficReport> = LazyLock::new(|| TrafficReport::new(Local::now()));
+
+/// 流量报表的重置周期
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum TrafficReportPeriod {
+    /// 每月1日0点重置
+    #[default]
+    Monthly,
+    /// 每周一0点重置
+    Weekly,
+    /// 每天0点重置
+    Daily,
+    /// 从不重置
+    Never,
+}
+
+impl TrafficReportPeriod {
+    /// 包含`now`的统计周期的起始时间，Never返回None
+    fn period_start(&self, now: DateTime<Local>) -> Option<DateTime<Local>> {
+        let today = now.date_naive();
+        let start_date = match self {
+            TrafficReportPeriod::Monthly => today.with_day(1)?,
+            TrafficReportPeriod::Weekly => today - Duration::days(today.weekday().num_days_from_monday() as i64),
+            TrafficReportPeriod::Daily => today,
+            TrafficReportPeriod::Never => return None,
+        };
+        Local
+            .from_local_datetime(&start_date.and_time(NaiveTime::MIN))
+            .earliest()
+    }
+}
+
+struct UserTraffic {
+    counter: Counter,
+    // 周期开始时counter的值，正在进行的连接仍持有同一个counter，因此通过基线而不是替换counter来重置
+    baseline: u64,
+}
+
+struct Inner {
+    period_start: DateTime<Local>,
+    users: HashMap<String, UserTraffic>,
+}
+
+pub(crate) struct TrafficReport {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct TrafficReportSnapshot {
+    pub(crate) period: String,
+    pub(crate) period_start: String,
+    pub(crate) generated_at: String,
+    pub(crate) users: Vec<UserTrafficRow>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct UserTrafficRow {
+    pub(crate) username: String,
+    pub(crate) bytes: u64,
+}
+
+impl TrafficReport {
+    fn new(now: DateTime<Local>) -> Self {
+        TrafficReport {
+            inner: Mutex::new(Inner {
+                period_start: now,
+                users: HashMap::new(),
+            }),
+        }
+    }
+
+    /// 获取用户的流量counter，用于 [`io_x::CounterIO::with_extra_counter`]
+    pub(crate) fn counter_for(&self, username: &str) -> Counter {
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        inner
+            .users
+            .entry(username.to_string())
+            .or_insert_with(|| UserTraffic {
+                counter: Counter::default(),
+                baseline: 0,
+            })
+            .counter
+            .clone()
+    }
+
+    /// 如已进入新的统计周期，则以当前值作为基线
+    fn roll_over(&self, period: TrafficReportPeriod, now: DateTime<Local>) {
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(current_start) = period.period_start(now) {
+            if current_start > inner.period_start {
+                info!("traffic report period rolls over to {current_start}");
+                inner.period_start = current_start;
+                for user in inner.users.values_mut() {
+                    user.baseline = user.counter.get();
+                }
+            }
+        }
+    }
+
+    pub(crate) fn snapshot(&self, period: TrafficReportPeriod, now: DateTime<Local>) -> TrafficReportSnapshot {
+        self.roll_over(period, now);
+        let inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        let mut users: Vec<UserTrafficRow> = inner
+            .users
+            .iter()
+            .map(|(username, user)| UserTrafficRow {
+                username: username.clone(),
+                bytes: user.counter.get().saturating_sub(user.baseline),
+            })
+            .collect();
+        users.sort_by(|a, b| b.bytes.cmp(&a.bytes).then_with(|| a.username.cmp(&b.username)));
+        TrafficReportSnapshot {
+            period: format!("{period:?}").to_lowercase(),
+            period_start: inner.period_start.to_rfc3339(),
+            generated_at: now.to_rfc3339(),
+            users,
+        }
+    }
+}
+
+/// 每分钟检查一次是否进入新的统计周期，使周期切换的误差不超过一分钟
+pub(crate) fn spawn_roll_over_task(period: TrafficReportPeriod) {
+    if period == TrafficReportPeriod::Never {
+        return;
+    }
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(std::time::Duration::from_secs(60)).await;
+            TRAFFIC_REPORT.roll_over(period, Local::now());
+        }
+    });
+}
+
+impl TrafficReportSnapshot {
+    pub(crate) fn to_csv(&self) -> String {
+        let mut csv = String::from("username,bytes,period_start,generated_at\n");
+        for user in &self.users {
+            csv.push_str(&format!(
+                "{},{},{},{}\n",
+                escape_csv(&user.username),
+                user.bytes,
+                self.period_start,
+                self.generated_at
+            ));
+        }
+        csv
+    }
+}
+
+fn escape_csv(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reset_on_new_period() {
+        let start = Local
+            .with_ymd_and_hms(2024, 1, 15, 12, 0, 0)
+            .earliest()
+            .unwrap_or_else(Local::now);
+        let report = TrafficReport::new(start);
+        report.counter_for("alice").inc_by(100);
+        report.counter_for("bob").inc_by(300);
+
+        let snapshot = report.snapshot(TrafficReportPeriod::Monthly, start + Duration::days(1));
+        assert_eq!(snapshot.users[0].username, "bob");
+        assert_eq!(snapshot.users[0].bytes, 300);
+        assert_eq!(snapshot.users[1].bytes, 100);
+
+        // 进入下个月，之前的流量不再计入
+        report.counter_for("alice").inc_by(5);
+        let next_month = start + Duration::days(20);
+        let snapshot = report.snapshot(TrafficReportPeriod::Monthly, next_month);
+        assert!(snapshot.period_start.starts_with("2024-02-01T00:00:00"));
+        assert_eq!(snapshot.users[0].username, "alice");
+        assert_eq!(snapshot.users[0].bytes, 0);
+        report.counter_for("alice").inc_by(7);
+        let snapshot = report.snapshot(TrafficReportPeriod::Monthly, next_month);
+        assert_eq!(snapshot.users[0].bytes, 7);
+        assert_eq!(snapshot.to_csv().lines().nth(1).map(|l| l.starts_with("alice,7,")), Some(true));
+    }
+}
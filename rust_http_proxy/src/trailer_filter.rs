@@ -0,0 +1,196 @@
+//! location的 `trailers`：控制是否转发上游响应的trailers
+//!
+//! 默认原样转发，gRPC的 `grpc-status` 等依赖trailers。部分客户端无法处理意外的trailers时，
+//! 可以全部丢弃（`"drop"`），或者只转发指定的名称（`{ allow = ["grpc-status", "grpc-message"] }`，不区分大小写）。
+//! 响应头 `Trailer` 中声明的名称同样过滤，丢弃后为空时删除该响应头。
+
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use http::header::TRAILER;
+use http::{HeaderMap, HeaderName, HeaderValue, Response};
+use http_body_util::{combinators::BoxBody, BodyExt};
+use hyper::body::{Body, Bytes, Frame, SizeHint};
+use pin_project_lite::pin_project;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum TrailerPolicy {
+    /// 原样转发
+    Forward,
+    /// 全部丢弃
+    Drop,
+    /// 只转发这些名称
+    Allow(Vec<String>),
+}
+
+impl TrailerPolicy {
+    /// 校验 `allow` 中的名称，并统一为小写
+    pub(crate) fn normalize(&mut self) -> Result<(), String> {
+        if let TrailerPolicy::Allow(names) = self {
+            for name in names.iter_mut() {
+                let parsed =
+                    HeaderName::from_bytes(name.as_bytes()).map_err(|_| format!("invalid trailer name {name:?}"))?;
+                *name = parsed.as_str().to_string();
+            }
+        }
+        Ok(())
+    }
+
+    fn allows(&self, name: &HeaderName) -> bool {
+        match self {
+            TrailerPolicy::Forward => true,
+            TrailerPolicy::Drop => false,
+            TrailerPolicy::Allow(names) => names.iter().any(|allowed| allowed == name.as_str()),
+        }
+    }
+
+    pub(crate) fn apply(&self, resp: Response<BoxBody<Bytes, io::Error>>) -> Response<BoxBody<Bytes, io::Error>> {
+        if *self == TrailerPolicy::Forward {
+            return resp;
+        }
+        let (mut parts, body) = resp.into_parts();
+        self.filter_declared(&mut parts.headers);
+        let body = TrailerFilterBody {
+            inner: body,
+            policy: self.clone(),
+        };
+        Response::from_parts(parts, BodyExt::boxed(body))
+    }
+
+    /// 过滤 `Trailer` 响应头中声明的名称
+    fn filter_declared(&self, headers: &mut HeaderMap) {
+        let declared = headers
+            .get_all(TRAILER)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+            .filter(|name| self.allows(name))
+            .map(|name| name.as_str().to_string())
+            .collect::<Vec<_>>();
+        headers.remove(TRAILER);
+        if declared.is_empty() {
+            return;
+        }
+        if let Ok(value) = HeaderValue::from_str(&declared.join(", ")) {
+            headers.insert(TRAILER, value);
+        }
+    }
+
+    fn filter_trailers(&self, trailers: HeaderMap) -> HeaderMap {
+        let mut filtered = HeaderMap::new();
+        let mut current = None;
+        for (name, value) in trailers {
+            if let Some(name) = name {
+                current = Some(name);
+            }
+            if let Some(name) = current.as_ref().filter(|name| self.allows(name)) {
+                filtered.append(name.clone(), value);
+            }
+        }
+        filtered
+    }
+}
+
+pin_project! {
+    struct TrailerFilterBody<B> {
+        #[pin]
+        inner: B,
+        policy: TrailerPolicy,
+    }
+}
+
+impl<B> Body for TrailerFilterBody<B>
+where
+    B: Body<Data = Bytes>,
+{
+    type Data = Bytes;
+    type Error = B::Error;
+
+    fn poll_frame(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let mut this = self.project();
+        loop {
+            let frame = match this.inner.as_mut().poll_frame(cx) {
+                Poll::Ready(Some(Ok(frame))) => frame,
+                other => return other,
+            };
+            match frame.into_trailers() {
+                Ok(trailers) => {
+                    let trailers = this.policy.filter_trailers(trailers);
+                    // 没有需要转发的trailers时不发送空的trailers帧
+                    if !trailers.is_empty() {
+                        return Poll::Ready(Some(Ok(Frame::trailers(trailers))));
+                    }
+                }
+                Err(frame) => return Poll::Ready(Some(Ok(frame))),
+            }
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::stream;
+    use http_body_util::StreamBody;
+
+    fn with_trailers() -> Result<Response<BoxBody<Bytes, io::Error>>, http::Error> {
+        let mut trailers = HeaderMap::new();
+        trailers.insert("grpc-status", HeaderValue::from_static("0"));
+        trailers.insert("grpc-message", HeaderValue::from_static("ok"));
+        trailers.insert("x-debug", HeaderValue::from_static("1"));
+        let frames = vec![
+            Ok::<_, io::Error>(Frame::data(Bytes::from_static(b"hello"))),
+            Ok(Frame::trailers(trailers)),
+        ];
+        Response::builder()
+            .header(TRAILER, "grpc-status, Grpc-Message, x-debug")
+            .body(BodyExt::boxed(StreamBody::new(stream::iter(frames))))
+    }
+
+    async fn collect(resp: Response<BoxBody<Bytes, io::Error>>) -> Result<(Bytes, Option<HeaderMap>), io::Error> {
+        let collected = resp.into_body().collect().await?;
+        let trailers = collected.trailers().cloned();
+        Ok((collected.to_bytes(), trailers))
+    }
+
+    #[tokio::test]
+    async fn test_trailer_policy() -> Result<(), Box<dyn std::error::Error>> {
+        let resp = TrailerPolicy::Forward.apply(with_trailers()?);
+        let (data, trailers) = collect(resp).await?;
+        assert_eq!(data, "hello");
+        assert_eq!(trailers.ok_or("no trailers")?.len(), 3);
+
+        let resp = TrailerPolicy::Drop.apply(with_trailers()?);
+        assert!(!resp.headers().contains_key(TRAILER));
+        let (data, trailers) = collect(resp).await?;
+        assert_eq!(data, "hello");
+        assert!(trailers.is_none());
+
+        let mut policy = TrailerPolicy::Allow(vec!["GRPC-Status".to_string(), "grpc-message".to_string()]);
+        policy.normalize()?;
+        let resp = policy.apply(with_trailers()?);
+        assert_eq!(resp.headers().get(TRAILER), Some(&HeaderValue::from_static("grpc-status, grpc-message")));
+        let (_, trailers) = collect(resp).await?;
+        let trailers = trailers.ok_or("no trailers")?;
+        assert_eq!(trailers.get("grpc-status"), Some(&HeaderValue::from_static("0")));
+        assert_eq!(trailers.get("grpc-message"), Some(&HeaderValue::from_static("ok")));
+        assert!(!trailers.contains_key("x-debug"));
+
+        assert!(TrailerPolicy::Allow(vec!["bad name".to_string()]).normalize().is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,81 @@
+//! `--tunnel-timing`：CONNECT隧道建立过程的耗时，用于区分DNS慢和上游慢
+//!
+//! 在隧道升级之后分阶段计时：`dns` 为解析目标地址，`connect` 为TCP建连，
+//! `first_byte` 为从升级到收到目标的第一个字节。各阶段以debug日志输出，
+//! 并计入Prometheus histogram `tunnel_phase_seconds`（label `phase`）。未开启时不做额外的解析和计时。
+
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use log::debug;
+use pin_project_lite::pin_project;
+use prom_label::LabelImpl;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::proxy::TunnelPhaseLabel;
+use crate::METRICS;
+
+pub(crate) fn observe(phase: &'static str, target: &str, elapsed: Duration) {
+    debug!("[tunnel timing] {target} {phase}: {elapsed:?}");
+    METRICS
+        .tunnel_phase_seconds
+        .get_or_create(&LabelImpl::new(TunnelPhaseLabel { phase }))
+        .observe(elapsed.as_secs_f64());
+}
+
+pin_project! {
+    /// 第一次读到数据时记录 `first_byte`，`started` 为None时只是透传
+    pub(crate) struct FirstByteIO<T> {
+        #[pin]
+        inner: T,
+        started: Option<(Instant, String)>,
+    }
+}
+
+impl<T> FirstByteIO<T> {
+    pub(crate) fn new(inner: T, started: Option<(Instant, String)>) -> Self {
+        FirstByteIO { inner, started }
+    }
+}
+
+impl<T: AsyncRead> AsyncRead for FirstByteIO<T> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.project();
+        let filled = buf.filled().len();
+        let poll = this.inner.poll_read(cx, buf);
+        if matches!(poll, Poll::Ready(Ok(()))) && buf.filled().len() > filled {
+            if let Some((started, target)) = this.started.take() {
+                observe("first_byte", &target, started.elapsed());
+            }
+        }
+        poll
+    }
+}
+
+impl<T: AsyncWrite> AsyncWrite for FirstByteIO<T> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        self.project().inner.poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.project().inner.poll_shutdown(cx)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>, cx: &mut Context<'_>, bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        self.project().inner.poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+}
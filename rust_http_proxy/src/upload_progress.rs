@@ -0,0 +1,214 @@
+//! 大请求体（上传）的实时进度，由 `--upload-progress-min-bytes` 开启
+//!
+//! 正向代理和反向代理的请求体在转发过程中按帧计数，不等请求结束即可看到已接收的字节数：
+//! `GET /uploads` 列出进行中的上传，Prometheus指标 `upload_in_progress` 为进行中的上传数，
+//! `upload_bytes` 为这些上传已接收的字节数，上传结束后减去。`Content-Length` 小于阈值的请求不跟踪，
+//! 没有 `Content-Length` 的请求体（chunked）总是跟踪。
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, LazyLock, Mutex,
+    },
+    task::{Context, Poll},
+    time::Instant,
+};
+
+use http::Request;
+use hyper::body::{Body, Bytes, Frame, SizeHint};
+use pin_project_lite::pin_project;
+use serde::Serialize;
+
+use crate::ip_x::SocketAddrFormat;
+use crate::METRICS;
+
+static UPLOADS: LazyLock<Mutex<HashMap<u64, Arc<UploadProgress>>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug)]
+struct UploadProgress {
+    client: String,
+    method: String,
+    uri: String,
+    total_bytes: Option<u64>,
+    received_bytes: AtomicU64,
+    started: Instant,
+    /// 最近一次收到数据时距离started的毫秒数
+    last_received_millis: AtomicU64,
+}
+
+/// `GET /uploads` 中的一项
+#[derive(Debug, Serialize)]
+pub(crate) struct UploadSnapshot {
+    client: String,
+    method: String,
+    uri: String,
+    received_bytes: u64,
+    /// 没有Content-Length时为null
+    total_bytes: Option<u64>,
+    elapsed_secs: f64,
+    /// 距离上次收到数据的秒数，用于发现卡住的上传
+    idle_secs: f64,
+}
+
+/// 进行中的上传，按开始时间从早到晚排列
+pub(crate) fn snapshot() -> Vec<UploadSnapshot> {
+    let uploads: Vec<Arc<UploadProgress>> = UPLOADS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .values()
+        .cloned()
+        .collect();
+    let now = Instant::now();
+    let mut snapshots: Vec<(Instant, UploadSnapshot)> = uploads
+        .iter()
+        .map(|upload| {
+            let elapsed = now.duration_since(upload.started);
+            let last_received = upload.last_received_millis.load(Ordering::Relaxed) as f64 / 1000.0;
+            (
+                upload.started,
+                UploadSnapshot {
+                    client: upload.client.clone(),
+                    method: upload.method.clone(),
+                    uri: upload.uri.clone(),
+                    received_bytes: upload.received_bytes.load(Ordering::Relaxed),
+                    total_bytes: upload.total_bytes,
+                    elapsed_secs: elapsed.as_secs_f64(),
+                    idle_secs: (elapsed.as_secs_f64() - last_received).max(0.0),
+                },
+            )
+        })
+        .collect();
+    snapshots.sort_by_key(|(started, _)| *started);
+    snapshots.into_iter().map(|(_, snapshot)| snapshot).collect()
+}
+
+/// 按 `--upload-progress-min-bytes` 决定是否跟踪请求体
+pub(crate) fn track<B: Body>(req: Request<B>, client: SocketAddr) -> Request<UploadProgressBody<B>> {
+    track_with(req, client, crate::CONFIG.upload_progress_min_bytes)
+}
+
+fn track_with<B: Body>(req: Request<B>, client: SocketAddr, min_bytes: Option<u64>) -> Request<UploadProgressBody<B>> {
+    let body = req.body();
+    let tracked = min_bytes.is_some_and(|min_bytes| {
+        !body.is_end_stream() && body.size_hint().exact().map_or(true, |len| len >= min_bytes)
+    });
+    let guard = tracked.then(|| {
+        let progress = Arc::new(UploadProgress {
+            client: SocketAddrFormat(&client).to_string(),
+            method: req.method().to_string(),
+            uri: req.uri().to_string(),
+            total_bytes: body.size_hint().exact(),
+            received_bytes: AtomicU64::new(0),
+            started: Instant::now(),
+            last_received_millis: AtomicU64::new(0),
+        });
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        UPLOADS
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(id, progress.clone());
+        METRICS.upload_in_progress.inc();
+        UploadGuard { id, progress }
+    });
+    req.map(|inner| UploadProgressBody { inner, guard })
+}
+
+/// 请求体读完或被丢弃时从进行中的上传中移除
+#[derive(Debug)]
+struct UploadGuard {
+    id: u64,
+    progress: Arc<UploadProgress>,
+}
+
+impl Drop for UploadGuard {
+    fn drop(&mut self) {
+        UPLOADS.lock().unwrap_or_else(|e| e.into_inner()).remove(&self.id);
+        METRICS.upload_in_progress.dec();
+        METRICS
+            .upload_bytes
+            .dec_by(self.progress.received_bytes.load(Ordering::Relaxed) as i64);
+    }
+}
+
+pin_project! {
+    /// 不跟踪时只是透传
+    #[derive(Debug)]
+    pub(crate) struct UploadProgressBody<B> {
+        #[pin]
+        inner: B,
+        guard: Option<UploadGuard>,
+    }
+}
+
+impl<B> Body for UploadProgressBody<B>
+where
+    B: Body<Data = Bytes>,
+{
+    type Data = Bytes;
+    type Error = B::Error;
+
+    fn poll_frame(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = self.project();
+        let poll = this.inner.poll_frame(cx);
+        match (&poll, this.guard.as_ref()) {
+            (Poll::Ready(Some(Ok(frame))), Some(guard)) => {
+                if let Some(data) = frame.data_ref() {
+                    let progress = &guard.progress;
+                    progress.received_bytes.fetch_add(data.len() as u64, Ordering::Relaxed);
+                    progress
+                        .last_received_millis
+                        .store(progress.started.elapsed().as_millis() as u64, Ordering::Relaxed);
+                    METRICS.upload_bytes.inc_by(data.len() as i64);
+                }
+            }
+            (Poll::Ready(None), Some(_)) => *this.guard = None,
+            _ => {}
+        }
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::{BodyExt, Full, StreamBody};
+
+    #[tokio::test]
+    async fn test_track_progress() -> Result<(), Box<dyn std::error::Error>> {
+        let client = SocketAddr::from(([127, 0, 0, 1], 12345));
+        let small = track_with(Request::post("/small").body(Full::new(Bytes::from("abc")))?, client, Some(1024));
+        assert!(small.body().guard.is_none());
+
+        let frames = vec![Ok::<_, std::convert::Infallible>(Frame::data(Bytes::from("hello")))];
+        let stream = futures_util::stream::iter(frames);
+        let req = track_with(Request::put("/chunked").body(StreamBody::new(stream))?, client, Some(1024));
+        let mut body = req.into_body();
+        let bytes_before = METRICS.upload_bytes.get();
+        body.frame().await.ok_or("no frame")??;
+        assert_eq!(METRICS.upload_bytes.get(), bytes_before + 5);
+        let uploads = snapshot();
+        let upload = uploads
+            .iter()
+            .find(|upload| upload.uri == "/chunked")
+            .ok_or("upload is not tracked")?;
+        assert_eq!(upload.received_bytes, 5);
+        assert_eq!(upload.total_bytes, None);
+        assert!(body.frame().await.is_none());
+        assert!(snapshot().iter().all(|upload| upload.uri != "/chunked"));
+        // 上传结束后不再计入
+        assert_eq!(METRICS.upload_bytes.get(), bytes_before);
+        Ok(())
+    }
+}
@@ -0,0 +1,138 @@
+//! `--upstream-ca-dir`：从目录加载额外信任的CA证书，用于校验反向代理和远程配置的上游证书
+//!
+//! 启动时读取目录（不递归）中所有 `.pem`、`.crt` 文件里的证书，任何一个文件无法解析都会启动失败。
+//! `--upstream-ca-mode add` 在系统证书（或 `--use-webpki-roots` 的Mozilla根证书）之外额外信任这些证书，
+//! `only` 只信任这些证书，适用于只访问内部PKI签发的上游。
+
+use std::{
+    fs::File,
+    io::{self, BufReader},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use log::info;
+use rustls_platform_verifier::Verifier;
+use tokio_rustls::rustls::{pki_types::CertificateDer, ClientConfig, RootCertStore};
+
+use crate::config::UpstreamCaMode;
+use crate::DynError;
+
+/// 未设置 `--upstream-ca-dir` 时为空，使用默认的证书校验
+#[derive(Clone, Debug, Default)]
+pub struct UpstreamCa {
+    certs: Arc<Vec<CertificateDer<'static>>>,
+    mode: UpstreamCaMode,
+}
+
+impl UpstreamCa {
+    pub(crate) fn load(dir: Option<&str>, mode: UpstreamCaMode) -> Result<Self, DynError> {
+        let Some(dir) = dir else {
+            if mode == UpstreamCaMode::Only {
+                return Err("--upstream-ca-mode only requires --upstream-ca-dir".into());
+            }
+            return Ok(UpstreamCa::default());
+        };
+        let mut certs = vec![];
+        for path in ca_files(Path::new(dir))? {
+            certs.extend(load_file(&path)?);
+        }
+        if certs.is_empty() {
+            return Err(format!("no .pem or .crt file in --upstream-ca-dir {dir}").into());
+        }
+        info!("trust {} upstream CA certificates from {dir} ({mode:?})", certs.len());
+        Ok(UpstreamCa {
+            certs: Arc::new(certs),
+            mode,
+        })
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.certs.is_empty()
+    }
+
+    /// `use_webpki_roots` 为true时以Mozilla根证书代替系统的证书
+    pub(crate) fn client_config(&self, use_webpki_roots: bool) -> io::Result<ClientConfig> {
+        let builder = ClientConfig::builder();
+        let builder = match self.mode {
+            UpstreamCaMode::Add if !use_webpki_roots => {
+                let verifier =
+                    Verifier::new_with_extra_roots(self.certs.iter().cloned(), builder.crypto_provider().clone())
+                        .map_err(io::Error::other)?;
+                builder.dangerous().with_custom_certificate_verifier(Arc::new(verifier))
+            }
+            mode => {
+                let mut roots = RootCertStore::empty();
+                if mode == UpstreamCaMode::Add {
+                    roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+                }
+                // 证书在加载时已经校验过
+                roots.add_parsable_certificates(self.certs.iter().cloned());
+                builder.with_root_certificates(roots)
+            }
+        };
+        Ok(builder.with_no_client_auth())
+    }
+}
+
+/// 按文件名排序，便于定位出错的文件
+fn ca_files(dir: &Path) -> Result<Vec<PathBuf>, DynError> {
+    let mut files = vec![];
+    for entry in std::fs::read_dir(dir).map_err(|e| format!("read --upstream-ca-dir {} error: {e}", dir.display()))? {
+        let path = entry?.path();
+        let is_ca = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("pem") || ext.eq_ignore_ascii_case("crt"));
+        if is_ca && path.is_file() {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+fn load_file(path: &Path) -> Result<Vec<CertificateDer<'static>>, DynError> {
+    let invalid = |e: &dyn std::fmt::Display| format!("invalid CA certificate file {}: {e}", path.display());
+    let file = File::open(path).map_err(|e| invalid(&e))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| invalid(&e))?;
+    if certs.is_empty() {
+        return Err(invalid(&"no PEM certificate found").into());
+    }
+    // 校验证书可以作为信任锚，避免到请求上游时才失败
+    let mut roots = RootCertStore::empty();
+    for cert in &certs {
+        roots.add(cert.clone()).map_err(|e| invalid(&e))?;
+    }
+    Ok(certs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_tls::CA_PEM;
+
+    #[test]
+    fn test_load_dir() -> Result<(), DynError> {
+        let dir = std::env::temp_dir().join(format!("upstream_ca_{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        std::fs::write(dir.join("a.pem"), CA_PEM)?;
+        std::fs::write(dir.join("b.CRT"), CA_PEM)?;
+        std::fs::write(dir.join("README"), "not a certificate")?;
+        let dir_str = dir.to_string_lossy().to_string();
+
+        let upstream_ca = UpstreamCa::load(Some(&dir_str), UpstreamCaMode::Only)?;
+        assert_eq!(upstream_ca.certs.len(), 2);
+        upstream_ca.client_config(false)?;
+        assert!(UpstreamCa::load(None, UpstreamCaMode::Add)?.is_empty());
+        assert!(UpstreamCa::load(None, UpstreamCaMode::Only).is_err());
+
+        std::fs::write(dir.join("c.pem"), "-----BEGIN CERTIFICATE-----\nbroken\n-----END CERTIFICATE-----\n")?;
+        let result = UpstreamCa::load(Some(&dir_str), UpstreamCaMode::Add);
+        std::fs::remove_dir_all(&dir)?;
+        assert!(result.is_err_and(|e| e.to_string().contains("c.pem")));
+        Ok(())
+    }
+}
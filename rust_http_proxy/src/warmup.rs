@@ -0,0 +1,109 @@
+//! location的 `warmup_connections`：启动和重新加载配置后，预先建立到上游的空闲连接
+//!
+//! 并发向 `upstream`（以及 `ip_hash_upstreams`）的 `url_base` 发送N个HEAD请求，响应结束后连接留在连接池中，
+//! 第一批用户请求不需要等待建连和TLS握手。N不超过该location连接池的大小（`pool_max_idle_per_host`）；
+//! 空闲连接90秒后仍会被关闭，因此只对启动或重新加载后的首批请求有效。预热失败只记录日志。
+
+use std::sync::{Arc, LazyLock};
+
+use futures_util::future::join_all;
+use http::header::HOST;
+use http::{HeaderValue, Method, Request};
+use log::{info, warn};
+use tokio::sync::watch;
+
+use crate::proxy::{empty_body, ProxyHandler, ReverseClient};
+use crate::reverse::{LocationConfig, Upstream, Version};
+
+static RELOADED: LazyLock<watch::Sender<()>> = LazyLock::new(|| watch::channel(()).0);
+
+/// 替换反向代理配置后调用，通知预热新的配置
+pub(crate) fn notify_reloaded() {
+    RELOADED.send_replace(());
+}
+
+/// 预热当前的配置，之后每次重新加载配置时再次预热
+pub(crate) fn spawn(proxy_handler: Arc<ProxyHandler>) {
+    let mut reloaded = RELOADED.subscribe();
+    tokio::spawn(async move {
+        loop {
+            warmup_all(&proxy_handler).await;
+            if reloaded.changed().await.is_err() {
+                return;
+            }
+        }
+    });
+}
+
+async fn warmup_all(proxy_handler: &ProxyHandler) {
+    let config = crate::reverse::current_config();
+    let tasks = config
+        .locations
+        .values()
+        .flatten()
+        .filter(|location_config| !location_config.opaque_upstream_body)
+        .filter_map(|location_config| {
+            let connections = warmup_size(location_config)?;
+            let client = proxy_handler.reverse_client_for(location_config);
+            Some(
+                std::iter::once(&location_config.upstream)
+                    .chain(&location_config.ip_hash_upstreams)
+                    .map(move |upstream| warmup(client.clone(), upstream, connections)),
+            )
+        })
+        .flatten();
+    join_all(tasks).await;
+}
+
+/// 不超过连接池的大小，多余的连接在响应结束后会被直接关闭
+fn warmup_size(location_config: &LocationConfig) -> Option<usize> {
+    let connections = location_config.warmup_connections?;
+    let pool_size = location_config
+        .pool_max_idle_per_host
+        .unwrap_or(crate::CONFIG.pool_max_idle_per_host);
+    if connections > pool_size {
+        warn!(
+            "warmup_connections {connections} of location {} exceeds the pool size {pool_size}, only {pool_size} will be kept",
+            location_config.location
+        );
+    }
+    Some(connections.min(pool_size)).filter(|connections| *connections > 0)
+}
+
+async fn warmup(client: ReverseClient, upstream: &Upstream, connections: usize) {
+    let url_base = match upstream.srv.url_base(&upstream.url_base).await {
+        Ok(url_base) => url_base.into_owned(),
+        Err(e) => {
+            warn!("[warmup] {} is unavailable: {e}", upstream.url_base);
+            return;
+        }
+    };
+    // HTTP/2的请求复用同一个连接
+    let (version, connections) = match upstream.version {
+        Version::H2 => (http::Version::HTTP_2, 1),
+        _ => (http::Version::HTTP_11, connections),
+    };
+    let requests = (0..connections).map(|_| {
+        let client = client.clone();
+        let url_base = url_base.clone();
+        async move {
+            let mut req = Request::builder()
+                .method(Method::HEAD)
+                .uri(&url_base)
+                .version(version)
+                .body(empty_body())
+                .map_err(|e| e.to_string())?;
+            if let Some(authority_override) = &upstream.authority_override {
+                let host = HeaderValue::from_str(authority_override).map_err(|e| e.to_string())?;
+                req.headers_mut().insert(HOST, host);
+            }
+            client.request(req).await.map(drop).map_err(|e| e.to_string())
+        }
+    });
+    let results = join_all(requests).await;
+    let ok = results.iter().filter(|result| result.is_ok()).count();
+    match results.into_iter().find_map(Result::err) {
+        None => info!("[warmup] {url_base}: {ok} connections"),
+        Some(e) => warn!("[warmup] {url_base}: {ok}/{connections} connections, error: {e}"),
+    }
+}